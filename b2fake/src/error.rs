@@ -0,0 +1,189 @@
+//! Errors returned by the fake server in the shape used by the B2 API and the
+//! S3-compatible API
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// An error response as documented by Backblaze
+///
+/// Every error from the B2 API is a JSON object containing the HTTP status, a
+/// machine-readable code and a human-readable message.
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    /// The HTTP status code of the response
+    pub(crate) status: StatusCode,
+    /// The machine-readable error code
+    pub(crate) code: &'static str,
+    /// The human-readable error message
+    pub(crate) message: String,
+}
+
+impl ApiError {
+    /// Create a new error from its parts
+    pub(crate) fn new<S: Into<String>>(
+        status: StatusCode,
+        code: &'static str,
+        message: S,
+    ) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// A `400 bad_request` error
+    pub(crate) fn bad_request<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    /// A `400 bad_bucket_id` error
+    pub(crate) fn bad_bucket_id(bucket_id: &str) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "bad_bucket_id",
+            format!("Invalid bucketId: {bucket_id}"),
+        )
+    }
+
+    /// A `401 bad_auth_token` error
+    pub(crate) fn bad_auth_token() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            "bad_auth_token",
+            "Invalid authorization token",
+        )
+    }
+
+    /// A `401 expired_auth_token` error
+    pub(crate) fn expired_auth_token() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            "expired_auth_token",
+            "Authorization token has expired",
+        )
+    }
+
+    /// A `401 unauthorized` error
+    pub(crate) fn unauthorized<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    /// A `404 not_found` error
+    pub(crate) fn not_found<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    /// A `503 service_unavailable` error
+    pub(crate) fn service_unavailable() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            "Service temporarily unavailable",
+        )
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "status": self.status.as_u16(),
+            "code": self.code,
+            "message": self.message,
+        });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// An error response in the shape used by the S3-compatible API
+///
+/// S3 errors are XML documents with a code and a message, and their status
+/// is only carried by the HTTP response.
+#[derive(Debug)]
+pub(crate) struct S3Error {
+    /// The HTTP status code of the response
+    pub(crate) status: StatusCode,
+    /// The machine-readable error code, such as `NoSuchKey`
+    pub(crate) code: &'static str,
+    /// The human-readable error message
+    pub(crate) message: String,
+}
+
+impl S3Error {
+    /// Create a new error from its parts
+    pub(crate) fn new<S: Into<String>>(
+        status: StatusCode,
+        code: &'static str,
+        message: S,
+    ) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// A `403 AccessDenied` error
+    pub(crate) fn access_denied<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "AccessDenied", message)
+    }
+
+    /// A `400 InvalidArgument` error
+    pub(crate) fn invalid_argument<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+    }
+
+    /// A `404 NoSuchBucket` error
+    pub(crate) fn no_such_bucket(name: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchBucket",
+            format!("The specified bucket does not exist: {name}"),
+        )
+    }
+
+    /// A `404 NoSuchKey` error
+    pub(crate) fn no_such_key(key: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchKey",
+            format!("The specified key does not exist: {key}"),
+        )
+    }
+
+    /// A `404 NoSuchUpload` error
+    pub(crate) fn no_such_upload(upload_id: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchUpload",
+            format!("The specified upload does not exist: {upload_id}"),
+        )
+    }
+
+    /// A `501 NotImplemented` error
+    pub(crate) fn not_implemented(operation: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_IMPLEMENTED,
+            "NotImplemented",
+            format!("{operation} is not supported by the fake server"),
+        )
+    }
+}
+
+impl IntoResponse for S3Error {
+    fn into_response(self) -> Response {
+        let body = format!(
+            "<?xml version=\"1.0\" \
+             encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Message>{}</\
+             Message></Error>",
+            self.code,
+            quick_xml::escape::escape(self.message.as_str()),
+        );
+        (self.status, [(header::CONTENT_TYPE, "application/xml")], body)
+            .into_response()
+    }
+}
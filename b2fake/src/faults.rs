@@ -0,0 +1,119 @@
+//! Fault injection
+//!
+//! Faults are queued against an endpoint name (or against every endpoint) and
+//! are consumed by the requests that trigger them, oldest first.
+
+use std::{sync::Mutex, time::Duration};
+
+/// A failure the fake server can simulate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Respond with `503 service_unavailable` without handling the request
+    ServiceUnavailable,
+    /// Respond with `401 expired_auth_token` without handling the request
+    ///
+    /// This fault never applies to ``b2_authorize_account``, which does not
+    /// take an authorization token.
+    ExpiredToken,
+    /// Wait for the given duration before handling the request
+    Delay(Duration),
+    /// Handle the request, but cut the response body off halfway through
+    ///
+    /// The response still advertises the full `Content-Length`, so clients
+    /// see the connection close before the body is complete.
+    TruncatedBody,
+    /// Handle the request, but send the response body in chunks without a
+    /// `Content-Length`
+    ChunkedBody,
+}
+
+/// A queued fault waiting to be triggered
+#[derive(Debug)]
+struct Rule {
+    /// The endpoint the fault applies to, or `None` for every endpoint
+    endpoint: Option<String>,
+    /// The fault to trigger
+    fault: Fault,
+    /// How many more requests will trigger this fault
+    remaining: usize,
+}
+
+/// The set of faults queued on a server
+#[derive(Debug, Default)]
+pub(crate) struct Faults {
+    /// Queued faults in the order they were injected
+    rules: Mutex<Vec<Rule>>,
+}
+
+impl Faults {
+    /// Queue a fault for the next `count` matching requests
+    pub(crate) fn inject(
+        &self,
+        endpoint: Option<&str>,
+        fault: Fault,
+        count: usize,
+    ) {
+        if count == 0 {
+            return;
+        }
+        self.rules.lock().expect("Fault rules poisoned").push(Rule {
+            endpoint: endpoint.map(str::to_owned),
+            fault,
+            remaining: count,
+        });
+    }
+
+    /// Remove every queued fault
+    pub(crate) fn clear(&self) {
+        self.rules.lock().expect("Fault rules poisoned").clear();
+    }
+
+    /// Consume the first fault queued for a request to `endpoint`
+    pub(crate) fn take(&self, endpoint: &str) -> Option<Fault> {
+        let mut rules = self.rules.lock().expect("Fault rules poisoned");
+        let index = rules.iter().position(|rule| {
+            rule.endpoint.as_deref().is_none_or(|e| e == endpoint)
+                && !(rule.fault == Fault::ExpiredToken
+                    && endpoint == "b2_authorize_account")
+        })?;
+        let rule = &mut rules[index];
+        rule.remaining -= 1;
+        let fault = rule.fault.clone();
+        if rule.remaining == 0 {
+            rules.remove(index);
+        }
+        Some(fault)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::faults::{Fault, Faults};
+
+    #[test]
+    fn faults_are_consumed_in_order() {
+        let faults = Faults::default();
+        faults.inject(Some("b2_upload_file"), Fault::TruncatedBody, 1);
+        faults.inject(None, Fault::Delay(Duration::from_millis(5)), 2);
+        assert_eq!(faults.take("b2_upload_file"), Some(Fault::TruncatedBody));
+        assert_eq!(
+            faults.take("b2_upload_file"),
+            Some(Fault::Delay(Duration::from_millis(5)))
+        );
+        assert_eq!(
+            faults.take("b2_list_buckets"),
+            Some(Fault::Delay(Duration::from_millis(5)))
+        );
+        assert_eq!(faults.take("b2_list_buckets"), None);
+    }
+
+    #[test]
+    fn expired_token_skips_authorization() {
+        let faults = Faults::default();
+        faults.inject(None, Fault::ExpiredToken, 1);
+        assert_eq!(faults.take("b2_authorize_account"), None);
+        assert_eq!(faults.take("b2_list_buckets"), Some(Fault::ExpiredToken));
+    }
+}
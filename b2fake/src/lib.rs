@@ -0,0 +1,454 @@
+#![doc = include_str!("../README.md")]
+
+mod error;
+mod faults;
+mod routes;
+mod state;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub use faults::Fault;
+use serde_json::json;
+use tokio::{net::TcpListener, task::JoinHandle};
+
+use crate::{routes::App, state::State};
+
+/// Configuration for a fake server
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How long authorization tokens stay valid
+    pub token_lifetime: Duration,
+    /// The smallest part of a large file, other than the last, the server
+    /// accepts
+    pub absolute_minimum_part_size: usize,
+    /// The part size the server recommends to clients
+    pub recommended_part_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            token_lifetime: Duration::from_hours(24),
+            absolute_minimum_part_size: 5_000_000,
+            recommended_part_size: 100_000_000,
+        }
+    }
+}
+
+/// A description of an application key to add to a fake server
+#[derive(Clone, Debug, Default)]
+pub struct KeySpec {
+    /// The human-readable name of the key
+    pub name: String,
+    /// The capabilities of the key, such as `listBuckets`
+    pub capabilities: Vec<String>,
+    /// The bucket the key is restricted to
+    pub bucket_id: Option<String>,
+    /// The file name prefix the key is restricted to
+    pub name_prefix: Option<String>,
+    /// How long the key stays valid, if it expires
+    pub valid_for: Option<Duration>,
+}
+
+/// An application key ID and its secret
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    /// The application key ID
+    pub key_id: String,
+    /// The application key
+    pub key: String,
+}
+
+/// A running fake B2 server
+///
+/// The server listens on an ephemeral port on `127.0.0.1` and stops when this
+/// handle is dropped.
+#[derive(Debug)]
+pub struct FakeB2 {
+    /// Shared state behind every request
+    app: Arc<App>,
+    /// The address the server is listening on
+    address: SocketAddr,
+    /// The task running the server
+    task: JoinHandle<()>,
+}
+
+impl FakeB2 {
+    /// Start a fake server with the default configuration
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no local port could be bound.
+    pub async fn start() -> io::Result<Self> {
+        Self::start_with(Config::default()).await
+    }
+
+    /// Start a fake server with a custom configuration
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if no local port could be bound.
+    pub async fn start_with(config: Config) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let address = listener.local_addr()?;
+        let app = Arc::new(App {
+            state: Mutex::new(State::new(config)),
+            faults: faults::Faults::default(),
+            calls: Mutex::new(HashMap::new()),
+            base_url: format!("http://{address}"),
+        });
+        let router = routes::router(app.clone());
+        let task = tokio::spawn(async move {
+            if let Err(error) = axum::serve(listener, router).await {
+                tracing::error!(%error, "Fake B2 server stopped");
+            }
+        });
+        Ok(Self {
+            app,
+            address,
+            task,
+        })
+    }
+
+    /// The address the server is listening on
+    #[must_use]
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The base URL of the server, used for API calls and downloads
+    #[must_use]
+    pub fn base_url(&self) -> &str {
+        &self.app.base_url
+    }
+
+    /// The URL of the S3-compatible API, which serves buckets path-style
+    ///
+    /// Requests are signed with AWS Signature Version 4, using an application
+    /// key ID as the access key and the application key as the secret. Any
+    /// region is accepted.
+    #[must_use]
+    pub fn s3_url(&self) -> String {
+        format!("{}/s3", self.app.base_url)
+    }
+
+    /// The URL of the ``b2_authorize_account`` endpoint
+    #[must_use]
+    pub fn authorize_url(&self) -> String {
+        format!("{}/b2api/v3/b2_authorize_account", self.app.base_url)
+    }
+
+    /// The identifier of the account the server hosts
+    #[must_use]
+    pub fn account_id(&self) -> String {
+        self.app.state().account_id.clone()
+    }
+
+    /// The credentials of the account's master application key
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous request panicked while holding the server state.
+    #[must_use]
+    pub fn master_credentials(&self) -> Credentials {
+        let state = self.app.state();
+        let master = state
+            .keys
+            .get(&state.account_id)
+            .expect("The master key always exists");
+        Credentials {
+            key_id: master.id.clone(),
+            key: master.secret.clone(),
+        }
+    }
+
+    /// Add an application key and return its credentials
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous request panicked while holding the server state.
+    #[must_use]
+    pub fn add_key(&self, spec: KeySpec) -> Credentials {
+        let key = self.app.state().create_key(
+            spec.name,
+            spec.capabilities,
+            spec.bucket_id,
+            spec.name_prefix,
+            spec.valid_for,
+        );
+        Credentials {
+            key_id: key.id,
+            key: key.secret,
+        }
+    }
+
+    /// Remove an application key, revoking every token issued for it
+    ///
+    /// Returns whether the key existed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous request panicked while holding the server state.
+    #[must_use]
+    pub fn revoke_key(&self, key_id: &str) -> bool {
+        self.app.state().keys.remove(key_id).is_some()
+    }
+
+    /// Create a bucket and return its ID
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is invalid or already in use.
+    #[must_use]
+    pub fn create_bucket(&self, name: &str, bucket_type: &str) -> String {
+        self.app
+            .state()
+            .create_bucket(name, bucket_type, json!({}), json!([]))
+            .expect("Bucket name should be valid and unused")
+            .id
+    }
+
+    /// Queue a fault for the next `count` requests to `endpoint`
+    ///
+    /// Endpoints are named after the B2 API calls, such as
+    /// ``b2_upload_file`` or ``b2_download_file_by_name``. Passing `None`
+    /// applies the fault to requests to any endpoint.
+    pub fn inject(&self, endpoint: Option<&str>, fault: Fault, count: usize) {
+        self.app.faults.inject(endpoint, fault, count);
+    }
+
+    /// Remove every queued fault
+    pub fn clear_faults(&self) {
+        self.app.faults.clear();
+    }
+
+    /// Make every authorization token issued so far expire immediately
+    pub fn expire_tokens(&self) {
+        self.app.state().expire_tokens();
+    }
+
+    /// The number of requests received by an endpoint so far
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous request panicked while counting calls.
+    #[must_use]
+    pub fn calls(&self, endpoint: &str) -> usize {
+        self.app
+            .calls
+            .lock()
+            .expect("Call counts poisoned")
+            .get(endpoint)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for FakeB2 {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::{Client, StatusCode};
+    use serde_json::{json, Value};
+
+    use crate::{FakeB2, Fault};
+
+    async fn authorize(server: &FakeB2, client: &Client) -> Value {
+        let credentials = server.master_credentials();
+        client
+            .get(server.authorize_url())
+            .basic_auth(credentials.key_id, Some(credentials.key))
+            .send()
+            .await
+            .expect("Authorization should be sent")
+            .json()
+            .await
+            .expect("Authorization should be JSON")
+    }
+
+    async fn call(
+        client: &Client,
+        auth: &Value,
+        endpoint: &str,
+        body: Value,
+    ) -> reqwest::Response {
+        let url = format!(
+            "{}/b2api/v3/{endpoint}",
+            auth["apiInfo"]["storageApi"]["apiUrl"]
+                .as_str()
+                .expect("apiUrl is a string")
+        );
+        client
+            .post(url)
+            .header(
+                "Authorization",
+                auth["authorizationToken"].as_str().expect("token is a string"),
+            )
+            .json(&body)
+            .send()
+            .await
+            .expect("Request should be sent")
+    }
+
+    async fn upload(
+        client: &Client,
+        auth: &Value,
+        bucket_id: &str,
+        name: &str,
+        data: &'static [u8],
+    ) -> Value {
+        let target: Value = call(
+            client,
+            auth,
+            "b2_get_upload_url",
+            json!({ "bucketId": bucket_id }),
+        )
+        .await
+        .json()
+        .await
+        .expect("Upload URL should be JSON");
+        client
+            .post(target["uploadUrl"].as_str().expect("uploadUrl is a string"))
+            .header(
+                "Authorization",
+                target["authorizationToken"]
+                    .as_str()
+                    .expect("token is a string"),
+            )
+            .header("X-Bz-File-Name", name)
+            .header("Content-Type", "text/plain")
+            .header("X-Bz-Content-Sha1", crate::state::sha1_hex(data))
+            .header("X-Bz-Info-Author", "test")
+            .body(data)
+            .send()
+            .await
+            .expect("Upload should be sent")
+            .json()
+            .await
+            .expect("Upload response should be JSON")
+    }
+
+    #[tokio::test]
+    async fn authorize_rejects_wrong_key() {
+        let server = FakeB2::start().await.expect("Server should start");
+        let response = Client::new()
+            .get(server.authorize_url())
+            .basic_auth("wrong", Some("wrong"))
+            .send()
+            .await
+            .expect("Authorization should be sent");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body: Value = response.json().await.expect("Error should be JSON");
+        assert_eq!(body["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn upload_and_download() {
+        let server = FakeB2::start().await.expect("Server should start");
+        let bucket_id = server.create_bucket("fake-bucket", "allPrivate");
+        let client = Client::new();
+        let auth = authorize(&server, &client).await;
+        let file =
+            upload(&client, &auth, &bucket_id, "dir/hello.txt", b"hello world")
+                .await;
+        assert_eq!(file["action"], "upload");
+        assert_eq!(file["contentLength"], 11);
+        assert_eq!(file["fileInfo"]["author"], "test");
+
+        let download = client
+            .get(format!(
+                "{}/file/fake-bucket/dir/hello.txt",
+                server.base_url()
+            ))
+            .header(
+                "Authorization",
+                auth["authorizationToken"].as_str().expect("token is a string"),
+            )
+            .header("Range", "bytes=6-")
+            .send()
+            .await
+            .expect("Download should be sent");
+        assert_eq!(download.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            download.text().await.expect("Download should be text"),
+            "world"
+        );
+
+        let listing: Value = call(
+            &client,
+            &auth,
+            "b2_list_file_names",
+            json!({ "bucketId": bucket_id, "delimiter": "/" }),
+        )
+        .await
+        .json()
+        .await
+        .expect("Listing should be JSON");
+        assert_eq!(listing["files"][0]["fileName"], "dir/");
+        assert_eq!(listing["files"][0]["action"], "folder");
+    }
+
+    #[tokio::test]
+    async fn faults_are_injected() {
+        let server = FakeB2::start().await.expect("Server should start");
+        let client = Client::new();
+        let auth = authorize(&server, &client).await;
+        let body = json!({ "accountId": server.account_id() });
+
+        server.inject(Some("b2_list_buckets"), Fault::ServiceUnavailable, 1);
+        let response =
+            call(&client, &auth, "b2_list_buckets", body.clone()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        server.inject(None, Fault::ExpiredToken, 1);
+        let response =
+            call(&client, &auth, "b2_list_buckets", body.clone()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        server.inject(Some("b2_list_buckets"), Fault::TruncatedBody, 1);
+        let truncated = client
+            .post(format!("{}/b2api/v3/b2_list_buckets", server.base_url()))
+            .header(
+                "Authorization",
+                auth["authorizationToken"].as_str().expect("token is a string"),
+            )
+            .json(&body)
+            .send()
+            .await;
+        match truncated {
+            Ok(response) => assert!(response.bytes().await.is_err()),
+            Err(error) => assert!(error.is_request()),
+        }
+
+        let response = call(&client, &auth, "b2_list_buckets", body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(server.calls("b2_list_buckets"), 4);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let server = FakeB2::start().await.expect("Server should start");
+        let client = Client::new();
+        let auth = authorize(&server, &client).await;
+        server.expire_tokens();
+        let response = call(
+            &client,
+            &auth,
+            "b2_list_buckets",
+            json!({ "accountId": server.account_id() }),
+        )
+        .await;
+        let body: Value = response.json().await.expect("Error should be JSON");
+        assert_eq!(body["code"], "expired_auth_token");
+    }
+}
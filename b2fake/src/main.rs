@@ -0,0 +1,17 @@
+//! Run a fake B2 server until interrupted
+//!
+//! The server prints its authorization endpoint and master credentials so that
+//! `BackMate`, or any other client, can be pointed at it.
+
+use b2fake::FakeB2;
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let server = FakeB2::start().await?;
+    let credentials = server.master_credentials();
+    println!("Fake B2 server listening on {}", server.base_url());
+    println!("B2NATIVE_AUTHORIZE_ACCOUNT_ENDPOINT={}", server.authorize_url());
+    println!("Application key ID: {}", credentials.key_id);
+    println!("Application key: {}", credentials.key);
+    tokio::signal::ctrl_c().await
+}
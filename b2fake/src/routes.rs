@@ -0,0 +1,264 @@
+//! HTTP routing for the fake server
+//!
+//! API calls are served under `/b2api/v3/`, uploads go to per-URL paths under
+//! ``/b2api/v3/b2_upload_file`` and ``/b2api/v3/b2_upload_part``, and downloads
+//! by name are served under `/file/`. The S3-compatible API is served
+//! path-style under `/s3/`.
+
+mod account;
+mod buckets;
+mod downloads;
+mod files;
+mod keys;
+mod large_files;
+mod s3;
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State as Extract},
+    http::{header, HeaderMap, HeaderValue, Method},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::ApiError,
+    faults::{Fault, Faults},
+    state::State,
+};
+
+/// Shared state behind every request to a server
+#[derive(Debug)]
+pub(crate) struct App {
+    /// The account contents
+    pub(crate) state: Mutex<State>,
+    /// Queued faults
+    pub(crate) faults: Faults,
+    /// The number of requests received per endpoint
+    pub(crate) calls: Mutex<HashMap<String, usize>>,
+    /// The base URL the server is reachable at
+    pub(crate) base_url: String,
+}
+
+impl App {
+    /// Lock the account contents
+    pub(crate) fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Fake B2 state poisoned")
+    }
+}
+
+/// The shared application handed to every handler
+pub(crate) type SharedApp = Arc<App>;
+
+/// Build the router serving every endpoint
+pub(crate) fn router(app: SharedApp) -> Router {
+    Router::new()
+        .route(
+            "/b2api/v3/b2_authorize_account",
+            get(account::authorize_account),
+        )
+        .route("/b2api/v3/b2_create_bucket", post(buckets::create_bucket))
+        .route("/b2api/v3/b2_delete_bucket", post(buckets::delete_bucket))
+        .route("/b2api/v3/b2_list_buckets", post(buckets::list_buckets))
+        .route("/b2api/v3/b2_get_upload_url", post(files::get_upload_url))
+        .route("/b2api/v3/b2_upload_file/{url_id}", post(files::upload_file))
+        .route("/b2api/v3/b2_list_file_names", post(files::list_file_names))
+        .route(
+            "/b2api/v3/b2_list_file_versions",
+            post(files::list_file_versions),
+        )
+        .route("/b2api/v3/b2_get_file_info", post(files::get_file_info))
+        .route("/b2api/v3/b2_copy_file", post(files::copy_file))
+        .route("/b2api/v3/b2_hide_file", post(files::hide_file))
+        .route(
+            "/b2api/v3/b2_delete_file_version",
+            post(files::delete_file_version),
+        )
+        .route(
+            "/b2api/v3/b2_start_large_file",
+            post(large_files::start_large_file),
+        )
+        .route(
+            "/b2api/v3/b2_get_upload_part_url",
+            post(large_files::get_upload_part_url),
+        )
+        .route(
+            "/b2api/v3/b2_upload_part/{url_id}",
+            post(large_files::upload_part),
+        )
+        .route("/b2api/v3/b2_copy_part", post(large_files::copy_part))
+        .route("/b2api/v3/b2_list_parts", post(large_files::list_parts))
+        .route(
+            "/b2api/v3/b2_list_unfinished_large_files",
+            post(large_files::list_unfinished_large_files),
+        )
+        .route(
+            "/b2api/v3/b2_finish_large_file",
+            post(large_files::finish_large_file),
+        )
+        .route(
+            "/b2api/v3/b2_cancel_large_file",
+            post(large_files::cancel_large_file),
+        )
+        .route(
+            "/b2api/v3/b2_get_download_authorization",
+            post(downloads::get_download_authorization),
+        )
+        .route(
+            "/b2api/v3/b2_download_file_by_id",
+            get(downloads::download_file_by_id),
+        )
+        .route(
+            "/file/{bucket_name}/{*file_name}",
+            get(downloads::download_file_by_name),
+        )
+        .route("/b2api/v3/b2_create_key", post(keys::create_key))
+        .route("/b2api/v3/b2_list_keys", post(keys::list_keys))
+        .route("/b2api/v3/b2_delete_key", post(keys::delete_key))
+        .route("/s3/{bucket_name}", get(s3::bucket))
+        .route(
+            "/s3/{bucket_name}/{*file_name}",
+            get(s3::get_object)
+                .put(s3::put_object)
+                .post(s3::post_object)
+                .delete(s3::delete_object),
+        )
+        .layer(middleware::from_fn_with_state(app.clone(), intercept))
+        .with_state(app)
+}
+
+/// The B2 endpoint, or S3 operation, a request belongs to
+fn endpoint_name(method: &Method, path: &str, query: Option<&str>) -> String {
+    if path.starts_with("/s3/") {
+        return s3::operation(method, path, query).to_owned();
+    }
+    if path.starts_with("/file/") {
+        return "b2_download_file_by_name".to_owned();
+    }
+    path.strip_prefix("/b2api/v3/")
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or(path)
+        .to_owned()
+}
+
+/// Count every request and apply any fault queued for its endpoint
+async fn intercept(
+    Extract(app): Extract<SharedApp>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let endpoint = endpoint_name(
+        request.method(),
+        request.uri().path(),
+        request.uri().query(),
+    );
+    *app.calls
+        .lock()
+        .expect("Call counts poisoned")
+        .entry(endpoint.clone())
+        .or_default() += 1;
+    match app.faults.take(&endpoint) {
+        None => next.run(request).await,
+        Some(Fault::Delay(duration)) => {
+            tokio::time::sleep(duration).await;
+            next.run(request).await
+        }
+        Some(Fault::ServiceUnavailable) => {
+            let mut response = ApiError::service_unavailable().into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+            response
+        }
+        Some(Fault::ExpiredToken) => {
+            ApiError::expired_auth_token().into_response()
+        }
+        Some(Fault::TruncatedBody) => truncate(next.run(request).await).await,
+        Some(Fault::ChunkedBody) => chunked(next.run(request).await).await,
+    }
+}
+
+/// Cut a response body off halfway while advertising its full length
+async fn truncate(response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let full = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(full.len()));
+    let half = full.slice(..full.len() / 2);
+    let chunks = [
+        Ok(half),
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Response body truncated by fault injection",
+        )),
+    ];
+    Response::from_parts(
+        parts,
+        Body::from_stream(futures_util::stream::iter(chunks)),
+    )
+}
+
+/// Send a response body in two chunks, without advertising its length
+async fn chunked(response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let full = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let half = full.len() / 2;
+    let chunks: [Result<Bytes, io::Error>; 2] =
+        [Ok(full.slice(..half)), Ok(full.slice(half..))];
+    Response::from_parts(
+        parts,
+        Body::from_stream(futures_util::stream::iter(chunks)),
+    )
+}
+
+/// The value of the `Authorization` header, if present
+pub(crate) fn auth_token(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok())
+}
+
+/// Parse a JSON request body, failing with `400 bad_request`
+pub(crate) fn parse<T: DeserializeOwned>(body: &Bytes) -> Result<T, ApiError> {
+    serde_json::from_slice(body).map_err(|e| {
+        ApiError::bad_request(format!("Invalid request body: {e}"))
+    })
+}
+
+/// Clamp a requested page size to the documented default and maximum
+pub(crate) fn page_size(
+    requested: Option<usize>,
+    default: usize,
+    maximum: usize,
+) -> usize {
+    requested.unwrap_or(default).clamp(1, maximum)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+
+    use crate::routes::endpoint_name;
+
+    #[test]
+    fn endpoint_names() {
+        let name = |path| endpoint_name(&Method::POST, path, None);
+        assert_eq!(name("/b2api/v3/b2_list_buckets"), "b2_list_buckets");
+        assert_eq!(
+            name("/b2api/v3/b2_upload_file/0000000000000001"),
+            "b2_upload_file"
+        );
+        assert_eq!(name("/file/bucket/a/b.txt"), "b2_download_file_by_name");
+        assert_eq!(
+            endpoint_name(&Method::DELETE, "/s3/bucket/a/b.txt", None),
+            "s3_delete_object"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! The ``b2_authorize_account`` endpoint
+
+use axum::{extract::State, http::HeaderMap, Json};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+
+use crate::{
+    error::ApiError,
+    routes::{auth_token, SharedApp},
+};
+
+/// Decode HTTP basic credentials into a key ID and secret
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = auth_token(headers)?.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (key_id, secret) = decoded.split_once(':')?;
+    Some((key_id.to_owned(), secret.to_owned()))
+}
+
+/// Handle ``b2_authorize_account``
+pub(crate) async fn authorize_account(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ApiError> {
+    let (key_id, secret) = basic_credentials(&headers).ok_or_else(|| {
+        ApiError::bad_request("Missing or malformed basic authorization")
+    })?;
+    let mut state = app.state();
+    let key = state.check_credentials(&key_id, &secret)?.clone();
+    let token = state.issue_token(&key.id);
+    let bucket_name = key
+        .bucket_id
+        .as_deref()
+        .and_then(|id| state.buckets.get(id))
+        .map(|bucket| bucket.name.clone());
+    Ok(Json(json!({
+        "accountId": state.account_id,
+        "apiInfo": {
+            "storageApi": {
+                "absoluteMinimumPartSize":
+                    state.config.absolute_minimum_part_size,
+                "apiUrl": app.base_url,
+                "bucketId": key.bucket_id,
+                "bucketName": bucket_name,
+                "capabilities": key.capabilities,
+                "downloadUrl": app.base_url,
+                "infoType": "storageApi",
+                "namePrefix": key.name_prefix,
+                "recommendedPartSize": state.config.recommended_part_size,
+                "s3ApiUrl": format!("{}/s3", app.base_url),
+            },
+        },
+        "applicationKeyExpirationTimestamp": key.expiration_timestamp,
+        "authorizationToken": token,
+    })))
+}
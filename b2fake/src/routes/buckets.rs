@@ -0,0 +1,149 @@
+//! The bucket endpoints
+
+use axum::{body::Bytes, extract::State, http::HeaderMap, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    error::ApiError,
+    routes::{auth_token, parse, SharedApp},
+    state,
+};
+
+/// The ``b2_create_bucket`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CreateBucket {
+    /// The account the bucket is created in
+    account_id: String,
+    /// The name of the new bucket
+    bucket_name: String,
+    /// The type of the new bucket
+    bucket_type: String,
+    /// User data to store with the bucket
+    #[serde(default)]
+    bucket_info: Option<Value>,
+    /// Lifecycle rules for the bucket
+    #[serde(default)]
+    lifecycle_rules: Option<Value>,
+}
+
+/// Handle ``b2_create_bucket``
+pub(crate) async fn create_bucket(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: CreateBucket = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeBuckets")?;
+    if key.bucket_id.is_some() {
+        return Err(ApiError::unauthorized(
+            "Keys restricted to a bucket cannot create buckets",
+        ));
+    }
+    if request.account_id != state.account_id {
+        return Err(ApiError::unauthorized("Wrong account ID"));
+    }
+    let bucket = state.create_bucket(
+        &request.bucket_name,
+        &request.bucket_type,
+        request.bucket_info.unwrap_or_else(|| json!({})),
+        request.lifecycle_rules.unwrap_or_else(|| json!([])),
+    )?;
+    Ok(Json(bucket.to_json(&state.account_id)))
+}
+
+/// The ``b2_delete_bucket`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeleteBucket {
+    /// The account the bucket is in
+    account_id: String,
+    /// The bucket to delete
+    bucket_id: String,
+}
+
+/// Handle ``b2_delete_bucket``
+pub(crate) async fn delete_bucket(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: DeleteBucket = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "deleteBuckets")?;
+    state::State::check_restrictions(&key, &request.bucket_id, None)?;
+    if request.account_id != state.account_id {
+        return Err(ApiError::unauthorized("Wrong account ID"));
+    }
+    state.bucket(&request.bucket_id)?;
+    if state.files.values().any(|f| f.bucket_id == request.bucket_id) {
+        return Err(ApiError::bad_request("Cannot delete non-empty bucket"));
+    }
+    let bucket = state
+        .buckets
+        .remove(&request.bucket_id)
+        .ok_or_else(|| ApiError::bad_bucket_id(&request.bucket_id))?;
+    Ok(Json(bucket.to_json(&state.account_id)))
+}
+
+/// The ``b2_list_buckets`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListBuckets {
+    /// The account to list buckets in
+    account_id: String,
+    /// Only list the bucket with this ID
+    #[serde(default)]
+    bucket_id: Option<String>,
+    /// Only list the bucket with this name
+    #[serde(default)]
+    bucket_name: Option<String>,
+    /// Only list buckets of these types
+    #[serde(default)]
+    bucket_types: Option<Vec<String>>,
+}
+
+/// Handle ``b2_list_buckets``
+pub(crate) async fn list_buckets(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: ListBuckets = parse(&body)?;
+    let state = app.state();
+    let key = state.authorize(auth_token(&headers), "listBuckets")?;
+    if request.account_id != state.account_id {
+        return Err(ApiError::unauthorized("Wrong account ID"));
+    }
+    if let Some(restricted) = &key.bucket_id {
+        let named = request.bucket_name.as_deref().and_then(|name| {
+            state.bucket_by_name(name).map(|bucket| bucket.id.as_str())
+        });
+        let requested = request.bucket_id.as_deref().or(named);
+        if requested != Some(restricted.as_str()) {
+            return Err(ApiError::unauthorized(
+                "Keys restricted to a bucket must list only that bucket",
+            ));
+        }
+    }
+    let types = request.bucket_types.unwrap_or_else(|| {
+        ["allPublic", "allPrivate", "snapshot"].map(str::to_owned).to_vec()
+    });
+    let buckets: Vec<Value> = state
+        .buckets
+        .values()
+        .filter(|bucket| {
+            request.bucket_id.as_ref().is_none_or(|id| *id == bucket.id)
+                && request
+                    .bucket_name
+                    .as_ref()
+                    .is_none_or(|name| *name == bucket.name)
+                && (types.iter().any(|t| t == "all")
+                    || types.contains(&bucket.kind))
+        })
+        .map(|bucket| bucket.to_json(&state.account_id))
+        .collect();
+    Ok(Json(json!({ "buckets": buckets })))
+}
@@ -0,0 +1,316 @@
+//! The download endpoints
+
+use std::collections::HashMap;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::Response,
+    Json,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    error::ApiError,
+    routes::{auth_token, parse, SharedApp},
+    state::{self, now_millis, Action, DownloadAuthorization, FileVersion},
+};
+
+/// The longest a download authorization may be valid for, in seconds
+const MAX_DOWNLOAD_AUTHORIZATION_SECONDS: u64 = 604_800;
+
+/// Characters B2 percent-encodes in file names and file info headers
+const HEADER_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Parse a `bytes=` range against a body of `length` bytes
+///
+/// Returns the inclusive start and end offsets, or `416
+/// range_not_satisfiable` if the range does not overlap the body.
+pub(crate) fn parse_range(
+    range: &str,
+    length: usize,
+) -> Result<(usize, usize), ApiError> {
+    let unsatisfiable = || {
+        ApiError::new(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "range_not_satisfiable",
+            format!("Range {range} not satisfiable for length {length}"),
+        )
+    };
+    let spec = range.trim().strip_prefix("bytes=").ok_or_else(|| {
+        ApiError::bad_request(format!("Invalid range: {range}"))
+    })?;
+    let (start, end) = spec.split_once('-').ok_or_else(|| {
+        ApiError::bad_request(format!("Invalid range: {range}"))
+    })?;
+    let number = |n: &str| {
+        n.parse::<usize>().map_err(|e| {
+            ApiError::bad_request(format!("Invalid range {range}: {e}"))
+        })
+    };
+    let last = length.checked_sub(1).ok_or_else(unsatisfiable)?;
+    let (start, end) = match (start.is_empty(), end.is_empty()) {
+        (true, false) => {
+            let suffix = number(end)?;
+            if suffix == 0 {
+                return Err(unsatisfiable());
+            }
+            (length.saturating_sub(suffix), last)
+        }
+        (false, true) => (number(start)?, last),
+        (false, false) => (number(start)?, number(end)?.min(last)),
+        (true, true) => {
+            return Err(ApiError::bad_request(format!(
+                "Invalid range: {range}"
+            )))
+        }
+    };
+    if start > end || start > last {
+        return Err(unsatisfiable());
+    }
+    Ok((start, end))
+}
+
+/// Build a header value, percent-encoding it the way B2 does
+fn encoded(value: &str) -> HeaderValue {
+    HeaderValue::from_str(
+        &utf8_percent_encode(value, HEADER_ENCODE_SET).to_string(),
+    )
+    .expect("Percent-encoded values are valid header values")
+}
+
+/// Build the response for downloading a file, honoring any `Range` header
+/// and header overrides in the query
+fn file_response(
+    file: &FileVersion,
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+) -> Result<Response, ApiError> {
+    let length = file.data.len();
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|range| parse_range(range, length))
+        .transpose()?;
+    let mut response = match range {
+        Some((start, end)) => {
+            let mut response =
+                Response::new(Body::from(file.data.slice(start..=end)));
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            response.headers_mut().insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {start}-{end}/{length}"))
+                    .expect("Content ranges are valid header values"),
+            );
+            response
+        }
+        None => Response::new(Body::from(file.data.clone())),
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        header::CONTENT_TYPE,
+        encoded(query.get("b2ContentType").unwrap_or(&file.content_type)),
+    );
+    if let Some(disposition) = query.get("b2ContentDisposition") {
+        headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(disposition).map_err(|_error| {
+                ApiError::bad_request("Invalid b2ContentDisposition")
+            })?,
+        );
+    }
+    headers.insert("x-bz-file-name", encoded(&file.name));
+    headers.insert("x-bz-file-id", encoded(&file.id));
+    headers.insert("x-bz-content-sha1", encoded(&file.content_sha1));
+    headers.insert(
+        "x-bz-upload-timestamp",
+        HeaderValue::from(file.upload_timestamp),
+    );
+    for (key, value) in &file.file_info {
+        let Ok(name) = HeaderName::try_from(format!("x-bz-info-{key}")) else {
+            continue;
+        };
+        if let Some(value) = value.as_str() {
+            headers.insert(name, encoded(value));
+        }
+    }
+    Ok(response)
+}
+
+/// The authorization token of a download, from the header or the query
+fn download_token<'a>(
+    headers: &'a HeaderMap,
+    query: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    auth_token(headers).or(query.get("Authorization").map(String::as_str))
+}
+
+/// Check that a download of a file is authorized
+///
+/// The token can be an account authorization token with `readFiles`, or a
+/// download authorization covering the file.
+fn authorize_download(
+    state: &state::State,
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+    bucket_id: &str,
+    file_name: &str,
+) -> Result<(), ApiError> {
+    let token = download_token(headers, query);
+    let Some(grant) = state.download_authorization(token) else {
+        let key = state.authorize(token, "readFiles")?;
+        return state::State::check_restrictions(
+            &key,
+            bucket_id,
+            Some(file_name),
+        );
+    };
+    if grant.expires_at <= now_millis() {
+        return Err(ApiError::expired_auth_token());
+    }
+    if grant.bucket_id != bucket_id
+        || !file_name.starts_with(grant.file_name_prefix.as_str())
+    {
+        return Err(ApiError::unauthorized(
+            "Download authorization does not cover this file",
+        ));
+    }
+    for (parameter, required) in [
+        ("b2ContentDisposition", &grant.content_disposition),
+        ("b2ContentType", &grant.content_type),
+    ] {
+        if required.is_some() && query.get(parameter) != required.as_ref() {
+            return Err(ApiError::unauthorized(format!(
+                "{parameter} does not match the download authorization"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The ``b2_get_download_authorization`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GetDownloadAuthorization {
+    /// The bucket downloads are authorized from
+    bucket_id: String,
+    /// The prefix the names of downloaded files must start with
+    file_name_prefix: String,
+    /// How long the authorization is valid for
+    valid_duration_in_seconds: u64,
+    /// The `Content-Disposition` downloads have to ask for
+    #[serde(default)]
+    b2_content_disposition: Option<String>,
+    /// The `Content-Type` downloads have to ask for
+    #[serde(default)]
+    b2_content_type: Option<String>,
+}
+
+/// Handle ``b2_get_download_authorization``
+pub(crate) async fn get_download_authorization(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: GetDownloadAuthorization = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "shareFiles")?;
+    state::State::check_restrictions(
+        &key,
+        &request.bucket_id,
+        Some(&request.file_name_prefix),
+    )?;
+    state.bucket(&request.bucket_id)?;
+    let seconds = request.valid_duration_in_seconds;
+    if !(1..=MAX_DOWNLOAD_AUTHORIZATION_SECONDS).contains(&seconds) {
+        return Err(ApiError::bad_request(format!(
+            "validDurationInSeconds must be between 1 and \
+             {MAX_DOWNLOAD_AUTHORIZATION_SECONDS}"
+        )));
+    }
+    let token = state.issue_download_authorization(DownloadAuthorization {
+        bucket_id: request.bucket_id.clone(),
+        file_name_prefix: request.file_name_prefix.clone(),
+        expires_at: now_millis().saturating_add(seconds.saturating_mul(1000)),
+        content_disposition: request.b2_content_disposition,
+        content_type: request.b2_content_type,
+    });
+    Ok(Json(json!({
+        "bucketId": request.bucket_id,
+        "fileNamePrefix": request.file_name_prefix,
+        "authorizationToken": token,
+    })))
+}
+
+/// Handle ``b2_download_file_by_id``
+pub(crate) async fn download_file_by_id(
+    State(app): State<SharedApp>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let file_id = query
+        .get("fileId")
+        .ok_or_else(|| ApiError::bad_request("Missing fileId"))?;
+    let state = app.state();
+    let file = state.file(file_id)?;
+    if file.action != Action::Upload {
+        return Err(ApiError::not_found(format!(
+            "File not present: {file_id}"
+        )));
+    }
+    let bucket = state.bucket(&file.bucket_id)?;
+    if bucket.kind != "allPublic" {
+        authorize_download(
+            &state,
+            &headers,
+            &query,
+            &file.bucket_id,
+            &file.name,
+        )?;
+    }
+    file_response(file, &headers, &query)
+}
+
+/// Handle downloads by bucket and file name
+pub(crate) async fn download_file_by_name(
+    State(app): State<SharedApp>,
+    Path((bucket_name, file_name)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let state = app.state();
+    let bucket = state.bucket_by_name(&bucket_name).ok_or_else(|| {
+        ApiError::not_found(format!("Bucket not found: {bucket_name}"))
+    })?;
+    if bucket.kind != "allPublic" {
+        authorize_download(&state, &headers, &query, &bucket.id, &file_name)?;
+    }
+    let file = state.visible(&bucket.id, &file_name).ok_or_else(|| {
+        ApiError::not_found(format!("File not present: {file_name}"))
+    })?;
+    file_response(file, &headers, &query)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::downloads::parse_range;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-9", 100).ok(), Some((0, 9)));
+        assert_eq!(parse_range("bytes=90-", 100).ok(), Some((90, 99)));
+        assert_eq!(parse_range("bytes=-10", 100).ok(), Some((90, 99)));
+        assert_eq!(parse_range("bytes=95-200", 100).ok(), Some((95, 99)));
+        assert!(parse_range("bytes=100-", 100).is_err());
+        assert!(parse_range("bytes=0-0", 0).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+//! The endpoints for simple uploads and for working with file versions
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    error::ApiError,
+    routes::{auth_token, downloads::parse_range, page_size, parse, SharedApp},
+    state::{self, sha1_hex, Action, UploadTarget},
+};
+
+/// The ``b2_get_upload_url`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GetUploadUrl {
+    /// The bucket to upload into
+    bucket_id: String,
+}
+
+/// Handle ``b2_get_upload_url``
+pub(crate) async fn get_upload_url(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: GetUploadUrl = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeFiles")?;
+    state::State::check_restrictions(&key, &request.bucket_id, None)?;
+    state.bucket(&request.bucket_id)?;
+    let (url_id, token) = state.create_upload_url(
+        UploadTarget::Bucket(request.bucket_id.clone()),
+        &key.id,
+    );
+    Ok(Json(json!({
+        "bucketId": request.bucket_id,
+        "uploadUrl": format!("{}/b2api/v3/b2_upload_file/{url_id}", app.base_url),
+        "authorizationToken": token,
+    })))
+}
+
+/// Read a header as a string
+pub(crate) fn header<'a>(
+    headers: &'a HeaderMap,
+    name: &str,
+) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Decode a percent-encoded header value
+fn decode_header(value: &str) -> Result<String, ApiError> {
+    percent_decode_str(value)
+        .decode_utf8()
+        .map(std::borrow::Cow::into_owned)
+        .map_err(|e| ApiError::bad_request(format!("Invalid header: {e}")))
+}
+
+/// Check the uploaded data against the `X-Bz-Content-Sha1` header
+///
+/// Returns the data without any trailing checksum and its hex SHA1.
+pub(crate) fn verify_upload(
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<(Bytes, String), ApiError> {
+    let declared = header(headers, "x-bz-content-sha1")
+        .ok_or_else(|| ApiError::bad_request("Missing X-Bz-Content-Sha1"))?;
+    let (data, expected) = match declared {
+        "do_not_verify" => (body, None),
+        "hex_digits_at_end" => {
+            let split = body.len().checked_sub(40).ok_or_else(|| {
+                ApiError::bad_request("Body too short for trailing SHA1")
+            })?;
+            let trailer = String::from_utf8_lossy(&body[split..]).into_owned();
+            (body.slice(..split), Some(trailer))
+        }
+        sha1 => (body, Some(sha1.to_owned())),
+    };
+    let actual = sha1_hex(&data);
+    if expected.is_some_and(|e| !e.eq_ignore_ascii_case(&actual)) {
+        return Err(ApiError::bad_request("Sha1 did not match data received"));
+    }
+    Ok((data, actual))
+}
+
+/// Collect the `X-Bz-Info-*` headers of an upload into file info
+fn file_info(headers: &HeaderMap) -> Result<Map<String, Value>, ApiError> {
+    let mut info = Map::new();
+    for (name, value) in headers {
+        if let Some(key) = name.as_str().strip_prefix("x-bz-info-") {
+            let value = value.to_str().map_err(|e| {
+                ApiError::bad_request(format!("Invalid file info: {e}"))
+            })?;
+            info.insert(key.to_owned(), Value::String(decode_header(value)?));
+        }
+    }
+    Ok(info)
+}
+
+/// Handle ``b2_upload_file``
+pub(crate) async fn upload_file(
+    State(app): State<SharedApp>,
+    Path(url_id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let name = decode_header(
+        header(&headers, "x-bz-file-name")
+            .ok_or_else(|| ApiError::bad_request("Missing X-Bz-File-Name"))?,
+    )?;
+    let content_type = match header(&headers, "content-type") {
+        None | Some("b2/x-auto") => "application/octet-stream",
+        Some(content_type) => content_type,
+    }
+    .to_owned();
+    let info = file_info(&headers)?;
+    let (data, sha1) = verify_upload(&headers, body)?;
+    let mut state = app.state();
+    let (target, key) =
+        state.authorize_upload(&url_id, auth_token(&headers))?;
+    let UploadTarget::Bucket(bucket_id) = target else {
+        return Err(ApiError::bad_auth_token());
+    };
+    state::State::check_restrictions(&key, &bucket_id, Some(&name))?;
+    state.bucket(&bucket_id)?;
+    let file = state.add_version(
+        &bucket_id,
+        &name,
+        Action::Upload,
+        data,
+        sha1,
+        content_type,
+        info,
+    );
+    Ok(Json(file.to_json(&state.account_id)))
+}
+
+/// The ``b2_list_file_names`` and ``b2_list_file_versions`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListFiles {
+    /// The bucket to list
+    bucket_id: String,
+    /// The first file name to return
+    #[serde(default)]
+    start_file_name: Option<String>,
+    /// The first file ID to return, for ``b2_list_file_versions``
+    #[serde(default)]
+    start_file_id: Option<String>,
+    /// The maximum number of entries to return
+    #[serde(default)]
+    max_file_count: Option<usize>,
+    /// Only return files whose names start with this prefix
+    #[serde(default)]
+    prefix: Option<String>,
+    /// Collapse names into folders at this delimiter
+    #[serde(default)]
+    delimiter: Option<String>,
+}
+
+impl ListFiles {
+    /// Check that a key may list the requested files
+    fn authorize(&self, key: &state::Key) -> Result<(), ApiError> {
+        state::State::check_restrictions(key, &self.bucket_id, None)?;
+        if let Some(restriction) = &key.name_prefix {
+            if !self
+                .prefix
+                .as_deref()
+                .unwrap_or_default()
+                .starts_with(restriction.as_str())
+            {
+                return Err(ApiError::unauthorized(format!(
+                    "Key is restricted to file names starting with \
+                     {restriction}"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Handle ``b2_list_file_names``
+pub(crate) async fn list_file_names(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: ListFiles = parse(&body)?;
+    let state = app.state();
+    let key = state.authorize(auth_token(&headers), "listFiles")?;
+    request.authorize(&key)?;
+    state.bucket(&request.bucket_id)?;
+    let page = state.list_names(
+        &request.bucket_id,
+        request.start_file_name.as_deref(),
+        page_size(request.max_file_count, 100, 10000),
+        request.prefix.as_deref().unwrap_or_default(),
+        request.delimiter.as_deref(),
+    );
+    Ok(Json(json!({
+        "files": page.files,
+        "nextFileName": page.next_file_name,
+    })))
+}
+
+/// Handle ``b2_list_file_versions``
+pub(crate) async fn list_file_versions(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: ListFiles = parse(&body)?;
+    let state = app.state();
+    let key = state.authorize(auth_token(&headers), "listFiles")?;
+    request.authorize(&key)?;
+    state.bucket(&request.bucket_id)?;
+    let page = state.list_versions(
+        &request.bucket_id,
+        request.start_file_name.as_deref(),
+        request.start_file_id.as_deref(),
+        page_size(request.max_file_count, 100, 10000),
+        request.prefix.as_deref().unwrap_or_default(),
+        request.delimiter.as_deref(),
+    );
+    Ok(Json(json!({
+        "files": page.files,
+        "nextFileName": page.next_file_name,
+        "nextFileId": page.next_file_id,
+    })))
+}
+
+/// A request body naming a single file version
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FileId {
+    /// The file version to act on
+    file_id: String,
+}
+
+/// Handle ``b2_get_file_info``
+pub(crate) async fn get_file_info(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: FileId = parse(&body)?;
+    let state = app.state();
+    let key = state.authorize(auth_token(&headers), "readFiles")?;
+    let file = state.file(&request.file_id)?;
+    state::State::check_restrictions(&key, &file.bucket_id, Some(&file.name))?;
+    Ok(Json(file.to_json(&state.account_id)))
+}
+
+/// The ``b2_copy_file`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CopyFile {
+    /// The file version to copy from
+    source_file_id: String,
+    /// The bucket to copy into, defaulting to the source bucket
+    #[serde(default)]
+    destination_bucket_id: Option<String>,
+    /// The name of the new file
+    file_name: String,
+    /// The byte range of the source to copy, such as `bytes=0-99`
+    #[serde(default)]
+    range: Option<String>,
+    /// Either `COPY` or `REPLACE`
+    #[serde(default)]
+    metadata_directive: Option<String>,
+    /// The content type of the new file when replacing metadata
+    #[serde(default)]
+    content_type: Option<String>,
+    /// The file info of the new file when replacing metadata
+    #[serde(default)]
+    file_info: Option<Map<String, Value>>,
+}
+
+/// Handle ``b2_copy_file``
+pub(crate) async fn copy_file(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: CopyFile = parse(&body)?;
+    let mut state = app.state();
+    let token = auth_token(&headers);
+    let reader = state.authorize(token, "readFiles")?;
+    let writer = state.authorize(token, "writeFiles")?;
+    let source = state.file(&request.source_file_id)?.clone();
+    if source.action != Action::Upload {
+        return Err(ApiError::bad_request("Source file is not an upload"));
+    }
+    state::State::check_restrictions(
+        &reader,
+        &source.bucket_id,
+        Some(&source.name),
+    )?;
+    let destination = request
+        .destination_bucket_id
+        .unwrap_or_else(|| source.bucket_id.clone());
+    state::State::check_restrictions(
+        &writer,
+        &destination,
+        Some(&request.file_name),
+    )?;
+    state.bucket(&destination)?;
+    let data = match request.range.as_deref() {
+        Some(range) => {
+            let (start, end) = parse_range(range, source.data.len())?;
+            source.data.slice(start..=end)
+        }
+        None => source.data.clone(),
+    };
+    let (content_type, info) = match request.metadata_directive.as_deref() {
+        None | Some("COPY") => (source.content_type, source.file_info),
+        Some("REPLACE") => (
+            request.content_type.ok_or_else(|| {
+                ApiError::bad_request("contentType is required with REPLACE")
+            })?,
+            request.file_info.unwrap_or_default(),
+        ),
+        Some(other) => {
+            return Err(ApiError::bad_request(format!(
+                "Invalid metadataDirective: {other}"
+            )))
+        }
+    };
+    let sha1 = sha1_hex(&data);
+    let file = state.add_version(
+        &destination,
+        &request.file_name,
+        Action::Upload,
+        data,
+        sha1,
+        content_type,
+        info,
+    );
+    Ok(Json(file.to_json(&state.account_id)))
+}
+
+/// The ``b2_hide_file`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct HideFile {
+    /// The bucket containing the file
+    bucket_id: String,
+    /// The name of the file to hide
+    file_name: String,
+}
+
+/// Handle ``b2_hide_file``
+pub(crate) async fn hide_file(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: HideFile = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeFiles")?;
+    state::State::check_restrictions(
+        &key,
+        &request.bucket_id,
+        Some(&request.file_name),
+    )?;
+    state.bucket(&request.bucket_id)?;
+    if state.visible(&request.bucket_id, &request.file_name).is_none() {
+        return Err(ApiError::not_found(format!(
+            "File not present: {}",
+            request.file_name
+        )));
+    }
+    let marker = state.add_version(
+        &request.bucket_id,
+        &request.file_name,
+        Action::Hide,
+        Bytes::new(),
+        "none".to_owned(),
+        "application/x-bz-hide-marker".to_owned(),
+        Map::new(),
+    );
+    Ok(Json(marker.to_json(&state.account_id)))
+}
+
+/// The ``b2_delete_file_version`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeleteFileVersion {
+    /// The name of the file
+    file_name: String,
+    /// The version to delete
+    file_id: String,
+}
+
+/// Handle ``b2_delete_file_version``
+pub(crate) async fn delete_file_version(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: DeleteFileVersion = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "deleteFiles")?;
+    let file = state.file(&request.file_id)?;
+    if file.name != request.file_name {
+        return Err(ApiError::bad_request("File name does not match file ID"));
+    }
+    state::State::check_restrictions(&key, &file.bucket_id, Some(&file.name))?;
+    state.remove_version(&request.file_id);
+    Ok(Json(json!({
+        "fileId": request.file_id,
+        "fileName": request.file_name,
+    })))
+}
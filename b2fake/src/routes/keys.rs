@@ -0,0 +1,156 @@
+//! The application key endpoints
+
+use std::time::Duration;
+
+use axum::{body::Bytes, extract::State, http::HeaderMap, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    error::ApiError,
+    routes::{auth_token, page_size, parse, SharedApp},
+    state::ALL_CAPABILITIES,
+};
+
+/// The ``b2_create_key`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CreateKey {
+    /// The account the key belongs to
+    account_id: String,
+    /// The capabilities the key grants
+    capabilities: Vec<String>,
+    /// The name of the key
+    key_name: String,
+    /// How long the key is valid for, if it expires
+    #[serde(default)]
+    valid_duration_in_seconds: Option<u64>,
+    /// The bucket the key is restricted to
+    #[serde(default)]
+    bucket_id: Option<String>,
+    /// The file name prefix the key is restricted to
+    #[serde(default)]
+    name_prefix: Option<String>,
+}
+
+/// Handle ``b2_create_key``
+pub(crate) async fn create_key(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: CreateKey = parse(&body)?;
+    let mut state = app.state();
+    let creator = state.authorize(auth_token(&headers), "writeKeys")?;
+    if creator.bucket_id.is_some() {
+        return Err(ApiError::unauthorized(
+            "Keys restricted to a bucket cannot create keys",
+        ));
+    }
+    if request.account_id != state.account_id {
+        return Err(ApiError::unauthorized("Wrong account ID"));
+    }
+    if let Some(unknown) = request
+        .capabilities
+        .iter()
+        .find(|c| !ALL_CAPABILITIES.contains(&c.as_str()))
+    {
+        return Err(ApiError::bad_request(format!(
+            "Unknown capability: {unknown}"
+        )));
+    }
+    if request.name_prefix.is_some() && request.bucket_id.is_none() {
+        return Err(ApiError::bad_request("namePrefix requires bucketId"));
+    }
+    if let Some(bucket_id) = &request.bucket_id {
+        state.bucket(bucket_id)?;
+    }
+    let key = state.create_key(
+        request.key_name,
+        request.capabilities,
+        request.bucket_id,
+        request.name_prefix,
+        request.valid_duration_in_seconds.map(Duration::from_secs),
+    );
+    let mut response = key.to_json(&state.account_id);
+    response["applicationKey"] = Value::String(key.secret);
+    Ok(Json(response))
+}
+
+/// The ``b2_list_keys`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListKeys {
+    /// The account to list keys of
+    account_id: String,
+    /// The maximum number of keys to return
+    #[serde(default)]
+    max_key_count: Option<usize>,
+    /// The first key ID to return
+    #[serde(default)]
+    start_application_key_id: Option<String>,
+}
+
+/// Handle ``b2_list_keys``
+pub(crate) async fn list_keys(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: ListKeys = parse(&body)?;
+    let state = app.state();
+    state.authorize(auth_token(&headers), "listKeys")?;
+    if request.account_id != state.account_id {
+        return Err(ApiError::unauthorized("Wrong account ID"));
+    }
+    let count = page_size(request.max_key_count, 100, 10000);
+    let mut keys = state.keys.values().filter(|key| {
+        key.id != state.account_id
+            && request
+                .start_application_key_id
+                .as_ref()
+                .is_none_or(|start| key.id >= *start)
+    });
+    let page: Vec<Value> = keys
+        .by_ref()
+        .take(count)
+        .map(|key| key.to_json(&state.account_id))
+        .collect();
+    let next = keys.next().map(|key| key.id.clone());
+    Ok(Json(json!({
+        "keys": page,
+        "nextApplicationKeyId": next,
+    })))
+}
+
+/// The ``b2_delete_key`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeleteKey {
+    /// The key to delete
+    application_key_id: String,
+}
+
+/// Handle ``b2_delete_key``
+pub(crate) async fn delete_key(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: DeleteKey = parse(&body)?;
+    let mut state = app.state();
+    state.authorize(auth_token(&headers), "deleteKeys")?;
+    if request.application_key_id == state.account_id {
+        return Err(ApiError::bad_request(
+            "The master application key cannot be deleted",
+        ));
+    }
+    let key =
+        state.keys.remove(&request.application_key_id).ok_or_else(|| {
+            ApiError::bad_request(format!(
+                "Key not found: {}",
+                request.application_key_id
+            ))
+        })?;
+    Ok(Json(key.to_json(&state.account_id)))
+}
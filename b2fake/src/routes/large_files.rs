@@ -0,0 +1,399 @@
+//! The large file endpoints
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    error::ApiError,
+    routes::{
+        auth_token,
+        downloads::parse_range,
+        files::{header, verify_upload},
+        page_size, parse, SharedApp,
+    },
+    state::{self, sha1_hex, Action, Part, UploadTarget},
+};
+
+/// The largest part number B2 accepts
+const MAX_PART_NUMBER: u32 = 10000;
+
+/// Render an uploaded part
+fn part_json(file_id: &str, number: u32, part: &Part) -> Value {
+    json!({
+        "fileId": file_id,
+        "partNumber": number,
+        "contentLength": part.data.len(),
+        "contentSha1": part.sha1,
+        "contentMd5": null,
+        "serverSideEncryption": {
+            "mode": null,
+        },
+        "uploadTimestamp": part.upload_timestamp,
+    })
+}
+
+/// The ``b2_start_large_file`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct StartLargeFile {
+    /// The bucket the file goes in
+    bucket_id: String,
+    /// The name of the file
+    file_name: String,
+    /// The MIME type of the file
+    content_type: String,
+    /// Custom information to store with the file
+    #[serde(default)]
+    file_info: Option<Map<String, Value>>,
+}
+
+/// Handle ``b2_start_large_file``
+pub(crate) async fn start_large_file(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: StartLargeFile = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeFiles")?;
+    state::State::check_restrictions(
+        &key,
+        &request.bucket_id,
+        Some(&request.file_name),
+    )?;
+    state.bucket(&request.bucket_id)?;
+    let content_type = if request.content_type == "b2/x-auto" {
+        "application/octet-stream".to_owned()
+    } else {
+        request.content_type
+    };
+    let file = state.add_version(
+        &request.bucket_id,
+        &request.file_name,
+        Action::Start,
+        Bytes::new(),
+        "none".to_owned(),
+        content_type,
+        request.file_info.unwrap_or_default(),
+    );
+    state.parts.insert(file.id.clone(), std::collections::BTreeMap::new());
+    Ok(Json(file.to_json(&state.account_id)))
+}
+
+/// A request body naming a single large file
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LargeFileId {
+    /// The large file to act on
+    file_id: String,
+}
+
+/// Handle ``b2_get_upload_part_url``
+pub(crate) async fn get_upload_part_url(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: LargeFileId = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeFiles")?;
+    let file = state.large_file(&request.file_id)?;
+    state::State::check_restrictions(&key, &file.bucket_id, Some(&file.name))?;
+    let (url_id, token) = state.create_upload_url(
+        UploadTarget::LargeFile(request.file_id.clone()),
+        &key.id,
+    );
+    Ok(Json(json!({
+        "fileId": request.file_id,
+        "uploadUrl": format!("{}/b2api/v3/b2_upload_part/{url_id}", app.base_url),
+        "authorizationToken": token,
+    })))
+}
+
+/// Check a part number is within the range B2 accepts
+fn check_part_number(number: u32) -> Result<u32, ApiError> {
+    if (1..=MAX_PART_NUMBER).contains(&number) {
+        Ok(number)
+    } else {
+        Err(ApiError::bad_request(format!("Invalid part number: {number}")))
+    }
+}
+
+/// Handle ``b2_upload_part``
+pub(crate) async fn upload_part(
+    State(app): State<SharedApp>,
+    Path(url_id): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let number = header(&headers, "x-bz-part-number")
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| ApiError::bad_request("Missing X-Bz-Part-Number"))
+        .and_then(check_part_number)?;
+    let (data, sha1) = verify_upload(&headers, body)?;
+    let mut state = app.state();
+    let (target, _) = state.authorize_upload(&url_id, auth_token(&headers))?;
+    let UploadTarget::LargeFile(file_id) = target else {
+        return Err(ApiError::bad_auth_token());
+    };
+    state.large_file(&file_id)?;
+    let part = Part {
+        data,
+        sha1,
+        upload_timestamp: state.timestamp(),
+    };
+    let response = part_json(&file_id, number, &part);
+    state.parts.entry(file_id).or_default().insert(number, part);
+    Ok(Json(response))
+}
+
+/// The ``b2_copy_part`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CopyPart {
+    /// The file version to copy from
+    source_file_id: String,
+    /// The large file the part belongs to
+    large_file_id: String,
+    /// The number of the part
+    part_number: u32,
+    /// The byte range of the source to copy, such as `bytes=0-99`
+    #[serde(default)]
+    range: Option<String>,
+}
+
+/// Handle ``b2_copy_part``
+pub(crate) async fn copy_part(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: CopyPart = parse(&body)?;
+    let number = check_part_number(request.part_number)?;
+    let mut state = app.state();
+    let token = auth_token(&headers);
+    let reader = state.authorize(token, "readFiles")?;
+    let writer = state.authorize(token, "writeFiles")?;
+    let source = state.file(&request.source_file_id)?.clone();
+    if source.action != Action::Upload {
+        return Err(ApiError::bad_request("Source file is not an upload"));
+    }
+    state::State::check_restrictions(
+        &reader,
+        &source.bucket_id,
+        Some(&source.name),
+    )?;
+    let target = state.large_file(&request.large_file_id)?;
+    state::State::check_restrictions(
+        &writer,
+        &target.bucket_id,
+        Some(&target.name),
+    )?;
+    let data = match request.range.as_deref() {
+        Some(range) => {
+            let (start, end) = parse_range(range, source.data.len())?;
+            source.data.slice(start..=end)
+        }
+        None => source.data.clone(),
+    };
+    let part = Part {
+        sha1: sha1_hex(&data),
+        data,
+        upload_timestamp: state.timestamp(),
+    };
+    let response = part_json(&request.large_file_id, number, &part);
+    state.parts.entry(request.large_file_id).or_default().insert(number, part);
+    Ok(Json(response))
+}
+
+/// The ``b2_list_parts`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListParts {
+    /// The large file to list parts of
+    file_id: String,
+    /// The first part number to return
+    #[serde(default)]
+    start_part_number: Option<u32>,
+    /// The maximum number of parts to return
+    #[serde(default)]
+    max_part_count: Option<usize>,
+}
+
+/// Handle ``b2_list_parts``
+pub(crate) async fn list_parts(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: ListParts = parse(&body)?;
+    let state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeFiles")?;
+    let file = state.large_file(&request.file_id)?;
+    state::State::check_restrictions(&key, &file.bucket_id, Some(&file.name))?;
+    let count = page_size(request.max_part_count, 100, 1000);
+    let mut parts =
+        state.parts.get(&request.file_id).into_iter().flatten().filter(
+            |(&number, _)| number >= request.start_part_number.unwrap_or(1),
+        );
+    let page: Vec<Value> = parts
+        .by_ref()
+        .take(count)
+        .map(|(&number, part)| part_json(&request.file_id, number, part))
+        .collect();
+    let next = parts.next().map(|(&number, _)| number);
+    Ok(Json(json!({
+        "parts": page,
+        "nextPartNumber": next,
+    })))
+}
+
+/// The ``b2_list_unfinished_large_files`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ListUnfinished {
+    /// The bucket to list
+    bucket_id: String,
+    /// Only return files whose names start with this prefix
+    #[serde(default)]
+    name_prefix: Option<String>,
+    /// The first file ID to return
+    #[serde(default)]
+    start_file_id: Option<String>,
+    /// The maximum number of files to return
+    #[serde(default)]
+    max_file_count: Option<usize>,
+}
+
+/// Handle ``b2_list_unfinished_large_files``
+pub(crate) async fn list_unfinished_large_files(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: ListUnfinished = parse(&body)?;
+    let state = app.state();
+    let key = state.authorize(auth_token(&headers), "listFiles")?;
+    state::State::check_restrictions(&key, &request.bucket_id, None)?;
+    state.bucket(&request.bucket_id)?;
+    let prefix = request.name_prefix.unwrap_or_default();
+    let count = page_size(request.max_file_count, 100, 100);
+    let mut files = state
+        .files
+        .values()
+        .filter(|file| {
+            file.bucket_id == request.bucket_id
+                && file.action == Action::Start
+                && file.name.starts_with(&prefix)
+                && request
+                    .start_file_id
+                    .as_ref()
+                    .is_none_or(|start| file.id >= *start)
+        })
+        .filter(|file| {
+            key.name_prefix
+                .as_ref()
+                .is_none_or(|restriction| file.name.starts_with(restriction))
+        });
+    let page: Vec<Value> = files
+        .by_ref()
+        .take(count)
+        .map(|file| file.to_json(&state.account_id))
+        .collect();
+    let next = files.next().map(|file| file.id.clone());
+    Ok(Json(json!({
+        "files": page,
+        "nextFileId": next,
+    })))
+}
+
+/// The ``b2_finish_large_file`` request body
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FinishLargeFile {
+    /// The large file to finish
+    file_id: String,
+    /// The SHA1 of every part, in order
+    part_sha1_array: Vec<String>,
+}
+
+/// Handle ``b2_finish_large_file``
+pub(crate) async fn finish_large_file(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: FinishLargeFile = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeFiles")?;
+    let file = state.large_file(&request.file_id)?.clone();
+    state::State::check_restrictions(&key, &file.bucket_id, Some(&file.name))?;
+    let parts = state.parts.get(&request.file_id).cloned().unwrap_or_default();
+    if parts.len() < 2 {
+        return Err(ApiError::bad_request(
+            "A large file must have at least two parts",
+        ));
+    }
+    if parts.len() != request.part_sha1_array.len() {
+        return Err(ApiError::bad_request(
+            "partSha1Array does not match the uploaded parts",
+        ));
+    }
+    let minimum = state.config.absolute_minimum_part_size;
+    let mut data = Vec::new();
+    for (index, ((&number, part), sha1)) in
+        parts.iter().zip(&request.part_sha1_array).enumerate()
+    {
+        if usize::try_from(number).ok() != Some(index + 1) {
+            return Err(ApiError::bad_request(format!(
+                "Missing part {}",
+                index + 1
+            )));
+        }
+        if !part.sha1.eq_ignore_ascii_case(sha1) {
+            return Err(ApiError::bad_request(format!(
+                "Part {number} SHA1 does not match"
+            )));
+        }
+        if index + 1 < parts.len() && part.data.len() < minimum {
+            return Err(ApiError::bad_request(format!(
+                "Part {number} is smaller than the minimum part size"
+            )));
+        }
+        data.extend_from_slice(&part.data);
+    }
+    let finished = state::FileVersion {
+        action: Action::Upload,
+        data: Bytes::from(data),
+        ..file
+    };
+    state.remove_version(&request.file_id);
+    state.files.insert(finished.id.clone(), finished.clone());
+    Ok(Json(finished.to_json(&state.account_id)))
+}
+
+/// Handle ``b2_cancel_large_file``
+pub(crate) async fn cancel_large_file(
+    State(app): State<SharedApp>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let request: LargeFileId = parse(&body)?;
+    let mut state = app.state();
+    let key = state.authorize(auth_token(&headers), "writeFiles")?;
+    let file = state.large_file(&request.file_id)?.clone();
+    state::State::check_restrictions(&key, &file.bucket_id, Some(&file.name))?;
+    state.remove_version(&request.file_id);
+    Ok(Json(json!({
+        "fileId": file.id,
+        "accountId": state.account_id,
+        "bucketId": file.bucket_id,
+        "fileName": file.name,
+    })))
+}
@@ -0,0 +1,825 @@
+//! The S3-compatible API
+//!
+//! Path-style requests under `/s3/{bucket}` are served from the same account
+//! as the native API, the way B2 serves both APIs from one account. Requests
+//! are authenticated with AWS Signature Version 4, using an application key ID
+//! as the access key and the application key as the secret.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    response::Response,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use percent_encoding::{
+    percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC,
+};
+use quick_xml::escape::escape;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::S3Error,
+    routes::{downloads::parse_range, files::header, page_size, SharedApp},
+    state::{self, now_millis, sha1_hex, Action, FileVersion, Key, Part},
+};
+
+/// The characters Signature Version 4 leaves unencoded in canonical query
+/// strings
+const UNRESERVED: &AsciiSet =
+    &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// The payload hash clients send when they don't sign the body
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// The largest part number S3 accepts
+const MAX_PART_NUMBER: u32 = 10000;
+
+/// The content type of objects uploaded without one
+const DEFAULT_CONTENT_TYPE: &str = "binary/octet-stream";
+
+/// The prefix of headers carrying user metadata
+const METADATA_PREFIX: &str = "x-amz-meta-";
+
+/// The S3 operation a request is for, used to name it in call counts and
+/// fault injection
+pub(crate) fn operation(
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+) -> &'static str {
+    let has = |name: &str| {
+        query
+            .unwrap_or_default()
+            .split('&')
+            .any(|pair| pair.split('=').next() == Some(name))
+    };
+    let object = path
+        .strip_prefix("/s3/")
+        .is_some_and(|rest| rest.trim_end_matches('/').contains('/'));
+    match *method {
+        Method::PUT if has("uploadId") => "s3_upload_part",
+        Method::PUT => "s3_put_object",
+        Method::POST if has("uploads") => "s3_create_multipart_upload",
+        Method::POST => "s3_complete_multipart_upload",
+        Method::DELETE if has("uploadId") => "s3_abort_multipart_upload",
+        Method::DELETE => "s3_delete_object",
+        Method::HEAD => "s3_head_object",
+        _ if object => "s3_get_object",
+        _ => "s3_list_objects_v2",
+    }
+}
+
+/// A request as it arrived, kept whole so its signature can be checked
+struct Incoming<'a> {
+    /// The request method
+    method: &'a Method,
+    /// The request URI, still percent-encoded
+    uri: &'a Uri,
+    /// The request headers
+    headers: &'a HeaderMap,
+    /// The request body
+    body: &'a [u8],
+}
+
+/// The parts of a signed `Authorization` header
+struct Authorization<'a> {
+    /// The access key the request was signed with
+    key_id: &'a str,
+    /// The credential scope, `date/region/service/aws4_request`
+    scope: &'a str,
+    /// The names of the signed headers, separated by `;`
+    signed_headers: &'a str,
+    /// The hex signature
+    signature: &'a str,
+}
+
+/// Parse a signed `Authorization` header
+fn parse_authorization(value: &str) -> Option<Authorization<'_>> {
+    let rest = value.strip_prefix("AWS4-HMAC-SHA256 ")?;
+    let (mut credential, mut signed_headers, mut signature) =
+        (None, None, None);
+    for part in rest.split(',') {
+        match part.trim().split_once('=')? {
+            ("Credential", value) => credential = Some(value),
+            ("SignedHeaders", value) => signed_headers = Some(value),
+            ("Signature", value) => signature = Some(value),
+            _ => {}
+        }
+    }
+    let (key_id, scope) = credential?.split_once('/')?;
+    Some(Authorization {
+        key_id,
+        scope,
+        signed_headers: signed_headers?,
+        signature: signature?,
+    })
+}
+
+/// The hex-encoded SHA256 digest of some data
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// The HMAC-SHA256 of some data
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Encode a query string the way Signature Version 4 canonicalizes it
+fn canonical_query(query: Option<&str>) -> String {
+    let encode = |value: &str| {
+        utf8_percent_encode(
+            &percent_decode_str(value).decode_utf8_lossy(),
+            UNRESERVED,
+        )
+        .to_string()
+    };
+    let mut pairs: Vec<(String, String)> = query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (encode(name), encode(value))
+        })
+        .collect();
+    pairs.sort();
+    pairs
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Check the signature of a request, returning the key that signed it
+fn authenticate(
+    state: &state::State,
+    request: &Incoming<'_>,
+) -> Result<Key, S3Error> {
+    let malformed = || {
+        S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "AuthorizationHeaderMalformed",
+            "The authorization header is malformed",
+        )
+    };
+    let signature = header(request.headers, header::AUTHORIZATION.as_str())
+        .ok_or_else(|| S3Error::access_denied("Missing Authorization header"))
+        .and_then(|value| parse_authorization(value).ok_or_else(malformed))?;
+    let key = state.keys.get(signature.key_id).ok_or_else(|| {
+        S3Error::new(
+            StatusCode::FORBIDDEN,
+            "InvalidAccessKeyId",
+            "The access key ID does not exist",
+        )
+    })?;
+    if key.expiration_timestamp.is_some_and(|at| at <= now_millis()) {
+        return Err(S3Error::access_denied("The application key has expired"));
+    }
+    let payload_hash = header(request.headers, "x-amz-content-sha256")
+        .ok_or_else(|| {
+            S3Error::invalid_argument("Missing x-amz-content-sha256")
+        })?;
+    if payload_hash != UNSIGNED_PAYLOAD
+        && payload_hash != sha256_hex(request.body)
+    {
+        return Err(S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "XAmzContentSHA256Mismatch",
+            "The body does not match x-amz-content-sha256",
+        ));
+    }
+    let date = header(request.headers, "x-amz-date")
+        .ok_or_else(|| S3Error::access_denied("Missing x-amz-date"))?;
+    if date.split('T').next() != signature.scope.split('/').next() {
+        return Err(malformed());
+    }
+    let mut canonical_headers = String::new();
+    for name in signature.signed_headers.split(';') {
+        let values: Vec<&str> = request
+            .headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(str::trim)
+            .collect();
+        canonical_headers.push_str(name);
+        canonical_headers.push(':');
+        canonical_headers.push_str(&values.join(","));
+        canonical_headers.push('\n');
+    }
+    let canonical_request = [
+        request.method.as_str(),
+        request.uri.path(),
+        &canonical_query(request.uri.query()),
+        &canonical_headers,
+        signature.signed_headers,
+        payload_hash,
+    ]
+    .join("\n");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{date}\n{}\n{}",
+        signature.scope,
+        sha256_hex(canonical_request.as_bytes())
+    );
+    let signing_key = signature
+        .scope
+        .split('/')
+        .fold(format!("AWS4{}", key.secret).into_bytes(), |key, part| {
+            hmac(&key, part)
+        });
+    if hex::encode(hmac(&signing_key, &string_to_sign)) != signature.signature {
+        return Err(S3Error::new(
+            StatusCode::FORBIDDEN,
+            "SignatureDoesNotMatch",
+            "The request signature does not match",
+        ));
+    }
+    Ok(key.clone())
+}
+
+/// Check that a key has a capability and may access a bucket and name
+fn require(
+    key: &Key,
+    capability: &str,
+    bucket_id: &str,
+    name: Option<&str>,
+) -> Result<(), S3Error> {
+    if !key.capabilities.iter().any(|c| c == capability) {
+        return Err(S3Error::access_denied(format!(
+            "Key is missing the {capability} capability"
+        )));
+    }
+    state::State::check_restrictions(key, bucket_id, name)
+        .map_err(|error| S3Error::access_denied(error.message))
+}
+
+/// Authenticate a request and look up the ID of the bucket it targets
+fn open(
+    state: &state::State,
+    request: &Incoming<'_>,
+    bucket_name: &str,
+) -> Result<(Key, String), S3Error> {
+    let key = authenticate(state, request)?;
+    let bucket = state
+        .bucket_by_name(bucket_name)
+        .ok_or_else(|| S3Error::no_such_bucket(bucket_name))?;
+    Ok((key, bucket.id.clone()))
+}
+
+/// The quoted entity tag of some data, the MD5 of its contents
+///
+/// Objects assembled from parts get the same kind of tag as any other
+/// object, unlike on S3, so that every tag can be checked against the data.
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Md5::digest(data)))
+}
+
+/// A timestamp in milliseconds since the epoch as a date
+fn date(millis: u64) -> DateTime<Utc> {
+    i64::try_from(millis)
+        .ok()
+        .and_then(DateTime::from_timestamp_millis)
+        .unwrap_or_default()
+}
+
+/// An XML element containing escaped text
+fn element(name: &str, text: &str) -> String {
+    format!("<{name}>{}</{name}>", escape(text))
+}
+
+/// A response with an XML body
+fn xml_response(body: &str) -> Response {
+    let mut response = Response::new(Body::from(format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{body}"
+    )));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/xml"),
+    );
+    response
+}
+
+/// Build a header value, or fail with `400 InvalidArgument`
+fn header_value(value: &str) -> Result<HeaderValue, S3Error> {
+    HeaderValue::from_str(value).map_err(|_error| {
+        S3Error::invalid_argument(format!("Invalid header value: {value}"))
+    })
+}
+
+/// The user metadata sent with an upload, as file info
+fn metadata(headers: &HeaderMap) -> Map<String, Value> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix(METADATA_PREFIX)?;
+            Some((key.to_owned(), Value::from(value.to_str().ok()?)))
+        })
+        .collect()
+}
+
+/// Add the headers describing an object to a response
+fn object_headers(
+    response: &mut Response,
+    file: &FileVersion,
+) -> Result<(), S3Error> {
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::ETAG, header_value(&etag(&file.data))?);
+    headers.insert(
+        header::LAST_MODIFIED,
+        header_value(
+            &date(file.upload_timestamp)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        )?,
+    );
+    headers.insert(header::CONTENT_TYPE, header_value(&file.content_type)?);
+    headers.insert("x-amz-version-id", header_value(&file.id)?);
+    for (key, value) in &file.file_info {
+        if let Some(value) = value.as_str() {
+            headers.insert(
+                header::HeaderName::try_from(format!("{METADATA_PREFIX}{key}"))
+                    .map_err(|_error| {
+                        S3Error::invalid_argument("Invalid metadata key")
+                    })?,
+                header_value(value)?,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Handle ``ListObjectsV2``, the only request on a bucket the server supports
+pub(crate) async fn bucket(
+    State(app): State<SharedApp>,
+    Path(bucket_name): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, S3Error> {
+    let request = Incoming {
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+        body: &body,
+    };
+    let state = app.state();
+    let (key, bucket_id) = open(&state, &request, &bucket_name)?;
+    if query.get("list-type").map(String::as_str) != Some("2") {
+        return Err(S3Error::not_implemented("ListObjects version 1"));
+    }
+    let prefix = query.get("prefix").map(String::as_str).unwrap_or_default();
+    require(&key, "listFiles", &bucket_id, Some(prefix))?;
+    let delimiter = query.get("delimiter").filter(|d| !d.is_empty());
+    let max_keys = page_size(
+        query.get("max-keys").and_then(|count| count.parse().ok()),
+        1000,
+        1000,
+    );
+    let start = query
+        .get("continuation-token")
+        .cloned()
+        .or_else(|| query.get("start-after").map(|after| format!("{after}\0")));
+    let page = state.list_names(
+        &bucket_id,
+        start.as_deref(),
+        max_keys,
+        prefix,
+        delimiter.map(String::as_str),
+    );
+    let mut body = String::from("<ListBucketResult>");
+    body.push_str(&element("Name", &bucket_name));
+    body.push_str(&element("Prefix", prefix));
+    body.push_str(&element("KeyCount", &page.files.len().to_string()));
+    body.push_str(&element("MaxKeys", &max_keys.to_string()));
+    body.push_str(&element(
+        "IsTruncated",
+        &page.next_file_name.is_some().to_string(),
+    ));
+    if let Some(delimiter) = delimiter {
+        body.push_str(&element("Delimiter", delimiter));
+    }
+    if let Some(next) = &page.next_file_name {
+        body.push_str(&element("NextContinuationToken", next));
+    }
+    for file in &page.files {
+        let name = file["fileName"].as_str().unwrap_or_default();
+        if file["action"] == "folder" {
+            body.push_str("<CommonPrefixes>");
+            body.push_str(&element("Prefix", name));
+            body.push_str("</CommonPrefixes>");
+            continue;
+        }
+        let Some(version) =
+            file["fileId"].as_str().and_then(|id| state.files.get(id))
+        else {
+            continue;
+        };
+        body.push_str("<Contents>");
+        body.push_str(&element("Key", name));
+        body.push_str(&element(
+            "LastModified",
+            &date(version.upload_timestamp)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+        ));
+        body.push_str(&element("ETag", &etag(&version.data)));
+        body.push_str(&element("Size", &version.data.len().to_string()));
+        body.push_str(&element("StorageClass", "STANDARD"));
+        body.push_str("</Contents>");
+    }
+    body.push_str("</ListBucketResult>");
+    Ok(xml_response(&body))
+}
+
+/// Handle ``GetObject`` and ``HeadObject``, honoring any `Range` header
+pub(crate) async fn get_object(
+    State(app): State<SharedApp>,
+    Path((bucket_name, name)): Path<(String, String)>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, S3Error> {
+    let request = Incoming {
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+        body: &body,
+    };
+    let state = app.state();
+    let (key, bucket_id) = open(&state, &request, &bucket_name)?;
+    require(&key, "readFiles", &bucket_id, Some(&name))?;
+    let file = state
+        .visible(&bucket_id, &name)
+        .ok_or_else(|| S3Error::no_such_key(&name))?;
+    let length = file.data.len();
+    let range = header(&headers, header::RANGE.as_str())
+        .map(|range| parse_range(range, length))
+        .transpose()
+        .map_err(|error| {
+            if error.status == StatusCode::RANGE_NOT_SATISFIABLE {
+                S3Error::new(error.status, "InvalidRange", error.message)
+            } else {
+                S3Error::invalid_argument(error.message)
+            }
+        })?;
+    let mut response = if let Some((start, end)) = range {
+        let mut response =
+            Response::new(Body::from(file.data.slice(start..=end)));
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            header_value(&format!("bytes {start}-{end}/{length}"))?,
+        );
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(end - start + 1));
+        response
+    } else {
+        let mut response = Response::new(Body::from(file.data.clone()));
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+        response
+    };
+    object_headers(&mut response, file)?;
+    Ok(response)
+}
+
+/// Handle ``PutObject`` and ``UploadPart``
+pub(crate) async fn put_object(
+    State(app): State<SharedApp>,
+    Path((bucket_name, name)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, S3Error> {
+    let request = Incoming {
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+        body: &body,
+    };
+    let mut state = app.state();
+    let (key, bucket_id) = open(&state, &request, &bucket_name)?;
+    require(&key, "writeFiles", &bucket_id, Some(&name))?;
+    if headers.contains_key("x-amz-copy-source") {
+        return Err(S3Error::not_implemented("CopyObject"));
+    }
+    let mut response = Response::new(Body::empty());
+    if let Some(upload_id) = query.get("uploadId") {
+        let number = query
+            .get("partNumber")
+            .and_then(|number| number.parse().ok())
+            .filter(|number| (1..=MAX_PART_NUMBER).contains(number))
+            .ok_or_else(|| S3Error::invalid_argument("Invalid partNumber"))?;
+        state
+            .large_file(upload_id)
+            .ok()
+            .filter(|file| file.bucket_id == bucket_id && file.name == name)
+            .ok_or_else(|| S3Error::no_such_upload(upload_id))?;
+        let part = Part {
+            sha1: sha1_hex(&body),
+            data: body,
+            upload_timestamp: state.timestamp(),
+        };
+        response
+            .headers_mut()
+            .insert(header::ETAG, header_value(&etag(&part.data))?);
+        state.parts.entry(upload_id.clone()).or_default().insert(number, part);
+        return Ok(response);
+    }
+    let content_type = header(&headers, header::CONTENT_TYPE.as_str())
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+        .to_owned();
+    let file = state.add_version(
+        &bucket_id,
+        &name,
+        Action::Upload,
+        body.clone(),
+        sha1_hex(&body),
+        content_type,
+        metadata(&headers),
+    );
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, header_value(&etag(&file.data))?);
+    headers.insert("x-amz-version-id", header_value(&file.id)?);
+    Ok(response)
+}
+
+/// Handle ``DeleteObject`` and ``AbortMultipartUpload``
+///
+/// Deleting an object hides it, like ``b2_hide_file``, leaving its earlier
+/// versions in place.
+pub(crate) async fn delete_object(
+    State(app): State<SharedApp>,
+    Path((bucket_name, name)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, S3Error> {
+    let request = Incoming {
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+        body: &body,
+    };
+    let mut state = app.state();
+    let (key, bucket_id) = open(&state, &request, &bucket_name)?;
+    require(&key, "writeFiles", &bucket_id, Some(&name))?;
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    if let Some(upload_id) = query.get("uploadId") {
+        state
+            .large_file(upload_id)
+            .ok()
+            .filter(|file| file.bucket_id == bucket_id && file.name == name)
+            .ok_or_else(|| S3Error::no_such_upload(upload_id))?;
+        state.remove_version(upload_id);
+        return Ok(response);
+    }
+    if state.visible(&bucket_id, &name).is_some() {
+        let marker = state.add_version(
+            &bucket_id,
+            &name,
+            Action::Hide,
+            Bytes::new(),
+            "none".to_owned(),
+            "application/x-bz-hide-marker".to_owned(),
+            Map::new(),
+        );
+        let headers = response.headers_mut();
+        headers.insert("x-amz-delete-marker", HeaderValue::from_static("true"));
+        headers.insert("x-amz-version-id", header_value(&marker.id)?);
+    }
+    Ok(response)
+}
+
+/// The ``CompleteMultipartUpload`` request body
+#[derive(Deserialize)]
+struct CompleteMultipartUpload {
+    /// The parts to assemble, in order
+    #[serde(default, rename = "Part")]
+    parts: Vec<CompletedPart>,
+}
+
+/// A part listed in a ``CompleteMultipartUpload`` request
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CompletedPart {
+    /// The number of the part
+    part_number: u32,
+    /// The entity tag returned when the part was uploaded
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+/// Handle ``CreateMultipartUpload`` and ``CompleteMultipartUpload``
+///
+/// Multipart uploads are unfinished large files, and their upload IDs are
+/// the IDs of those files.
+pub(crate) async fn post_object(
+    State(app): State<SharedApp>,
+    Path((bucket_name, name)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, S3Error> {
+    let request = Incoming {
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+        body: &body,
+    };
+    let mut state = app.state();
+    let (key, bucket_id) = open(&state, &request, &bucket_name)?;
+    require(&key, "writeFiles", &bucket_id, Some(&name))?;
+    if query.contains_key("uploads") {
+        let content_type = header(&headers, header::CONTENT_TYPE.as_str())
+            .unwrap_or(DEFAULT_CONTENT_TYPE)
+            .to_owned();
+        let file = state.add_version(
+            &bucket_id,
+            &name,
+            Action::Start,
+            Bytes::new(),
+            "none".to_owned(),
+            content_type,
+            metadata(&headers),
+        );
+        state.parts.insert(file.id.clone(), BTreeMap::new());
+        return Ok(xml_response(&format!(
+            "<InitiateMultipartUploadResult>{}{}{}</\
+             InitiateMultipartUploadResult>",
+            element("Bucket", &bucket_name),
+            element("Key", &name),
+            element("UploadId", &file.id),
+        )));
+    }
+    let Some(upload_id) = query.get("uploadId") else {
+        return Err(S3Error::not_implemented(
+            "POST without uploads or uploadId",
+        ));
+    };
+    let finished = complete_multipart_upload(
+        &mut state, &bucket_id, &name, upload_id, &body,
+    )?;
+    let mut response = xml_response(&format!(
+        "<CompleteMultipartUploadResult>{}{}{}</CompleteMultipartUploadResult>",
+        element("Bucket", &bucket_name),
+        element("Key", &name),
+        element("ETag", &etag(&finished.data)),
+    ));
+    response
+        .headers_mut()
+        .insert("x-amz-version-id", header_value(&finished.id)?);
+    Ok(response)
+}
+
+/// Assemble the listed parts of a multipart upload into a finished file
+fn complete_multipart_upload(
+    state: &mut state::State,
+    bucket_id: &str,
+    name: &str,
+    upload_id: &str,
+    body: &[u8],
+) -> Result<FileVersion, S3Error> {
+    let malformed = || {
+        S3Error::new(
+            StatusCode::BAD_REQUEST,
+            "MalformedXML",
+            "The XML you provided was not well-formed",
+        )
+    };
+    let completion: CompleteMultipartUpload = std::str::from_utf8(body)
+        .ok()
+        .and_then(|body| quick_xml::de::from_str(body).ok())
+        .ok_or_else(malformed)?;
+    if completion.parts.is_empty() {
+        return Err(malformed());
+    }
+    let file = state
+        .large_file(upload_id)
+        .ok()
+        .filter(|file| file.bucket_id == bucket_id && file.name == name)
+        .ok_or_else(|| S3Error::no_such_upload(upload_id))?
+        .clone();
+    let uploaded = state.parts.get(upload_id).cloned().unwrap_or_default();
+    let minimum = state.config.absolute_minimum_part_size;
+    let mut previous = 0;
+    let mut data = Vec::new();
+    for (index, part) in completion.parts.iter().enumerate() {
+        if part.part_number <= previous {
+            return Err(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidPartOrder",
+                "Parts must be listed in ascending order",
+            ));
+        }
+        previous = part.part_number;
+        let stored = uploaded
+            .get(&part.part_number)
+            .filter(|stored| {
+                etag(&stored.data).trim_matches('"')
+                    == part.etag.trim_matches('"')
+            })
+            .ok_or_else(|| {
+                S3Error::new(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidPart",
+                    format!("Part {} was not uploaded", part.part_number),
+                )
+            })?;
+        if index + 1 < completion.parts.len() && stored.data.len() < minimum {
+            return Err(S3Error::new(
+                StatusCode::BAD_REQUEST,
+                "EntityTooSmall",
+                format!(
+                    "Part {} is smaller than the minimum part size",
+                    part.part_number
+                ),
+            ));
+        }
+        data.extend_from_slice(&stored.data);
+    }
+    let finished = FileVersion {
+        action: Action::Upload,
+        data: Bytes::from(data),
+        ..file
+    };
+    state.remove_version(upload_id);
+    state.files.insert(finished.id.clone(), finished.clone());
+    Ok(finished)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Method;
+
+    use crate::routes::s3::{canonical_query, operation, parse_authorization};
+
+    #[test]
+    fn operations() {
+        let get = |path, query| operation(&Method::GET, path, query);
+        assert_eq!(
+            get("/s3/bucket", Some("list-type=2")),
+            "s3_list_objects_v2"
+        );
+        assert_eq!(get("/s3/bucket/a/b.txt", None), "s3_get_object");
+        assert_eq!(
+            operation(
+                &Method::PUT,
+                "/s3/bucket/a",
+                Some("partNumber=1&uploadId=x")
+            ),
+            "s3_upload_part"
+        );
+        assert_eq!(
+            operation(&Method::POST, "/s3/bucket/a", Some("uploads")),
+            "s3_create_multipart_upload"
+        );
+    }
+
+    #[test]
+    fn canonical_queries_are_sorted_and_encoded() {
+        assert_eq!(
+            canonical_query(Some("prefix=a%2Fb&list-type=2&uploads")),
+            "list-type=2&prefix=a%2Fb&uploads="
+        );
+        assert_eq!(canonical_query(None), "");
+    }
+
+    #[test]
+    fn authorization_headers() {
+        let signature = parse_authorization(
+            "AWS4-HMAC-SHA256 \
+             Credential=key/20240101/us-east-1/s3/aws4_request, \
+             SignedHeaders=host;x-amz-date, Signature=abc",
+        )
+        .expect("Header is well-formed");
+        assert_eq!(signature.key_id, "key");
+        assert_eq!(signature.scope, "20240101/us-east-1/s3/aws4_request");
+        assert_eq!(signature.signed_headers, "host;x-amz-date");
+        assert_eq!(signature.signature, "abc");
+        assert!(parse_authorization("Bearer token").is_none());
+    }
+}
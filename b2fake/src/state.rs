@@ -0,0 +1,931 @@
+//! The in-memory contents of a fake B2 account
+//!
+//! Everything the server knows lives in a single [`State`] guarded by a mutex.
+//! Handlers lock it, check authorization, mutate it and render B2-shaped JSON
+//! from it without ever holding the lock across an await point.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::body::Bytes;
+use serde_json::{json, Map, Value};
+use sha1::{Digest, Sha1};
+
+use crate::{error::ApiError, Config};
+
+/// Every capability a master application key has
+pub(crate) const ALL_CAPABILITIES: &[&str] = &[
+    "listKeys",
+    "writeKeys",
+    "deleteKeys",
+    "listAllBucketNames",
+    "listBuckets",
+    "readBuckets",
+    "writeBuckets",
+    "deleteBuckets",
+    "readBucketEncryption",
+    "writeBucketEncryption",
+    "readBucketRetentions",
+    "writeBucketRetentions",
+    "readBucketNotifications",
+    "writeBucketNotifications",
+    "readBucketReplications",
+    "writeBucketReplications",
+    "listFiles",
+    "readFiles",
+    "shareFiles",
+    "writeFiles",
+    "deleteFiles",
+    "readFileLegalHolds",
+    "writeFileLegalHolds",
+    "readFileRetentions",
+    "writeFileRetentions",
+    "bypassGovernance",
+];
+
+/// The current time in milliseconds since the Unix epoch
+pub(crate) fn now_millis() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is before the Unix epoch");
+    u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Convert a duration to milliseconds, saturating on overflow
+fn duration_millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// The hex-encoded SHA1 digest of some data
+pub(crate) fn sha1_hex(data: &[u8]) -> String {
+    hex::encode(Sha1::digest(data))
+}
+
+/// An application key
+#[derive(Clone, Debug)]
+pub(crate) struct Key {
+    /// The public identifier of the key
+    pub(crate) id: String,
+    /// The secret half of the key
+    pub(crate) secret: String,
+    /// The human-readable name of the key
+    pub(crate) name: String,
+    /// The capabilities granted to the key
+    pub(crate) capabilities: Vec<String>,
+    /// The bucket the key is restricted to, if any
+    pub(crate) bucket_id: Option<String>,
+    /// The file name prefix the key is restricted to, if any
+    pub(crate) name_prefix: Option<String>,
+    /// When the key expires in milliseconds since the epoch, if ever
+    pub(crate) expiration_timestamp: Option<u64>,
+}
+
+impl Key {
+    /// Render the key as returned by ``b2_list_keys``
+    pub(crate) fn to_json(&self, account_id: &str) -> Value {
+        json!({
+            "accountId": account_id,
+            "applicationKeyId": self.id,
+            "bucketId": self.bucket_id,
+            "capabilities": self.capabilities,
+            "expirationTimestamp": self.expiration_timestamp,
+            "keyName": self.name,
+            "namePrefix": self.name_prefix,
+            "options": ["s3"],
+        })
+    }
+}
+
+/// An authorization token handed out by ``b2_authorize_account``
+#[derive(Debug)]
+struct Token {
+    /// The application key the token was issued for
+    key_id: String,
+    /// When the token stops being accepted, in milliseconds since the epoch
+    expires_at: u64,
+}
+
+/// A token handed out by ``b2_get_download_authorization``
+#[derive(Clone, Debug)]
+pub(crate) struct DownloadAuthorization {
+    /// The bucket the token may download from
+    pub(crate) bucket_id: String,
+    /// The prefix the names of downloaded files must start with
+    pub(crate) file_name_prefix: String,
+    /// When the token stops being accepted, in milliseconds since the epoch
+    pub(crate) expires_at: u64,
+    /// The `b2ContentDisposition` every download has to ask for, if any
+    pub(crate) content_disposition: Option<String>,
+    /// The `b2ContentType` every download has to ask for, if any
+    pub(crate) content_type: Option<String>,
+}
+
+/// What an upload URL uploads into
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum UploadTarget {
+    /// Simple uploads into a bucket
+    Bucket(String),
+    /// Part uploads into an unfinished large file
+    LargeFile(String),
+}
+
+/// An upload URL handed out by ``b2_get_upload_url`` or
+/// ``b2_get_upload_part_url``
+#[derive(Debug)]
+struct UploadUrl {
+    /// What the URL uploads into
+    target: UploadTarget,
+    /// The authorization token that must accompany uploads to the URL
+    token: String,
+    /// The application key that requested the URL
+    key_id: String,
+}
+
+/// A bucket
+#[derive(Clone, Debug)]
+pub(crate) struct Bucket {
+    /// The unique identifier of the bucket
+    pub(crate) id: String,
+    /// The unique name of the bucket
+    pub(crate) name: String,
+    /// The bucket type, such as `allPrivate`
+    pub(crate) kind: String,
+    /// User data stored with the bucket
+    pub(crate) info: Value,
+    /// Lifecycle rules stored with the bucket
+    pub(crate) lifecycle_rules: Value,
+    /// Incremented every time the bucket changes
+    pub(crate) revision: u64,
+}
+
+impl Bucket {
+    /// Render the bucket as returned by ``b2_list_buckets``
+    pub(crate) fn to_json(&self, account_id: &str) -> Value {
+        json!({
+            "accountId": account_id,
+            "bucketId": self.id,
+            "bucketName": self.name,
+            "bucketType": self.kind,
+            "bucketInfo": self.info,
+            "corsRules": [],
+            "fileLockConfiguration": {
+                "isClientAuthorizedToRead": true,
+                "value": {
+                    "defaultRetention": {
+                        "mode": null,
+                        "period": null,
+                    },
+                    "isFileLockEnabled": false,
+                },
+            },
+            "defaultServerSideEncryption": {
+                "isClientAuthorizedToRead": true,
+                "value": {
+                    "mode": null,
+                },
+            },
+            "lifecycleRules": self.lifecycle_rules,
+            "replicationConfiguration": {
+                "isClientAuthorizedToRead": true,
+                "value": null,
+            },
+            "revision": self.revision,
+            "options": ["s3"],
+        })
+    }
+}
+
+/// The kind of a file version
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// A file that was uploaded and can be downloaded
+    Upload,
+    /// A large file that was started but not finished or canceled
+    Start,
+    /// A marker hiding earlier versions of the same name
+    Hide,
+}
+
+impl Action {
+    /// The name B2 uses for this action
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Action::Upload => "upload",
+            Action::Start => "start",
+            Action::Hide => "hide",
+        }
+    }
+}
+
+/// A version of a file
+#[derive(Clone, Debug)]
+pub(crate) struct FileVersion {
+    /// The unique identifier of the version
+    pub(crate) id: String,
+    /// The bucket the version lives in
+    pub(crate) bucket_id: String,
+    /// The name of the file
+    pub(crate) name: String,
+    /// What kind of version this is
+    pub(crate) action: Action,
+    /// The contents of the file
+    pub(crate) data: Bytes,
+    /// The hex SHA1 of the contents, or `none` for large files
+    pub(crate) content_sha1: String,
+    /// The MIME type of the file
+    pub(crate) content_type: String,
+    /// Custom information stored with the file
+    pub(crate) file_info: Map<String, Value>,
+    /// When the version was created, in milliseconds since the epoch
+    pub(crate) upload_timestamp: u64,
+}
+
+impl FileVersion {
+    /// Render the version as returned by the file endpoints
+    pub(crate) fn to_json(&self, account_id: &str) -> Value {
+        json!({
+            "accountId": account_id,
+            "action": self.action.as_str(),
+            "bucketId": self.bucket_id,
+            "contentLength": self.data.len(),
+            "contentMd5": null,
+            "contentSha1": self.content_sha1,
+            "contentType": self.content_type,
+            "fileId": self.id,
+            "fileInfo": self.file_info,
+            "fileName": self.name,
+            "fileRetention": {
+                "isClientAuthorizedToRead": true,
+                "value": {
+                    "mode": null,
+                    "retainUntilTimestamp": null,
+                },
+            },
+            "legalHold": {
+                "isClientAuthorizedToRead": true,
+                "value": null,
+            },
+            "replicationStatus": null,
+            "serverSideEncryption": {
+                "mode": null,
+            },
+            "uploadTimestamp": self.upload_timestamp,
+        })
+    }
+}
+
+/// Render a virtual folder entry as returned by listings with a delimiter
+fn folder_json(name: &str) -> Value {
+    json!({
+        "accountId": null,
+        "action": "folder",
+        "bucketId": null,
+        "contentLength": 0,
+        "contentMd5": null,
+        "contentSha1": null,
+        "contentType": null,
+        "fileId": null,
+        "fileInfo": {},
+        "fileName": name,
+        "uploadTimestamp": 0,
+    })
+}
+
+/// An uploaded part of an unfinished large file
+#[derive(Clone, Debug)]
+pub(crate) struct Part {
+    /// The contents of the part
+    pub(crate) data: Bytes,
+    /// The hex SHA1 of the contents
+    pub(crate) sha1: String,
+    /// When the part was uploaded, in milliseconds since the epoch
+    pub(crate) upload_timestamp: u64,
+}
+
+/// One entry in a file listing, before rendering
+enum Entry<'a> {
+    /// A real file version
+    File(&'a FileVersion),
+    /// A virtual folder made by splitting names on the delimiter
+    Folder(String),
+}
+
+impl Entry<'_> {
+    /// The name used to order and paginate the entry
+    fn name(&self) -> &str {
+        match self {
+            Entry::File(file) => &file.name,
+            Entry::Folder(name) => name,
+        }
+    }
+
+    /// Render the entry as JSON
+    fn to_json(&self, account_id: &str) -> Value {
+        match self {
+            Entry::File(file) => file.to_json(account_id),
+            Entry::Folder(name) => folder_json(name),
+        }
+    }
+}
+
+/// A page of listing results
+pub(crate) struct Page {
+    /// The rendered entries on this page
+    pub(crate) files: Vec<Value>,
+    /// The name to start the next page at, if there is one
+    pub(crate) next_file_name: Option<String>,
+    /// The file ID to start the next page at, if there is one
+    pub(crate) next_file_id: Option<String>,
+}
+
+/// The complete state of the fake account
+#[derive(Debug)]
+pub(crate) struct State {
+    /// Server configuration
+    pub(crate) config: Config,
+    /// The identifier of the single account the server hosts
+    pub(crate) account_id: String,
+    /// Application keys by key ID
+    pub(crate) keys: BTreeMap<String, Key>,
+    /// Live authorization tokens
+    tokens: HashMap<String, Token>,
+    /// Download authorization tokens
+    download_authorizations: HashMap<String, DownloadAuthorization>,
+    /// Live upload URLs by the identifier in their path
+    upload_urls: HashMap<String, UploadUrl>,
+    /// Buckets by bucket ID
+    pub(crate) buckets: BTreeMap<String, Bucket>,
+    /// File versions by file ID
+    pub(crate) files: BTreeMap<String, FileVersion>,
+    /// Uploaded parts of unfinished large files by file ID
+    pub(crate) parts: HashMap<String, BTreeMap<u32, Part>>,
+    /// Source of unique identifiers
+    counter: u64,
+    /// The last timestamp handed out, used to keep timestamps increasing
+    last_timestamp: u64,
+}
+
+impl State {
+    /// Create the state of a fresh account with only a master key
+    pub(crate) fn new(config: Config) -> Self {
+        let account_id = "000fakeb2account".to_owned();
+        let master = Key {
+            id: account_id.clone(),
+            secret: "K000fakeb2masterapplicationkey".to_owned(),
+            name: "Master Application Key".to_owned(),
+            capabilities: ALL_CAPABILITIES
+                .iter()
+                .map(|&c| c.to_owned())
+                .collect(),
+            bucket_id: None,
+            name_prefix: None,
+            expiration_timestamp: None,
+        };
+        let mut keys = BTreeMap::new();
+        keys.insert(master.id.clone(), master);
+        Self {
+            config,
+            account_id,
+            keys,
+            tokens: HashMap::new(),
+            download_authorizations: HashMap::new(),
+            upload_urls: HashMap::new(),
+            buckets: BTreeMap::new(),
+            files: BTreeMap::new(),
+            parts: HashMap::new(),
+            counter: 0,
+            last_timestamp: 0,
+        }
+    }
+
+    /// Produce a new unique number
+    pub(crate) fn next_id(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+
+    /// Produce a timestamp that is later than every previous one
+    pub(crate) fn timestamp(&mut self) -> u64 {
+        self.last_timestamp = now_millis().max(self.last_timestamp + 1);
+        self.last_timestamp
+    }
+
+    /// Create a new application key
+    pub(crate) fn create_key(
+        &mut self,
+        name: String,
+        capabilities: Vec<String>,
+        bucket_id: Option<String>,
+        name_prefix: Option<String>,
+        valid_for: Option<Duration>,
+    ) -> Key {
+        let id = self.next_id();
+        let key = Key {
+            id: format!("005fake{id:018}"),
+            secret: format!("K005fakesecret{id:017}"),
+            name,
+            capabilities,
+            bucket_id,
+            name_prefix,
+            expiration_timestamp: valid_for.map(|valid| {
+                now_millis().saturating_add(duration_millis(valid))
+            }),
+        };
+        self.keys.insert(key.id.clone(), key.clone());
+        key
+    }
+
+    /// Check an application key ID and secret, returning the key
+    pub(crate) fn check_credentials(
+        &self,
+        key_id: &str,
+        secret: &str,
+    ) -> Result<&Key, ApiError> {
+        let key = self
+            .keys
+            .get(key_id)
+            .filter(|key| key.secret == secret)
+            .ok_or_else(|| ApiError::unauthorized("Invalid application key"))?;
+        if key.expiration_timestamp.is_some_and(|at| at <= now_millis()) {
+            return Err(ApiError::unauthorized(
+                "The application key has expired",
+            ));
+        }
+        Ok(key)
+    }
+
+    /// Issue a new authorization token for a key
+    pub(crate) fn issue_token(&mut self, key_id: &str) -> String {
+        let token = format!("4_fake_token_{:016}", self.next_id());
+        let expires_at = now_millis()
+            .saturating_add(duration_millis(self.config.token_lifetime));
+        self.tokens.insert(
+            token.clone(),
+            Token {
+                key_id: key_id.to_owned(),
+                expires_at,
+            },
+        );
+        token
+    }
+
+    /// Issue a new download authorization token
+    pub(crate) fn issue_download_authorization(
+        &mut self,
+        authorization: DownloadAuthorization,
+    ) -> String {
+        let token = format!("3_fake_download_{:016}", self.next_id());
+        self.download_authorizations.insert(token.clone(), authorization);
+        token
+    }
+
+    /// Look up the download authorization behind a token
+    pub(crate) fn download_authorization(
+        &self,
+        token: Option<&str>,
+    ) -> Option<&DownloadAuthorization> {
+        self.download_authorizations.get(token?)
+    }
+
+    /// Make every token issued so far expire immediately
+    pub(crate) fn expire_tokens(&mut self) {
+        for token in self.tokens.values_mut() {
+            token.expires_at = 0;
+        }
+        for authorization in self.download_authorizations.values_mut() {
+            authorization.expires_at = 0;
+        }
+    }
+
+    /// Look up the key behind an authorization token
+    fn token_key(&self, token: Option<&str>) -> Result<&Key, ApiError> {
+        let token = token
+            .and_then(|t| self.tokens.get(t))
+            .ok_or_else(ApiError::bad_auth_token)?;
+        if token.expires_at <= now_millis() {
+            return Err(ApiError::expired_auth_token());
+        }
+        self.keys.get(&token.key_id).ok_or_else(ApiError::bad_auth_token)
+    }
+
+    /// Check that a token is valid and its key has a capability
+    pub(crate) fn authorize(
+        &self,
+        token: Option<&str>,
+        capability: &str,
+    ) -> Result<Key, ApiError> {
+        let key = self.token_key(token)?;
+        if key.capabilities.iter().any(|c| c == capability) {
+            Ok(key.clone())
+        } else {
+            Err(ApiError::unauthorized(format!(
+                "Key is missing the {capability} capability"
+            )))
+        }
+    }
+
+    /// Check that a key may access a bucket and, optionally, a file name
+    pub(crate) fn check_restrictions(
+        key: &Key,
+        bucket_id: &str,
+        file_name: Option<&str>,
+    ) -> Result<(), ApiError> {
+        if key.bucket_id.as_deref().is_some_and(|b| b != bucket_id) {
+            return Err(ApiError::unauthorized(
+                "Key is restricted to a different bucket",
+            ));
+        }
+        if let (Some(prefix), Some(name)) = (&key.name_prefix, file_name) {
+            if !name.starts_with(prefix.as_str()) {
+                return Err(ApiError::unauthorized(format!(
+                    "Key is restricted to file names starting with {prefix}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up a bucket by ID
+    pub(crate) fn bucket(&self, bucket_id: &str) -> Result<&Bucket, ApiError> {
+        self.buckets
+            .get(bucket_id)
+            .ok_or_else(|| ApiError::bad_bucket_id(bucket_id))
+    }
+
+    /// Look up a bucket by name
+    pub(crate) fn bucket_by_name(&self, name: &str) -> Option<&Bucket> {
+        self.buckets.values().find(|bucket| bucket.name == name)
+    }
+
+    /// Create a new bucket
+    pub(crate) fn create_bucket(
+        &mut self,
+        name: &str,
+        bucket_type: &str,
+        bucket_info: Value,
+        lifecycle_rules: Value,
+    ) -> Result<Bucket, ApiError> {
+        if self.bucket_by_name(name).is_some() {
+            return Err(ApiError::new(
+                axum::http::StatusCode::BAD_REQUEST,
+                "duplicate_bucket_name",
+                format!("Bucket name is already in use: {name}"),
+            ));
+        }
+        let valid_name = (6..=63).contains(&name.len())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !name.to_ascii_lowercase().starts_with("b2-");
+        if !valid_name {
+            return Err(ApiError::bad_request(format!(
+                "Invalid bucket name: {name}"
+            )));
+        }
+        let bucket = Bucket {
+            id: format!("fakebucket{:014x}", self.next_id()),
+            name: name.to_owned(),
+            kind: bucket_type.to_owned(),
+            info: bucket_info,
+            lifecycle_rules,
+            revision: 1,
+        };
+        self.buckets.insert(bucket.id.clone(), bucket.clone());
+        Ok(bucket)
+    }
+
+    /// Create an upload URL and return its path identifier and token
+    pub(crate) fn create_upload_url(
+        &mut self,
+        target: UploadTarget,
+        key_id: &str,
+    ) -> (String, String) {
+        let id = format!("{:016}", self.next_id());
+        let token = format!("4_fake_upload_{id}");
+        self.upload_urls.insert(
+            id.clone(),
+            UploadUrl {
+                target,
+                token: token.clone(),
+                key_id: key_id.to_owned(),
+            },
+        );
+        (id, token)
+    }
+
+    /// Check the token presented to an upload URL
+    ///
+    /// Returns the target of the URL and the key that requested it.
+    pub(crate) fn authorize_upload(
+        &self,
+        url_id: &str,
+        token: Option<&str>,
+    ) -> Result<(UploadTarget, Key), ApiError> {
+        let url = self
+            .upload_urls
+            .get(url_id)
+            .ok_or_else(ApiError::bad_auth_token)?;
+        if token != Some(url.token.as_str()) {
+            return Err(ApiError::bad_auth_token());
+        }
+        let key =
+            self.keys.get(&url.key_id).ok_or_else(ApiError::bad_auth_token)?;
+        Ok((url.target.clone(), key.clone()))
+    }
+
+    /// Look up a file version by ID
+    pub(crate) fn file(&self, file_id: &str) -> Result<&FileVersion, ApiError> {
+        self.files.get(file_id).ok_or_else(|| {
+            ApiError::new(
+                axum::http::StatusCode::NOT_FOUND,
+                "file_not_present",
+                format!("File not present: {file_id}"),
+            )
+        })
+    }
+
+    /// Look up an unfinished large file by ID
+    pub(crate) fn large_file(
+        &self,
+        file_id: &str,
+    ) -> Result<&FileVersion, ApiError> {
+        self.files
+            .get(file_id)
+            .filter(|file| file.action == Action::Start)
+            .ok_or_else(|| {
+                ApiError::bad_request(format!(
+                    "No active upload for: {file_id}"
+                ))
+            })
+    }
+
+    /// Store a new file version and return it
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn add_version(
+        &mut self,
+        bucket_id: &str,
+        name: &str,
+        action: Action,
+        data: Bytes,
+        content_sha1: String,
+        content_type: String,
+        file_info: Map<String, Value>,
+    ) -> FileVersion {
+        let upload_timestamp = self.timestamp();
+        let file = FileVersion {
+            id: format!("4_z{bucket_id}_f{:018}", self.next_id()),
+            bucket_id: bucket_id.to_owned(),
+            name: name.to_owned(),
+            action,
+            data,
+            content_sha1,
+            content_type,
+            file_info,
+            upload_timestamp,
+        };
+        self.files.insert(file.id.clone(), file.clone());
+        file
+    }
+
+    /// Remove a file version, along with any parts it has
+    pub(crate) fn remove_version(
+        &mut self,
+        file_id: &str,
+    ) -> Option<FileVersion> {
+        self.parts.remove(file_id);
+        self.upload_urls.retain(|_, url| {
+            url.target != UploadTarget::LargeFile(file_id.to_owned())
+        });
+        self.files.remove(file_id)
+    }
+
+    /// Every version in a bucket, ordered by name then newest first
+    pub(crate) fn versions(&self, bucket_id: &str) -> Vec<&FileVersion> {
+        let mut versions: Vec<&FileVersion> = self
+            .files
+            .values()
+            .filter(|file| file.bucket_id == bucket_id)
+            .collect();
+        versions.sort_by(|a, b| {
+            a.name
+                .cmp(&b.name)
+                .then(b.upload_timestamp.cmp(&a.upload_timestamp))
+        });
+        versions
+    }
+
+    /// The version of a name that is currently visible in a bucket
+    ///
+    /// Unfinished large files are never visible, and a name whose newest
+    /// finished version is a hide marker has no visible version.
+    pub(crate) fn visible(
+        &self,
+        bucket_id: &str,
+        name: &str,
+    ) -> Option<&FileVersion> {
+        self.files
+            .values()
+            .filter(|f| {
+                f.bucket_id == bucket_id
+                    && f.name == name
+                    && f.action != Action::Start
+            })
+            .max_by_key(|f| f.upload_timestamp)
+            .filter(|f| f.action == Action::Upload)
+    }
+
+    /// List the visible files in a bucket, as ``b2_list_file_names`` does
+    pub(crate) fn list_names(
+        &self,
+        bucket_id: &str,
+        start_file_name: Option<&str>,
+        max_file_count: usize,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Page {
+        let mut latest: BTreeMap<&str, &FileVersion> = BTreeMap::new();
+        for file in self.versions(bucket_id) {
+            if file.action != Action::Start {
+                latest.entry(file.name.as_str()).or_insert(file);
+            }
+        }
+        let entries =
+            latest.into_values().filter(|file| file.action == Action::Upload);
+        let page = self.paginate(
+            entries,
+            start_file_name,
+            None,
+            max_file_count,
+            prefix,
+            delimiter,
+        );
+        Page {
+            next_file_id: None,
+            ..page
+        }
+    }
+
+    /// List every version in a bucket, as ``b2_list_file_versions`` does
+    pub(crate) fn list_versions(
+        &self,
+        bucket_id: &str,
+        start_file_name: Option<&str>,
+        start_file_id: Option<&str>,
+        max_file_count: usize,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Page {
+        let entries = self.versions(bucket_id).into_iter();
+        self.paginate(
+            entries,
+            start_file_name,
+            start_file_id,
+            max_file_count,
+            prefix,
+            delimiter,
+        )
+    }
+
+    /// Apply prefix, delimiter and pagination rules to sorted versions
+    fn paginate<'a>(
+        &self,
+        files: impl Iterator<Item = &'a FileVersion>,
+        start_file_name: Option<&str>,
+        start_file_id: Option<&str>,
+        max_file_count: usize,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Page {
+        let mut entries: Vec<Entry<'a>> = Vec::new();
+        let mut started = start_file_name.is_none();
+        for file in files.filter(|f| f.name.starts_with(prefix)) {
+            if !started {
+                let start = start_file_name.unwrap_or_default();
+                started = match start_file_id {
+                    Some(id) => {
+                        file.name.as_str() > start
+                            || (file.name == start && file.id == id)
+                    }
+                    None => file.name.as_str() >= start,
+                };
+                if !started {
+                    continue;
+                }
+            }
+            let folder = delimiter.and_then(|delimiter| {
+                let rest = file.name.strip_prefix(prefix)?;
+                let (head, _) = rest.split_once(delimiter)?;
+                Some(format!("{prefix}{head}{delimiter}"))
+            });
+            match folder {
+                Some(folder) => {
+                    if entries.last().is_none_or(|e| e.name() != folder) {
+                        entries.push(Entry::Folder(folder));
+                    }
+                }
+                None => entries.push(Entry::File(file)),
+            }
+            if entries.len() > max_file_count {
+                break;
+            }
+        }
+        let next = if entries.len() > max_file_count {
+            entries.pop()
+        } else {
+            None
+        };
+        Page {
+            files: entries
+                .iter()
+                .map(|entry| entry.to_json(&self.account_id))
+                .collect(),
+            next_file_name: next.as_ref().map(|e| e.name().to_owned()),
+            next_file_id: next.and_then(|entry| match entry {
+                Entry::File(file) => Some(file.id.clone()),
+                Entry::Folder(_) => None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Bytes;
+    use serde_json::Map;
+
+    use crate::{
+        state::{Action, State},
+        Config,
+    };
+
+    fn state_with_files(names: &[&str]) -> (State, String) {
+        let mut state = State::new(Config::default());
+        let bucket = state
+            .create_bucket(
+                "listing",
+                "allPrivate",
+                serde_json::json!({}),
+                serde_json::json!([]),
+            )
+            .expect("Bucket should be created");
+        for name in names {
+            state.add_version(
+                &bucket.id,
+                name,
+                Action::Upload,
+                Bytes::from_static(b"data"),
+                String::new(),
+                "text/plain".to_owned(),
+                Map::new(),
+            );
+        }
+        (state, bucket.id)
+    }
+
+    fn names(page: &crate::state::Page) -> Vec<&str> {
+        page.files
+            .iter()
+            .map(|f| f["fileName"].as_str().expect("fileName is a string"))
+            .collect()
+    }
+
+    #[test]
+    fn list_names_with_delimiter() {
+        let (state, bucket) =
+            state_with_files(&["a/1", "a/2", "a/b/3", "b", "c/4"]);
+        let page = state.list_names(&bucket, None, 100, "", Some("/"));
+        assert_eq!(names(&page), ["a/", "b", "c/"]);
+        let page = state.list_names(&bucket, None, 100, "a/", Some("/"));
+        assert_eq!(names(&page), ["a/1", "a/2", "a/b/"]);
+    }
+
+    #[test]
+    fn list_names_paginates() {
+        let (state, bucket) = state_with_files(&["a", "b", "c"]);
+        let page = state.list_names(&bucket, None, 2, "", None);
+        assert_eq!(names(&page), ["a", "b"]);
+        assert_eq!(page.next_file_name.as_deref(), Some("c"));
+        let page = state.list_names(&bucket, Some("c"), 2, "", None);
+        assert_eq!(names(&page), ["c"]);
+        assert_eq!(page.next_file_name, None);
+    }
+
+    #[test]
+    fn hidden_files_are_not_visible() {
+        let (mut state, bucket) = state_with_files(&["a", "b"]);
+        state.add_version(
+            &bucket,
+            "a",
+            Action::Hide,
+            Bytes::new(),
+            "none".to_owned(),
+            "application/x-bz-hide-marker".to_owned(),
+            Map::new(),
+        );
+        assert!(state.visible(&bucket, "a").is_none());
+        let page = state.list_names(&bucket, None, 100, "", None);
+        assert_eq!(names(&page), ["b"]);
+        let page = state.list_versions(&bucket, None, None, 100, "", None);
+        assert_eq!(names(&page), ["a", "a", "b"]);
+    }
+}
@@ -5,11 +5,55 @@ use std::{
     ops::{ControlFlow, FromResidual, Try},
 };
 
-use reqwest::Response;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{header::CONTENT_TYPE, Response};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{
+    metrics::{bucket_of, operation_span},
+    Session, SessionError,
+};
 
 pub(crate) mod b2_authorize_account;
+pub(crate) mod b2_cancel_large_file;
+pub(crate) mod b2_copy_file;
+pub(crate) mod b2_copy_part;
+pub(crate) mod b2_finish_large_file;
+pub(crate) mod b2_get_download_authorization;
+pub(crate) mod b2_get_upload_part_url;
+pub(crate) mod b2_get_upload_url;
+pub(crate) mod b2_hide_file;
 pub(crate) mod b2_list_buckets;
+pub(crate) mod b2_list_file_names;
+pub(crate) mod b2_list_file_versions;
+pub(crate) mod b2_start_large_file;
+pub(crate) mod b2_upload_file;
+pub(crate) mod b2_upload_part;
+
+/// The characters B2 requires to be percent-encoded in file names and file
+/// info sent as headers or in download URLs
+const B2_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'-')
+    .remove(b'/')
+    .remove(b'~')
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')')
+    .remove(b'*')
+    .remove(b';')
+    .remove(b'=')
+    .remove(b':')
+    .remove(b'@');
+
+/// Percent-encode a value for use in an `X-Bz-*` header or a download URL
+pub(crate) fn percent_encode(value: &str) -> String {
+    utf8_percent_encode(value, B2_ENCODE_SET).to_string()
+}
 
 /// A representation of an error returned from the Backblaze API
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,7 +68,7 @@ pub struct ApiError {
     pub message: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum ApiErrorCode {
     /// The requested bucket ID does not match an existing bucket.
@@ -52,6 +96,16 @@ pub enum ApiErrorCode {
     Other,
 }
 
+impl ApiErrorCode {
+    /// Whether this code means the authorization token itself was rejected
+    ///
+    /// Calls rejected this way can succeed after calling
+    /// ``b2_authorize_account`` again.
+    pub(crate) fn is_token_rejection(self) -> bool {
+        matches!(self, Self::BadAuthToken | Self::ExpiredAuthToken)
+    }
+}
+
 pub enum ApiResult<R, E, F> {
     Response(ApiResponse<R, E>),
     Failure(F),
@@ -62,9 +116,13 @@ pub enum ApiResponse<R, E> {
     Error(E),
 }
 
-impl<R, E, F> FromResidual for ApiResult<R, E, F> {
-    fn from_residual(residual: <Self as Try>::Residual) -> Self {
-        Self::Failure(residual.unwrap_err())
+impl<R, E, F, G> FromResidual<Result<Infallible, G>> for ApiResult<R, E, F>
+where
+    G: Into<F>,
+{
+    fn from_residual(residual: Result<Infallible, G>) -> Self {
+        let Err(failure) = residual;
+        Self::Failure(failure.into())
     }
 }
 
@@ -84,27 +142,108 @@ impl<R, E, F> Try for ApiResult<R, E, F> {
     }
 }
 
-impl<R, E> From<Response> for ApiResult<R, E, reqwest::Error>
+impl<R, E, F> ApiResult<R, E, F>
 where
-    R: DeserializeOwned + Send,
-    E: DeserializeOwned + Send,
+    R: DeserializeOwned,
+    E: DeserializeOwned,
+    F: From<reqwest::Error>,
 {
-    fn from(value: Response) -> Self {
-        let rt = tokio::runtime::Runtime::new()
-            .expect("Failed to start tokio runtime");
-        rt.block_on(async move {
-            if value.status().is_success() {
-                match value.json::<R>().await {
-                    Ok(r) => ApiResult::Response(ApiResponse::Ok(r)),
-                    Err(e) => ApiResult::Failure(e),
-                }
-            } else {
-                match value.json::<E>().await {
-                    Ok(e) => ApiResult::Response(ApiResponse::Error(e)),
-                    Err(e) => ApiResult::Failure(e),
+    /// Read a response from the Backblaze API
+    ///
+    /// Successful responses are deserialized as `R` and error responses as
+    /// `E`. Failing to read or deserialize the body is a failure.
+    pub(crate) async fn from_response(value: Response) -> Self {
+        if value.status().is_success() {
+            match value.json::<R>().await {
+                Ok(r) => ApiResult::Response(ApiResponse::Ok(r)),
+                Err(e) => ApiResult::Failure(e.into()),
+            }
+        } else {
+            match value.json::<E>().await {
+                Ok(e) => ApiResult::Response(ApiResponse::Error(e)),
+                Err(e) => ApiResult::Failure(e.into()),
+            }
+        }
+    }
+}
+
+impl<R> ApiResult<R, ApiError, SessionError> {
+    /// Collapse the result of a call into a plain `Result`
+    ///
+    /// Error responses from the Backblaze API become
+    /// `SessionError::RequestRejected`.
+    pub(crate) fn into_result(self) -> Result<R, SessionError> {
+        match self {
+            ApiResult::Response(ApiResponse::Ok(response)) => Ok(response),
+            ApiResult::Response(ApiResponse::Error(error)) => {
+                Err(SessionError::RequestRejected(error))
+            }
+            ApiResult::Failure(failure) => Err(failure),
+        }
+    }
+}
+
+impl Session {
+    /// Call a JSON endpoint of the B2 Native API
+    ///
+    /// If the authorization token is rejected as invalid or expired, the
+    /// session re-authorizes once and repeats the call.
+    pub(crate) async fn call<B, R>(
+        &self,
+        endpoint: &str,
+        body: &B,
+    ) -> ApiResult<R, ApiError, SessionError>
+    where
+        B: Serialize,
+        R: DeserializeOwned,
+    {
+        // Requests that can't be serialized fail to send, just as they would
+        // with `RequestBuilder::json`
+        let Ok(payload) = serde_json::to_vec(body) else {
+            return ApiResult::Failure(SessionError::RequestFailed);
+        };
+        let bucket = bucket_of(&payload);
+        let bytes_sent = u64::try_from(payload.len()).unwrap_or(u64::MAX);
+        let metrics = &self.inner.metrics;
+        async {
+            let mut retries = 0;
+            loop {
+                let (url, token) = {
+                    let authorization = self.authorization();
+                    (
+                        format!(
+                            "{}/b2api/v3/{endpoint}",
+                            authorization.storage_api_info.api_url
+                        ),
+                        authorization.token.clone(),
+                    )
+                };
+                let request = self
+                    .http_client()
+                    .post(&url)
+                    .header("Authorization", &token)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(payload.clone());
+                let response =
+                    self.send_request(endpoint, bytes_sent, request).await?;
+                match ApiResult::<R, ApiError, SessionError>::from_response(
+                    response,
+                )
+                .await
+                {
+                    ApiResult::Response(ApiResponse::Error(error))
+                        if retries == 0 && error.code.is_token_rejection() =>
+                    {
+                        self.reauthorize(&token).await?;
+                        retries += 1;
+                        metrics.retry(endpoint, retries);
+                    }
+                    other => return other,
                 }
             }
-        })
+        }
+        .instrument(operation_span(endpoint, bucket.as_deref()))
+        .await
     }
 }
 
@@ -116,7 +255,7 @@ where
     type Error;
     type Failure;
     async fn send(
-        &mut self,
+        &self,
         body: T,
     ) -> ApiResult<Self::Response, Self::Error, Self::Failure>;
 }
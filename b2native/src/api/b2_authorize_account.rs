@@ -2,18 +2,15 @@
 //!
 //! [API Docs](https://www.backblaze.com/apidocs/b2-authorize-account)
 
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use tracing::Instrument;
 
 use crate::{
-    api::{ApiResult, OutgoingRequest},
-    config::CONFIG,
-    ApiError, Session,
+    metrics::{operation_span, Metrics},
+    ApiError, Credentials, SessionError,
 };
 
-/// The request body
-#[derive(Serialize, Deserialize)]
-pub(crate) struct Request;
-
 /// The expected response body
 ///
 /// This response body structure correlates to the expected response structure
@@ -29,8 +26,9 @@ pub(crate) struct Response {
     /// ``b2_authorize_account``, that need an Authorization header. This
     /// authorization token is valid for at most 24 hours.
     pub(crate) authorization_token: String,
-    /// Expiration timestamp for the application key.
-    pub(crate) application_key_expiration_timestamp: Option<usize>,
+    /// Expiration timestamp for the application key, in milliseconds since
+    /// the Unix epoch.
+    pub(crate) application_key_expiration_timestamp: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -58,7 +56,7 @@ pub(crate) struct GroupsAPI {
 
 /// A data structure that contains the information you need for the B2 Native
 /// API.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct StorageApi {
     /// The smallest possible size of a part of a large file (except the last
@@ -90,43 +88,111 @@ pub(crate) struct StorageApi {
     pub(crate) s3_api_url: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename = "camelCase")]
-#[allow(clippy::missing_docs_in_private_items)]
-pub(crate) enum Capability {
-    DeleteFiles,
-    DeleteKeys,
-    ReadBucketEncryption,
+/// A capability an application key can have
+///
+/// Each capability allows a group of API calls. See [Application Keys](https://www.backblaze.com/docs/cloud-storage-application-keys)
+/// for the calls each capability allows.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    /// List the application keys of the account
+    ListKeys,
+    /// Create application keys
     WriteKeys,
+    /// Delete application keys
+    DeleteKeys,
+    /// List the names of all buckets, even with a key restricted to one
+    ListAllBucketNames,
+    /// List buckets
+    ListBuckets,
+    /// Read bucket information
+    ReadBuckets,
+    /// Create and update buckets
     WriteBuckets,
-    WriteBucketNotifications,
-    WriteBucketReplications,
-    ReadBucketNotifications,
-    ReadBucketReplications,
+    /// Delete buckets
     DeleteBuckets,
-    ReadBuckets,
-    BypassGovernance,
-    ReadFileLegalHolds,
-    ReadFiles,
-    ListAllBucketNames,
+    /// Read the default server-side encryption settings of buckets
+    ReadBucketEncryption,
+    /// Change the default server-side encryption settings of buckets
+    WriteBucketEncryption,
+    /// Read the Object Lock settings of buckets
     ReadBucketRetentions,
+    /// Change the Object Lock settings of buckets
     WriteBucketRetentions,
-    WriteFileLegalHolds,
+    /// Read the event notification rules of buckets
+    ReadBucketNotifications,
+    /// Change the event notification rules of buckets
+    WriteBucketNotifications,
+    /// Read the replication rules of buckets
+    ReadBucketReplications,
+    /// Change the replication rules of buckets
+    WriteBucketReplications,
+    /// List files and their versions
+    ListFiles,
+    /// Download files and read their information
+    ReadFiles,
+    /// Create download authorizations for sharing files
     ShareFiles,
+    /// Upload, copy and hide files
     WriteFiles,
-    ListKeys,
-    ListBuckets,
-    ListFiles,
-    WriteFileRetentions,
-    WriteBucketEncryption,
+    /// Delete file versions
+    DeleteFiles,
+    /// Read the legal hold status of files
+    ReadFileLegalHolds,
+    /// Change the legal hold status of files
+    WriteFileLegalHolds,
+    /// Read the retention settings of files
     ReadFileRetentions,
+    /// Change the retention settings of files
+    WriteFileRetentions,
+    /// Shorten or remove governance mode retention
+    BypassGovernance,
+    /// A capability this version of the library doesn't know about
     #[serde(other)]
     Other,
 }
 
+/// Call ``b2_authorize_account`` with a set of credentials
+///
+/// # Errors
+///
+/// This function can return the following errors:
+/// - `SessionError:RequestFailed`
+/// - `SessionError::AuthenticationRejected`
+/// - `SessionError::SuccessfulDeserializationFailed`
+/// - `SessionError::ErrorDeserializationFailed`
+pub(crate) async fn authorize(
+    client: &Client,
+    credentials: &Credentials,
+    metrics: &Metrics,
+) -> Result<Response, SessionError> {
+    let request = client.get(&credentials.endpoint).basic_auth(
+        &credentials.application_key_id,
+        Some(&credentials.application_key),
+    );
+    let response = metrics
+        .send("b2_authorize_account", 0, request, None)
+        .instrument(operation_span("b2_authorize_account", None))
+        .await?;
+    if response.status().is_success() {
+        if let Ok(body) = response.json::<Response>().await {
+            Ok(body)
+        } else {
+            Err(SessionError::SuccessfulDeserializationFailed)
+        }
+    } else if let Ok(error) = response.json::<ApiError>().await {
+        Err(SessionError::AuthenticationRejected {
+            code: error.code,
+            message: error.message,
+        })
+    } else {
+        Err(SessionError::ErrorDeserializationFailed)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::api::b2_authorize_account::Response;
+    use crate::api::b2_authorize_account::{Capability, Response};
 
     #[test]
     fn deserialize_ok() {
@@ -188,4 +254,21 @@ mod tests {
         )
         .is_ok());
     }
+
+    #[test]
+    fn deserialize_capabilities() {
+        let capabilities: Vec<Capability> = serde_json::from_str(
+            r#"["listBuckets", "readFiles", "bypassGovernance", "notYet"]"#,
+        )
+        .expect("Capabilities should deserialize");
+        assert_eq!(
+            capabilities,
+            [
+                Capability::ListBuckets,
+                Capability::ReadFiles,
+                Capability::BypassGovernance,
+                Capability::Other
+            ]
+        );
+    }
 }
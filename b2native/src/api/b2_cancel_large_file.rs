@@ -0,0 +1,46 @@
+//! Functionality related to the ``b2_cancel_large_file`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-cancel-large-file)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    ApiError, Capability, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The ID returned by ``b2_start_large_file``.
+    pub(crate) file_id: String,
+}
+
+/// The expected response body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Response {
+    /// The ID of the file whose upload that was canceled.
+    pub(crate) file_id: String,
+    /// The account that the bucket is in.
+    pub(crate) account_id: String,
+    /// The unique ID of the bucket.
+    pub(crate) bucket_id: String,
+    /// The name of the file that was canceled.
+    pub(crate) file_name: String,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = Response;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.require(Capability::WriteFiles)?;
+        self.call("b2_cancel_large_file", &body).await
+    }
+}
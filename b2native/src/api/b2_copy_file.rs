@@ -0,0 +1,42 @@
+//! Functionality related to the ``b2_copy_file`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-copy-file)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    permissions::BucketRef,
+    ApiError, Capability, FileVersion, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The ID of the source file being copied.
+    pub(crate) source_file_id: String,
+    /// The ID of the bucket where the copied file will be stored.
+    pub(crate) destination_bucket_id: String,
+    /// The name of the new file being created.
+    pub(crate) file_name: String,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = FileVersion;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.require(Capability::ReadFiles)?;
+        self.preflight(
+            Capability::WriteFiles,
+            Some(BucketRef::Id(&body.destination_bucket_id)),
+            Some(&body.file_name),
+        )?;
+        self.call("b2_copy_file", &body).await
+    }
+}
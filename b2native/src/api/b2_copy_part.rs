@@ -0,0 +1,43 @@
+//! Functionality related to the ``b2_copy_part`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-copy-part)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{b2_upload_part, ApiResult, OutgoingRequest},
+    ApiError, Capability, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The ID of the source file being copied.
+    pub(crate) source_file_id: String,
+    /// The ID of the large file the part will belong to, as returned by
+    /// ``b2_start_large_file``.
+    pub(crate) large_file_id: String,
+    /// A number from 1 to 10000. The parts uploaded for one file must have
+    /// contiguous numbers, starting with 1.
+    pub(crate) part_number: u32,
+    /// The range of bytes to copy, such as `bytes=0-99`. If not provided, the
+    /// whole source file will be copied.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) range: Option<String>,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = b2_upload_part::Response;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.require(Capability::ReadFiles)?;
+        self.require(Capability::WriteFiles)?;
+        self.call("b2_copy_part", &body).await
+    }
+}
@@ -0,0 +1,36 @@
+//! Functionality related to the ``b2_finish_large_file`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-finish-large-file)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    ApiError, Capability, FileVersion, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The ID returned by ``b2_start_large_file``.
+    pub(crate) file_id: String,
+    /// An array of hex SHA1 checksums of the parts of the large file. This is
+    /// a double-check that the right parts were uploaded in the right order,
+    /// and that none were missed.
+    pub(crate) part_sha1_array: Vec<String>,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = FileVersion;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.require(Capability::WriteFiles)?;
+        self.call("b2_finish_large_file", &body).await
+    }
+}
@@ -0,0 +1,83 @@
+//! Functionality related to the ``b2_get_download_authorization`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-get-download-authorization)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    permissions::BucketRef,
+    ApiError, Capability, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The identifier for the bucket.
+    pub(crate) bucket_id: String,
+    /// The file name prefix of files the download authorization token will
+    /// allow ``b2_download_file_by_name`` to access.
+    pub(crate) file_name_prefix: String,
+    /// The number of seconds before the authorization token will expire. The
+    /// minimum value is 1 second. The maximum value is 604800 which is one
+    /// week in seconds.
+    pub(crate) valid_duration_in_seconds: u64,
+    /// If this is present, download requests using the returned authorization
+    /// must include the same value for `b2ContentDisposition`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) b2_content_disposition: Option<String>,
+    /// If this is present, download requests using the returned authorization
+    /// must include the same value for `b2ContentLanguage`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) b2_content_language: Option<String>,
+    /// If this is present, download requests using the returned authorization
+    /// must include the same value for `b2Expires`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) b2_expires: Option<String>,
+    /// If this is present, download requests using the returned authorization
+    /// must include the same value for `b2CacheControl`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) b2_cache_control: Option<String>,
+    /// If this is present, download requests using the returned authorization
+    /// must include the same value for `b2ContentEncoding`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) b2_content_encoding: Option<String>,
+    /// If this is present, download requests using the returned authorization
+    /// must include the same value for `b2ContentType`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) b2_content_type: Option<String>,
+}
+
+/// The expected response body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Response {
+    /// The identifier for the bucket.
+    pub(crate) bucket_id: String,
+    /// The prefix for files the authorization token will allow
+    /// ``b2_download_file_by_name`` to access.
+    pub(crate) file_name_prefix: String,
+    /// The authorization token that can be passed in the Authorization header
+    /// or as an Authorization parameter to ``b2_download_file_by_name`` to
+    /// access files beginning with the file name prefix.
+    pub(crate) authorization_token: String,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = Response;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.preflight(
+            Capability::ShareFiles,
+            Some(BucketRef::Id(&body.bucket_id)),
+            Some(&body.file_name_prefix),
+        )?;
+        self.call("b2_get_download_authorization", &body).await
+    }
+}
@@ -0,0 +1,46 @@
+//! Functionality related to the ``b2_get_upload_part_url`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-get-upload-part-url)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    ApiError, Capability, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The ID of the large file whose parts you want to upload.
+    pub(crate) file_id: String,
+}
+
+/// The expected response body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Response {
+    /// The unique ID of file being uploaded.
+    pub(crate) file_id: String,
+    /// The URL that can be used to upload parts of this file.
+    pub(crate) upload_url: String,
+    /// The authorizationToken that must be used when uploading parts of this
+    /// file. This token is valid for 24 hours or until the uploadUrl endpoint
+    /// rejects an upload.
+    pub(crate) authorization_token: String,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = Response;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.require(Capability::WriteFiles)?;
+        self.call("b2_get_upload_part_url", &body).await
+    }
+}
@@ -0,0 +1,51 @@
+//! Functionality related to the ``b2_get_upload_url`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-get-upload-url)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    permissions::BucketRef,
+    ApiError, Capability, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The ID of the bucket that you want to upload to.
+    pub(crate) bucket_id: String,
+}
+
+/// The expected response body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Response {
+    /// The unique ID of the bucket.
+    pub(crate) bucket_id: String,
+    /// The URL that can be used to upload files to this bucket.
+    pub(crate) upload_url: String,
+    /// The authorizationToken that must be used when uploading files to this
+    /// bucket. This token is valid for 24 hours or until the uploadUrl
+    /// endpoint rejects an upload.
+    pub(crate) authorization_token: String,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = Response;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.preflight(
+            Capability::WriteFiles,
+            Some(BucketRef::Id(&body.bucket_id)),
+            None,
+        )?;
+        self.call("b2_get_upload_url", &body).await
+    }
+}
@@ -0,0 +1,39 @@
+//! Functionality related to the ``b2_hide_file`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-hide-file)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    permissions::BucketRef,
+    ApiError, Capability, FileVersion, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The bucket containing the file to hide.
+    pub(crate) bucket_id: String,
+    /// The name of the file to hide.
+    pub(crate) file_name: String,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = FileVersion;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.preflight(
+            Capability::WriteFiles,
+            Some(BucketRef::Id(&body.bucket_id)),
+            Some(&body.file_name),
+        )?;
+        self.call("b2_hide_file", &body).await
+    }
+}
@@ -6,7 +6,8 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     api::{ApiResult, OutgoingRequest},
-    ApiError, Session,
+    permissions::BucketRef,
+    ApiError, Capability, Session, SessionError,
 };
 
 /// The request body
@@ -14,15 +15,15 @@ use crate::{
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Request {
     /// Your account ID.
-    account_id: String,
+    pub(crate) account_id: String,
     /// When bucketId is specified, the result will be a list containing just
     /// this bucket, if it's present in the account, or no buckets if the
     /// account does not have a bucket with this ID.
-    bucket_id: Option<String>,
+    pub(crate) bucket_id: Option<String>,
     /// When bucketName is specified, the result will be a list containing just
     /// this bucket, if it's present in the account, or no buckets if the
     /// account does not have a bucket with this name.
-    bucket_name: Option<String>,
+    pub(crate) bucket_name: Option<String>,
     /// If present, this will be used as a filter for bucket types returned in
     /// the list buckets response. If not present, only buckets with bucket
     /// types "allPublic", "allPrivate" and "snapshot" will be returned. A
@@ -36,7 +37,7 @@ pub(crate) struct Request {
     /// A bad request error will be returned if "all" is used with other
     /// bucketTypes, bucketTypes is empty, or invalid bucketTypes are
     /// requested.
-    bucket_types: Option<Vec<BucketType>>,
+    pub(crate) bucket_types: Option<Vec<BucketType>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -63,11 +64,11 @@ pub(crate) struct Response {
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Bucket {
     /// The account that the bucket is in.
-    account_id: String,
+    pub(crate) account_id: String,
     /// The unique identifier of the bucket.
-    bucket_id: String,
+    pub(crate) bucket_id: String,
     /// The unique name of the bucket
-    bucket_name: String,
+    pub(crate) bucket_name: String,
     /// One of: allPublic, allPrivate, restricted, snapshot, shared, or other
     /// values added in the future. allPublic means that anybody can download
     /// the files is the bucket; allPrivate means that you need an
@@ -101,7 +102,7 @@ pub(crate) struct Bucket {
     /// The list of lifecycle rules for this bucket.
     ///
     /// See [Lifecycle Rules](https://www.backblaze.com/docs/cloud-storage-lifecycle-rules) for an overview and the rule structure.
-    lifecycle_rules: LifecycleRules,
+    lifecycle_rules: Vec<LifecycleRules>,
     /// The list of replication rules for this bucket.
     ///
     /// See [Cloud Replication Rules](https://www.backblaze.com/docs/cloud-storage-create-a-cloud-replication-rule-with-the-native-api#file-name-prefixes) for an overview and the rule structure.
@@ -114,7 +115,7 @@ pub(crate) struct Bucket {
 #[serde(rename_all = "camelCase")]
 pub(crate) struct FileLockConfiguration {
     is_client_authorized_to_read: bool,
-    value: FileLockConfigurationValue,
+    value: Option<FileLockConfigurationValue>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -126,22 +127,22 @@ pub(crate) struct FileLockConfigurationValue {
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct FileLockConfigurationValueRetention {
-    mode: String,
-    period: String,
+    mode: Option<String>,
+    period: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ServerSideEncryption {
     is_client_authorized_to_read: bool,
-    value: ServerSideEncryptionValue,
+    value: Option<ServerSideEncryptionValue>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct ServerSideEncryptionValue {
-    algorithm: String,
-    mode: String,
+    algorithm: Option<String>,
+    mode: Option<String>,
 }
 
 /// Lifecycle Rules
@@ -156,7 +157,7 @@ pub(crate) struct LifecycleRules {
     /// Because files are automatically hidden when replaced by a newer
     /// version, if you set this property to 10, then the older version is
     /// deleted 10 days after you upload a newer version of the file.
-    days_from_hiding_to_deleting: usize,
+    days_from_hiding_to_deleting: Option<usize>,
     /// This value causes the specified files to be automatically hidden after
     /// a designated number of days.
     ///
@@ -167,7 +168,7 @@ pub(crate) struct LifecycleRules {
     /// you want to hide all of the versions of the specified files. Valid
     /// values are null or numbers one and greater. Null means that no files
     /// are hidden based on this rule.
-    days_from_uploading_to_hiding: usize,
+    days_from_uploading_to_hiding: Option<usize>,
     /// This setting cancels any unfinished large file versions after a given
     /// number of days.
     ///
@@ -176,7 +177,7 @@ pub(crate) struct LifecycleRules {
     /// b2_cancel_large_file on the unfinished large files. During Lifecycle
     /// Rule processing, unfinished large files cannot hide other files, or be
     /// hidden by other files.
-    days_from_starting_to_canceling_unfinished_large_files: usize,
+    days_from_starting_to_canceling_unfinished_large_files: Option<usize>,
     /// This property specifies the files in the bucket to which the Lifecycle
     /// Rule applies.
     ///
@@ -221,26 +222,19 @@ pub(crate) struct ReplicationDestination {
 
 impl OutgoingRequest<Request> for Session {
     type Error = ApiError;
-    type Failure = reqwest::Error;
+    type Failure = SessionError;
     type Response = Response;
 
     async fn send(
-        &mut self,
+        &self,
         body: Request,
     ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
-        let url = format!(
-            "{}/b2api/v3/b2_list_buckets",
-            self.storage_api_info.api_url
-        );
-        let body_ser = serde_json::to_string(&body)
-            .expect("Failed to serialize request body");
-        let request = self
-            .http_client
-            .post(&url)
-            .header("Authorization", &self.token)
-            .body(body_ser)
-            .build()?;
-        let response = self.http_client.execute(request).await?;
-        response.into()
+        let bucket = body
+            .bucket_id
+            .as_deref()
+            .map(BucketRef::Id)
+            .or(body.bucket_name.as_deref().map(BucketRef::Name));
+        self.preflight(Capability::ListBuckets, bucket, None)?;
+        self.call("b2_list_buckets", &body).await
     }
 }
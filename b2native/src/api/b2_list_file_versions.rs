@@ -0,0 +1,72 @@
+//! Functionality related to the ``b2_list_file_versions`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-list-file-versions)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    permissions::BucketRef,
+    ApiError, Capability, FileVersion, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The bucket to look for file names in.
+    pub(crate) bucket_id: String,
+    /// The first file name to return. If there are no files with this name,
+    /// the first version of the file with the first name after the given name
+    /// will be the first in the list.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) start_file_name: Option<String>,
+    /// The first file ID to return. `startFileName` must also be provided if
+    /// `startFileId` is specified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) start_file_id: Option<String>,
+    /// The maximum number of files to return from this call. The default value
+    /// is 100, and the maximum is 10000.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_file_count: Option<u32>,
+    /// Files returned will be limited to those with the given prefix.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) prefix: Option<String>,
+    /// Files returned will be limited to those within the top folder, or any
+    /// one subfolder. Folder names will also be returned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) delimiter: Option<String>,
+}
+
+/// The expected response body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Response {
+    /// The file versions found, sorted by name and then by upload time, newest
+    /// first.
+    pub(crate) files: Vec<FileVersion>,
+    /// What to pass in to `startFileName` for the next search to continue
+    /// where this one left off, or null if there are no more files.
+    pub(crate) next_file_name: Option<String>,
+    /// What to pass in to `startFileId` for the next search to continue where
+    /// this one left off, or null if there are no more files.
+    pub(crate) next_file_id: Option<String>,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = Response;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.preflight(
+            Capability::ListFiles,
+            Some(BucketRef::Id(&body.bucket_id)),
+            Some(body.prefix.as_deref().unwrap_or_default()),
+        )?;
+        self.call("b2_list_file_versions", &body).await
+    }
+}
@@ -0,0 +1,47 @@
+//! Functionality related to the ``b2_start_large_file`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-start-large-file)
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::{ApiResult, OutgoingRequest},
+    permissions::BucketRef,
+    ApiError, Capability, FileVersion, Session, SessionError,
+};
+
+/// The request body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Request {
+    /// The ID of the bucket that the file will go in.
+    pub(crate) bucket_id: String,
+    /// The name of the file.
+    pub(crate) file_name: String,
+    /// The MIME type of the content of the file, which will be returned in the
+    /// Content-Type header when downloading the file. Use the Content-Type
+    /// b2/x-auto to automatically set the stored Content-Type post upload.
+    pub(crate) content_type: String,
+    /// A JSON object holding the name/value pairs for the custom file info.
+    pub(crate) file_info: BTreeMap<String, String>,
+}
+
+impl OutgoingRequest<Request> for Session {
+    type Error = ApiError;
+    type Failure = SessionError;
+    type Response = FileVersion;
+
+    async fn send(
+        &self,
+        body: Request,
+    ) -> ApiResult<Self::Response, Self::Error, Self::Failure> {
+        self.preflight(
+            Capability::WriteFiles,
+            Some(BucketRef::Id(&body.bucket_id)),
+            Some(&body.file_name),
+        )?;
+        self.call("b2_start_large_file", &body).await
+    }
+}
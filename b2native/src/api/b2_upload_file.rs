@@ -0,0 +1,66 @@
+//! Functionality related to the ``b2_upload_file`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-upload-file)
+
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+
+use crate::{
+    api::{percent_encode, ApiResult},
+    progress::Transfer,
+    throttle::upload_body,
+    upload_urls::UploadUrl,
+    ApiError, FileVersion, Session, SessionError,
+};
+
+/// A file to upload
+///
+/// Unlike most endpoints, ``b2_upload_file`` takes the file contents as the
+/// request body and everything else as headers.
+pub(crate) struct Request<'a> {
+    /// The name of the file.
+    pub(crate) file_name: &'a str,
+    /// The MIME type of the content of the file, or `b2/x-auto` to have B2
+    /// determine it from the file name.
+    pub(crate) content_type: &'a str,
+    /// Custom information to store with the file, sent as `X-Bz-Info-*`
+    /// headers.
+    pub(crate) file_info: &'a BTreeMap<String, String>,
+    /// The contents of the file.
+    pub(crate) data: Bytes,
+    /// The hex SHA1 of the contents of the file.
+    pub(crate) content_sha1: &'a str,
+}
+
+impl Session {
+    /// Upload a file to an upload URL from ``b2_get_upload_url``
+    pub(crate) async fn upload_file_to(
+        &self,
+        url: &UploadUrl,
+        request: &Request<'_>,
+        transfer: &Transfer,
+    ) -> ApiResult<FileVersion, ApiError, SessionError> {
+        let mut builder = self
+            .http_client()
+            .post(&url.upload_url)
+            .header("Authorization", &url.authorization_token)
+            .header("X-Bz-File-Name", percent_encode(request.file_name))
+            .header("Content-Type", request.content_type)
+            .header("Content-Length", request.data.len())
+            .header("X-Bz-Content-Sha1", request.content_sha1);
+        for (key, value) in request.file_info {
+            builder = builder
+                .header(format!("X-Bz-Info-{key}"), percent_encode(value));
+        }
+        let builder = builder.body(upload_body(
+            self.throttles(),
+            transfer.clone(),
+            request.data.clone(),
+        ));
+        let bytes_sent = u64::try_from(request.data.len()).unwrap_or(u64::MAX);
+        let response =
+            self.send_request("b2_upload_file", bytes_sent, builder).await?;
+        ApiResult::from_response(response).await
+    }
+}
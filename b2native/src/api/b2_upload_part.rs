@@ -0,0 +1,66 @@
+//! Functionality related to the ``b2_upload_part`` endpoint
+//!
+//! [API Docs](https://www.backblaze.com/apidocs/b2-upload-part)
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::ApiResult, progress::Transfer, throttle::upload_body,
+    upload_urls::UploadUrl, ApiError, Session, SessionError,
+};
+
+/// A part of a large file to upload
+///
+/// Like ``b2_upload_file``, ``b2_upload_part`` takes the part contents as the
+/// request body and everything else as headers.
+pub(crate) struct Request<'a> {
+    /// A number from 1 to 10000. The parts uploaded for one file must have
+    /// contiguous numbers, starting with 1.
+    pub(crate) part_number: u32,
+    /// The contents of the part.
+    pub(crate) data: Bytes,
+    /// The hex SHA1 of the contents of the part.
+    pub(crate) content_sha1: &'a str,
+}
+
+/// The expected response body
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Response {
+    /// The unique ID for this file.
+    pub(crate) file_id: String,
+    /// Which part this is.
+    pub(crate) part_number: u32,
+    /// The number of bytes stored in the part.
+    pub(crate) content_length: u64,
+    /// The SHA1 of the bytes stored in the part.
+    pub(crate) content_sha1: String,
+}
+
+impl Session {
+    /// Upload a part to an upload URL from ``b2_get_upload_part_url``
+    pub(crate) async fn upload_part_to(
+        &self,
+        url: &UploadUrl,
+        request: &Request<'_>,
+        transfer: &Transfer,
+    ) -> ApiResult<Response, ApiError, SessionError> {
+        let builder = self
+            .http_client()
+            .post(&url.upload_url)
+            .header("Authorization", &url.authorization_token)
+            .header("X-Bz-Part-Number", request.part_number)
+            .header("Content-Length", request.data.len())
+            .header("X-Bz-Content-Sha1", request.content_sha1)
+            .body(upload_body(
+                self.throttles(),
+                transfer.clone(),
+                request.data.clone(),
+            ));
+        let bytes_sent = u64::try_from(request.data.len()).unwrap_or(u64::MAX);
+        let response =
+            self.send_request("b2_upload_part", bytes_sent, builder).await?;
+        ApiResult::from_response(response).await
+    }
+}
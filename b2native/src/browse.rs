@@ -0,0 +1,528 @@
+//! Browsing a bucket as a tree of folders
+//!
+//! B2 stores files under flat names, but names containing `/` are commonly
+//! treated as paths. Listing with a delimiter makes B2 collapse everything
+//! below the next `/` into a single folder entry, which is what
+//! [`Bucket::read_dir`] does. [`Bucket::dir_usage`] adds up the sizes of
+//! everything below a folder, and [`DirTree`] caches both so a file browser
+//! doesn't list the same folder again every time it is shown.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    api::{b2_list_file_names, OutgoingRequest},
+    file::timestamp,
+    Bucket, FileAction, FileVersion, SessionError,
+};
+
+/// The delimiter folders are split on
+const DELIMITER: &str = "/";
+
+/// How many names are listed per call
+///
+/// B2 bills a class C transaction per 1000 names returned, so asking for
+/// fewer per call would only cost more round trips.
+const PAGE_SIZE: u32 = 1000;
+
+/// A file listed in a folder
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileEntry {
+    /// The full name of the file in the bucket
+    pub path: String,
+    /// The name of the file within its folder
+    pub name: String,
+    /// The ID of the latest version of the file
+    pub file_id: Option<String>,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The MIME type of the file
+    pub content_type: Option<String>,
+    /// When the latest version of the file was uploaded
+    pub uploaded_at: Option<DateTime<Utc>>,
+}
+
+/// An entry listed in a folder
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum DirEntry {
+    /// A folder containing more files
+    #[serde(rename_all = "camelCase")]
+    Folder {
+        /// The full path of the folder, ending in `/`
+        path: String,
+        /// The name of the folder within its parent, without the `/`
+        name: String,
+    },
+    /// A file
+    File(FileEntry),
+}
+
+impl DirEntry {
+    /// The name of the entry within its folder
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Folder {
+                name,
+                ..
+            }
+            | Self::File(FileEntry {
+                name,
+                ..
+            }) => name,
+        }
+    }
+
+    /// The full path of the entry in the bucket
+    #[must_use]
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Folder {
+                path,
+                ..
+            }
+            | Self::File(FileEntry {
+                path,
+                ..
+            }) => path,
+        }
+    }
+
+    /// Whether the entry is a folder
+    #[must_use]
+    pub fn is_folder(&self) -> bool {
+        matches!(self, Self::Folder { .. })
+    }
+
+    /// Build an entry from a listed file version, if it is a file or folder
+    fn from_version(prefix: &str, version: FileVersion) -> Option<Self> {
+        let name = version.file_name.strip_prefix(prefix)?.to_owned();
+        match version.action {
+            FileAction::Folder => Some(Self::Folder {
+                name: name.trim_end_matches(DELIMITER).to_owned(),
+                path: version.file_name,
+            }),
+            FileAction::Upload => Some(Self::File(FileEntry {
+                name,
+                uploaded_at: timestamp(version.upload_timestamp),
+                path: version.file_name,
+                file_id: version.file_id,
+                size: version.content_length,
+                content_type: version.content_type,
+            })),
+            _ => None,
+        }
+    }
+}
+
+/// The space used below a folder, and by each of its subfolders
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirUsage {
+    /// The full path of the folder, ending in `/`, or empty for the root
+    pub path: String,
+    /// How many files are below the folder, at any depth
+    pub files: u64,
+    /// How many bytes the files below the folder take up, at any depth
+    pub bytes: u64,
+    /// When the most recently uploaded file below the folder was uploaded
+    pub last_uploaded_at: Option<DateTime<Utc>>,
+    /// The usage of each subfolder, by name
+    pub children: BTreeMap<String, DirUsage>,
+}
+
+impl DirUsage {
+    /// Count a file below this folder
+    ///
+    /// `rest` is the name of the file relative to this folder.
+    fn add(
+        &mut self,
+        rest: &str,
+        size: u64,
+        uploaded_at: Option<DateTime<Utc>>,
+    ) {
+        self.files += 1;
+        self.bytes += size;
+        self.last_uploaded_at = self.last_uploaded_at.max(uploaded_at);
+        if let Some((folder, rest)) = rest.split_once(DELIMITER) {
+            let path = format!("{}{folder}{DELIMITER}", self.path);
+            self.children
+                .entry(folder.to_owned())
+                .or_insert_with(|| Self {
+                    path,
+                    ..Self::default()
+                })
+                .add(rest, size, uploaded_at);
+        }
+    }
+
+    /// The usage of a folder below this one
+    ///
+    /// `relative` is the path of the folder relative to this one. An empty
+    /// path returns this folder itself.
+    #[must_use]
+    pub fn find(&self, relative: &str) -> Option<&Self> {
+        relative
+            .split(DELIMITER)
+            .filter(|folder| !folder.is_empty())
+            .try_fold(self, |usage, folder| usage.children.get(folder))
+    }
+
+    /// This folder and every folder below it, parents before children
+    pub fn walk(&self) -> impl Iterator<Item = &Self> {
+        let mut stack = vec![self];
+        std::iter::from_fn(move || {
+            let next = stack.pop()?;
+            stack.extend(next.children.values().rev());
+            Some(next)
+        })
+    }
+}
+
+/// The listing prefix of a folder path
+///
+/// Leading slashes are ignored and a trailing one is added, so `""`, `"/"`
+/// and `"photos"` become `""`, `""` and `"photos/"`.
+fn dir_prefix(path: &str) -> String {
+    let path = path.trim_start_matches(DELIMITER);
+    if path.is_empty() || path.ends_with(DELIMITER) {
+        path.to_owned()
+    } else {
+        format!("{path}{DELIMITER}")
+    }
+}
+
+impl Bucket {
+    /// List every name starting with a prefix, optionally collapsing folders
+    async fn list_names(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Result<Vec<FileVersion>, SessionError> {
+        let mut files: Vec<FileVersion> = Vec::new();
+        let mut start_file_name = None;
+        loop {
+            let page = self
+                .session()
+                .send(b2_list_file_names::Request {
+                    bucket_id: self.id().to_owned(),
+                    start_file_name,
+                    max_file_count: Some(PAGE_SIZE),
+                    prefix: Some(prefix.to_owned()),
+                    delimiter: delimiter.map(str::to_owned),
+                })
+                .await
+                .into_result()?;
+            for file in page.files {
+                // A folder can be cut in two by a page boundary
+                if files
+                    .last()
+                    .is_none_or(|last| last.file_name != file.file_name)
+                {
+                    files.push(file);
+                }
+            }
+            match page.next_file_name {
+                Some(next) => start_file_name = Some(next),
+                None => return Ok(files),
+            }
+        }
+    }
+
+    /// List the files and folders directly inside a folder
+    ///
+    /// `path` is the folder's path, with or without a trailing `/`; an empty
+    /// path lists the root of the bucket. Entries are sorted by name. Hidden
+    /// files are left out, and only the latest version of each file is
+    /// listed.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub async fn read_dir(
+        &self,
+        path: &str,
+    ) -> Result<Vec<DirEntry>, SessionError> {
+        let prefix = dir_prefix(path);
+        let files = self.list_names(&prefix, Some(DELIMITER)).await?;
+        Ok(files
+            .into_iter()
+            .filter_map(|file| DirEntry::from_version(&prefix, file))
+            .collect())
+    }
+
+    /// Add up the space used below a folder, at any depth
+    ///
+    /// Every name below the folder is listed once, without a delimiter, so
+    /// this costs one class C transaction per 1000 files rather than one per
+    /// folder.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Bucket::read_dir`].
+    pub async fn dir_usage(
+        &self,
+        path: &str,
+    ) -> Result<DirUsage, SessionError> {
+        let prefix = dir_prefix(path);
+        let files = self.list_names(&prefix, None).await?;
+        let mut usage = DirUsage {
+            path: prefix.clone(),
+            ..DirUsage::default()
+        };
+        for file in files {
+            if let Some(rest) = file.file_name.strip_prefix(&prefix) {
+                usage.add(
+                    rest,
+                    file.content_length,
+                    timestamp(file.upload_timestamp),
+                );
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Create a cache of the folders of this bucket
+    ///
+    /// See [`DirTree`].
+    #[must_use]
+    pub fn tree(&self, max_age: Duration) -> DirTree {
+        DirTree {
+            bucket: self.clone(),
+            max_age,
+            dirs: Mutex::default(),
+            usage: Mutex::default(),
+        }
+    }
+}
+
+/// A cached value and when it was fetched
+#[derive(Debug)]
+struct Cached<T> {
+    /// The cached value
+    value: T,
+    /// When the value was fetched
+    fetched_at: Instant,
+}
+
+/// A lazily refreshed cache of the folders of a bucket
+///
+/// Folders are only listed when first asked for, and listed again once their
+/// cached entries are older than the tree's maximum age. Changes made to the
+/// bucket through this library don't update the cache on their own; call
+/// [`DirTree::invalidate`] with the path that changed.
+#[derive(Debug)]
+pub struct DirTree {
+    /// The bucket being browsed
+    bucket: Bucket,
+    /// How long listings are reused for
+    max_age: Duration,
+    /// The cached entries of each folder, by listing prefix
+    dirs: Mutex<HashMap<String, Cached<Vec<DirEntry>>>>,
+    /// The cached usage of each folder, by listing prefix
+    usage: Mutex<HashMap<String, Cached<DirUsage>>>,
+}
+
+impl DirTree {
+    /// The bucket being browsed
+    #[must_use]
+    pub fn bucket(&self) -> &Bucket {
+        &self.bucket
+    }
+
+    /// Whether something fetched at `fetched_at` can still be used
+    fn fresh(&self, fetched_at: Instant) -> bool {
+        fetched_at.elapsed() < self.max_age
+    }
+
+    /// The files and folders directly inside a folder
+    ///
+    /// See [`Bucket::read_dir`].
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Bucket::read_dir`].
+    pub async fn read_dir(
+        &self,
+        path: &str,
+    ) -> Result<Vec<DirEntry>, SessionError> {
+        let prefix = dir_prefix(path);
+        if let Some(cached) = self
+            .dirs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&prefix)
+            .filter(|cached| self.fresh(cached.fetched_at))
+        {
+            return Ok(cached.value.clone());
+        }
+        let entries = self.bucket.read_dir(&prefix).await?;
+        self.dirs.lock().unwrap_or_else(PoisonError::into_inner).insert(
+            prefix,
+            Cached {
+                value: entries.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+        Ok(entries)
+    }
+
+    /// The space used below a folder, at any depth
+    ///
+    /// The usage of a folder is answered from the cached usage of any of its
+    /// parents, so asking for the root first makes every later question free
+    /// until the cache expires. See [`Bucket::dir_usage`].
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Bucket::read_dir`].
+    pub async fn usage(&self, path: &str) -> Result<DirUsage, SessionError> {
+        let prefix = dir_prefix(path);
+        {
+            let cache =
+                self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+            let cached = cache
+                .iter()
+                .filter(|(_, cached)| self.fresh(cached.fetched_at))
+                .find_map(|(parent, cached)| {
+                    cached.value.find(prefix.strip_prefix(parent.as_str())?)
+                });
+            if let Some(usage) = cached {
+                return Ok(usage.clone());
+            }
+        }
+        let usage = self.bucket.dir_usage(&prefix).await?;
+        self.usage.lock().unwrap_or_else(PoisonError::into_inner).insert(
+            prefix,
+            Cached {
+                value: usage.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+        Ok(usage)
+    }
+
+    /// Forget what is cached about a path that changed
+    ///
+    /// `path` can name a file or a folder. The listing of the folder holding
+    /// it and the usage of every folder above it are fetched again when next
+    /// asked for.
+    pub fn invalidate(&self, path: &str) {
+        let path = path.trim_start_matches(DELIMITER);
+        let parent = path
+            .trim_end_matches(DELIMITER)
+            .rsplit_once(DELIMITER)
+            .map_or_else(String::new, |(parent, _)| dir_prefix(parent));
+        let mut dirs = self.dirs.lock().unwrap_or_else(PoisonError::into_inner);
+        let _removed = dirs.remove(&parent);
+        let _removed = dirs.remove(&dir_prefix(path));
+        self.usage
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|prefix, _| !path.starts_with(prefix.as_str()));
+    }
+
+    /// Forget everything cached
+    pub fn clear(&self) {
+        self.dirs.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        self.usage.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use b2fake::FakeB2;
+
+    use crate::{tests, Bucket, DirEntry, UploadOptions};
+
+    async fn bucket(server: &FakeB2) -> Bucket {
+        let bucket = tests::bucket(server, "browsing").await;
+        for (name, size) in [
+            ("readme.txt", 10),
+            ("photos/a.jpg", 100),
+            ("photos/b.jpg", 200),
+            ("photos/2024/c.jpg", 400),
+            ("docs/d.pdf", 1000),
+        ] {
+            let _file = bucket
+                .upload_file(name, vec![0; size], &UploadOptions::default())
+                .await
+                .expect("Upload should succeed");
+        }
+        bucket
+    }
+
+    #[tokio::test]
+    async fn folders_are_listed() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server).await;
+        let root = bucket.read_dir("/").await.expect("Listing should succeed");
+        let names: Vec<_> =
+            root.iter().map(|e| (e.name(), e.is_folder())).collect();
+        assert_eq!(
+            names,
+            [("docs", true), ("photos", true), ("readme.txt", false)]
+        );
+        let photos =
+            bucket.read_dir("photos").await.expect("Listing should succeed");
+        assert_eq!(photos[0].path(), "photos/2024/");
+        let DirEntry::File(file) = &photos[1] else {
+            panic!("Expected a file, got {:?}", photos[1]);
+        };
+        assert_eq!((file.name.as_str(), file.size), ("a.jpg", 100));
+        assert!(file.uploaded_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn usage_is_aggregated_and_cached() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server).await;
+        let usage = bucket.dir_usage("").await.expect("Listing should succeed");
+        assert_eq!((usage.files, usage.bytes), (5, 1710));
+        let photos = usage.find("photos/").expect("Folder should exist");
+        assert_eq!((photos.files, photos.bytes), (3, 700));
+        let paths: Vec<_> = usage.walk().map(|dir| dir.path.as_str()).collect();
+        assert_eq!(paths, ["", "docs/", "photos/", "photos/2024/"]);
+
+        let tree = bucket.tree(Duration::from_mins(1));
+        let calls = server.calls("b2_list_file_names");
+        let _root = tree.usage("").await.expect("Listing should succeed");
+        let nested =
+            tree.usage("photos/2024").await.expect("Listing should succeed");
+        assert_eq!(nested.bytes, 400);
+        let _entries = tree.read_dir("docs").await.expect("Listing works");
+        let _entries = tree.read_dir("docs/").await.expect("Listing works");
+        assert_eq!(server.calls("b2_list_file_names"), calls + 2);
+
+        let _file = bucket
+            .upload_file("docs/e.pdf", vec![0; 90], &UploadOptions::default())
+            .await
+            .expect("Upload should succeed");
+        tree.invalidate("docs/e.pdf");
+        let docs = tree.read_dir("docs").await.expect("Listing works");
+        assert_eq!(docs.len(), 2);
+        let root = tree.usage("/").await.expect("Listing should succeed");
+        assert_eq!(root.bytes, 1800);
+        assert_eq!(server.calls("b2_list_file_names"), calls + 4);
+
+        let expired = bucket.tree(Duration::ZERO);
+        let _entries = expired.read_dir("").await.expect("Listing works");
+        let _entries = expired.read_dir("").await.expect("Listing works");
+        assert_eq!(server.calls("b2_list_file_names"), calls + 6);
+    }
+}
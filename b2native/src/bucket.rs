@@ -0,0 +1,338 @@
+//! Working with the files in a bucket
+
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    api::{
+        b2_copy_file, b2_list_buckets, b2_start_large_file, b2_upload_file,
+        OutgoingRequest,
+    },
+    permissions::BucketRef,
+    progress::{Phase, TransferKind},
+    upload_urls::UploadTarget,
+    Capability, FileVersion, LargeFile, Session, SessionError,
+};
+
+/// The content type that asks B2 to pick one based on the file name
+const AUTO_CONTENT_TYPE: &str = "b2/x-auto";
+
+/// A bucket in the account a [`Session`] is authorized for
+///
+/// Buckets are cheap to clone, and every clone shares the session it was
+/// looked up with.
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    /// The session used to reach the bucket
+    session: Session,
+    /// The unique identifier of the bucket
+    id: String,
+    /// The unique name of the bucket
+    name: String,
+}
+
+/// Optional settings for uploading a file
+#[derive(Clone, Debug, Default)]
+pub struct UploadOptions {
+    /// The MIME type of the file
+    ///
+    /// When `None`, B2 picks one based on the file name.
+    pub content_type: Option<String>,
+    /// Custom information to store with the file
+    ///
+    /// B2 allows at most 10 entries, including any it adds itself.
+    pub file_info: BTreeMap<String, String>,
+}
+
+impl UploadOptions {
+    /// The content type to send to B2
+    pub(crate) fn content_type(&self) -> &str {
+        self.content_type.as_deref().unwrap_or(AUTO_CONTENT_TYPE)
+    }
+}
+
+/// The hex SHA1 of some data
+pub(crate) fn sha1_hex(data: &[u8]) -> String {
+    hex::encode(Sha1::digest(data))
+}
+
+impl Session {
+    /// Look up a bucket by name
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::KeyExpired`
+    /// - `SessionError::BucketNotFound`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    pub async fn bucket(&self, name: &str) -> Result<Bucket, SessionError> {
+        let response = self
+            .send(b2_list_buckets::Request {
+                account_id: self.account_id(),
+                bucket_id: None,
+                bucket_name: Some(name.to_owned()),
+                bucket_types: None,
+            })
+            .await
+            .into_result()?;
+        let bucket = response.buckets.into_iter().next().ok_or_else(|| {
+            SessionError::BucketNotFound {
+                name: name.to_owned(),
+            }
+        })?;
+        Ok(Bucket {
+            session: self.clone(),
+            id: bucket.bucket_id,
+            name: bucket.bucket_name,
+        })
+    }
+}
+
+impl Bucket {
+    /// The unique identifier of the bucket
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The unique name of the bucket
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The session used to reach the bucket
+    #[must_use]
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Upload a file in a single request
+    ///
+    /// B2 accepts files of up to 5 GB this way, but files larger than the
+    /// recommended part size upload faster with [`Bucket::start_large_file`].
+    /// Upload URLs are reused between uploads, and uploads that fail in a way
+    /// B2 says is worth retrying are retried with a new upload URL.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub async fn upload_file(
+        &self,
+        file_name: &str,
+        data: impl Into<Bytes>,
+        options: &UploadOptions,
+    ) -> Result<FileVersion, SessionError> {
+        self.session.preflight(
+            Capability::WriteFiles,
+            Some(BucketRef::Id(&self.id)),
+            Some(file_name),
+        )?;
+        let data = data.into();
+        let transfer = self.session.transfer(
+            TransferKind::Upload,
+            file_name,
+            u64::try_from(data.len()).ok(),
+        );
+        let content_sha1 = sha1_hex(&data);
+        let request = b2_upload_file::Request {
+            file_name,
+            content_type: options.content_type(),
+            file_info: &options.file_info,
+            data,
+            content_sha1: &content_sha1,
+        };
+        transfer.phase(Phase::Transferring);
+        let result = self
+            .session
+            .with_upload_url(
+                &UploadTarget::Bucket(self.id.clone()),
+                &transfer,
+                |url| {
+                    let (request, transfer) = (&request, &transfer);
+                    async move {
+                        self.session
+                            .upload_file_to(&url, request, transfer)
+                            .await
+                    }
+                },
+            )
+            .await;
+        transfer.finish(&result);
+        result
+    }
+
+    /// Start uploading a large file in parts
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub async fn start_large_file(
+        &self,
+        file_name: &str,
+        options: &UploadOptions,
+    ) -> Result<LargeFile, SessionError> {
+        let transfer = self.session.transfer(
+            TransferKind::LargeFileUpload,
+            file_name,
+            None,
+        );
+        let result = self
+            .session
+            .send(b2_start_large_file::Request {
+                bucket_id: self.id.clone(),
+                file_name: file_name.to_owned(),
+                content_type: options.content_type().to_owned(),
+                file_info: options.file_info.clone(),
+            })
+            .await
+            .into_result();
+        match result {
+            Ok(file) => {
+                transfer.phase(Phase::Transferring);
+                Ok(LargeFile::new(self.session.clone(), file, transfer))
+            }
+            Err(error) => {
+                transfer.phase(Phase::Failed);
+                Err(error)
+            }
+        }
+    }
+
+    /// Copy a file version into this bucket under a new name
+    ///
+    /// B2 copies the data itself, so nothing passes through this library. The
+    /// copy keeps the content type and file info of the source. Sources larger
+    /// than 5 GB have to be copied in parts with [`LargeFile::copy_part`].
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub async fn copy_file(
+        &self,
+        source_file_id: &str,
+        file_name: &str,
+    ) -> Result<FileVersion, SessionError> {
+        let transfer =
+            self.session.transfer(TransferKind::Copy, file_name, None);
+        transfer.phase(Phase::Transferring);
+        let result = self
+            .session
+            .send(b2_copy_file::Request {
+                source_file_id: source_file_id.to_owned(),
+                destination_bucket_id: self.id.clone(),
+                file_name: file_name.to_owned(),
+            })
+            .await
+            .into_result();
+        if let Ok(file) = &result {
+            transfer.length(file.content_length);
+            transfer.copied(file.content_length);
+        }
+        transfer.finish(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use b2fake::{FakeB2, Fault};
+
+    use crate::{tests::session, Bucket, SessionError, UploadOptions};
+
+    async fn uploads(server: &FakeB2) -> Bucket {
+        let id = server.create_bucket("uploads", "allPrivate");
+        let bucket = session(server)
+            .await
+            .bucket("uploads")
+            .await
+            .expect("Bucket should exist");
+        assert_eq!(bucket.id(), id);
+        bucket
+    }
+
+    #[tokio::test]
+    async fn upload_urls_are_reused() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = uploads(&server).await;
+        for index in 0..3 {
+            let file = bucket
+                .upload_file(
+                    &format!("file {index}.txt"),
+                    b"hello".to_vec(),
+                    &UploadOptions::default(),
+                )
+                .await
+                .expect("Upload should succeed");
+            assert_eq!(file.content_length, 5);
+        }
+        assert_eq!(server.calls("b2_get_upload_url"), 1);
+        assert_eq!(server.calls("b2_upload_file"), 3);
+    }
+
+    #[tokio::test]
+    async fn failed_upload_urls_are_dropped() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = uploads(&server).await;
+        server.inject(Some("b2_upload_file"), Fault::ServiceUnavailable, 1);
+        bucket
+            .upload_file("a.txt", b"hello".to_vec(), &UploadOptions::default())
+            .await
+            .expect("Upload should be retried");
+        assert_eq!(server.calls("b2_get_upload_url"), 2);
+        assert_eq!(server.calls("b2_upload_file"), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_uploads_are_capped() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = uploads(&server).await;
+        let tasks: Vec<_> = (0..32)
+            .map(|index| {
+                let bucket = bucket.clone();
+                tokio::spawn(async move {
+                    bucket
+                        .upload_file(
+                            &format!("{index}.txt"),
+                            vec![0; 1000],
+                            &UploadOptions::default(),
+                        )
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await
+                .expect("Task should not panic")
+                .expect("Upload should succeed");
+        }
+        assert!(server.calls("b2_get_upload_url") <= 8);
+    }
+
+    #[tokio::test]
+    async fn missing_bucket_is_reported() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let result = session(&server).await.bucket("missing").await;
+        assert!(matches!(result, Err(SessionError::BucketNotFound { .. })));
+    }
+}
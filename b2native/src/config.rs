@@ -12,6 +12,8 @@ use figment::{
 };
 use serde::{Deserialize, Serialize};
 
+use crate::PriceTable;
+
 /// A lazily-evaluated static containing the global library configuration
 pub(crate) static CONFIG: LazyLock<Config> = LazyLock::new(Config::new);
 
@@ -20,6 +22,10 @@ pub(crate) static CONFIG: LazyLock<Config> = LazyLock::new(Config::new);
 pub(crate) struct Config {
     /// The URL to call for the ``b2_authorize_account`` endpoint
     pub(crate) authorize_account_endpoint: String,
+    /// The most upload URLs a session keeps for any one bucket or large file
+    pub(crate) upload_urls_per_target: usize,
+    /// The prices cost estimates are made with
+    pub(crate) prices: PriceTable,
 }
 
 impl Default for Config {
@@ -28,6 +34,8 @@ impl Default for Config {
             authorize_account_endpoint:
                 "https://api.backblazeb2.com/b2api/v3/b2_authorize_account"
                     .to_owned(),
+            upload_urls_per_target: 8,
+            prices: PriceTable::default(),
         }
     }
 }
@@ -0,0 +1,534 @@
+//! Accounting for what a session costs
+//!
+//! Backblaze bills API calls by transaction class, downloads by the gigabyte
+//! and storage by the gigabyte-month. Every request a session sends is counted
+//! against its [`Usage`], which a [`PriceTable`] turns into a
+//! [`CostEstimate`]. A [`Budget`] caps the usage of a session: once a request
+//! would exceed it, the request fails with `SessionError::BudgetExceeded`
+//! instead of being sent.
+//!
+//! Estimates ignore the daily allowances Backblaze gives for free, so they are
+//! an upper bound on what a session costs.
+
+use std::{
+    fmt,
+    sync::{Mutex, PoisonError},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::CONFIG, Session, SessionError};
+
+/// The bytes in a gigabyte, as Backblaze counts them
+const GIGABYTE: f64 = 1_000_000_000.0;
+
+/// How Backblaze bills a call to an endpoint
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize,
+)]
+pub enum TransactionClass {
+    /// Free calls, mostly uploads and deletions
+    A,
+    /// Downloads and file information
+    B,
+    /// Listings, copies and account management
+    C,
+}
+
+impl TransactionClass {
+    /// The class of a call to an endpoint
+    ///
+    /// Calls to the S3-compatible API are named after their operation, such
+    /// as `s3_put_object`. Endpoints this library doesn't know about are
+    /// assumed to be Class C, the most expensive.
+    #[must_use]
+    pub fn of(endpoint: &str) -> Self {
+        match endpoint {
+            "b2_cancel_large_file"
+            | "b2_delete_file_version"
+            | "b2_finish_large_file"
+            | "b2_get_upload_part_url"
+            | "b2_get_upload_url"
+            | "b2_hide_file"
+            | "b2_start_large_file"
+            | "b2_update_file_legal_hold"
+            | "b2_update_file_retention"
+            | "b2_upload_file"
+            | "b2_upload_part"
+            | "s3_abort_multipart_upload"
+            | "s3_complete_multipart_upload"
+            | "s3_create_multipart_upload"
+            | "s3_delete_object"
+            | "s3_put_object"
+            | "s3_upload_part" => Self::A,
+            "b2_download_file_by_id"
+            | "b2_download_file_by_name"
+            | "b2_get_file_info"
+            | "s3_get_object"
+            | "s3_head_object" => Self::B,
+            _ => Self::C,
+        }
+    }
+}
+
+impl fmt::Display for TransactionClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::A => "Class A",
+            Self::B => "Class B",
+            Self::C => "Class C",
+        })
+    }
+}
+
+/// Whether an endpoint downloads file contents, which is billed as egress
+fn is_download(endpoint: &str) -> bool {
+    matches!(
+        endpoint,
+        "b2_download_file_by_id" | "b2_download_file_by_name" | "s3_get_object"
+    )
+}
+
+/// Whether an endpoint uploads file contents
+fn is_upload(endpoint: &str) -> bool {
+    matches!(
+        endpoint,
+        "b2_upload_file"
+            | "b2_upload_part"
+            | "s3_put_object"
+            | "s3_upload_part"
+    )
+}
+
+/// The prices a cost estimate is made with, in US dollars
+///
+/// The defaults are Backblaze's list prices. They can be overridden for every
+/// session through the `B2NATIVE_PRICES` environment variable, such as
+/// `B2NATIVE_PRICES='{class_c=0.000005}'`, or for one session with
+/// [`Session::set_price_table`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PriceTable {
+    /// The price of one Class A transaction
+    pub class_a: f64,
+    /// The price of one Class B transaction
+    pub class_b: f64,
+    /// The price of one Class C transaction
+    pub class_c: f64,
+    /// The price of downloading a gigabyte
+    pub egress_per_gb: f64,
+    /// The price of storing a gigabyte for a month
+    pub storage_per_gb_month: f64,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        Self {
+            class_a: 0.0,
+            class_b: 0.004 / 10_000.0,
+            class_c: 0.004 / 1_000.0,
+            egress_per_gb: 0.01,
+            storage_per_gb_month: 0.006,
+        }
+    }
+}
+
+/// Convert a count to a float for estimates
+///
+/// Counts above 2^53 lose precision, which doesn't matter for an estimate.
+#[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+pub(crate) fn to_f64(count: u64) -> f64 {
+    count as f64
+}
+
+impl PriceTable {
+    /// Estimate what some usage costs
+    #[must_use]
+    pub fn estimate(&self, usage: &Usage) -> CostEstimate {
+        let class_a = to_f64(usage.class_a) * self.class_a;
+        let class_b = to_f64(usage.class_b) * self.class_b;
+        let class_c = to_f64(usage.class_c) * self.class_c;
+        let egress =
+            to_f64(usage.bytes_downloaded) / GIGABYTE * self.egress_per_gb;
+        CostEstimate {
+            class_a,
+            class_b,
+            class_c,
+            egress,
+            total: class_a + class_b + class_c + egress,
+        }
+    }
+
+    /// Estimate what storing some bytes for some months costs
+    #[must_use]
+    pub fn storage(&self, bytes: u64, months: f64) -> f64 {
+        to_f64(bytes) / GIGABYTE * months * self.storage_per_gb_month
+    }
+}
+
+/// What a session has used that Backblaze bills for
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    /// The number of Class A transactions
+    pub class_a: u64,
+    /// The number of Class B transactions
+    pub class_b: u64,
+    /// The number of Class C transactions
+    pub class_c: u64,
+    /// The bytes of file contents uploaded
+    pub bytes_uploaded: u64,
+    /// The bytes of file contents downloaded
+    pub bytes_downloaded: u64,
+}
+
+impl Usage {
+    /// The number of transactions of a class
+    #[must_use]
+    pub fn transactions(&self, class: TransactionClass) -> u64 {
+        match class {
+            TransactionClass::A => self.class_a,
+            TransactionClass::B => self.class_b,
+            TransactionClass::C => self.class_c,
+        }
+    }
+
+    /// A mutable reference to the number of transactions of a class
+    fn transactions_mut(&mut self, class: TransactionClass) -> &mut u64 {
+        match class {
+            TransactionClass::A => &mut self.class_a,
+            TransactionClass::B => &mut self.class_b,
+            TransactionClass::C => &mut self.class_c,
+        }
+    }
+}
+
+/// An estimate of what some usage costs, in US dollars
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CostEstimate {
+    /// The cost of Class A transactions
+    pub class_a: f64,
+    /// The cost of Class B transactions
+    pub class_b: f64,
+    /// The cost of Class C transactions
+    pub class_c: f64,
+    /// The cost of downloads
+    pub egress: f64,
+    /// The cost of everything
+    pub total: f64,
+}
+
+/// Caps on what a session may use
+///
+/// Every cap is optional. A request that would take usage past a cap fails
+/// with `SessionError::BudgetExceeded` without being sent.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Budget {
+    /// The most Class B transactions
+    pub max_class_b: Option<u64>,
+    /// The most Class C transactions
+    pub max_class_c: Option<u64>,
+    /// The most bytes of file contents to download
+    pub max_bytes_downloaded: Option<u64>,
+    /// The most the estimated cost may reach, in US dollars
+    pub max_cost: Option<f64>,
+}
+
+/// Which cap of a [`Budget`] a request would have exceeded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum BudgetLimit {
+    /// [`Budget::max_class_b`]
+    ClassB,
+    /// [`Budget::max_class_c`]
+    ClassC,
+    /// [`Budget::max_bytes_downloaded`]
+    BytesDownloaded,
+    /// [`Budget::max_cost`]
+    Cost,
+}
+
+impl fmt::Display for BudgetLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::ClassB => "Class B transactions",
+            Self::ClassC => "Class C transactions",
+            Self::BytesDownloaded => "bytes downloaded",
+            Self::Cost => "estimated cost",
+        })
+    }
+}
+
+impl Budget {
+    /// The first cap that some usage exceeds, if any
+    fn exceeded(
+        &self,
+        usage: &Usage,
+        prices: &PriceTable,
+    ) -> Option<BudgetLimit> {
+        let over =
+            |max: Option<u64>, value: u64| max.is_some_and(|max| value > max);
+        if over(self.max_class_b, usage.class_b) {
+            Some(BudgetLimit::ClassB)
+        } else if over(self.max_class_c, usage.class_c) {
+            Some(BudgetLimit::ClassC)
+        } else if over(self.max_bytes_downloaded, usage.bytes_downloaded) {
+            Some(BudgetLimit::BytesDownloaded)
+        } else if self
+            .max_cost
+            .is_some_and(|max| prices.estimate(usage).total > max)
+        {
+            Some(BudgetLimit::Cost)
+        } else {
+            None
+        }
+    }
+}
+
+/// The usage, prices and budget of a session
+#[derive(Debug)]
+struct AccountingState {
+    /// What the session has used
+    usage: Usage,
+    /// The prices estimates are made with
+    prices: PriceTable,
+    /// The caps on what the session may use
+    budget: Option<Budget>,
+}
+
+/// Keeps the usage of a session and enforces its budget
+#[derive(Debug)]
+pub(crate) struct Accounting {
+    /// The usage, prices and budget so far
+    state: Mutex<AccountingState>,
+}
+
+impl Default for Accounting {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(AccountingState {
+                usage: Usage::default(),
+                prices: CONFIG.prices.clone(),
+                budget: None,
+            }),
+        }
+    }
+}
+
+impl Accounting {
+    /// Lock the state
+    fn state(&self) -> std::sync::MutexGuard<'_, AccountingState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Count a call to an endpoint, unless it would exceed the budget
+    ///
+    /// `bytes_sent` is the size of the request body.
+    pub(crate) fn admit(
+        &self,
+        endpoint: &str,
+        bytes_sent: u64,
+    ) -> Result<(), SessionError> {
+        self.apply(|usage| {
+            *usage.transactions_mut(TransactionClass::of(endpoint)) += 1;
+            if is_upload(endpoint) {
+                usage.bytes_uploaded += bytes_sent;
+            }
+        })
+    }
+
+    /// Check that a download of `bytes` would fit the budget
+    ///
+    /// Nothing is counted: the bytes are recorded as they are read.
+    pub(crate) fn check_download(
+        &self,
+        endpoint: &str,
+        bytes: u64,
+    ) -> Result<(), SessionError> {
+        if !is_download(endpoint) {
+            return Ok(());
+        }
+        let state = self.state();
+        let mut usage = state.usage.clone();
+        usage.bytes_downloaded += bytes;
+        Self::check(&state, &usage)
+    }
+
+    /// Count bytes read from a download
+    ///
+    /// The bytes have already arrived, so they are counted even if they go
+    /// over the budget. That stops the next request instead.
+    pub(crate) fn record_download(&self, endpoint: &str, bytes: u64) {
+        if is_download(endpoint) {
+            self.state().usage.bytes_downloaded += bytes;
+        }
+    }
+
+    /// Apply a change to the usage, unless it would exceed the budget
+    fn apply(
+        &self,
+        change: impl FnOnce(&mut Usage),
+    ) -> Result<(), SessionError> {
+        let mut state = self.state();
+        let mut usage = state.usage.clone();
+        change(&mut usage);
+        Self::check(&state, &usage)?;
+        state.usage = usage;
+        Ok(())
+    }
+
+    /// Check that `usage` fits the budget in `state`
+    fn check(
+        state: &AccountingState,
+        usage: &Usage,
+    ) -> Result<(), SessionError> {
+        if let Some(limit) = state
+            .budget
+            .as_ref()
+            .and_then(|budget| budget.exceeded(usage, &state.prices))
+        {
+            tracing::warn!(%limit, "request would exceed the budget");
+            return Err(SessionError::BudgetExceeded {
+                limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    /// What this session has used that Backblaze bills for
+    #[must_use]
+    pub fn usage(&self) -> Usage {
+        self.inner.metrics.accounting().state().usage.clone()
+    }
+
+    /// Forget the usage of this session, starting its budget over
+    pub fn reset_usage(&self) {
+        self.inner.metrics.accounting().state().usage = Usage::default();
+    }
+
+    /// The prices cost estimates of this session are made with
+    #[must_use]
+    pub fn price_table(&self) -> PriceTable {
+        self.inner.metrics.accounting().state().prices.clone()
+    }
+
+    /// Replace the prices cost estimates of this session are made with
+    pub fn set_price_table(&self, prices: PriceTable) {
+        self.inner.metrics.accounting().state().prices = prices;
+    }
+
+    /// Estimate what this session has cost so far
+    #[must_use]
+    pub fn estimated_cost(&self) -> CostEstimate {
+        let accounting = self.inner.metrics.accounting();
+        let state = accounting.state();
+        state.prices.estimate(&state.usage)
+    }
+
+    /// The caps on what this session may use
+    #[must_use]
+    pub fn budget(&self) -> Option<Budget> {
+        self.inner.metrics.accounting().state().budget.clone()
+    }
+
+    /// Replace the caps on what this session may use
+    ///
+    /// Passing `None` removes every cap. Usage so far counts against the new
+    /// budget.
+    pub fn set_budget(&self, budget: Option<Budget>) {
+        self.inner.metrics.accounting().state().budget = budget;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use b2fake::FakeB2;
+
+    use crate::{
+        tests::bucket, Budget, BudgetLimit, PriceTable, SessionError,
+        TransactionClass, UploadOptions, Usage,
+    };
+
+    #[test]
+    fn endpoints_have_classes() {
+        assert_eq!(TransactionClass::of("b2_upload_part"), TransactionClass::A);
+        assert_eq!(
+            TransactionClass::of("b2_download_file_by_name"),
+            TransactionClass::B
+        );
+        assert_eq!(
+            TransactionClass::of("b2_list_buckets"),
+            TransactionClass::C
+        );
+        assert_eq!(
+            TransactionClass::of("b2_new_endpoint"),
+            TransactionClass::C
+        );
+    }
+
+    #[test]
+    fn estimates_use_the_price_table() {
+        let usage = Usage {
+            class_a: 100,
+            class_b: 10_000,
+            class_c: 1_000,
+            bytes_uploaded: 0,
+            bytes_downloaded: 2_000_000_000,
+        };
+        let estimate = PriceTable::default().estimate(&usage);
+        assert!((estimate.total - 0.028).abs() < 1e-9);
+        let storage = PriceTable::default().storage(1_000_000_000_000, 1.0);
+        assert!((storage - 6.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn budgets_stop_requests() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server, "costs-bucket").await;
+        let session = bucket.session();
+        bucket
+            .upload_file("a.txt", b"hello".to_vec(), &UploadOptions::default())
+            .await
+            .expect("Upload should succeed");
+        let usage = session.usage();
+        assert_eq!(usage.class_a, 2);
+        assert_eq!(usage.class_c, 2);
+        assert_eq!(usage.bytes_uploaded, 5);
+
+        session.set_budget(Some(Budget {
+            max_class_b: Some(1),
+            max_bytes_downloaded: Some(8),
+            ..Budget::default()
+        }));
+        bucket
+            .download_file("a.txt", None)
+            .await
+            .expect("Download should fit the budget");
+        assert_eq!(session.usage().bytes_downloaded, 5);
+        let calls = server.calls("b2_download_file_by_name");
+        let result = bucket.download_file("a.txt", None).await;
+        assert!(matches!(
+            result,
+            Err(SessionError::BudgetExceeded {
+                limit: BudgetLimit::ClassB
+            })
+        ));
+        assert_eq!(server.calls("b2_download_file_by_name"), calls);
+
+        session.set_budget(Some(Budget {
+            max_bytes_downloaded: Some(8),
+            ..Budget::default()
+        }));
+        let result = bucket.download_file("a.txt", None).await;
+        assert!(matches!(
+            result,
+            Err(SessionError::BudgetExceeded {
+                limit: BudgetLimit::BytesDownloaded
+            })
+        ));
+        assert!(session.estimated_cost().total > 0.0);
+    }
+}
@@ -0,0 +1,357 @@
+//! Downloading files
+//!
+//! Downloads go to the download URL rather than the API URL, and return the
+//! file contents as the body with everything else in headers.
+//!
+//! - [``b2_download_file_by_id`` API Docs](https://www.backblaze.com/apidocs/b2-download-file-by-id)
+//! - [``b2_download_file_by_name`` API Docs](https://www.backblaze.com/apidocs/b2-download-file-by-name)
+
+use std::{collections::BTreeMap, ops::Range};
+
+use bytes::Bytes;
+use percent_encoding::percent_decode_str;
+use reqwest::{header::HeaderMap, RequestBuilder, StatusCode};
+use tracing::Instrument;
+
+use crate::{
+    api::percent_encode,
+    bucket::sha1_hex,
+    metrics::{advertised_length, operation_span},
+    permissions::BucketRef,
+    progress::{Phase, Transfer, TransferKind},
+    range_header,
+    throttle::download_body,
+    ApiError, Authorization, Bucket, Capability, Session, SessionError,
+};
+
+/// A downloaded file, or a range of one
+#[derive(Clone, Debug)]
+pub struct Download {
+    /// The unique identifier of the file version
+    pub file_id: String,
+    /// The name of the file
+    pub file_name: String,
+    /// The MIME type of the file
+    pub content_type: String,
+    /// The SHA1 of the whole file, if B2 knows it
+    pub content_sha1: Option<String>,
+    /// When the file was uploaded, in milliseconds since the Unix epoch
+    pub upload_timestamp: u64,
+    /// The custom information uploaded with the file
+    pub file_info: BTreeMap<String, String>,
+    /// The length of the whole file, even when only a range was downloaded
+    pub total_length: u64,
+    /// The downloaded bytes
+    pub data: Bytes,
+}
+
+/// Read a header as a string
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Read a percent-encoded header
+fn decoded_header(headers: &HeaderMap, name: &str) -> Option<String> {
+    header(headers, name)
+        .map(|value| percent_decode_str(value).decode_utf8_lossy().into_owned())
+}
+
+impl Download {
+    /// Build a download from the headers and body of a response
+    fn from_parts(headers: &HeaderMap, data: Bytes) -> Self {
+        let total_length = header(headers, "content-range")
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok())
+            .unwrap_or_else(|| u64::try_from(data.len()).unwrap_or(u64::MAX));
+        let file_info = headers
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.as_str().strip_prefix("x-bz-info-")?;
+                let value = percent_decode_str(value.to_str().ok()?)
+                    .decode_utf8_lossy()
+                    .into_owned();
+                Some((key.to_owned(), value))
+            })
+            .collect();
+        Self {
+            file_id: decoded_header(headers, "x-bz-file-id")
+                .unwrap_or_default(),
+            file_name: decoded_header(headers, "x-bz-file-name")
+                .unwrap_or_default(),
+            content_type: header(headers, "content-type")
+                .unwrap_or_default()
+                .to_owned(),
+            content_sha1: header(headers, "x-bz-content-sha1")
+                .filter(|sha1| *sha1 != "none")
+                .map(|sha1| {
+                    sha1.strip_prefix("unverified:").unwrap_or(sha1).to_owned()
+                }),
+            upload_timestamp: header(headers, "x-bz-upload-timestamp")
+                .and_then(|timestamp| timestamp.parse().ok())
+                .unwrap_or_default(),
+            file_info,
+            total_length,
+            data,
+        }
+    }
+}
+
+impl Session {
+    /// Download a file, re-authorizing once if the token is rejected
+    ///
+    /// `request` builds the request from the current authorization. When the
+    /// whole file is downloaded, it is checked against the SHA1 B2 reports.
+    async fn download(
+        &self,
+        endpoint: &str,
+        request: impl Fn(&Authorization) -> RequestBuilder,
+        range: Option<Range<u64>>,
+        transfer: &Transfer,
+    ) -> Result<Download, SessionError> {
+        let range_value = range.as_ref().map(range_header).transpose()?;
+        let mut attempt = 1;
+        loop {
+            let (builder, token) = {
+                let authorization = self.authorization();
+                (request(&authorization), authorization.token.clone())
+            };
+            let builder = builder.header("Authorization", &token);
+            let builder = match &range_value {
+                Some(range) => builder.header("Range", range),
+                None => builder,
+            };
+            let response = self.send_request(endpoint, 0, builder).await?;
+            let status = response.status();
+            if status == StatusCode::OK || status == StatusCode::PARTIAL_CONTENT
+            {
+                let headers = response.headers().clone();
+                if let Some(length) = advertised_length(&response) {
+                    transfer.length(length);
+                }
+                transfer.phase(Phase::Transferring);
+                let data = download_body(&self.throttles(), transfer, response)
+                    .await?;
+                let download = Download::from_parts(&headers, data);
+                if range.is_none() {
+                    if let Some(expected) = &download.content_sha1 {
+                        let actual = sha1_hex(&download.data);
+                        if !actual.eq_ignore_ascii_case(expected) {
+                            return Err(SessionError::ChecksumMismatch {
+                                expected: expected.clone(),
+                                actual,
+                            });
+                        }
+                    }
+                }
+                return Ok(download);
+            }
+            let Ok(error) = response.json::<ApiError>().await else {
+                return Err(SessionError::ErrorDeserializationFailed);
+            };
+            if attempt > 1 || !error.code.is_token_rejection() {
+                return Err(SessionError::RequestRejected(error));
+            }
+            transfer
+                .retry(attempt, format!("{} {}", error.status, error.message));
+            self.reauthorize(&token).await?;
+            self.inner.metrics.retry(endpoint, attempt);
+            attempt += 1;
+        }
+    }
+
+    /// Download a file version by its ID
+    ///
+    /// `range` selects the bytes to download, or the whole file if `None`.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::ChecksumMismatch`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::EmptyRange`
+    pub async fn download_file_by_id(
+        &self,
+        file_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Download, SessionError> {
+        self.require(Capability::ReadFiles)?;
+        let transfer = self.transfer(
+            TransferKind::Download,
+            file_id,
+            range.as_ref().map(|range| range.end.saturating_sub(range.start)),
+        );
+        let result = self
+            .download(
+                "b2_download_file_by_id",
+                |authorization| {
+                    self.http_client()
+                        .get(format!(
+                            "{}/b2api/v3/b2_download_file_by_id",
+                            authorization.storage_api_info.download_url
+                        ))
+                        .query(&[("fileId", file_id)])
+                },
+                range,
+                &transfer,
+            )
+            .instrument(operation_span("b2_download_file_by_id", None))
+            .await;
+        transfer.finish(&result);
+        result
+    }
+}
+
+impl Bucket {
+    /// Download the latest version of a file by its name
+    ///
+    /// `range` selects the bytes to download, or the whole file if `None`.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::ChecksumMismatch`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::EmptyRange`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub async fn download_file(
+        &self,
+        file_name: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Download, SessionError> {
+        let session = self.session();
+        session.preflight(
+            Capability::ReadFiles,
+            Some(BucketRef::Id(self.id())),
+            Some(file_name),
+        )?;
+        let transfer = session.transfer(
+            TransferKind::Download,
+            file_name,
+            range.as_ref().map(|range| range.end.saturating_sub(range.start)),
+        );
+        let result = session
+            .download(
+                "b2_download_file_by_name",
+                |authorization| {
+                    session.http_client().get(format!(
+                        "{}/file/{}/{}",
+                        authorization.storage_api_info.download_url,
+                        percent_encode(self.name()),
+                        percent_encode(file_name)
+                    ))
+                },
+                range,
+                &transfer,
+            )
+            .instrument(operation_span(
+                "b2_download_file_by_name",
+                Some(self.name()),
+            ))
+            .await;
+        transfer.finish(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use b2fake::{FakeB2, Fault};
+    use chrono::NaiveDate;
+
+    use crate::{
+        tests::bucket, Clock, Limits, MockClock, Schedule, SessionError,
+        Throttle, UploadOptions,
+    };
+
+    fn mock_throttle(limits: Limits) -> (MockClock, Arc<Throttle>) {
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .and_then(|date| date.and_hms_opt(12, 0, 0))
+            .expect("Date should be valid");
+        let clock = MockClock::new(start);
+        let throttle = Throttle::with_clock(
+            Schedule::constant(limits),
+            Arc::new(clock.clone()),
+        );
+        (clock, Arc::new(throttle))
+    }
+
+    #[tokio::test]
+    async fn download_roundtrip() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server, "downloads").await;
+        let mut options = UploadOptions::default();
+        options
+            .file_info
+            .insert("src_last_modified_millis".to_owned(), "1000".to_owned());
+        let uploaded = bucket
+            .upload_file("dir/a file.txt", b"hello world".to_vec(), &options)
+            .await
+            .expect("Upload should succeed");
+        let download = bucket
+            .download_file("dir/a file.txt", None)
+            .await
+            .expect("Download should succeed");
+        assert_eq!(download.data.as_ref(), b"hello world");
+        assert_eq!(Some(download.file_id.clone()), uploaded.file_id);
+        assert_eq!(download.file_name, "dir/a file.txt");
+        assert_eq!(
+            download.file_info.get("src_last_modified_millis"),
+            Some(&"1000".to_owned())
+        );
+        let range = bucket
+            .session()
+            .download_file_by_id(&download.file_id, Some(6..11))
+            .await
+            .expect("Ranged download should succeed");
+        assert_eq!(range.data.as_ref(), b"world");
+        assert_eq!(range.total_length, 11);
+        assert!(matches!(
+            bucket.download_file("dir/a file.txt", Some(6..6)).await,
+            Err(SessionError::EmptyRange)
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_download_token_reauthorizes() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server, "downloads").await;
+        bucket
+            .upload_file("a.txt", b"hello".to_vec(), &UploadOptions::default())
+            .await
+            .expect("Upload should succeed");
+        server.inject(Some("b2_download_file_by_name"), Fault::ExpiredToken, 1);
+        let download = bucket
+            .download_file("a.txt", None)
+            .await
+            .expect("Download should be retried");
+        assert_eq!(download.data.as_ref(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn transfers_are_throttled() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server, "downloads").await;
+        let (clock, throttle) = mock_throttle(Limits {
+            upload: Some(1000),
+            download: Some(500),
+        });
+        bucket.session().set_throttle(throttle);
+        bucket
+            .upload_file("a.bin", vec![0; 5000], &UploadOptions::default())
+            .await
+            .expect("Upload should succeed");
+        assert_eq!(clock.elapsed(), Duration::from_secs(4));
+        bucket
+            .download_file("a.bin", None)
+            .await
+            .expect("Download should succeed");
+        assert_eq!(clock.elapsed(), Duration::from_secs(13));
+    }
+}
@@ -0,0 +1,172 @@
+//! Watching for application keys that are about to expire
+//!
+//! Application keys can be created with an expiration date. Once it passes,
+//! every call fails until the user creates a new key, so applications should
+//! warn about it ahead of time with [`Session::key_expiry_warning`].
+
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+
+use crate::{file::timestamp, Session, SessionError};
+
+/// How long in advance [`Session::key_expiry_warning`] warns by default
+pub const DEFAULT_KEY_EXPIRY_WARNING: Duration = Duration::from_hours(14 * 24);
+
+/// A warning that the application key of a session expires soon, or has
+/// expired
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyExpiryWarning {
+    /// When the key expires
+    pub expires_at: DateTime<Utc>,
+    /// Whether the key has already expired
+    pub expired: bool,
+    /// How many whole days are left before the key expires, zero once it has
+    pub days_remaining: u64,
+}
+
+/// Whether a key that expires at `expires_at`, in milliseconds since the Unix
+/// epoch, has expired by now
+///
+/// This is the only way expiry is decided: Backblaze's error messages don't
+/// say reliably whether a key was rejected because it expired.
+pub(crate) fn key_has_expired(expires_at: Option<u64>) -> bool {
+    expires_at.and_then(timestamp).is_some_and(|at| at <= Utc::now())
+}
+
+impl Session {
+    /// When the application key of this session expires, if it ever does
+    #[must_use]
+    pub fn key_expires_at(&self) -> Option<DateTime<Utc>> {
+        self.authorization().key_expires_at.and_then(timestamp)
+    }
+
+    /// Whether the application key of this session has expired
+    #[must_use]
+    pub fn key_expired(&self) -> bool {
+        key_has_expired(self.authorization().key_expires_at)
+    }
+
+    /// A warning if the application key of this session expires within
+    /// `horizon`, or has already expired
+    ///
+    /// Keys without an expiration date never cause a warning.
+    #[must_use]
+    pub fn key_expiry_warning(
+        &self,
+        horizon: Duration,
+    ) -> Option<KeyExpiryWarning> {
+        let expires_at = self.key_expires_at()?;
+        let remaining = expires_at - Utc::now();
+        let horizon = TimeDelta::from_std(horizon).unwrap_or(TimeDelta::MAX);
+        (remaining <= horizon).then(|| KeyExpiryWarning {
+            expires_at,
+            expired: remaining <= TimeDelta::zero(),
+            days_remaining: u64::try_from(remaining.num_days())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Turn a rejection of this session's credentials into
+    /// [`SessionError::KeyExpired`] when its key is known to have expired
+    pub(crate) fn explain_rejection(
+        &self,
+        error: SessionError,
+    ) -> SessionError {
+        let known = self.authorization().key_expires_at;
+        match error {
+            SessionError::AuthenticationRejected {
+                ..
+            } if key_has_expired(known) => SessionError::KeyExpired {
+                expired_at: known.and_then(timestamp),
+            },
+            error => error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use b2fake::{Credentials, FakeB2, KeySpec};
+
+    use crate::{
+        tests::{authorize, session},
+        Session, SessionError,
+    };
+
+    fn add_key(server: &FakeB2, valid_for: Duration) -> Credentials {
+        server.add_key(KeySpec {
+            name: "expiring".to_owned(),
+            capabilities: vec!["listBuckets".to_owned()],
+            valid_for: Some(valid_for),
+            ..KeySpec::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn warns_before_expiry() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let master = session(&server).await;
+        assert_eq!(master.key_expires_at(), None);
+        assert_eq!(master.key_expiry_warning(Duration::MAX), None);
+
+        let ten_days = Duration::from_hours(10 * 24);
+        let expiring = authorize(&server, add_key(&server, ten_days)).await;
+        assert!(!expiring.key_expired());
+        assert_eq!(
+            expiring.key_expiry_warning(Duration::from_hours(7 * 24)),
+            None
+        );
+        let warning = expiring
+            .key_expiry_warning(Duration::from_hours(14 * 24))
+            .expect("Key expires within the horizon");
+        assert!(!warning.expired);
+        assert_eq!(warning.days_remaining, 9);
+        let restored = Session::restore_with_endpoint(
+            expiring.export(),
+            &server.authorize_url(),
+            expiring.credentials().application_key_id.clone(),
+            expiring.credentials().application_key.clone(),
+        )
+        .await
+        .expect("Session should restore");
+        assert_eq!(restored.key_expires_at(), expiring.key_expires_at());
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_reported() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let credentials = add_key(&server, Duration::from_millis(300));
+        let session = authorize(&server, credentials.clone()).await;
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(session.key_expired());
+        assert!(session
+            .key_expiry_warning(Duration::ZERO)
+            .is_some_and(|warning| warning.expired));
+
+        server.expire_tokens();
+        let error = session
+            .bucket("expired-bucket")
+            .await
+            .expect_err("Expired key should not re-authorize");
+        assert!(matches!(
+            error,
+            SessionError::KeyExpired { expired_at: Some(at) }
+                if Some(at) == session.key_expires_at()
+        ));
+
+        let error = Session::try_new_with_endpoint(
+            &server.authorize_url(),
+            credentials.key_id,
+            credentials.key,
+        )
+        .await
+        .expect_err("Expired key should not authorize");
+        // Without a known expiration, nothing says why the key was rejected
+        assert!(matches!(error, SessionError::AuthenticationRejected { .. }));
+    }
+}
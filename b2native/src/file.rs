@@ -0,0 +1,63 @@
+//! The file version structure shared by the file endpoints
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Convert a timestamp in milliseconds since the Unix epoch, as B2 reports
+/// them, to a date
+pub(crate) fn timestamp(millis: u64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_millis(i64::try_from(millis).ok()?)
+}
+
+/// What a file version represents
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FileAction {
+    /// A large file that has been started, but not finished or canceled
+    Start,
+    /// A file that was uploaded
+    Upload,
+    /// A marker hiding the versions of a file uploaded before it
+    Hide,
+    /// A virtual folder returned by listings with a delimiter
+    Folder,
+    /// An action this version of the library doesn't know about
+    #[serde(other)]
+    Other,
+}
+
+/// A version of a file, as returned by most file endpoints
+///
+/// Fields that B2 only includes for some actions are optional.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FileVersion {
+    /// The account that owns the file
+    pub account_id: Option<String>,
+    /// What this version represents
+    pub action: FileAction,
+    /// The bucket the file is in
+    pub bucket_id: Option<String>,
+    /// The number of bytes stored in the file
+    pub content_length: u64,
+    /// The SHA1 of the bytes stored in the file, if known
+    ///
+    /// Large files don't have a SHA1 of the whole file unless the uploader
+    /// supplied one in `large_file_sha1` file info.
+    pub content_sha1: Option<String>,
+    /// The MIME type of the file
+    pub content_type: Option<String>,
+    /// The unique identifier of this version
+    ///
+    /// This is `None` for folders.
+    pub file_id: Option<String>,
+    /// The custom information uploaded with the file
+    #[serde(default)]
+    pub file_info: BTreeMap<String, String>,
+    /// The name of the file
+    pub file_name: String,
+    /// When the version was uploaded, in milliseconds since the Unix epoch
+    pub upload_timestamp: u64,
+}
@@ -0,0 +1,250 @@
+//! Uploading large files in parts
+
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    api::{
+        b2_cancel_large_file, b2_copy_part, b2_finish_large_file,
+        b2_upload_part, OutgoingRequest,
+    },
+    bucket::sha1_hex,
+    progress::{Phase, Transfer},
+    range_header,
+    upload_urls::UploadTarget,
+    FileVersion, Session, SessionError,
+};
+
+/// A large file that has been started but not finished
+///
+/// Parts can be uploaded concurrently from clones of the same `LargeFile`.
+/// Every part except the last must be at least the absolute minimum part size
+/// reported by ``b2_authorize_account``.
+#[derive(Clone, Debug)]
+pub struct LargeFile {
+    /// The session the file was started with
+    session: Session,
+    /// The file version returned by ``b2_start_large_file``
+    file: Arc<FileVersion>,
+    /// The SHA1 of every part uploaded so far, by part number
+    part_sha1s: Arc<Mutex<BTreeMap<u32, String>>>,
+    /// Where the progress of the upload is reported
+    transfer: Transfer,
+}
+
+impl LargeFile {
+    /// Wrap a file version returned by ``b2_start_large_file``
+    pub(crate) fn new(
+        session: Session,
+        file: FileVersion,
+        transfer: Transfer,
+    ) -> Self {
+        Self {
+            session,
+            file: Arc::new(file),
+            part_sha1s: Arc::default(),
+            transfer,
+        }
+    }
+
+    /// The unique identifier of the file
+    #[must_use]
+    pub fn file_id(&self) -> &str {
+        self.file.file_id.as_deref().unwrap_or_default()
+    }
+
+    /// The name of the file
+    #[must_use]
+    pub fn file_name(&self) -> &str {
+        &self.file.file_name
+    }
+
+    /// Report the total size of the file to the progress observer
+    ///
+    /// B2 doesn't need to know the size of a large file up front, so its
+    /// progress has no total, and no estimated time remaining, until one is
+    /// set.
+    pub fn set_total_length(&self, total_bytes: u64) {
+        self.transfer.length(total_bytes);
+    }
+
+    /// Record the SHA1 of a part that was uploaded or copied
+    fn record_part(&self, part: b2_upload_part::Response) {
+        self.part_sha1s
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(part.part_number, part.content_sha1);
+    }
+
+    /// The target that part upload URLs of this file upload into
+    fn target(&self) -> UploadTarget {
+        UploadTarget::LargeFile(self.file_id().to_owned())
+    }
+
+    /// Upload one part of the file
+    ///
+    /// Part numbers start at 1 and must be contiguous. Uploading a part again
+    /// replaces it.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    pub async fn upload_part(
+        &self,
+        part_number: u32,
+        data: impl Into<Bytes>,
+    ) -> Result<(), SessionError> {
+        let data = data.into();
+        let content_sha1 = sha1_hex(&data);
+        let request = b2_upload_part::Request {
+            part_number,
+            data,
+            content_sha1: &content_sha1,
+        };
+        let transfer = self.transfer.scope();
+        let part = self
+            .session
+            .with_upload_url(&self.target(), &transfer, |url| {
+                let (request, transfer) = (&request, &transfer);
+                async move {
+                    self.session.upload_part_to(&url, request, transfer).await
+                }
+            })
+            .await?;
+        transfer.part_completed(part.part_number, part.content_length);
+        self.record_part(part);
+        Ok(())
+    }
+
+    /// Copy a part of the file from an existing file version
+    ///
+    /// `range` selects the bytes of the source to copy, or the whole source if
+    /// `None`. B2 copies the data itself, so nothing passes through this
+    /// library.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::EmptyRange`
+    pub async fn copy_part(
+        &self,
+        part_number: u32,
+        source_file_id: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<(), SessionError> {
+        let part = self
+            .session
+            .send(b2_copy_part::Request {
+                source_file_id: source_file_id.to_owned(),
+                large_file_id: self.file_id().to_owned(),
+                part_number,
+                range: range.as_ref().map(range_header).transpose()?,
+            })
+            .await
+            .into_result()?;
+        self.transfer.copied(part.content_length);
+        self.transfer.part_completed(part.part_number, part.content_length);
+        self.record_part(part);
+        Ok(())
+    }
+
+    /// Assemble the uploaded parts into the finished file
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    pub async fn finish(self) -> Result<FileVersion, SessionError> {
+        let part_sha1_array = self
+            .part_sha1s
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .cloned()
+            .collect();
+        self.transfer.phase(Phase::Finishing);
+        let result = self
+            .session
+            .send(b2_finish_large_file::Request {
+                file_id: self.file_id().to_owned(),
+                part_sha1_array,
+            })
+            .await
+            .into_result();
+        self.transfer.finish(&result);
+        let file = result?;
+        self.session.inner.upload_urls.forget(&self.target());
+        Ok(file)
+    }
+
+    /// Abandon the file, deleting any parts uploaded so far
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    pub async fn cancel(self) -> Result<(), SessionError> {
+        self.session
+            .send(b2_cancel_large_file::Request {
+                file_id: self.file_id().to_owned(),
+            })
+            .await
+            .into_result()?;
+        self.session.inner.upload_urls.forget(&self.target());
+        self.transfer.phase(Phase::Canceled);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use b2fake::{Config, FakeB2};
+
+    use crate::{tests::bucket, SessionError, UploadOptions};
+
+    #[tokio::test]
+    async fn large_file_roundtrip() {
+        let server = FakeB2::start_with(Config {
+            absolute_minimum_part_size: 10,
+            ..Config::default()
+        })
+        .await
+        .expect("Fake server should start");
+        let bucket = bucket(&server, "uploads").await;
+        let file = bucket
+            .start_large_file("big.bin", &UploadOptions::default())
+            .await
+            .expect("Large file should start");
+        let (first, second, third) = tokio::join!(
+            file.upload_part(1, vec![1; 10]),
+            file.upload_part(2, vec![2; 10]),
+            file.upload_part(3, vec![3; 5]),
+        );
+        for part in [first, second, third] {
+            part.expect("Part should upload");
+        }
+        assert!(matches!(
+            file.copy_part(4, "any-file", Some(3..3)).await,
+            Err(SessionError::EmptyRange)
+        ));
+        assert_eq!(server.calls("b2_copy_part"), 0);
+        let finished = file.finish().await.expect("File should finish");
+        assert_eq!(finished.content_length, 25);
+        assert!(server.calls("b2_get_upload_part_url") <= 3);
+    }
+}
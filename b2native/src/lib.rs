@@ -2,24 +2,158 @@
 #![doc = include_str!("../README.md")]
 
 mod api;
+mod browse;
+mod bucket;
 mod config;
+mod costs;
+mod download;
+mod expiration;
+mod file;
+mod large_file;
+mod manager;
+mod metrics;
+mod objects;
+mod permissions;
+mod persistence;
+mod progress;
+mod report;
+mod s3;
+mod share;
+mod throttle;
+mod upload_urls;
 
-pub use api::ApiError;
+use std::{
+    fmt,
+    ops::Range,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub use api::{b2_authorize_account::Capability, ApiError};
+pub use browse::{DirEntry, DirTree, DirUsage, FileEntry};
+pub use bucket::{Bucket, UploadOptions};
+use chrono::{DateTime, Utc};
+pub use costs::{
+    Budget, BudgetLimit, CostEstimate, PriceTable, TransactionClass, Usage,
+};
+pub use download::Download;
+pub use expiration::{KeyExpiryWarning, DEFAULT_KEY_EXPIRY_WARNING};
+pub use file::{FileAction, FileVersion};
+pub use large_file::LargeFile;
+pub use manager::{HealthReport, HealthStatus, SessionManager};
+pub use metrics::{EndpointMetrics, LatencyHistogram, MetricsSnapshot};
+pub use objects::{Object, ObjectInfo, ObjectStore};
+pub use permissions::PermissionReport;
+pub use persistence::PersistedSession;
+pub use progress::{
+    BatchProgress, FileProgress, Phase, ProgressEvent, ProgressObserver,
+    ProgressTracker, TransferId, TransferKind,
+};
+pub use report::{
+    AgeBucket, LargestFile, PrefixStats, ReportOptions, UnfinishedLargeFile,
+    UsageReport, VersionStats,
+};
 use reqwest::{Client, Error};
+pub use s3::{MultipartUpload, S3Bucket, S3Client, S3Error, S3Listing};
+pub use share::{
+    DownloadAuthorization, ShareLink, ShareOptions, MAX_SHARE_DURATION,
+};
+pub use throttle::{
+    Clock, Limits, MockClock, Schedule, ScheduleRule, SystemClock, Throttle,
+};
+use tokio::sync::Mutex;
 
-use crate::config::CONFIG;
+use crate::{
+    config::CONFIG, manager::SharedLimits, metrics::Metrics,
+    upload_urls::UploadUrlPool,
+};
 
 /// A session for interacting with the Backblaze API
-#[derive(Debug)]
+///
+/// Sessions are cheap to clone. Every clone shares the same HTTP connection
+/// pool and authorization, so one login can drive many concurrent requests
+/// from different tasks.
+#[derive(Clone, Debug)]
 pub struct Session {
-    /// The authorization token this session will use
-    token: String,
+    /// The state shared by every clone of this session
+    inner: Arc<Inner>,
+}
+
+/// The state shared by every clone of a [`Session`]
+#[derive(Debug)]
+struct Inner {
     /// The HTTP client this session will reuse to take advantage of connection
     /// pooling
     http_client: Client,
+    /// The credentials this session was created with, kept so that it can
+    /// re-authorize when its token is rejected
+    credentials: Credentials,
+    /// The current authorization of this session
+    authorization: RwLock<Authorization>,
+    /// Held while re-authorizing, so that concurrent requests rejected at the
+    /// same time only re-authorize once
+    reauthorizing: Mutex<()>,
+    /// Upload URLs that aren't in use
+    upload_urls: UploadUrlPool,
+    /// The bandwidth limiter of every transfer of this session
+    throttle: RwLock<Arc<Throttle>>,
+    /// The limits shared with the other sessions of a [`SessionManager`]
+    shared_limits: RwLock<Option<SharedLimits>>,
+    /// Where the transfers of this session report their progress
+    progress: RwLock<Option<Arc<dyn ProgressObserver>>>,
+    /// Counters and histograms of every request this session has sent
+    metrics: Metrics,
+}
+
+/// The result of a call to ``b2_authorize_account``
+#[derive(Clone, Debug)]
+pub(crate) struct Authorization {
+    /// The authorization token this session will use
+    pub(crate) token: String,
+    /// The identifier of the account this session is authorized for
+    pub(crate) account_id: String,
+    /// When the authorization token was issued, in seconds since the Unix
+    /// epoch
+    pub(crate) authorized_at: u64,
     /// Information about using the storage API returned by
     /// `b2_authorize_account`
-    storage_api_info: api::b2_authorize_account::StorageApi,
+    pub(crate) storage_api_info: api::b2_authorize_account::StorageApi,
+    /// When the application key expires, in milliseconds since the Unix
+    /// epoch, if it ever does
+    pub(crate) key_expires_at: Option<u64>,
+}
+
+impl From<api::b2_authorize_account::Response> for Authorization {
+    fn from(value: api::b2_authorize_account::Response) -> Self {
+        Self {
+            token: value.authorization_token,
+            account_id: value.account_id,
+            authorized_at: unix_now(),
+            storage_api_info: value.api_info.storage_api,
+            key_expires_at: value.application_key_expiration_timestamp,
+        }
+    }
+}
+
+/// The application key a session authorizes with
+#[derive(Clone)]
+pub(crate) struct Credentials {
+    /// The URL of the ``b2_authorize_account`` endpoint
+    pub(crate) endpoint: String,
+    /// The application key ID
+    pub(crate) application_key_id: String,
+    /// The application key
+    pub(crate) application_key: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("endpoint", &self.endpoint)
+            .field("application_key_id", &self.application_key_id)
+            .field("application_key", &"<redacted>")
+            .finish()
+    }
 }
 
 /// Errors that can be returned in the creation or use of a ``Session``
@@ -50,6 +184,88 @@ pub enum SessionError {
     /// This should only happen if the version of this library you're using
     /// doesn't line up with the API version served by Backblaze.
     ErrorDeserializationFailed,
+    /// A persisted session could not be decoded.
+    ///
+    /// The blob is corrupt, truncated, or was written by an incompatible
+    /// version of this library.
+    MalformedPersistedSession,
+    /// A persisted session could not be decrypted.
+    ///
+    /// Either the blob is encrypted and no key (or the wrong key) was given,
+    /// or the blob has been tampered with.
+    PersistedSessionDecryptionFailed,
+    /// The Backblaze API rejected a request.
+    ///
+    /// The error explains why. Requests that can be retried automatically,
+    /// such as uploads that fail with a 503 status, are only reported this way
+    /// once retrying has failed.
+    RequestRejected(ApiError),
+    /// An S3-compatible API rejected a request.
+    ///
+    /// Requests that failed with a 500 or 503 status are only reported this
+    /// way once retrying has failed.
+    S3RequestRejected(S3Error),
+    /// A downloaded file didn't match the SHA1 B2 reported for it.
+    ///
+    /// The download was corrupted in transit and can be retried.
+    ChecksumMismatch {
+        /// The SHA1 B2 reported
+        expected: String,
+        /// The SHA1 of the data received
+        actual: String,
+    },
+    /// No bucket with the requested name exists, or the application key
+    /// can't see it.
+    BucketNotFound {
+        /// The name of the bucket
+        name: String,
+    },
+    /// The application key lacks the capability a call needs.
+    ///
+    /// The call was not sent to the Backblaze API.
+    MissingCapability {
+        /// The capability the call needs
+        capability: Capability,
+    },
+    /// The application key is restricted to a bucket other than the one a
+    /// call targets.
+    ///
+    /// The call was not sent to the Backblaze API.
+    OutsideBucketRestriction {
+        /// The ID of the bucket the key is restricted to
+        bucket_id: String,
+        /// The name of the bucket the key is restricted to, if it still
+        /// exists
+        bucket_name: Option<String>,
+    },
+    /// The application key is restricted to file names starting with a prefix
+    /// that the target of a call doesn't start with.
+    ///
+    /// The call was not sent to the Backblaze API.
+    OutsideNamePrefixRestriction {
+        /// The prefix the key is restricted to
+        name_prefix: String,
+    },
+    /// The application key has expired.
+    ///
+    /// Unlike [`SessionError::AuthenticationRejected`], retrying with the same
+    /// key can never succeed; a new key has to be created.
+    KeyExpired {
+        /// When the key expired, if the session knew its expiration
+        expired_at: Option<DateTime<Utc>>,
+    },
+    /// A call would take the usage of the session past its budget.
+    ///
+    /// The call was not sent to the Backblaze API, or for downloads, its body
+    /// was not read.
+    BudgetExceeded {
+        /// The cap that would have been exceeded
+        limit: BudgetLimit,
+    },
+    /// A byte range selects no bytes.
+    ///
+    /// The call was not sent.
+    EmptyRange,
 }
 
 impl From<Error> for SessionError {
@@ -58,6 +274,25 @@ impl From<Error> for SessionError {
     }
 }
 
+/// The value of a `Range` header that selects the bytes in `range`
+///
+/// HTTP ranges include their last byte and can't be empty, so an empty range
+/// is refused rather than sent as one that selects a byte.
+pub(crate) fn range_header(range: &Range<u64>) -> Result<String, SessionError> {
+    if range.is_empty() {
+        return Err(SessionError::EmptyRange);
+    }
+    Ok(format!("bytes={}-{}", range.start, range.end - 1))
+}
+
+/// The current time in seconds since the Unix epoch
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
 impl Session {
     /// Create a new session
     ///
@@ -74,37 +309,248 @@ impl Session {
         application_key_id: S,
         application_key: S,
     ) -> Result<Self, SessionError> {
-        let client = Client::new();
-        let response = client
-            .get(&CONFIG.authorize_account_endpoint)
-            .basic_auth(application_key_id.into(), Some(application_key.into()))
-            .send()
-            .await?;
-        if response.status().is_success() {
-            if let Ok(body) =
-                response.json::<api::b2_authorize_account::Response>().await
-            {
-                Ok(Self {
-                    token: body.authorization_token,
-                    http_client: client,
-                    storage_api_info: body.api_info.storage_api,
-                })
-            } else {
-                Err(SessionError::SuccessfulDeserializationFailed)
-            }
-        } else if let Ok(error) = response.json::<ApiError>().await {
-            Err(SessionError::AuthenticationRejected {
-                code: error.code,
-                message: error.message,
-            })
-        } else {
-            Err(SessionError::ErrorDeserializationFailed)
+        Self::try_new_with_endpoint(
+            &CONFIG.authorize_account_endpoint,
+            application_key_id,
+            application_key,
+        )
+        .await
+    }
+
+    /// Create a new session using a specific ``b2_authorize_account`` URL
+    ///
+    /// This behaves exactly like [`Session::try_new`], but authorizes against
+    /// `endpoint` instead of the configured endpoint. It is mostly useful for
+    /// pointing a session at a stand-in server such as `b2fake`.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Session::try_new`].
+    pub async fn try_new_with_endpoint<S: Into<String>>(
+        endpoint: &str,
+        application_key_id: S,
+        application_key: S,
+    ) -> Result<Self, SessionError> {
+        Self::authorize(
+            Client::new(),
+            Credentials {
+                endpoint: endpoint.to_owned(),
+                application_key_id: application_key_id.into(),
+                application_key: application_key.into(),
+            },
+        )
+        .await
+    }
+
+    /// Authorize with a set of credentials and build a session around the
+    /// result
+    async fn authorize(
+        http_client: Client,
+        credentials: Credentials,
+    ) -> Result<Self, SessionError> {
+        let metrics = Metrics::default();
+        let body = api::b2_authorize_account::authorize(
+            &http_client,
+            &credentials,
+            &metrics,
+        )
+        .await?;
+        Ok(Self::from_parts(http_client, credentials, body.into(), metrics))
+    }
+
+    /// Build a session around an existing authorization
+    fn from_parts(
+        http_client: Client,
+        credentials: Credentials,
+        authorization: Authorization,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                http_client,
+                credentials,
+                authorization: RwLock::new(authorization),
+                reauthorizing: Mutex::new(()),
+                upload_urls: UploadUrlPool::new(CONFIG.upload_urls_per_target),
+                throttle: RwLock::default(),
+                shared_limits: RwLock::default(),
+                progress: RwLock::default(),
+                metrics,
+            }),
+        }
+    }
+
+    /// The HTTP client shared by every clone of this session
+    pub(crate) fn http_client(&self) -> &Client {
+        &self.inner.http_client
+    }
+
+    /// The credentials this session was created with
+    pub(crate) fn credentials(&self) -> &Credentials {
+        &self.inner.credentials
+    }
+
+    /// The current authorization of this session
+    ///
+    /// The returned guard must not be held across an await point.
+    pub(crate) fn authorization(&self) -> RwLockReadGuard<'_, Authorization> {
+        self.inner.authorization.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The identifier of the account this session is authorized for
+    #[must_use]
+    pub fn account_id(&self) -> String {
+        self.authorization().account_id.clone()
+    }
+
+    /// Replace this session's authorization token with a fresh one
+    ///
+    /// `rejected` is the token the Backblaze API rejected. If another clone of
+    /// this session already replaced it, nothing is done, so that many
+    /// requests failing at once cause a single re-authorization.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Session::try_new`].
+    pub(crate) async fn reauthorize(
+        &self,
+        rejected: &str,
+    ) -> Result<(), SessionError> {
+        let _guard = self.inner.reauthorizing.lock().await;
+        if self.authorization().token != rejected {
+            return Ok(());
         }
+        let body = api::b2_authorize_account::authorize(
+            self.http_client(),
+            self.credentials(),
+            &self.inner.metrics,
+        )
+        .await
+        .map_err(|error| self.explain_rejection(error))?;
+        self.inner.metrics.reauthorized();
+        *self
+            .inner
+            .authorization
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = body.into();
+        Ok(())
+    }
+
+    /// Re-authorize with this session's credentials, whether or not its
+    /// current token still works
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Session::try_new`].
+    pub(crate) async fn refresh(&self) -> Result<(), SessionError> {
+        let token = self.authorization().token.clone();
+        self.reauthorize(&token).await
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use b2fake::{Credentials, FakeB2};
+
+    use crate::{
+        api::{
+            b2_list_buckets, ApiErrorCode, ApiResponse, ApiResult,
+            OutgoingRequest,
+        },
+        Bucket, Session, SessionError,
+    };
+
+    /// Authorize a session with a key of a fake server
+    pub(crate) async fn authorize(
+        server: &FakeB2,
+        credentials: Credentials,
+    ) -> Session {
+        Session::try_new_with_endpoint(
+            &server.authorize_url(),
+            credentials.key_id,
+            credentials.key,
+        )
+        .await
+        .expect("Key should authorize")
+    }
+
+    /// Authorize a session with the master key of a fake server
+    pub(crate) async fn session(server: &FakeB2) -> Session {
+        authorize(server, server.master_credentials()).await
+    }
+
+    /// Create a private bucket on a fake server and open it with the master
+    /// key
+    pub(crate) async fn bucket(server: &FakeB2, name: &str) -> Bucket {
+        let _id = server.create_bucket(name, "allPrivate");
+        session(server).await.bucket(name).await.expect("Bucket should exist")
+    }
+
+    #[tokio::test]
+    async fn test_auth_offline() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let credentials = server.master_credentials();
+        let session = Session::try_new_with_endpoint(
+            &server.authorize_url(),
+            credentials.key_id,
+            credentials.key,
+        )
+        .await;
+        assert!(session.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_auth_rejected_offline() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let session = Session::try_new_with_endpoint(
+            &server.authorize_url(),
+            "not-a-key-id",
+            "not-a-key",
+        )
+        .await;
+        assert!(matches!(
+            session,
+            Err(SessionError::AuthenticationRejected {
+                code: ApiErrorCode::Unauthorized,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn session_is_shareable() {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<Session>();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_requests_reauthorize_once() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let session = session(&server).await;
+        server.expire_tokens();
+        let authorizations = server.calls("b2_authorize_account");
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let session = session.clone();
+                tokio::spawn(async move {
+                    session
+                        .send(b2_list_buckets::Request {
+                            account_id: session.account_id(),
+                            bucket_id: None,
+                            bucket_name: None,
+                            bucket_types: None,
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            let result = task.await.expect("Task should not panic");
+            assert!(matches!(result, ApiResult::Response(ApiResponse::Ok(_))));
+        }
+        assert_eq!(server.calls("b2_authorize_account"), authorizations + 1);
+    }
+
     #[tokio::test]
     #[cfg(feature = "integration-tests")]
     async fn test_auth() {
@@ -0,0 +1,508 @@
+//! Managing the sessions of several accounts
+//!
+//! A [`SessionManager`] holds one named session per account, each with its
+//! own credentials, authorization, throttle and budget. On top of those, the
+//! manager applies limits shared by every session it holds: a cap on how many
+//! requests run at once and a [`Throttle`] on their combined bandwidth.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use reqwest::{RequestBuilder, Response};
+use serde::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    api::ApiErrorCode, config::CONFIG, expiration::key_has_expired, Session,
+    SessionError, Throttle,
+};
+
+/// Limits shared by every session of a manager
+#[derive(Clone, Debug)]
+pub(crate) struct SharedLimits {
+    /// One permit for every request that may run at once, if capped
+    requests: Option<Arc<Semaphore>>,
+    /// The throttle on the combined bandwidth of every session
+    throttle: Arc<Throttle>,
+}
+
+impl Session {
+    /// The limits this session shares with the other sessions of a manager
+    fn shared_limits(&self) -> Option<SharedLimits> {
+        self.inner
+            .shared_limits
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replace the limits this session shares with other sessions
+    fn set_shared_limits(&self, limits: Option<SharedLimits>) {
+        *self
+            .inner
+            .shared_limits
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = limits;
+    }
+
+    /// Every throttle the transfers of this session have to pass
+    pub(crate) fn throttles(&self) -> Vec<Arc<Throttle>> {
+        let mut throttles = vec![self.throttle()];
+        if let Some(limits) = self.shared_limits() {
+            throttles.push(limits.throttle);
+        }
+        throttles
+    }
+
+    /// Wait for a request slot, if the requests of this session are capped
+    pub(crate) async fn request_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let requests = self.shared_limits()?.requests?;
+        requests.acquire_owned().await.ok()
+    }
+
+    /// Send a request to an endpoint once a request slot is free
+    ///
+    /// The slot stays taken until the body of the response has been read.
+    pub(crate) async fn send_request(
+        &self,
+        endpoint: &str,
+        bytes_sent: u64,
+        request: RequestBuilder,
+    ) -> Result<Response, SessionError> {
+        let permit = self.request_permit().await;
+        self.inner.metrics.send(endpoint, bytes_sent, request, permit).await
+    }
+}
+
+/// The state of the application key of a managed session
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "status")]
+pub enum HealthStatus {
+    /// The key authorized successfully
+    Healthy,
+    /// The key has expired
+    KeyExpired,
+    /// The key was deleted, or never existed
+    KeyRevoked,
+    /// Backblaze rejected the key for another reason
+    Rejected {
+        /// The message Backblaze rejected the key with
+        message: String,
+    },
+    /// Backblaze couldn't be reached, or sent something unexpected
+    Unreachable,
+}
+
+/// The result of checking the key of one managed session
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    /// The alias of the session
+    pub alias: String,
+    /// The account the session is authorized for
+    pub account_id: String,
+    /// The state of the key
+    pub status: HealthStatus,
+    /// When the key expires, if it ever does
+    pub key_expires_at: Option<DateTime<Utc>>,
+}
+
+impl HealthStatus {
+    /// The status matching the result of authorizing with a key that
+    /// expires at `key_expires_at`, in milliseconds since the Unix epoch
+    fn from_result(
+        result: Result<(), SessionError>,
+        key_expires_at: Option<u64>,
+    ) -> Self {
+        match result {
+            Ok(()) => Self::Healthy,
+            Err(SessionError::KeyExpired {
+                ..
+            }) => Self::KeyExpired,
+            Err(SessionError::AuthenticationRejected {
+                code: ApiErrorCode::Unauthorized | ApiErrorCode::BadAuthToken,
+                ..
+            }) => {
+                if key_has_expired(key_expires_at) {
+                    Self::KeyExpired
+                } else {
+                    Self::KeyRevoked
+                }
+            }
+            Err(SessionError::AuthenticationRejected {
+                message,
+                ..
+            }) => Self::Rejected {
+                message,
+            },
+            Err(_) => Self::Unreachable,
+        }
+    }
+}
+
+/// The state shared by every clone of a [`SessionManager`]
+#[derive(Debug)]
+struct ManagerInner {
+    /// The managed sessions, by alias
+    sessions: RwLock<BTreeMap<String, Session>>,
+    /// The limits applied to every managed session
+    limits: SharedLimits,
+}
+
+/// A set of named sessions, one per account, sharing global limits
+///
+/// Managers are cheap to clone, and every clone manages the same sessions.
+#[derive(Clone, Debug)]
+pub struct SessionManager {
+    /// The state shared by every clone of this manager
+    inner: Arc<ManagerInner>,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::with_limits(None, Arc::default())
+    }
+}
+
+impl SessionManager {
+    /// Create a manager without shared limits
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a manager allowing at most `max_concurrent_requests` requests
+    /// at once across every session, or any number if `None`, and passing
+    /// the transfers of every session through `throttle`
+    #[must_use]
+    pub fn with_limits(
+        max_concurrent_requests: Option<usize>,
+        throttle: Arc<Throttle>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(ManagerInner {
+                sessions: RwLock::default(),
+                limits: SharedLimits {
+                    requests: max_concurrent_requests
+                        .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+                    throttle,
+                },
+            }),
+        }
+    }
+
+    /// The throttle on the combined bandwidth of every managed session
+    ///
+    /// Each session's own throttle still applies on top of it.
+    #[must_use]
+    pub fn throttle(&self) -> Arc<Throttle> {
+        Arc::clone(&self.inner.limits.throttle)
+    }
+
+    /// Lock the managed sessions for reading
+    fn sessions(
+        &self,
+    ) -> std::sync::RwLockReadGuard<'_, BTreeMap<String, Session>> {
+        self.inner.sessions.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Lock the managed sessions for writing
+    fn sessions_mut(
+        &self,
+    ) -> std::sync::RwLockWriteGuard<'_, BTreeMap<String, Session>> {
+        self.inner.sessions.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Manage a session under an alias
+    ///
+    /// The session becomes subject to the limits of the manager. Any session
+    /// already managed under the alias is released from them and returned.
+    pub fn insert(
+        &self,
+        alias: impl Into<String>,
+        session: Session,
+    ) -> Option<Session> {
+        session.set_shared_limits(Some(self.inner.limits.clone()));
+        let inner = session.inner.clone();
+        let previous = self.sessions_mut().insert(alias.into(), session);
+        // Inserting a session again must not release it from the limits
+        if let Some(previous) = previous
+            .as_ref()
+            .filter(|previous| !Arc::ptr_eq(&previous.inner, &inner))
+        {
+            previous.set_shared_limits(None);
+        }
+        previous
+    }
+
+    /// Authorize a new session and manage it under an alias
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Session::try_new`].
+    pub async fn add<S: Into<String>>(
+        &self,
+        alias: impl Into<String>,
+        application_key_id: S,
+        application_key: S,
+    ) -> Result<Session, SessionError> {
+        self.add_with_endpoint(
+            alias,
+            &CONFIG.authorize_account_endpoint,
+            application_key_id,
+            application_key,
+        )
+        .await
+    }
+
+    /// Authorize a new session against a specific ``b2_authorize_account``
+    /// URL and manage it under an alias
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Session::try_new`].
+    pub async fn add_with_endpoint<S: Into<String>>(
+        &self,
+        alias: impl Into<String>,
+        endpoint: &str,
+        application_key_id: S,
+        application_key: S,
+    ) -> Result<Session, SessionError> {
+        let session = Session::try_new_with_endpoint(
+            endpoint,
+            application_key_id,
+            application_key,
+        )
+        .await?;
+        let _previous = self.insert(alias, session.clone());
+        Ok(session)
+    }
+
+    /// Stop managing the session under an alias, releasing it from the
+    /// limits of the manager
+    #[must_use]
+    pub fn remove(&self, alias: &str) -> Option<Session> {
+        let session = self.sessions_mut().remove(alias);
+        if let Some(session) = &session {
+            session.set_shared_limits(None);
+        }
+        session
+    }
+
+    /// The session managed under an alias
+    #[must_use]
+    pub fn get(&self, alias: &str) -> Option<Session> {
+        self.sessions().get(alias).cloned()
+    }
+
+    /// The session authorized for an account
+    ///
+    /// If several sessions are authorized for the account, the one with the
+    /// first alias is returned.
+    #[must_use]
+    pub fn get_by_account_id(&self, account_id: &str) -> Option<Session> {
+        self.sessions()
+            .values()
+            .find(|session| session.account_id() == account_id)
+            .cloned()
+    }
+
+    /// The session managed under an alias, or else authorized for an account
+    #[must_use]
+    pub fn lookup(&self, alias_or_account_id: &str) -> Option<Session> {
+        self.get(alias_or_account_id)
+            .or_else(|| self.get_by_account_id(alias_or_account_id))
+    }
+
+    /// The aliases of every managed session, in order
+    #[must_use]
+    pub fn aliases(&self) -> Vec<String> {
+        self.sessions().keys().cloned().collect()
+    }
+
+    /// Re-authorize every managed session, reporting whose keys still work
+    ///
+    /// Sessions whose keys work get a fresh authorization token as a side
+    /// effect. The checks run concurrently.
+    pub async fn check_health(&self) -> Vec<HealthReport> {
+        let sessions: Vec<_> = self
+            .sessions()
+            .iter()
+            .map(|(alias, session)| (alias.clone(), session.clone()))
+            .collect();
+        join_all(sessions.into_iter().map(|(alias, session)| async move {
+            let status = HealthStatus::from_result(
+                session.refresh().await,
+                session.authorization().key_expires_at,
+            );
+            HealthReport {
+                alias,
+                account_id: session.account_id(),
+                status,
+                key_expires_at: session.key_expires_at(),
+            }
+        }))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use b2fake::{FakeB2, KeySpec};
+    use chrono::NaiveDate;
+    use futures_util::future::join_all;
+
+    use crate::{
+        manager::HealthStatus,
+        tests::{authorize, session},
+        Clock, Limits, MockClock, Schedule, SessionManager, Throttle,
+        UploadOptions,
+    };
+
+    #[tokio::test]
+    async fn sessions_are_found_by_alias_or_account() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let manager = SessionManager::new();
+        let _previous = manager.insert("beta", session(&server).await);
+        let _previous = manager.insert("alpha", session(&server).await);
+        assert_eq!(manager.aliases(), ["alpha", "beta"]);
+        let session = manager
+            .lookup(&server.account_id())
+            .expect("Account should be managed");
+        assert_eq!(session.account_id(), server.account_id());
+        assert!(manager.lookup("beta").is_some());
+        let beta = manager.get("beta").expect("Session should exist");
+        let previous = manager.insert("beta", beta.clone());
+        assert!(previous.is_some_and(|previous| {
+            Arc::ptr_eq(&previous.inner, &beta.inner)
+        }));
+        assert!(beta.shared_limits().is_some());
+        assert!(manager.remove("alpha").is_some());
+        assert!(manager.remove("beta").is_some());
+        assert!(manager.lookup(&server.account_id()).is_none());
+        assert!(manager.lookup("beta").is_none());
+    }
+
+    #[tokio::test]
+    async fn bandwidth_is_shared() {
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1)
+            .and_then(|date| date.and_hms_opt(12, 0, 0))
+            .expect("Date should be valid");
+        let clock = MockClock::new(start);
+        let throttle = Throttle::with_clock(
+            Schedule::constant(Limits {
+                upload: Some(1000),
+                download: None,
+            }),
+            Arc::new(clock.clone()),
+        );
+        let manager = SessionManager::with_limits(Some(1), Arc::new(throttle));
+        let servers = [
+            FakeB2::start().await.expect("Fake server should start"),
+            FakeB2::start().await.expect("Fake server should start"),
+        ];
+        for (index, server) in servers.iter().enumerate() {
+            let _id = server.create_bucket("shared-bucket", "allPrivate");
+            let _previous = manager
+                .insert(format!("account {index}"), session(server).await);
+        }
+        let uploads = manager.aliases().into_iter().map(|alias| {
+            let session = manager.get(&alias).expect("Session should exist");
+            async move {
+                let bucket = session
+                    .bucket("shared-bucket")
+                    .await
+                    .expect("Bucket should exist");
+                bucket
+                    .upload_file(
+                        "a.bin",
+                        vec![0; 3000],
+                        &UploadOptions::default(),
+                    )
+                    .await
+                    .expect("Upload should succeed");
+            }
+        });
+        join_all(uploads).await;
+        assert_eq!(clock.elapsed(), Duration::from_secs(5));
+        let released = manager.remove("account 0").expect("Session exists");
+        assert_eq!(released.throttles().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn request_slots_are_held_until_the_body_is_read() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let manager = SessionManager::with_limits(Some(1), Arc::default());
+        let _previous = manager.insert("account", session(&server).await);
+        let session = manager.get("account").expect("Session should exist");
+        let (url, token) = {
+            let authorization = session.authorization();
+            (
+                format!(
+                    "{}/b2api/v3/b2_list_buckets",
+                    authorization.storage_api_info.api_url
+                ),
+                authorization.token.clone(),
+            )
+        };
+        let request = session
+            .http_client()
+            .post(url)
+            .header("Authorization", token)
+            .json(&serde_json::json!({ "accountId": session.account_id() }));
+        let response = session
+            .send_request("b2_list_buckets", 0, request)
+            .await
+            .expect("Request should be sent");
+        let waiting = tokio::time::timeout(
+            Duration::from_millis(100),
+            session.request_permit(),
+        );
+        assert!(waiting.await.is_err());
+        response.bytes().await.expect("Body should be read");
+        assert!(session.request_permit().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn health_checks_find_bad_keys() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let manager = SessionManager::new();
+        let _previous = manager.insert("healthy", session(&server).await);
+        for (alias, valid_for) in
+            [("expiring", Some(Duration::from_millis(200))), ("revoked", None)]
+        {
+            let credentials = server.add_key(KeySpec {
+                name: alias.to_owned(),
+                capabilities: vec!["listBuckets".to_owned()],
+                valid_for,
+                ..KeySpec::default()
+            });
+            let key_id = credentials.key_id.clone();
+            let _previous =
+                manager.insert(alias, authorize(&server, credentials).await);
+            if valid_for.is_none() {
+                assert!(server.revoke_key(&key_id));
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let reports = manager.check_health().await;
+        let statuses: Vec<_> = reports
+            .iter()
+            .map(|report| (report.alias.as_str(), report.status.clone()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("expiring", HealthStatus::KeyExpired),
+                ("healthy", HealthStatus::Healthy),
+                ("revoked", HealthStatus::KeyRevoked),
+            ]
+        );
+    }
+}
@@ -0,0 +1,497 @@
+//! Tracing and metrics for requests to the B2 API
+//!
+//! Every request is traced as a `b2_request` span inside a `b2_operation`
+//! span, and counted in the metrics of its session. Operations cover retries:
+//! an upload retried with a new URL is one operation with several requests.
+//! Spans carry the endpoint, bucket, byte counts, status, retries and latency
+//! of a request, but never authorization tokens, application keys or upload
+//! URLs.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use futures_util::TryStreamExt;
+use reqwest::{Body, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{field, Instrument, Span};
+
+use crate::{costs::Accounting, Session, SessionError};
+
+/// The upper bounds of the buckets of a [`LatencyHistogram`], in milliseconds
+const LATENCY_BOUNDS_MS: [u64; 12] =
+    [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000, 30_000, 60_000];
+
+/// A histogram of how long requests took
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LatencyHistogram {
+    /// The upper bound of each bucket
+    pub bounds: Vec<Duration>,
+    /// The number of requests in each bucket, with one extra bucket at the end
+    /// for requests slower than every bound
+    pub counts: Vec<u64>,
+    /// The number of requests recorded
+    pub count: u64,
+    /// The combined time of every request recorded
+    pub total: Duration,
+    /// The slowest request recorded
+    pub max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            bounds: LATENCY_BOUNDS_MS
+                .iter()
+                .map(|bound| Duration::from_millis(*bound))
+                .collect(),
+            counts: vec![0; LATENCY_BOUNDS_MS.len() + 1],
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Record how long a request took
+    fn record(&mut self, latency: Duration) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(self.bounds.len());
+        if let Some(count) = self.counts.get_mut(bucket) {
+            *count += 1;
+        }
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// The average time a request took
+    #[must_use]
+    pub fn mean(&self) -> Option<Duration> {
+        let count =
+            u32::try_from(self.count).ok().filter(|count| *count > 0)?;
+        Some(self.total / count)
+    }
+
+    /// An upper bound on the time taken by a fraction `q` of requests
+    ///
+    /// The answer is the bound of the bucket the quantile falls in, or the
+    /// slowest request if it falls past every bound.
+    #[must_use]
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+            if seen as f64 >= q * self.count as f64 {
+                return Some(
+                    self.bounds.get(index).copied().unwrap_or(self.max),
+                );
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// What happened to the requests sent to one endpoint
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointMetrics {
+    /// The number of requests sent
+    pub requests: u64,
+    /// The number of requests that failed or got an error response
+    pub failures: u64,
+    /// The number of requests that were retries of an earlier one
+    pub retries: u64,
+    /// The bytes sent in request bodies
+    pub bytes_sent: u64,
+    /// The bytes received in response bodies
+    pub bytes_received: u64,
+    /// The number of responses with each HTTP status
+    pub status_codes: BTreeMap<u16, u64>,
+    /// How long the requests took to get a response
+    pub latency: LatencyHistogram,
+}
+
+/// The metrics of a session at one point in time
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsSnapshot {
+    /// The metrics of each endpoint that has been called, by name
+    pub endpoints: BTreeMap<String, EndpointMetrics>,
+    /// The number of times the session re-authorized after its token was
+    /// rejected
+    pub reauthorizations: u64,
+}
+
+impl MetricsSnapshot {
+    /// The number of requests sent to every endpoint
+    #[must_use]
+    pub fn requests(&self) -> u64 {
+        self.endpoints.values().map(|endpoint| endpoint.requests).sum()
+    }
+
+    /// The bytes sent to every endpoint
+    #[must_use]
+    pub fn bytes_sent(&self) -> u64 {
+        self.endpoints.values().map(|endpoint| endpoint.bytes_sent).sum()
+    }
+
+    /// The bytes received from every endpoint
+    #[must_use]
+    pub fn bytes_received(&self) -> u64 {
+        self.endpoints.values().map(|endpoint| endpoint.bytes_received).sum()
+    }
+}
+
+/// The metrics of a session as they are collected
+///
+/// Clones share the same counters.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metrics {
+    /// The counters so far
+    state: Arc<Mutex<MetricsSnapshot>>,
+    /// What the session has used that Backblaze bills for
+    accounting: Arc<Accounting>,
+}
+
+impl Metrics {
+    /// Lock the counters
+    fn state(&self) -> std::sync::MutexGuard<'_, MetricsSnapshot> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The billable usage of the session
+    pub(crate) fn accounting(&self) -> &Accounting {
+        &self.accounting
+    }
+
+    /// Send a request to an endpoint, tracing and counting it
+    ///
+    /// Latency is measured until the response headers arrive. The bytes
+    /// received are counted as the body of the response is read, so they
+    /// include bodies sent without a `Content-Length`. Requests that would
+    /// exceed the budget of the session aren't sent, and downloads whose
+    /// advertised length would exceed it are dropped before their body is
+    /// read.
+    ///
+    /// `permit` is the request slot the request was sent in, if the session
+    /// has capped requests. It is held until the body of the response has
+    /// been read or dropped.
+    pub(crate) async fn send(
+        &self,
+        endpoint: &str,
+        bytes_sent: u64,
+        request: RequestBuilder,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<Response, SessionError> {
+        self.accounting.admit(endpoint, bytes_sent)?;
+        let span = tracing::info_span!(
+            "b2_request",
+            endpoint,
+            bytes_sent,
+            status = field::Empty,
+            bytes_received = 0_u64,
+            latency_ms = field::Empty,
+        );
+        let started = Instant::now();
+        let result = request.send().instrument(span.clone()).await;
+        let latency = started.elapsed();
+        let latency_ms = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+        let status = result.as_ref().ok().map(Response::status);
+        span.record("latency_ms", latency_ms);
+        if let Some(status) = status {
+            span.record("status", status.as_u16());
+        }
+        let failed = !status.is_some_and(|status| status.is_success());
+        span.in_scope(|| {
+            if failed {
+                tracing::warn!(
+                    status = status.map(|status| status.as_u16()),
+                    "request failed"
+                );
+            } else {
+                tracing::debug!("request succeeded");
+            }
+        });
+        let mut state = self.state();
+        let metrics = state.endpoints.entry(endpoint.to_owned()).or_default();
+        metrics.requests += 1;
+        metrics.bytes_sent += bytes_sent;
+        metrics.latency.record(latency);
+        if failed {
+            metrics.failures += 1;
+        }
+        if let Some(status) = status {
+            *metrics.status_codes.entry(status.as_u16()).or_default() += 1;
+        }
+        drop(state);
+        let response = result?;
+        let billable = !failed;
+        if billable {
+            if let Some(length) = advertised_length(&response) {
+                self.accounting.check_download(endpoint, length)?;
+            }
+        }
+        Ok(self.counted(endpoint, billable, span, permit, response))
+    }
+
+    /// Hand back a response whose body counts its bytes as they are read
+    ///
+    /// The bytes are added to the endpoint and to the `b2_request` span, and
+    /// to the billed downloads if `billable`. The status and headers are those
+    /// of `response`, but its length is only known from `Content-Length`.
+    /// `permit` is released along with the body.
+    fn counted(
+        &self,
+        endpoint: &str,
+        billable: bool,
+        span: Span,
+        permit: Option<OwnedSemaphorePermit>,
+        response: Response,
+    ) -> Response {
+        let mut counted = http::Response::new(());
+        *counted.status_mut() = response.status();
+        *counted.version_mut() = response.version();
+        *counted.headers_mut() = response.headers().clone();
+        let metrics = self.clone();
+        let endpoint = endpoint.to_owned();
+        let mut total = 0;
+        let body = response.bytes_stream().inspect_ok(move |chunk| {
+            // Moved in so the request slot is released with the body
+            let _permit = &permit;
+            let bytes = u64::try_from(chunk.len()).unwrap_or(u64::MAX);
+            total += bytes;
+            span.record("bytes_received", total);
+            metrics.received(&endpoint, billable, bytes);
+        });
+        counted.map(|()| Body::wrap_stream(body)).into()
+    }
+
+    /// Count bytes read from the body of a response
+    fn received(&self, endpoint: &str, billable: bool, bytes: u64) {
+        self.state()
+            .endpoints
+            .entry(endpoint.to_owned())
+            .or_default()
+            .bytes_received += bytes;
+        if billable {
+            self.accounting.record_download(endpoint, bytes);
+        }
+    }
+
+    /// Count a retry of an operation, and record it on the current span
+    pub(crate) fn retry(&self, endpoint: &str, retries: u32) {
+        Span::current().record("retries", retries);
+        tracing::debug!(endpoint, retries, "retrying request");
+        self.state()
+            .endpoints
+            .entry(endpoint.to_owned())
+            .or_default()
+            .retries += 1;
+    }
+
+    /// Count a re-authorization
+    pub(crate) fn reauthorized(&self) {
+        tracing::debug!("re-authorized after the token was rejected");
+        self.state().reauthorizations += 1;
+    }
+}
+
+/// The length a response advertises in its `Content-Length`
+pub(crate) fn advertised_length(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// A span covering an operation and any retries of it
+pub(crate) fn operation_span(endpoint: &str, bucket: Option<&str>) -> Span {
+    let span = tracing::info_span!(
+        "b2_operation",
+        endpoint,
+        bucket = field::Empty,
+        retries = 0_u32,
+    );
+    if let Some(bucket) = bucket {
+        span.record("bucket", bucket);
+    }
+    span
+}
+
+/// The bucket a JSON request body targets, for tracing
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BucketFields {
+    /// The bucket of most requests that target one
+    bucket_id: Option<String>,
+    /// The bucket of requests that look a bucket up by name
+    bucket_name: Option<String>,
+    /// The bucket copies are made into
+    destination_bucket_id: Option<String>,
+}
+
+/// The bucket a JSON request body targets, if any
+pub(crate) fn bucket_of(body: &[u8]) -> Option<String> {
+    let fields = serde_json::from_slice::<BucketFields>(body).ok()?;
+    fields.bucket_id.or(fields.bucket_name).or(fields.destination_bucket_id)
+}
+
+impl Session {
+    /// A snapshot of the metrics of this session
+    ///
+    /// Every clone of a session shares the same metrics.
+    #[must_use]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.inner.metrics.state().clone()
+    }
+
+    /// Reset every metric of this session to zero
+    pub fn reset_metrics(&self) {
+        *self.inner.metrics.state() = MetricsSnapshot::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex, PoisonError},
+        time::Duration,
+    };
+
+    use b2fake::{FakeB2, Fault};
+    use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+
+    use crate::{metrics::LatencyHistogram, tests::bucket, UploadOptions};
+
+    /// Collects everything a subscriber writes
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Captured {
+        type Writer = Self;
+
+        fn make_writer(&self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut histogram = LatencyHistogram::default();
+        for millis in [5, 20, 40, 90, 90_000] {
+            histogram.record(Duration::from_millis(millis));
+        }
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_secs(90)));
+        assert_eq!(histogram.max, Duration::from_secs(90));
+        assert_eq!(histogram.mean(), Some(Duration::from_millis(18_031)));
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted_and_traced() {
+        let captured = Captured::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer(captured.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server, "metrics").await;
+        let session = bucket.session();
+        let key = server.master_credentials().key;
+        server.inject(Some("b2_upload_file"), Fault::ServiceUnavailable, 1);
+        bucket
+            .upload_file(
+                "a.txt",
+                b"0123456789".to_vec(),
+                &UploadOptions::default(),
+            )
+            .await
+            .expect("Upload should be retried");
+
+        let metrics = session.metrics();
+        let uploads = &metrics.endpoints["b2_upload_file"];
+        assert_eq!(uploads.requests, 2);
+        assert_eq!(uploads.failures, 1);
+        assert_eq!(uploads.retries, 1);
+        assert_eq!(uploads.bytes_sent, 20);
+        assert_eq!(uploads.status_codes.get(&503), Some(&1));
+        assert_eq!(uploads.status_codes.get(&200), Some(&1));
+        assert_eq!(uploads.latency.count, 2);
+        assert_eq!(metrics.endpoints["b2_authorize_account"].requests, 1);
+        assert!(metrics.bytes_received() > 0);
+
+        let output = String::from_utf8(
+            captured.0.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+        )
+        .expect("Output should be UTF-8");
+        assert!(output.contains("endpoint=\"b2_list_buckets\""));
+        assert!(output.contains("bucket=\"metrics\""));
+        assert!(output.contains("status=503"));
+        assert!(output.contains("retries=1"));
+        assert!(!output.contains(&key));
+        assert!(!output.contains(&session.authorization().token));
+
+        session.reset_metrics();
+        assert_eq!(session.metrics().requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn chunked_bodies_are_counted() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server, "metrics").await;
+        let session = bucket.session();
+        bucket
+            .upload_file(
+                "a.txt",
+                b"0123456789".to_vec(),
+                &UploadOptions::default(),
+            )
+            .await
+            .expect("Upload should succeed");
+        server.inject(Some("b2_download_file_by_name"), Fault::ChunkedBody, 1);
+        let download = bucket
+            .download_file("a.txt", None)
+            .await
+            .expect("Download should succeed");
+        assert_eq!(download.data.as_ref(), b"0123456789");
+        let metrics = session.metrics();
+        assert_eq!(
+            metrics.endpoints["b2_download_file_by_name"].bytes_received,
+            10
+        );
+        assert_eq!(session.usage().bytes_downloaded, 10);
+    }
+}
@@ -0,0 +1,340 @@
+//! Object operations shared by the native and S3-compatible APIs
+//!
+//! [`ObjectStore`] covers what tools that only think in objects need: putting,
+//! getting, listing and deleting objects by key. It is implemented by
+//! [`Bucket`], which uses the B2 Native API, and by [`S3Bucket`], which uses
+//! the S3-compatible API of B2 or of any other S3-compatible store.
+
+use std::{collections::BTreeMap, future::Future, ops::Range};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    api::{b2_hide_file, b2_list_file_names, OutgoingRequest},
+    file::timestamp,
+    Bucket, Download, FileAction, FileVersion, S3Bucket, SessionError,
+    UploadOptions,
+};
+
+/// The most names requested per page when listing objects
+const PAGE_SIZE: u32 = 1000;
+
+/// What a store knows about an object
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectInfo {
+    /// The key of the object, which B2 calls the file name
+    pub key: String,
+    /// The size of the whole object in bytes
+    pub size: u64,
+    /// The MIME type of the object, when the store reported it
+    ///
+    /// S3 listings don't include content types.
+    pub content_type: Option<String>,
+    /// When the object was uploaded, when the store reported it
+    pub last_modified: Option<DateTime<Utc>>,
+    /// The version of the object, which B2 calls the file ID
+    pub version_id: Option<String>,
+    /// The custom metadata uploaded with the object, which B2 calls file info
+    ///
+    /// S3 listings don't include metadata.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// An object, or a range of one, as downloaded from a store
+#[derive(Clone, Debug)]
+pub struct Object {
+    /// What the store knows about the whole object
+    pub info: ObjectInfo,
+    /// The downloaded bytes
+    pub data: Bytes,
+}
+
+/// Operations on the objects of a bucket, independent of the API used
+///
+/// Deleting an object hides it, the same way on both APIs: earlier versions
+/// are kept, and the bucket's lifecycle rules decide when they go away.
+pub trait ObjectStore {
+    /// Upload an object in a single request
+    ///
+    /// # Errors
+    ///
+    /// This function can return any error of the underlying API.
+    fn put(
+        &self,
+        key: &str,
+        data: Bytes,
+        options: &UploadOptions,
+    ) -> impl Future<Output = Result<ObjectInfo, SessionError>> + Send;
+
+    /// Upload an object in parts, as a B2 large file or an S3 multipart upload
+    ///
+    /// Every part but the last must be at least the store's minimum part
+    /// size. A single part is uploaded with [`ObjectStore::put`].
+    ///
+    /// # Errors
+    ///
+    /// This function can return any error of the underlying API.
+    fn put_parts(
+        &self,
+        key: &str,
+        parts: Vec<Bytes>,
+        options: &UploadOptions,
+    ) -> impl Future<Output = Result<ObjectInfo, SessionError>> + Send;
+
+    /// Download an object, or the bytes of it in `range`
+    ///
+    /// # Errors
+    ///
+    /// This function can return any error of the underlying API, including
+    /// when the object doesn't exist.
+    fn get(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> impl Future<Output = Result<Object, SessionError>> + Send;
+
+    /// Look up an object without downloading it
+    ///
+    /// Returns `None` if no visible object has the key.
+    ///
+    /// # Errors
+    ///
+    /// This function can return any error of the underlying API.
+    fn head(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<ObjectInfo>, SessionError>> + Send;
+
+    /// List every visible object whose key starts with `prefix`, in key order
+    ///
+    /// # Errors
+    ///
+    /// This function can return any error of the underlying API.
+    fn list(
+        &self,
+        prefix: &str,
+    ) -> impl Future<Output = Result<Vec<ObjectInfo>, SessionError>> + Send;
+
+    /// Delete an object, which succeeds even if it doesn't exist
+    ///
+    /// # Errors
+    ///
+    /// This function can return any error of the underlying API.
+    fn delete(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<(), SessionError>> + Send;
+}
+
+impl From<FileVersion> for ObjectInfo {
+    fn from(file: FileVersion) -> Self {
+        Self {
+            key: file.file_name,
+            size: file.content_length,
+            content_type: file.content_type,
+            last_modified: timestamp(file.upload_timestamp),
+            version_id: file.file_id,
+            metadata: file.file_info,
+        }
+    }
+}
+
+impl From<Download> for Object {
+    fn from(download: Download) -> Self {
+        Self {
+            info: ObjectInfo {
+                key: download.file_name,
+                size: download.total_length,
+                content_type: Some(download.content_type),
+                last_modified: timestamp(download.upload_timestamp),
+                version_id: Some(download.file_id),
+                metadata: download.file_info,
+            },
+            data: download.data,
+        }
+    }
+}
+
+impl Bucket {
+    /// A page of the visible files in this bucket
+    async fn list_page(
+        &self,
+        prefix: &str,
+        start_file_name: Option<String>,
+        max_file_count: u32,
+    ) -> Result<b2_list_file_names::Response, SessionError> {
+        self.session()
+            .send(b2_list_file_names::Request {
+                bucket_id: self.id().to_owned(),
+                start_file_name,
+                max_file_count: Some(max_file_count),
+                prefix: Some(prefix.to_owned()),
+                delimiter: None,
+            })
+            .await
+            .into_result()
+    }
+}
+
+impl ObjectStore for Bucket {
+    async fn put(
+        &self,
+        key: &str,
+        data: Bytes,
+        options: &UploadOptions,
+    ) -> Result<ObjectInfo, SessionError> {
+        self.upload_file(key, data, options).await.map(ObjectInfo::from)
+    }
+
+    async fn put_parts(
+        &self,
+        key: &str,
+        parts: Vec<Bytes>,
+        options: &UploadOptions,
+    ) -> Result<ObjectInfo, SessionError> {
+        if parts.len() < 2 {
+            let data = parts.into_iter().next().unwrap_or_default();
+            return self.put(key, data, options).await;
+        }
+        let file = self.start_large_file(key, options).await?;
+        file.set_total_length(
+            parts
+                .iter()
+                .map(|part| u64::try_from(part.len()).unwrap_or(0))
+                .sum(),
+        );
+        for (part_number, data) in (1..).zip(parts) {
+            if let Err(error) = file.upload_part(part_number, data).await {
+                // The upload already failed, and canceling is only tidying up
+                let _canceled = file.cancel().await;
+                return Err(error);
+            }
+        }
+        file.finish().await.map(ObjectInfo::from)
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Object, SessionError> {
+        self.download_file(key, range).await.map(Object::from)
+    }
+
+    async fn head(
+        &self,
+        key: &str,
+    ) -> Result<Option<ObjectInfo>, SessionError> {
+        let page = self.list_page(key, Some(key.to_owned()), 1).await?;
+        Ok(page
+            .files
+            .into_iter()
+            .find(|file| {
+                file.file_name == key && file.action == FileAction::Upload
+            })
+            .map(ObjectInfo::from))
+    }
+
+    async fn list(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ObjectInfo>, SessionError> {
+        let mut objects = Vec::new();
+        let mut start = None;
+        loop {
+            let page = self.list_page(prefix, start, PAGE_SIZE).await?;
+            objects.extend(
+                page.files
+                    .into_iter()
+                    .filter(|file| file.action == FileAction::Upload)
+                    .map(ObjectInfo::from),
+            );
+            start = page.next_file_name;
+            if start.is_none() {
+                return Ok(objects);
+            }
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), SessionError> {
+        let result = self
+            .session()
+            .send(b2_hide_file::Request {
+                bucket_id: self.id().to_owned(),
+                file_name: key.to_owned(),
+            })
+            .await
+            .into_result();
+        match result {
+            Ok(_) => Ok(()),
+            // B2 refuses to hide names that have no visible file
+            Err(SessionError::RequestRejected(error))
+                if error.status == 404 =>
+            {
+                Ok(())
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl ObjectStore for S3Bucket {
+    async fn put(
+        &self,
+        key: &str,
+        data: Bytes,
+        options: &UploadOptions,
+    ) -> Result<ObjectInfo, SessionError> {
+        self.put_object(key, data, options).await
+    }
+
+    async fn put_parts(
+        &self,
+        key: &str,
+        parts: Vec<Bytes>,
+        options: &UploadOptions,
+    ) -> Result<ObjectInfo, SessionError> {
+        if parts.len() < 2 {
+            let data = parts.into_iter().next().unwrap_or_default();
+            return self.put_object(key, data, options).await;
+        }
+        let upload = self.create_multipart_upload(key, options).await?;
+        for (part_number, data) in (1..).zip(parts) {
+            if let Err(error) = upload.upload_part(part_number, data).await {
+                // The upload already failed, and aborting is only tidying up
+                let _aborted = upload.abort().await;
+                return Err(error);
+            }
+        }
+        upload.complete().await
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Object, SessionError> {
+        self.get_object(key, range).await
+    }
+
+    async fn head(
+        &self,
+        key: &str,
+    ) -> Result<Option<ObjectInfo>, SessionError> {
+        self.head_object(key).await
+    }
+
+    async fn list(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<ObjectInfo>, SessionError> {
+        self.list_objects(prefix, None).await.map(|listing| listing.objects)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), SessionError> {
+        self.delete_object(key).await
+    }
+}
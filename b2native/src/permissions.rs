@@ -0,0 +1,434 @@
+//! Checking what a session's application key allows
+//!
+//! ``b2_authorize_account`` tells us the capabilities of the key and any bucket
+//! or file name prefix it is restricted to. Calls the key can't make are
+//! rejected locally instead of costing a round trip and a transaction.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{api::b2_authorize_account::Capability, Session, SessionError};
+
+impl Capability {
+    /// Every capability known to this library, in the order Backblaze lists
+    /// them
+    pub const ALL: [Self; 26] = [
+        Self::ListKeys,
+        Self::WriteKeys,
+        Self::DeleteKeys,
+        Self::ListAllBucketNames,
+        Self::ListBuckets,
+        Self::ReadBuckets,
+        Self::WriteBuckets,
+        Self::DeleteBuckets,
+        Self::ReadBucketEncryption,
+        Self::WriteBucketEncryption,
+        Self::ReadBucketRetentions,
+        Self::WriteBucketRetentions,
+        Self::ReadBucketNotifications,
+        Self::WriteBucketNotifications,
+        Self::ReadBucketReplications,
+        Self::WriteBucketReplications,
+        Self::ListFiles,
+        Self::ReadFiles,
+        Self::ShareFiles,
+        Self::WriteFiles,
+        Self::DeleteFiles,
+        Self::ReadFileLegalHolds,
+        Self::WriteFileLegalHolds,
+        Self::ReadFileRetentions,
+        Self::WriteFileRetentions,
+        Self::BypassGovernance,
+    ];
+
+    /// The name Backblaze uses for this capability
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ListKeys => "listKeys",
+            Self::WriteKeys => "writeKeys",
+            Self::DeleteKeys => "deleteKeys",
+            Self::ListAllBucketNames => "listAllBucketNames",
+            Self::ListBuckets => "listBuckets",
+            Self::ReadBuckets => "readBuckets",
+            Self::WriteBuckets => "writeBuckets",
+            Self::DeleteBuckets => "deleteBuckets",
+            Self::ReadBucketEncryption => "readBucketEncryption",
+            Self::WriteBucketEncryption => "writeBucketEncryption",
+            Self::ReadBucketRetentions => "readBucketRetentions",
+            Self::WriteBucketRetentions => "writeBucketRetentions",
+            Self::ReadBucketNotifications => "readBucketNotifications",
+            Self::WriteBucketNotifications => "writeBucketNotifications",
+            Self::ReadBucketReplications => "readBucketReplications",
+            Self::WriteBucketReplications => "writeBucketReplications",
+            Self::ListFiles => "listFiles",
+            Self::ReadFiles => "readFiles",
+            Self::ShareFiles => "shareFiles",
+            Self::WriteFiles => "writeFiles",
+            Self::DeleteFiles => "deleteFiles",
+            Self::ReadFileLegalHolds => "readFileLegalHolds",
+            Self::WriteFileLegalHolds => "writeFileLegalHolds",
+            Self::ReadFileRetentions => "readFileRetentions",
+            Self::WriteFileRetentions => "writeFileRetentions",
+            Self::BypassGovernance => "bypassGovernance",
+            Self::Other => "other",
+        }
+    }
+
+    /// A short, plain-English description of what this capability allows
+    #[must_use]
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::ListKeys => "list application keys",
+            Self::WriteKeys => "create application keys",
+            Self::DeleteKeys => "delete application keys",
+            Self::ListAllBucketNames => "list the names of all buckets",
+            Self::ListBuckets => "list buckets",
+            Self::ReadBuckets => "read bucket settings",
+            Self::WriteBuckets => "create and update buckets",
+            Self::DeleteBuckets => "delete buckets",
+            Self::ReadBucketEncryption => "read bucket encryption settings",
+            Self::WriteBucketEncryption => "change bucket encryption settings",
+            Self::ReadBucketRetentions => "read bucket Object Lock settings",
+            Self::WriteBucketRetentions => "change bucket Object Lock settings",
+            Self::ReadBucketNotifications => "read bucket notification rules",
+            Self::WriteBucketNotifications => {
+                "change bucket notification rules"
+            }
+            Self::ReadBucketReplications => "read bucket replication rules",
+            Self::WriteBucketReplications => "change bucket replication rules",
+            Self::ListFiles => "list files",
+            Self::ReadFiles => "download files",
+            Self::ShareFiles => "share files with download authorizations",
+            Self::WriteFiles => "upload, copy and hide files",
+            Self::DeleteFiles => "delete file versions",
+            Self::ReadFileLegalHolds => "read file legal holds",
+            Self::WriteFileLegalHolds => "change file legal holds",
+            Self::ReadFileRetentions => "read file retention settings",
+            Self::WriteFileRetentions => "change file retention settings",
+            Self::BypassGovernance => "bypass governance mode retention",
+            Self::Other => "a capability unknown to this library",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// The bucket a call targets
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum BucketRef<'a> {
+    /// A bucket identified by its ID
+    Id(&'a str),
+    /// A bucket identified by its name
+    Name(&'a str),
+}
+
+/// A summary of what a session's application key can do
+///
+/// Its [`Display`](fmt::Display) implementation renders a plain-text report
+/// meant for people, and it serializes to JSON for tools.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionReport {
+    /// The identifier of the account the key belongs to
+    pub account_id: String,
+    /// The ID of the bucket the key is restricted to, if any
+    pub bucket_id: Option<String>,
+    /// The name of the bucket the key is restricted to
+    ///
+    /// This is `None` when the key isn't restricted to a bucket, or when the
+    /// bucket it is restricted to no longer exists.
+    pub bucket_name: Option<String>,
+    /// The file name prefix the key is restricted to, if any
+    pub name_prefix: Option<String>,
+    /// The capabilities the key has
+    pub granted: Vec<Capability>,
+    /// The capabilities the key doesn't have
+    pub missing: Vec<Capability>,
+    /// Whether the key has capabilities this library doesn't know about
+    pub has_unknown_capabilities: bool,
+}
+
+impl fmt::Display for PermissionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Account: {}", self.account_id)?;
+        match (&self.bucket_id, &self.bucket_name) {
+            (None, _) => writeln!(f, "Buckets: all buckets")?,
+            (Some(id), Some(name)) => {
+                writeln!(f, "Buckets: only {name} ({id})")?;
+            }
+            (Some(id), None) => {
+                writeln!(f, "Buckets: only {id}, which no longer exists")?;
+            }
+        }
+        match &self.name_prefix {
+            None => writeln!(f, "Files: all files")?,
+            Some(prefix) => {
+                writeln!(f, "Files: only names starting with {prefix:?}")?;
+            }
+        }
+        writeln!(f, "Can:")?;
+        for capability in &self.granted {
+            writeln!(f, "  {capability:<26} {}", capability.description())?;
+        }
+        if self.has_unknown_capabilities {
+            writeln!(f, "  (and capabilities unknown to this version)")?;
+        }
+        writeln!(f, "Cannot:")?;
+        for capability in &self.missing {
+            writeln!(f, "  {capability:<26} {}", capability.description())?;
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    /// Whether this session's application key has a capability
+    #[must_use]
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.authorization().storage_api_info.capabilities.contains(&capability)
+    }
+
+    /// Describe what this session's application key can do
+    #[must_use]
+    pub fn explain_permissions(&self) -> PermissionReport {
+        let authorization = self.authorization();
+        let info = &authorization.storage_api_info;
+        let (granted, missing) = Capability::ALL
+            .into_iter()
+            .partition(|capability| info.capabilities.contains(capability));
+        PermissionReport {
+            account_id: authorization.account_id.clone(),
+            bucket_id: info.bucket_id.clone(),
+            bucket_name: info.bucket_name.clone(),
+            name_prefix: info.name_prefix.clone(),
+            granted,
+            missing,
+            has_unknown_capabilities: info
+                .capabilities
+                .contains(&Capability::Other),
+        }
+    }
+
+    /// Check that this session's application key allows a call before making
+    /// it
+    ///
+    /// `bucket` is the bucket the call targets, if any, and `name` is the file
+    /// name or file name prefix it targets, if any. A key restricted to a
+    /// bucket can only make calls that target that bucket, and a key
+    /// restricted to a prefix can only make calls that target names starting
+    /// with that prefix. Calls that don't target names, such as
+    /// ``b2_get_upload_url``, pass `None` as the name.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub(crate) fn preflight(
+        &self,
+        capability: Capability,
+        bucket: Option<BucketRef<'_>>,
+        name: Option<&str>,
+    ) -> Result<(), SessionError> {
+        self.require(capability)?;
+        let authorization = self.authorization();
+        let info = &authorization.storage_api_info;
+        if let Some(allowed) = &info.bucket_id {
+            let permitted = match bucket {
+                Some(BucketRef::Id(id)) => id == allowed,
+                Some(BucketRef::Name(name)) => {
+                    info.bucket_name.as_deref() == Some(name)
+                }
+                None => false,
+            };
+            if !permitted {
+                return Err(SessionError::OutsideBucketRestriction {
+                    bucket_id: allowed.clone(),
+                    bucket_name: info.bucket_name.clone(),
+                });
+            }
+        }
+        if let (Some(prefix), Some(name)) = (&info.name_prefix, name) {
+            if !name.starts_with(prefix.as_str()) {
+                return Err(SessionError::OutsideNamePrefixRestriction {
+                    name_prefix: prefix.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that this session's application key has a capability
+    ///
+    /// This is the check [`Session::preflight`] starts with, for calls whose
+    /// bucket isn't known up front, such as calls on a file ID.
+    ///
+    /// # Errors
+    ///
+    /// This function can return `SessionError::MissingCapability`.
+    pub(crate) fn require(
+        &self,
+        capability: Capability,
+    ) -> Result<(), SessionError> {
+        if self.has_capability(capability) {
+            Ok(())
+        } else {
+            Err(SessionError::MissingCapability {
+                capability,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use b2fake::{FakeB2, KeySpec};
+
+    use crate::{
+        api::{b2_list_buckets, ApiResponse, ApiResult, OutgoingRequest},
+        permissions::BucketRef,
+        tests::authorize,
+        Capability, Session, SessionError,
+    };
+
+    fn list_buckets(
+        session: &Session,
+        bucket_name: Option<&str>,
+    ) -> b2_list_buckets::Request {
+        b2_list_buckets::Request {
+            account_id: session.account_id(),
+            bucket_id: None,
+            bucket_name: bucket_name.map(str::to_owned),
+            bucket_types: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_capability_is_rejected_locally() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let session = authorize(
+            &server,
+            server.add_key(KeySpec {
+                capabilities: vec!["readFiles".to_owned()],
+                ..KeySpec::default()
+            }),
+        )
+        .await;
+        let request = list_buckets(&session, None);
+        let result = session.send(request).await;
+        assert!(matches!(
+            result,
+            ApiResult::Failure(SessionError::MissingCapability {
+                capability: Capability::ListBuckets
+            })
+        ));
+        assert_eq!(server.calls("b2_list_buckets"), 0);
+    }
+
+    #[tokio::test]
+    async fn bucket_restriction_is_enforced() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket_id = server.create_bucket("allowed-bucket", "allPrivate");
+        assert_ne!(
+            server.create_bucket("other-bucket", "allPrivate"),
+            bucket_id
+        );
+        let session = authorize(
+            &server,
+            server.add_key(KeySpec {
+                capabilities: vec!["listBuckets".to_owned()],
+                bucket_id: Some(bucket_id.clone()),
+                ..KeySpec::default()
+            }),
+        )
+        .await;
+        let request = list_buckets(&session, Some("other-bucket"));
+        assert!(matches!(
+            session.send(request).await,
+            ApiResult::Failure(SessionError::OutsideBucketRestriction { .. })
+        ));
+        assert_eq!(server.calls("b2_list_buckets"), 0);
+        let request = list_buckets(&session, Some("allowed-bucket"));
+        assert!(matches!(
+            session.send(request).await,
+            ApiResult::Response(ApiResponse::Ok(_))
+        ));
+        assert!(session
+            .preflight(
+                Capability::ListBuckets,
+                Some(BucketRef::Id(&bucket_id)),
+                None
+            )
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn name_prefix_restriction_is_enforced() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket_id = server.create_bucket("photos", "allPrivate");
+        let session = authorize(
+            &server,
+            server.add_key(KeySpec {
+                capabilities: vec!["readFiles".to_owned()],
+                bucket_id: Some(bucket_id.clone()),
+                name_prefix: Some("2024/".to_owned()),
+                ..KeySpec::default()
+            }),
+        )
+        .await;
+        let bucket = Some(BucketRef::Id(&bucket_id));
+        assert!(session
+            .preflight(Capability::ReadFiles, bucket, Some("2024/a.jpg"))
+            .is_ok());
+        assert!(matches!(
+            session.preflight(
+                Capability::ReadFiles,
+                bucket,
+                Some("2023/a.jpg")
+            ),
+            Err(SessionError::OutsideNamePrefixRestriction { .. })
+        ));
+        assert!(matches!(
+            session.preflight(Capability::ReadFiles, bucket, Some("")),
+            Err(SessionError::OutsideNamePrefixRestriction { .. })
+        ));
+        assert!(session.preflight(Capability::ReadFiles, bucket, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn explain_permissions_reports_key() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket_id = server.create_bucket("photos", "allPrivate");
+        let session = authorize(
+            &server,
+            server.add_key(KeySpec {
+                capabilities: vec![
+                    "listFiles".to_owned(),
+                    "readFiles".to_owned(),
+                ],
+                bucket_id: Some(bucket_id.clone()),
+                name_prefix: Some("2024/".to_owned()),
+                ..KeySpec::default()
+            }),
+        )
+        .await;
+        let report = session.explain_permissions();
+        assert_eq!(
+            report.granted,
+            [Capability::ListFiles, Capability::ReadFiles]
+        );
+        assert_eq!(report.missing.len(), Capability::ALL.len() - 2);
+        assert_eq!(report.bucket_name.as_deref(), Some("photos"));
+        let text = report.to_string();
+        assert!(text.contains(&format!("Buckets: only photos ({bucket_id})")));
+        assert!(text.contains("Files: only names starting with \"2024/\""));
+        assert!(text.contains("readFiles"));
+    }
+}
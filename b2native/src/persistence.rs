@@ -0,0 +1,502 @@
+//! Saving an authorized session and picking it up again later
+//!
+//! Authorizing costs a round trip and counts against the account's class C
+//! transactions, so applications that start often can export their session,
+//! store it, and restore it on the next start. The application key itself is
+//! never written out; only the key ID is kept so a restored session can tell
+//! whether it belongs to the credentials it is restored with.
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api::b2_authorize_account::StorageApi, config::CONFIG, metrics::Metrics,
+    unix_now, Authorization, Credentials, Session, SessionError,
+};
+
+/// The version of the persisted session format written by this library
+const FORMAT_VERSION: u32 = 1;
+
+/// How long an authorization token is valid for, in seconds
+const TOKEN_LIFETIME: u64 = 24 * 60 * 60;
+
+/// How long before its expiry a persisted token is no longer reused, in
+/// seconds
+///
+/// This leaves room for a restored session to do some work before the token
+/// runs out from under it.
+const EXPIRY_MARGIN: u64 = 15 * 60;
+
+/// The tag byte of a blob containing a plain persisted session
+const PLAIN_TAG: u8 = 0;
+
+/// The tag byte of a blob containing an encrypted persisted session
+const ENCRYPTED_TAG: u8 = 1;
+
+/// The length of an ``XChaCha20Poly1305`` nonce
+const NONCE_LENGTH: usize = 24;
+
+/// A snapshot of an authorized [`Session`] that can be stored and restored
+///
+/// Obtain one with [`Session::export`], store it with
+/// [`PersistedSession::to_blob`], and turn it back into a session with
+/// [`Session::restore`]. The application key is never part of the snapshot,
+/// but the authorization token is, so stored blobs should be treated as
+/// secrets for as long as the token is valid.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersistedSession {
+    /// The version of the format the session was persisted with
+    version: u32,
+    /// The identifier of the account the session is authorized for
+    account_id: String,
+    /// The authorization token of the session
+    authorization_token: String,
+    /// Information about using the storage API returned by
+    /// ``b2_authorize_account``
+    storage_api: StorageApi,
+    /// When the authorization token was issued, in seconds since the Unix
+    /// epoch
+    authorized_at: u64,
+    /// The URL of the ``b2_authorize_account`` endpoint the session was
+    /// authorized with
+    authorize_endpoint: String,
+    /// The ID of the application key the session was authorized with
+    application_key_id: String,
+    /// When the application key expires, in milliseconds since the Unix
+    /// epoch, if it ever does
+    #[serde(default)]
+    key_expires_at: Option<u64>,
+}
+
+impl std::fmt::Debug for PersistedSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PersistedSession")
+            .field("version", &self.version)
+            .field("account_id", &self.account_id)
+            .field("authorization_token", &"<redacted>")
+            .field("authorized_at", &self.authorized_at)
+            .field("authorize_endpoint", &self.authorize_endpoint)
+            .field("application_key_id", &self.application_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PersistedSession {
+    /// The identifier of the account the session is authorized for
+    #[must_use]
+    pub fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    /// Whether the authorization token is still worth reusing
+    ///
+    /// Tokens are valid for 24 hours. A token that is within a few minutes of
+    /// expiring, or that claims to have been issued in the future, is treated
+    /// as expired.
+    #[must_use]
+    pub fn is_fresh(&self) -> bool {
+        let now = unix_now();
+        self.authorized_at <= now
+            && now - self.authorized_at < TOKEN_LIFETIME - EXPIRY_MARGIN
+    }
+
+    /// Whether the session was authorized with `endpoint`, and its API URLs
+    /// belong to the service behind it
+    ///
+    /// Blobs are stored outside this library, so a token is only reused if
+    /// the blob can't point it at a server of its own choosing.
+    fn is_from(&self, endpoint: &str) -> bool {
+        let Ok(endpoint_url) = Url::parse(endpoint) else {
+            return false;
+        };
+        self.authorize_endpoint == endpoint
+            && [
+                &self.storage_api.api_url,
+                &self.storage_api.download_url,
+                &self.storage_api.s3_api_url,
+            ]
+            .into_iter()
+            .all(|url| same_service(&endpoint_url, url))
+    }
+
+    /// Serialize the session into a blob suitable for storage
+    ///
+    /// When `key` is given, the blob is encrypted and authenticated with
+    /// ``XChaCha20Poly1305`` under that key. Otherwise the blob contains the
+    /// session as plain JSON, including its authorization token.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the session cannot be serialized, which cannot
+    /// happen for the types it contains.
+    #[must_use]
+    pub fn to_blob(&self, key: Option<&[u8; 32]>) -> Vec<u8> {
+        let json = serde_json::to_vec(self)
+            .expect("Persisted sessions always serialize");
+        match key {
+            None => {
+                let mut blob = Vec::with_capacity(json.len() + 1);
+                blob.push(PLAIN_TAG);
+                blob.extend_from_slice(&json);
+                blob
+            }
+            Some(key) => {
+                let cipher = XChaCha20Poly1305::new(key.into());
+                let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let ciphertext = cipher
+                    .encrypt(&nonce, json.as_slice())
+                    .expect("Encrypting an in-memory buffer cannot fail");
+                let mut blob =
+                    Vec::with_capacity(1 + NONCE_LENGTH + ciphertext.len());
+                blob.push(ENCRYPTED_TAG);
+                blob.extend_from_slice(&nonce);
+                blob.extend_from_slice(&ciphertext);
+                blob
+            }
+        }
+    }
+
+    /// Deserialize a session from a blob written by
+    /// [`PersistedSession::to_blob`]
+    ///
+    /// The blob must have been written with the same key, or without one if
+    /// `key` is `None`: a plain blob isn't accepted in place of an encrypted
+    /// one.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::MalformedPersistedSession`
+    /// - `SessionError::PersistedSessionDecryptionFailed`
+    pub fn from_blob(
+        blob: &[u8],
+        key: Option<&[u8; 32]>,
+    ) -> Result<Self, SessionError> {
+        let json = match (blob.split_first(), key) {
+            (Some((&PLAIN_TAG, json)), None) => json.to_vec(),
+            // A plain blob in place of an encrypted one could hold any
+            // session whoever replaced it chose
+            (Some((&PLAIN_TAG, _)), Some(_))
+            | (Some((&ENCRYPTED_TAG, _)), None) => {
+                return Err(SessionError::PersistedSessionDecryptionFailed)
+            }
+            (Some((&ENCRYPTED_TAG, sealed)), Some(key)) => {
+                if sealed.len() < NONCE_LENGTH {
+                    return Err(SessionError::MalformedPersistedSession);
+                }
+                let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+                XChaCha20Poly1305::new(key.into())
+                    .decrypt(XNonce::from_slice(nonce), ciphertext)
+                    .map_err(|_error| {
+                        SessionError::PersistedSessionDecryptionFailed
+                    })?
+            }
+            _ => return Err(SessionError::MalformedPersistedSession),
+        };
+        let persisted: Self = serde_json::from_slice(&json)
+            .map_err(|_error| SessionError::MalformedPersistedSession)?;
+        if persisted.version == FORMAT_VERSION {
+            Ok(persisted)
+        } else {
+            Err(SessionError::MalformedPersistedSession)
+        }
+    }
+}
+
+/// Whether `url` belongs to the same service as the authorization `endpoint`
+///
+/// B2 hands out API URLs on hosts next to the one accounts are authorized
+/// with, such as `api005.backblazeb2.com` for `api.backblazeb2.com`. A URL
+/// matches if it has the scheme and port of `endpoint`, and either its host or
+/// a host under the same parent domain.
+fn same_service(endpoint: &Url, url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    if url.scheme() != endpoint.scheme()
+        || url.port_or_known_default() != endpoint.port_or_known_default()
+    {
+        return false;
+    }
+    if url.host_str() == endpoint.host_str() {
+        return true;
+    }
+    let parent = endpoint
+        .domain()
+        .and_then(|domain| domain.split_once('.'))
+        .map(|(_, parent)| parent)
+        .filter(|parent| parent.contains('.'));
+    match (parent, url.domain()) {
+        (Some(parent), Some(domain)) => domain
+            .strip_suffix(parent)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        _ => false,
+    }
+}
+
+impl Session {
+    /// Export this session so it can be restored later
+    ///
+    /// See [`PersistedSession`] for what is and isn't exported.
+    #[must_use]
+    pub fn export(&self) -> PersistedSession {
+        let authorization = self.authorization();
+        PersistedSession {
+            version: FORMAT_VERSION,
+            account_id: authorization.account_id.clone(),
+            authorization_token: authorization.token.clone(),
+            storage_api: authorization.storage_api_info.clone(),
+            authorized_at: authorization.authorized_at,
+            authorize_endpoint: self.credentials().endpoint.clone(),
+            application_key_id: self.credentials().application_key_id.clone(),
+            key_expires_at: authorization.key_expires_at,
+        }
+    }
+
+    /// Restore a session exported with [`Session::export`]
+    ///
+    /// If the persisted token belongs to `application_key_id` and is still
+    /// fresh, it is reused without contacting the Backblaze API. Otherwise a
+    /// new session is authorized with the given credentials. Either way, the
+    /// session re-authorizes on its own if the token is rejected later.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Session::try_new`] when
+    /// the persisted token cannot be reused.
+    pub async fn restore<S: Into<String>>(
+        persisted: PersistedSession,
+        application_key_id: S,
+        application_key: S,
+    ) -> Result<Self, SessionError> {
+        Self::restore_with_endpoint(
+            persisted,
+            &CONFIG.authorize_account_endpoint,
+            application_key_id,
+            application_key,
+        )
+        .await
+    }
+
+    /// Restore a session exported with [`Session::export`] using a specific
+    /// ``b2_authorize_account`` URL
+    ///
+    /// This behaves like [`Session::restore`], but authorizes against
+    /// `endpoint` instead of the configured endpoint. The endpoint is never
+    /// taken from the persisted session: its token is only reused if it was
+    /// issued by `endpoint` for API URLs of the same service, so a tampered
+    /// blob can't send the application key or the token anywhere else.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as [`Session::try_new`] when
+    /// the persisted token cannot be reused.
+    pub async fn restore_with_endpoint<S: Into<String>>(
+        persisted: PersistedSession,
+        endpoint: &str,
+        application_key_id: S,
+        application_key: S,
+    ) -> Result<Self, SessionError> {
+        let application_key_id = application_key_id.into();
+        let reusable = persisted.is_fresh()
+            && persisted.application_key_id == application_key_id
+            && persisted.is_from(endpoint);
+        let credentials = Credentials {
+            endpoint: endpoint.to_owned(),
+            application_key_id,
+            application_key: application_key.into(),
+        };
+        let http_client = Client::new();
+        if reusable {
+            Ok(Self::from_parts(
+                http_client,
+                credentials,
+                Authorization {
+                    token: persisted.authorization_token,
+                    account_id: persisted.account_id,
+                    authorized_at: persisted.authorized_at,
+                    storage_api_info: persisted.storage_api,
+                    key_expires_at: persisted.key_expires_at,
+                },
+                Metrics::default(),
+            ))
+        } else {
+            Self::authorize(http_client, credentials).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use b2fake::{Credentials, FakeB2};
+    use reqwest::Url;
+
+    use crate::{
+        api::{b2_list_buckets, ApiResponse, ApiResult, OutgoingRequest},
+        persistence::same_service,
+        tests::session,
+        PersistedSession, Session, SessionError,
+    };
+
+    /// Restore a session against a fake server
+    async fn restore(
+        server: &FakeB2,
+        persisted: PersistedSession,
+        credentials: Credentials,
+    ) -> Session {
+        Session::restore_with_endpoint(
+            persisted,
+            &server.authorize_url(),
+            credentials.key_id,
+            credentials.key,
+        )
+        .await
+        .expect("Session should restore")
+    }
+
+    /// List the buckets of a session, which uses its token
+    async fn list_buckets(session: &Session) -> bool {
+        let result = session
+            .send(b2_list_buckets::Request {
+                account_id: session.account_id(),
+                bucket_id: None,
+                bucket_name: None,
+                bucket_types: None,
+            })
+            .await;
+        matches!(result, ApiResult::Response(ApiResponse::Ok(_)))
+    }
+
+    #[tokio::test]
+    async fn restore_reuses_fresh_token() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let blob = session(&server).await.export().to_blob(None);
+        let authorizations = server.calls("b2_authorize_account");
+        let credentials = server.master_credentials();
+        let persisted = PersistedSession::from_blob(&blob, None)
+            .expect("Plain blob should decode");
+        let restored = restore(&server, persisted, credentials).await;
+        assert_eq!(server.calls("b2_authorize_account"), authorizations);
+        assert_eq!(restored.account_id(), server.account_id());
+    }
+
+    #[tokio::test]
+    async fn restore_with_other_key_authorizes() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let persisted = session(&server).await.export();
+        let other = server.add_key(b2fake::KeySpec::default());
+        let authorizations = server.calls("b2_authorize_account");
+        let restored = restore(&server, persisted, other).await;
+        assert_eq!(server.calls("b2_authorize_account"), authorizations + 1);
+        assert_eq!(restored.account_id(), server.account_id());
+    }
+
+    #[tokio::test]
+    async fn encrypted_blob_roundtrip() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let persisted = session(&server).await.export();
+        let key = [7; 32];
+        let blob = persisted.to_blob(Some(&key));
+        assert!(!blob
+            .windows(persisted.authorization_token.len())
+            .any(|window| window == persisted.authorization_token.as_bytes()));
+        let decoded = PersistedSession::from_blob(&blob, Some(&key))
+            .expect("Encrypted blob should decrypt");
+        assert_eq!(decoded.authorization_token, persisted.authorization_token);
+        assert!(matches!(
+            PersistedSession::from_blob(&blob, Some(&[8; 32])),
+            Err(SessionError::PersistedSessionDecryptionFailed)
+        ));
+        assert!(matches!(
+            PersistedSession::from_blob(&blob, None),
+            Err(SessionError::PersistedSessionDecryptionFailed)
+        ));
+        assert!(matches!(
+            PersistedSession::from_blob(&[9, 2, 3], None),
+            Err(SessionError::MalformedPersistedSession)
+        ));
+    }
+
+    #[tokio::test]
+    async fn plain_blob_is_rejected_when_a_key_is_given() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let blob = session(&server).await.export().to_blob(None);
+        assert!(matches!(
+            PersistedSession::from_blob(&blob, Some(&[7; 32])),
+            Err(SessionError::PersistedSessionDecryptionFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejected_token_reauthorizes() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let persisted = session(&server).await.export();
+        let credentials = server.master_credentials();
+        let restored = restore(&server, persisted, credentials).await;
+        server.expire_tokens();
+        let authorizations = server.calls("b2_authorize_account");
+        assert!(list_buckets(&restored).await);
+        assert_eq!(server.calls("b2_authorize_account"), authorizations + 1);
+    }
+
+    #[test]
+    fn api_urls_must_belong_to_the_endpoint() {
+        let endpoint = Url::parse(
+            "https://api.backblazeb2.com/b2api/v3/b2_authorize_account",
+        )
+        .expect("URL should parse");
+        for url in [
+            "https://api005.backblazeb2.com",
+            "https://f005.backblazeb2.com",
+            "https://s3.us-west-004.backblazeb2.com",
+        ] {
+            assert!(same_service(&endpoint, url), "{url}");
+        }
+        for url in [
+            "http://api005.backblazeb2.com",
+            "https://api005.backblazeb2.com:8443",
+            "https://backblazeb2.com.example",
+            "https://evilbackblazeb2.com",
+            "https://example.com",
+            "not a URL",
+        ] {
+            assert!(!same_service(&endpoint, url), "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tampered_blob_is_not_trusted() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let elsewhere =
+            FakeB2::start().await.expect("Fake server should start");
+        let credentials = server.master_credentials();
+        let exported = session(&server).await.export();
+        let authorizations = server.calls("b2_authorize_account");
+
+        let mut persisted = exported;
+        persisted.authorize_endpoint = elsewhere.authorize_url();
+        let blob = persisted.to_blob(None);
+        let persisted = PersistedSession::from_blob(&blob, None)
+            .expect("Plain blob should decode");
+        let restored = restore(&server, persisted, credentials.clone()).await;
+        assert_eq!(server.calls("b2_authorize_account"), authorizations + 1);
+        assert_eq!(restored.credentials().endpoint, server.authorize_url());
+
+        let mut persisted = restored.export();
+        persisted.storage_api.api_url = persisted
+            .storage_api
+            .api_url
+            .replace(server.base_url(), elsewhere.base_url());
+        let restored = restore(&server, persisted, credentials).await;
+        assert_eq!(server.calls("b2_authorize_account"), authorizations + 2);
+        server.expire_tokens();
+        assert!(list_buckets(&restored).await);
+        assert_eq!(elsewhere.calls("b2_authorize_account"), 0);
+        assert_eq!(elsewhere.calls("b2_list_buckets"), 0);
+    }
+}
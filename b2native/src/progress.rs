@@ -0,0 +1,854 @@
+//! Reporting the progress of transfers
+//!
+//! A session reports what its transfers are doing to a [`ProgressObserver`]
+//! as a stream of [`ProgressEvent`]s. Observers can print or forward the
+//! events themselves, or use a [`ProgressTracker`] to turn them into progress
+//! per file and per batch, with transfer rates and estimated times remaining.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{costs::to_f64, Clock, Session, SessionError, SystemClock};
+
+/// How far back transfer rates are averaged over
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// The identifier of the next transfer to start
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies one transfer in the events of an observer
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize,
+)]
+pub struct TransferId(u64);
+
+/// What kind of operation a transfer is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferKind {
+    /// A file uploaded in a single request
+    Upload,
+    /// A large file uploaded or copied in parts
+    LargeFileUpload,
+    /// A file copied within B2 in a single request
+    Copy,
+    /// A file, or a range of one, being downloaded
+    Download,
+}
+
+/// What stage a transfer is in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    /// Getting ready to move data, such as starting a large file
+    Preparing,
+    /// Moving data
+    Transferring,
+    /// Waiting for B2 to assemble a large file
+    Finishing,
+    /// Finished successfully
+    Complete,
+    /// Stopped by an error
+    Failed,
+    /// Abandoned on purpose
+    Canceled,
+}
+
+impl Phase {
+    /// Whether the transfer has stopped
+    #[must_use]
+    pub fn is_done(self) -> bool {
+        matches!(self, Self::Complete | Self::Failed | Self::Canceled)
+    }
+}
+
+/// Something that happened to a transfer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "event")]
+pub enum ProgressEvent {
+    /// A transfer started
+    Started {
+        /// The transfer that started
+        transfer: TransferId,
+        /// What kind of operation the transfer is
+        kind: TransferKind,
+        /// The file being transferred, or its ID when the name isn't known
+        file_name: String,
+        /// The number of bytes to transfer, if known yet
+        total_bytes: Option<u64>,
+    },
+    /// The number of bytes to transfer became known
+    Length {
+        /// The transfer the length belongs to
+        transfer: TransferId,
+        /// The number of bytes to transfer
+        total_bytes: u64,
+    },
+    /// A transfer moved to another phase
+    Phase {
+        /// The transfer that changed phase
+        transfer: TransferId,
+        /// The phase it is in now
+        phase: Phase,
+    },
+    /// Bytes were sent to B2
+    BytesSent {
+        /// The transfer the bytes belong to
+        transfer: TransferId,
+        /// The number of bytes sent since the last event
+        bytes: u64,
+    },
+    /// Bytes were received from B2
+    BytesReceived {
+        /// The transfer the bytes belong to
+        transfer: TransferId,
+        /// The number of bytes received since the last event
+        bytes: u64,
+    },
+    /// Bytes were copied by B2 without passing through this library
+    BytesCopied {
+        /// The transfer the bytes belong to
+        transfer: TransferId,
+        /// The number of bytes copied
+        bytes: u64,
+    },
+    /// A part of a large file was uploaded or copied
+    PartCompleted {
+        /// The transfer the part belongs to
+        transfer: TransferId,
+        /// The number of the part
+        part_number: u32,
+        /// The number of bytes in the part
+        bytes: u64,
+    },
+    /// A request failed and is being retried
+    Retry {
+        /// The transfer being retried
+        transfer: TransferId,
+        /// The number of the attempt that failed, starting at 1
+        attempt: u32,
+        /// Bytes reported by the failed attempt that will be sent again
+        discarded_bytes: u64,
+        /// Why the attempt failed
+        reason: String,
+    },
+}
+
+impl ProgressEvent {
+    /// The transfer the event belongs to
+    #[must_use]
+    pub fn transfer(&self) -> TransferId {
+        match self {
+            Self::Started {
+                transfer,
+                ..
+            }
+            | Self::Length {
+                transfer,
+                ..
+            }
+            | Self::Phase {
+                transfer,
+                ..
+            }
+            | Self::BytesSent {
+                transfer,
+                ..
+            }
+            | Self::BytesReceived {
+                transfer,
+                ..
+            }
+            | Self::BytesCopied {
+                transfer,
+                ..
+            }
+            | Self::PartCompleted {
+                transfer,
+                ..
+            }
+            | Self::Retry {
+                transfer,
+                ..
+            } => *transfer,
+        }
+    }
+}
+
+/// Receives the progress events of a session's transfers
+///
+/// Events are delivered from whichever task is running the transfer, so
+/// observers should return quickly and must not block.
+pub trait ProgressObserver: fmt::Debug + Send + Sync {
+    /// Handle one event
+    fn on_event(&self, event: &ProgressEvent);
+}
+
+/// The handle a running transfer reports its progress through
+///
+/// Clones report as the same transfer. Bytes are also counted per attempt, so
+/// that a retry can say how many bytes it is discarding; [`Transfer::scope`]
+/// starts a separate count for concurrent parts of the same transfer.
+#[derive(Clone, Debug)]
+pub(crate) struct Transfer {
+    /// Where events are sent, if anywhere
+    observer: Option<Arc<dyn ProgressObserver>>,
+    /// The identifier of the transfer
+    id: TransferId,
+    /// Bytes reported by the current attempt
+    attempt_bytes: Arc<AtomicU64>,
+}
+
+impl Transfer {
+    /// Send an event to the observer
+    fn emit(&self, event: &ProgressEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(event);
+        }
+    }
+
+    /// A handle for a transfer that reports its progress to nobody
+    pub(crate) fn unobserved() -> Self {
+        Self {
+            observer: None,
+            id: TransferId(NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed)),
+            attempt_bytes: Arc::default(),
+        }
+    }
+
+    /// A handle to the same transfer with its own count of attempt bytes
+    pub(crate) fn scope(&self) -> Self {
+        Self {
+            observer: self.observer.clone(),
+            id: self.id,
+            attempt_bytes: Arc::default(),
+        }
+    }
+
+    /// Report that the transfer moved to another phase
+    pub(crate) fn phase(&self, phase: Phase) {
+        self.emit(&ProgressEvent::Phase {
+            transfer: self.id,
+            phase,
+        });
+    }
+
+    /// Report the number of bytes to transfer
+    pub(crate) fn length(&self, total_bytes: u64) {
+        self.emit(&ProgressEvent::Length {
+            transfer: self.id,
+            total_bytes,
+        });
+    }
+
+    /// Report bytes sent to B2
+    pub(crate) fn sent(&self, bytes: usize) {
+        let bytes = u64::try_from(bytes).unwrap_or(u64::MAX);
+        self.attempt_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.emit(&ProgressEvent::BytesSent {
+            transfer: self.id,
+            bytes,
+        });
+    }
+
+    /// Report bytes received from B2
+    pub(crate) fn received(&self, bytes: usize) {
+        let bytes = u64::try_from(bytes).unwrap_or(u64::MAX);
+        self.attempt_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.emit(&ProgressEvent::BytesReceived {
+            transfer: self.id,
+            bytes,
+        });
+    }
+
+    /// Report bytes copied by B2
+    pub(crate) fn copied(&self, bytes: u64) {
+        self.emit(&ProgressEvent::BytesCopied {
+            transfer: self.id,
+            bytes,
+        });
+    }
+
+    /// Report that a part of a large file was uploaded or copied
+    pub(crate) fn part_completed(&self, part_number: u32, bytes: u64) {
+        self.emit(&ProgressEvent::PartCompleted {
+            transfer: self.id,
+            part_number,
+            bytes,
+        });
+    }
+
+    /// Report that an attempt failed and is being retried
+    pub(crate) fn retry(&self, attempt: u32, reason: String) {
+        self.emit(&ProgressEvent::Retry {
+            transfer: self.id,
+            attempt,
+            discarded_bytes: self.attempt_bytes.swap(0, Ordering::Relaxed),
+            reason,
+        });
+    }
+
+    /// Report that the transfer finished, successfully or not
+    pub(crate) fn finish<T>(&self, result: &Result<T, SessionError>) {
+        self.phase(if result.is_ok() {
+            Phase::Complete
+        } else {
+            Phase::Failed
+        });
+    }
+}
+
+impl Session {
+    /// The observer the transfers of this session report progress to
+    #[must_use]
+    pub fn progress_observer(&self) -> Option<Arc<dyn ProgressObserver>> {
+        self.inner
+            .progress
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replace the observer the transfers of this session report progress to
+    ///
+    /// Transfers already in progress keep reporting to the observer they
+    /// started with. Passing `None` stops reporting.
+    pub fn set_progress_observer(
+        &self,
+        observer: Option<Arc<dyn ProgressObserver>>,
+    ) {
+        *self.inner.progress.write().unwrap_or_else(PoisonError::into_inner) =
+            observer;
+    }
+
+    /// Start reporting a new transfer
+    pub(crate) fn transfer(
+        &self,
+        kind: TransferKind,
+        file_name: &str,
+        total_bytes: Option<u64>,
+    ) -> Transfer {
+        let transfer = Transfer {
+            observer: self.progress_observer(),
+            id: TransferId(NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed)),
+            attempt_bytes: Arc::default(),
+        };
+        transfer.emit(&ProgressEvent::Started {
+            transfer: transfer.id,
+            kind,
+            file_name: file_name.to_owned(),
+            total_bytes,
+        });
+        transfer
+    }
+}
+
+/// Recent samples of a byte count, for estimating a rate
+#[derive(Debug, Default)]
+struct RateWindow {
+    /// When each sample was taken, and the count at the time
+    samples: VecDeque<(Duration, u64)>,
+}
+
+impl RateWindow {
+    /// Record the count at a point in time
+    fn record(&mut self, now: Duration, count: u64) {
+        self.samples.push_back((now, count));
+        // Keep one sample from before the window as the baseline
+        let start = now.saturating_sub(RATE_WINDOW);
+        while self.samples.get(1).is_some_and(|(at, _)| *at <= start) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// The average bytes per second over the window ending now
+    fn rate(&self, now: Duration) -> f64 {
+        let (Some((first_at, first)), Some((_, last))) =
+            (self.samples.front(), self.samples.back())
+        else {
+            return 0.0;
+        };
+        let elapsed = now.saturating_sub(*first_at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return 0.0;
+        }
+        to_f64(last.saturating_sub(*first)) / elapsed
+    }
+}
+
+/// How long `remaining` bytes take at `rate` bytes per second
+fn eta(remaining: u64, rate: f64) -> Option<Duration> {
+    if remaining == 0 {
+        return Some(Duration::ZERO);
+    }
+    let seconds = to_f64(remaining) / rate;
+    (rate > 0.0).then(|| Duration::try_from_secs_f64(seconds).ok()).flatten()
+}
+
+/// The progress of one transfer
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileProgress {
+    /// The transfer this is the progress of
+    pub transfer: TransferId,
+    /// What kind of operation the transfer is
+    pub kind: TransferKind,
+    /// The file being transferred, or its ID when the name isn't known
+    pub file_name: String,
+    /// The stage the transfer is in
+    pub phase: Phase,
+    /// The bytes transferred so far, not counting retried attempts
+    pub bytes_done: u64,
+    /// The number of bytes to transfer, if known
+    pub total_bytes: Option<u64>,
+    /// The number of large file parts finished so far
+    pub parts_completed: u32,
+    /// The number of times a request was retried
+    pub retries: u32,
+    /// The recent transfer rate, in bytes per second
+    pub bytes_per_second: f64,
+    /// The estimated time until the transfer finishes, if it can be estimated
+    pub eta: Option<Duration>,
+}
+
+/// The combined progress of every transfer a [`ProgressTracker`] has seen
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchProgress {
+    /// The progress of each transfer, in the order they started
+    pub files: Vec<FileProgress>,
+    /// The number of transfers that finished successfully
+    pub files_complete: usize,
+    /// The number of transfers that failed or were canceled
+    pub files_failed: usize,
+    /// The bytes transferred so far, not counting retried attempts
+    pub bytes_done: u64,
+    /// The number of bytes to transfer, if known for every transfer
+    pub total_bytes: Option<u64>,
+    /// The recent combined transfer rate, in bytes per second
+    pub bytes_per_second: f64,
+    /// The estimated time until every transfer finishes, if it can be
+    /// estimated
+    pub eta: Option<Duration>,
+}
+
+/// What a tracker knows about one transfer
+#[derive(Debug)]
+struct FileState {
+    /// What kind of operation the transfer is
+    kind: TransferKind,
+    /// The file being transferred
+    file_name: String,
+    /// The stage the transfer is in
+    phase: Phase,
+    /// The bytes transferred so far, not counting retried attempts
+    bytes_done: u64,
+    /// Every byte moved so far, including retried attempts
+    bytes_moved: u64,
+    /// The number of bytes to transfer, if known
+    total_bytes: Option<u64>,
+    /// The number of large file parts finished so far
+    parts_completed: u32,
+    /// The number of times a request was retried
+    retries: u32,
+    /// Recent values of `bytes_moved`
+    rate: RateWindow,
+}
+
+/// Everything a tracker knows
+#[derive(Debug, Default)]
+struct TrackerState {
+    /// Every transfer seen so far
+    files: BTreeMap<TransferId, FileState>,
+    /// Every byte moved by any transfer, including retried attempts
+    bytes_moved: u64,
+    /// Recent values of `bytes_moved`
+    rate: RateWindow,
+}
+
+/// A [`ProgressObserver`] that adds up events into progress per file and per
+/// batch
+///
+/// Every transfer the tracker observes belongs to its batch, so a new batch
+/// starts with a new tracker or a call to [`ProgressTracker::reset`].
+#[derive(Debug)]
+pub struct ProgressTracker {
+    /// The clock rates are measured with
+    clock: Arc<dyn Clock>,
+    /// The progress so far
+    state: Mutex<TrackerState>,
+}
+
+impl Default for ProgressTracker {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock::default()))
+    }
+}
+
+impl ProgressTracker {
+    /// Create a tracker that measures rates with the system clock
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a tracker that measures rates with another clock
+    #[must_use]
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Lock the state of the tracker
+    fn state(&self) -> std::sync::MutexGuard<'_, TrackerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Forget every transfer, starting a new batch
+    pub fn reset(&self) {
+        *self.state() = TrackerState::default();
+    }
+
+    /// The progress of one transfer
+    #[must_use]
+    pub fn file(&self, transfer: TransferId) -> Option<FileProgress> {
+        let now = self.clock.elapsed();
+        self.state()
+            .files
+            .get(&transfer)
+            .map(|file| file_progress(transfer, file, now))
+    }
+
+    /// The progress of the whole batch
+    #[must_use]
+    pub fn batch(&self) -> BatchProgress {
+        let now = self.clock.elapsed();
+        let state = self.state();
+        let files: Vec<_> = state
+            .files
+            .iter()
+            .map(|(transfer, file)| file_progress(*transfer, file, now))
+            .collect();
+        let bytes_done = files.iter().map(|file| file.bytes_done).sum();
+        let total_bytes = files
+            .iter()
+            .filter(|file| {
+                !matches!(file.phase, Phase::Failed | Phase::Canceled)
+            })
+            .map(|file| file.total_bytes)
+            .sum::<Option<u64>>();
+        let bytes_per_second = state.rate.rate(now);
+        let eta = total_bytes.and_then(|total| {
+            eta(total.saturating_sub(bytes_done), bytes_per_second)
+        });
+        BatchProgress {
+            files_complete: files
+                .iter()
+                .filter(|file| file.phase == Phase::Complete)
+                .count(),
+            files_failed: files
+                .iter()
+                .filter(|file| {
+                    matches!(file.phase, Phase::Failed | Phase::Canceled)
+                })
+                .count(),
+            files,
+            bytes_done,
+            total_bytes,
+            bytes_per_second,
+            eta,
+        }
+    }
+}
+
+/// Summarize what a tracker knows about one transfer
+fn file_progress(
+    transfer: TransferId,
+    file: &FileState,
+    now: Duration,
+) -> FileProgress {
+    let bytes_per_second = if file.phase.is_done() {
+        0.0
+    } else {
+        file.rate.rate(now)
+    };
+    let eta = match file.phase {
+        Phase::Complete => Some(Duration::ZERO),
+        Phase::Failed | Phase::Canceled => None,
+        _ => file.total_bytes.and_then(|total| {
+            eta(total.saturating_sub(file.bytes_done), bytes_per_second)
+        }),
+    };
+    FileProgress {
+        transfer,
+        kind: file.kind,
+        file_name: file.file_name.clone(),
+        phase: file.phase,
+        bytes_done: file.bytes_done,
+        total_bytes: file.total_bytes,
+        parts_completed: file.parts_completed,
+        retries: file.retries,
+        bytes_per_second,
+        eta,
+    }
+}
+
+impl ProgressObserver for ProgressTracker {
+    fn on_event(&self, event: &ProgressEvent) {
+        let now = self.clock.elapsed();
+        let mut state = self.state();
+        let state = &mut *state;
+        if let ProgressEvent::Started {
+            transfer,
+            kind,
+            file_name,
+            total_bytes,
+        } = event
+        {
+            state.files.insert(
+                *transfer,
+                FileState {
+                    kind: *kind,
+                    file_name: file_name.clone(),
+                    phase: Phase::Preparing,
+                    bytes_done: 0,
+                    bytes_moved: 0,
+                    total_bytes: *total_bytes,
+                    parts_completed: 0,
+                    retries: 0,
+                    rate: RateWindow::default(),
+                },
+            );
+            return;
+        }
+        let Some(file) = state.files.get_mut(&event.transfer()) else {
+            return;
+        };
+        match event {
+            ProgressEvent::Started {
+                ..
+            } => {}
+            ProgressEvent::Length {
+                total_bytes,
+                ..
+            } => {
+                file.total_bytes = Some(*total_bytes);
+            }
+            ProgressEvent::Phase {
+                phase,
+                ..
+            } => file.phase = *phase,
+            ProgressEvent::BytesSent {
+                bytes,
+                ..
+            }
+            | ProgressEvent::BytesReceived {
+                bytes,
+                ..
+            }
+            | ProgressEvent::BytesCopied {
+                bytes,
+                ..
+            } => {
+                file.bytes_done += bytes;
+                file.bytes_moved += bytes;
+                file.rate.record(now, file.bytes_moved);
+                state.bytes_moved += bytes;
+                state.rate.record(now, state.bytes_moved);
+            }
+            ProgressEvent::PartCompleted {
+                ..
+            } => file.parts_completed += 1,
+            ProgressEvent::Retry {
+                discarded_bytes,
+                ..
+            } => {
+                file.retries += 1;
+                file.bytes_done =
+                    file.bytes_done.saturating_sub(*discarded_bytes);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex, PoisonError},
+        time::Duration,
+    };
+
+    use b2fake::{Config, FakeB2, Fault};
+    use chrono::NaiveDate;
+
+    use crate::{
+        progress::{Phase, ProgressEvent, TransferId, TransferKind},
+        tests::bucket,
+        MockClock, ProgressObserver, ProgressTracker, UploadOptions,
+    };
+
+    /// An observer that keeps every event
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<ProgressEvent>>);
+
+    impl ProgressObserver for Recorder {
+        fn on_event(&self, event: &ProgressEvent) {
+            self.0
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(event.clone());
+        }
+    }
+
+    impl Recorder {
+        fn take(&self) -> Vec<ProgressEvent> {
+            std::mem::take(
+                &mut *self.0.lock().unwrap_or_else(PoisonError::into_inner),
+            )
+        }
+    }
+
+    fn phases(events: &[ProgressEvent]) -> Vec<Phase> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Phase {
+                    phase,
+                    ..
+                } => Some(*phase),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn transfers_report_progress() {
+        let server = FakeB2::start_with(Config {
+            absolute_minimum_part_size: 10,
+            ..Config::default()
+        })
+        .await
+        .expect("Fake server should start");
+        let bucket = bucket(&server, "progress").await;
+        let session = bucket.session();
+        let recorder = Arc::new(Recorder::default());
+        let tracker = Arc::new(ProgressTracker::new());
+
+        session.set_progress_observer(Some(recorder.clone()));
+        server.inject(Some("b2_upload_file"), Fault::ServiceUnavailable, 1);
+        let file = bucket
+            .upload_file("a.bin", vec![0; 100], &UploadOptions::default())
+            .await
+            .expect("Upload should be retried");
+        let events = recorder.take();
+        assert!(matches!(
+            events.first(),
+            Some(ProgressEvent::Started {
+                kind: TransferKind::Upload,
+                total_bytes: Some(100),
+                ..
+            })
+        ));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ProgressEvent::Retry { .. })));
+        assert_eq!(phases(&events), [Phase::Transferring, Phase::Complete]);
+
+        let large = bucket
+            .start_large_file("big.bin", &UploadOptions::default())
+            .await
+            .expect("Large file should start");
+        large.upload_part(1, vec![1; 10]).await.expect("Part should upload");
+        large
+            .copy_part(2, file.file_id.as_deref().unwrap_or_default(), None)
+            .await
+            .expect("Part should copy");
+        large.finish().await.expect("File should finish");
+        let events = recorder.take();
+        let parts = events
+            .iter()
+            .filter(|event| {
+                matches!(event, ProgressEvent::PartCompleted { .. })
+            })
+            .count();
+        assert_eq!(parts, 2);
+        assert_eq!(
+            phases(&events),
+            [Phase::Transferring, Phase::Finishing, Phase::Complete]
+        );
+
+        session.set_progress_observer(Some(tracker.clone()));
+        bucket
+            .copy_file(file.file_id.as_deref().unwrap_or_default(), "b.bin")
+            .await
+            .expect("Copy should succeed");
+        bucket
+            .download_file("big.bin", None)
+            .await
+            .expect("Download should succeed");
+        let batch = tracker.batch();
+        assert_eq!(batch.files_complete, 2);
+        assert_eq!(batch.bytes_done, 210);
+        assert_eq!(batch.total_bytes, Some(210));
+        assert_eq!(batch.files[1].kind, TransferKind::Download);
+        assert!(recorder.take().is_empty());
+    }
+
+    #[test]
+    fn tracker_estimates_rate_and_eta() {
+        let clock = MockClock::new(
+            NaiveDate::from_ymd_opt(2024, 1, 1)
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .expect("Date should be valid"),
+        );
+        let tracker = ProgressTracker::with_clock(Arc::new(clock.clone()));
+        let transfer = TransferId(1);
+        tracker.on_event(&ProgressEvent::Started {
+            transfer,
+            kind: TransferKind::Upload,
+            file_name: "a.bin".to_owned(),
+            total_bytes: Some(1000),
+        });
+        tracker.on_event(&ProgressEvent::BytesSent {
+            transfer,
+            bytes: 0,
+        });
+        for _ in 0..4 {
+            clock.advance(Duration::from_secs(1));
+            tracker.on_event(&ProgressEvent::BytesSent {
+                transfer,
+                bytes: 100,
+            });
+        }
+        let file = tracker.file(transfer).expect("Transfer should be known");
+        assert_eq!(file.bytes_done, 400);
+        assert!((file.bytes_per_second - 100.0).abs() < f64::EPSILON);
+        assert_eq!(file.eta, Some(Duration::from_secs(6)));
+        tracker.on_event(&ProgressEvent::Retry {
+            transfer,
+            attempt: 1,
+            discarded_bytes: 400,
+            reason: "service_unavailable".to_owned(),
+        });
+        let batch = tracker.batch();
+        assert_eq!(batch.bytes_done, 0);
+        assert_eq!(batch.total_bytes, Some(1000));
+        assert_eq!(batch.files[0].retries, 1);
+        tracker.on_event(&ProgressEvent::Phase {
+            transfer,
+            phase: Phase::Failed,
+        });
+        assert_eq!(tracker.batch().files_failed, 1);
+    }
+}
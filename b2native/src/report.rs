@@ -0,0 +1,441 @@
+//! Reporting what the versions in a bucket cost to keep
+//!
+//! Listing names only shows the files people see, but B2 bills for every
+//! stored version, including ones replaced by newer uploads or hidden by a
+//! hide marker. [`Bucket::usage_report`] walks every version with
+//! ``b2_list_file_versions`` one page at a time and adds them up, so even
+//! buckets with millions of versions are reported without holding them all in
+//! memory.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+
+use crate::{
+    api::{b2_list_file_versions, OutgoingRequest},
+    file::timestamp,
+    Bucket, FileAction, FileVersion, SessionError,
+};
+
+/// How many versions are listed per call
+const PAGE_SIZE: u32 = 1000;
+
+/// The upper bounds of the upload age histogram, in days
+const AGE_BOUNDS: [i64; 5] = [1, 7, 30, 90, 365];
+
+/// What to include in a usage report
+#[derive(Clone, Debug)]
+pub struct ReportOptions {
+    /// Only versions whose names start with this prefix are reported
+    pub prefix: String,
+    /// How many folder levels below the prefix get their own totals
+    pub max_depth: usize,
+    /// How many of the largest versions are listed
+    pub largest_files: usize,
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            max_depth: 2,
+            largest_files: 10,
+        }
+    }
+}
+
+/// A number of versions and the bytes they take up
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionStats {
+    /// How many versions there are
+    pub count: u64,
+    /// How many bytes the versions take up
+    pub bytes: u64,
+}
+
+impl VersionStats {
+    /// Count one version
+    fn add(&mut self, bytes: u64) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// The versions stored below a prefix
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrefixStats {
+    /// How many folder levels below the report's prefix this prefix is
+    pub depth: usize,
+    /// The versions that are the visible version of their file
+    pub live: VersionStats,
+    /// Older versions, and versions hidden by a hide marker
+    pub noncurrent: VersionStats,
+}
+
+/// One of the largest versions in a report
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LargestFile {
+    /// The size of the version in bytes
+    pub bytes: u64,
+    /// The name of the file
+    pub file_name: String,
+    /// The ID of the version
+    pub file_id: Option<String>,
+    /// Whether the version is the visible version of its file
+    pub live: bool,
+}
+
+/// A large file that was started but never finished or canceled
+///
+/// B2 keeps, and bills for, the parts uploaded so far until the file is
+/// canceled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnfinishedLargeFile {
+    /// The name of the file
+    pub file_name: String,
+    /// The ID of the large file
+    pub file_id: Option<String>,
+    /// When the large file was started
+    pub started_at: Option<DateTime<Utc>>,
+}
+
+/// The stored versions uploaded within a range of ages
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgeBucket {
+    /// The age in days below which versions fall into this bucket, or `None`
+    /// for the oldest bucket
+    pub max_age_days: Option<i64>,
+    /// The versions uploaded within the range
+    pub versions: VersionStats,
+}
+
+/// A summary of every version stored in a bucket
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageReport {
+    /// The name of the bucket
+    pub bucket_name: String,
+    /// The prefix the report covers
+    pub prefix: String,
+    /// When the report was made
+    pub generated_at: DateTime<Utc>,
+    /// The versions that are the visible version of their file
+    pub live: VersionStats,
+    /// Older versions, and versions hidden by a hide marker
+    pub noncurrent: VersionStats,
+    /// How many hide markers there are
+    pub hide_markers: u64,
+    /// Totals for each folder below the prefix, down to the report's depth
+    pub prefixes: BTreeMap<String, PrefixStats>,
+    /// The largest versions, largest first
+    pub largest_files: Vec<LargestFile>,
+    /// Large files that were started and never finished
+    pub unfinished_large_files: Vec<UnfinishedLargeFile>,
+    /// Every stored version by how long ago it was uploaded, newest first
+    pub age_histogram: Vec<AgeBucket>,
+}
+
+impl UsageReport {
+    /// Every stored version, live or not
+    #[must_use]
+    pub fn total(&self) -> VersionStats {
+        VersionStats {
+            count: self.live.count + self.noncurrent.count,
+            bytes: self.live.bytes + self.noncurrent.bytes,
+        }
+    }
+
+    /// The report as pretty-printed JSON
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the report cannot be serialized, which cannot
+    /// happen for the types it contains.
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self)
+            .expect("Usage reports always serialize")
+    }
+}
+
+/// A usage report being added up one version at a time
+struct ReportBuilder {
+    /// What to include
+    options: ReportOptions,
+    /// The report so far
+    report: UsageReport,
+    /// The smallest of the largest versions so far sits on top
+    largest: BinaryHeap<Reverse<LargestFile>>,
+    /// The name of the last finished version seen
+    current: Option<String>,
+}
+
+impl ReportBuilder {
+    /// Start an empty report
+    fn new(bucket_name: &str, options: ReportOptions) -> Self {
+        let age_histogram = AGE_BOUNDS
+            .iter()
+            .map(|&days| Some(days))
+            .chain([None])
+            .map(|max_age_days| AgeBucket {
+                max_age_days,
+                versions: VersionStats::default(),
+            })
+            .collect();
+        Self {
+            report: UsageReport {
+                bucket_name: bucket_name.to_owned(),
+                prefix: options.prefix.clone(),
+                generated_at: Utc::now(),
+                live: VersionStats::default(),
+                noncurrent: VersionStats::default(),
+                hide_markers: 0,
+                prefixes: BTreeMap::new(),
+                largest_files: Vec::new(),
+                unfinished_large_files: Vec::new(),
+                age_histogram,
+            },
+            options,
+            largest: BinaryHeap::new(),
+            current: None,
+        }
+    }
+
+    /// Count one version
+    ///
+    /// Versions have to be added in the order B2 lists them: by name, and
+    /// newest first within a name.
+    fn add(&mut self, version: FileVersion) {
+        let bytes = version.content_length;
+        match version.action {
+            FileAction::Start => {
+                self.report.unfinished_large_files.push(UnfinishedLargeFile {
+                    started_at: timestamp(version.upload_timestamp),
+                    file_name: version.file_name,
+                    file_id: version.file_id,
+                });
+                return;
+            }
+            FileAction::Upload | FileAction::Hide => {}
+            FileAction::Folder | FileAction::Other => return,
+        }
+        // The first finished version of a name decides whether it is visible
+        let first =
+            self.current.as_ref().is_none_or(|name| *name != version.file_name);
+        if first {
+            self.current = Some(version.file_name.clone());
+        }
+        if version.action == FileAction::Hide {
+            self.report.hide_markers += 1;
+            return;
+        }
+        let live = first;
+        if live {
+            self.report.live.add(bytes);
+        } else {
+            self.report.noncurrent.add(bytes);
+        }
+        self.add_to_prefixes(&version.file_name, bytes, live);
+        self.add_to_ages(version.upload_timestamp, bytes);
+        if self.options.largest_files > 0 {
+            self.largest.push(Reverse(LargestFile {
+                bytes,
+                file_name: version.file_name,
+                file_id: version.file_id,
+                live,
+            }));
+            if self.largest.len() > self.options.largest_files {
+                let _smallest = self.largest.pop();
+            }
+        }
+    }
+
+    /// Count a version towards every folder above it, down to the maximum
+    /// depth
+    fn add_to_prefixes(&mut self, file_name: &str, bytes: u64, live: bool) {
+        let Some(relative) = file_name.strip_prefix(&self.options.prefix)
+        else {
+            return;
+        };
+        let mut end = self.options.prefix.len();
+        for (depth, folder) in relative
+            .split_inclusive('/')
+            .take_while(|part| part.ends_with('/'))
+            .take(self.options.max_depth)
+            .enumerate()
+        {
+            end += folder.len();
+            let prefix = file_name.get(..end).unwrap_or(file_name);
+            let stats =
+                self.report.prefixes.entry(prefix.to_owned()).or_insert_with(
+                    || PrefixStats {
+                        depth: depth + 1,
+                        ..PrefixStats::default()
+                    },
+                );
+            if live {
+                stats.live.add(bytes);
+            } else {
+                stats.noncurrent.add(bytes);
+            }
+        }
+    }
+
+    /// Count a version in the age histogram
+    fn add_to_ages(&mut self, uploaded: u64, bytes: u64) {
+        let age = timestamp(uploaded)
+            .map_or(TimeDelta::MAX, |at| self.report.generated_at - at);
+        let position = AGE_BOUNDS
+            .iter()
+            .position(|&days| age < TimeDelta::days(days))
+            .unwrap_or(AGE_BOUNDS.len());
+        if let Some(bucket) = self.report.age_histogram.get_mut(position) {
+            bucket.versions.add(bytes);
+        }
+    }
+
+    /// Finish the report
+    fn finish(mut self) -> UsageReport {
+        let mut largest: Vec<_> =
+            self.largest.into_iter().map(|Reverse(file)| file).collect();
+        largest.sort_by(|a, b| b.cmp(a));
+        self.report.largest_files = largest;
+        self.report
+    }
+}
+
+impl Bucket {
+    /// Report on every version stored in this bucket
+    ///
+    /// This lists every version below the prefix, which costs one class C
+    /// transaction per 1000 versions.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub async fn usage_report(
+        &self,
+        options: &ReportOptions,
+    ) -> Result<UsageReport, SessionError> {
+        let mut builder = ReportBuilder::new(self.name(), options.clone());
+        let mut start = (None, None);
+        loop {
+            let page = self
+                .session()
+                .send(b2_list_file_versions::Request {
+                    bucket_id: self.id().to_owned(),
+                    start_file_name: start.0,
+                    start_file_id: start.1,
+                    max_file_count: Some(PAGE_SIZE),
+                    prefix: Some(options.prefix.clone()),
+                    delimiter: None,
+                })
+                .await
+                .into_result()?;
+            for version in page.files {
+                builder.add(version);
+            }
+            if page.next_file_name.is_none() {
+                return Ok(builder.finish());
+            }
+            start = (page.next_file_name, page.next_file_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use b2fake::FakeB2;
+    use serde_json::json;
+
+    use crate::{
+        report::{ReportOptions, VersionStats},
+        tests::bucket,
+        FileVersion, UploadOptions,
+    };
+
+    #[tokio::test]
+    async fn versions_are_reported() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server, "reported").await;
+        let bucket_id = bucket.id();
+        let session = bucket.session();
+        for (name, size) in [
+            ("logs/2024/a.log", 100),
+            ("logs/2024/a.log", 300),
+            ("logs/b.log", 50),
+            ("photos/c.jpg", 1000),
+            ("top.txt", 5),
+        ] {
+            let _file = bucket
+                .upload_file(name, vec![0; size], &UploadOptions::default())
+                .await
+                .expect("Upload should succeed");
+            // Keep upload timestamps of the same name apart
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let _marker: FileVersion = session
+            .call(
+                "b2_hide_file",
+                &json!({ "bucketId": bucket_id, "fileName": "photos/c.jpg" }),
+            )
+            .await
+            .into_result()
+            .expect("Hiding should succeed");
+        let _unfinished = bucket
+            .start_large_file("big.bin", &UploadOptions::default())
+            .await
+            .expect("Large file should start");
+
+        let report = bucket
+            .usage_report(&ReportOptions {
+                largest_files: 2,
+                ..ReportOptions::default()
+            })
+            .await
+            .expect("Report should succeed");
+        let stats = |count, bytes| VersionStats {
+            count,
+            bytes,
+        };
+        assert_eq!(report.live, stats(3, 355));
+        assert_eq!(report.noncurrent, stats(2, 1100));
+        assert_eq!(report.total(), stats(5, 1455));
+        assert_eq!(report.hide_markers, 1);
+        assert_eq!(report.unfinished_large_files.len(), 1);
+        assert_eq!(report.prefixes["logs/"].live, stats(2, 350));
+        assert_eq!(report.prefixes["logs/2024/"].noncurrent, stats(1, 100));
+        assert_eq!(report.prefixes["logs/2024/"].depth, 2);
+        assert!(!report.prefixes.contains_key("top.txt"));
+        let largest: Vec<_> = report
+            .largest_files
+            .iter()
+            .map(|file| (file.file_name.as_str(), file.live))
+            .collect();
+        assert_eq!(
+            largest,
+            [("photos/c.jpg", false), ("logs/2024/a.log", true)]
+        );
+        assert_eq!(report.age_histogram[0].versions, stats(5, 1455));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json())
+            .expect("Report should be valid JSON");
+        assert_eq!(json["live"]["bytes"], 355);
+    }
+}
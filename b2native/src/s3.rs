@@ -0,0 +1,1231 @@
+//! The S3-compatible API
+//!
+//! B2 serves every bucket through an S3-compatible API as well, at the
+//! `s3ApiUrl` returned by ``b2_authorize_account``. Requests are signed with
+//! AWS Signature Version 4, using the application key ID and key as the
+//! access key pair. [`S3Client`] sends path-style requests, so it works with
+//! other S3-compatible stores too.
+//!
+//! [API Docs](https://www.backblaze.com/docs/cloud-storage-s3-compatible-api)
+
+mod sigv4;
+
+use std::{
+    collections::BTreeMap,
+    ops::Range,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{
+        HeaderMap, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+        DATE, ETAG, LAST_MODIFIED,
+    },
+    Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::Deserialize;
+use tracing::Instrument;
+
+use crate::{
+    metrics::operation_span,
+    progress::Transfer,
+    range_header,
+    s3::sigv4::{
+        amz_date, encode_key, encode_query, sha256_hex, CanonicalRequest,
+        Signer,
+    },
+    throttle::{download_body, upload_body},
+    Object, ObjectInfo, Session, SessionError, Throttle, UploadOptions,
+};
+
+/// How many times a request is attempted before giving up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long to wait before the first retry of a request
+///
+/// The wait doubles with every further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The region used when it can't be told from the endpoint
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// The prefix of headers carrying custom metadata
+const METADATA_PREFIX: &str = "x-amz-meta-";
+
+/// An error returned by an S3-compatible API
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S3Error {
+    /// The HTTP status code of the response
+    pub status: u16,
+    /// The machine-readable error code, such as `NoSuchKey`
+    ///
+    /// Responses without a body, such as to `HEAD` requests, get a code made
+    /// from their status, such as `Forbidden`.
+    pub code: String,
+    /// The human-readable error message
+    pub message: String,
+}
+
+/// The body of an error response
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorBody {
+    /// The machine-readable error code
+    code: String,
+    /// The human-readable error message
+    #[serde(default)]
+    message: String,
+}
+
+/// A client for an S3-compatible API
+///
+/// Clones share the same connection pool and credentials.
+#[derive(Clone, Debug)]
+pub struct S3Client {
+    /// The state shared by every clone
+    inner: Arc<ClientInner>,
+}
+
+/// The state of an [`S3Client`]
+struct ClientInner {
+    /// The HTTP client requests are sent with
+    http_client: Client,
+    /// The session the client was made from, whose metrics, budget, request
+    /// cap and throttles its requests count toward
+    session: Option<Session>,
+    /// The URL of the API, without a trailing slash
+    endpoint: String,
+    /// The region requests are signed for
+    region: String,
+    /// The access key ID
+    access_key_id: String,
+    /// The secret access key
+    secret_access_key: String,
+}
+
+impl std::fmt::Debug for ClientInner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientInner")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &"<redacted>")
+            .finish_non_exhaustive()
+    }
+}
+
+/// A request to send to the API
+struct S3Request<'a> {
+    /// The name of the S3 operation, for tracing
+    operation: &'static str,
+    /// The request method
+    method: Method,
+    /// The bucket the request targets
+    bucket: &'a str,
+    /// The key of the object the request targets, if any
+    key: Option<&'a str>,
+    /// The query parameters, not yet encoded
+    query: Vec<(&'static str, String)>,
+    /// Headers to sign and send, with lowercase names
+    headers: Vec<(String, String)>,
+    /// The request body
+    body: Bytes,
+}
+
+/// Read a header as a string
+fn header(headers: &HeaderMap, name: impl AsRef<str>) -> Option<&str> {
+    headers.get(name.as_ref()).and_then(|value| value.to_str().ok())
+}
+
+/// Read an HTTP date header
+fn date_header(
+    headers: &HeaderMap,
+    name: impl AsRef<str>,
+) -> Option<DateTime<Utc>> {
+    header(headers, name)
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Read the custom metadata of an object from its headers
+fn metadata(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix(METADATA_PREFIX)?;
+            Some((key.to_owned(), value.to_str().ok()?.to_owned()))
+        })
+        .collect()
+}
+
+/// The headers to upload an object with
+fn upload_headers(options: &UploadOptions) -> Vec<(String, String)> {
+    options
+        .content_type
+        .iter()
+        .map(|content_type| {
+            (CONTENT_TYPE.as_str().to_owned(), content_type.clone())
+        })
+        .chain(options.file_info.iter().map(|(key, value)| {
+            (format!("{METADATA_PREFIX}{}", key.to_lowercase()), value.clone())
+        }))
+        .collect()
+}
+
+/// The region of a B2 endpoint such as `https://s3.us-west-004.backblazeb2.com`
+fn region_of(endpoint: &str) -> String {
+    Url::parse(endpoint)
+        .ok()
+        .and_then(|url| {
+            let host = url.host_str()?.strip_prefix("s3.")?;
+            let (region, _) = host.split_once('.')?;
+            Some(region.to_owned())
+        })
+        .unwrap_or_else(|| DEFAULT_REGION.to_owned())
+}
+
+/// Turn an error response into a [`SessionError`]
+async fn rejection(response: Response) -> SessionError {
+    let status = response.status();
+    let Ok(body) = response.text().await else {
+        return SessionError::RequestFailed;
+    };
+    if body.is_empty() {
+        return SessionError::S3RequestRejected(S3Error {
+            status: status.as_u16(),
+            code: status
+                .canonical_reason()
+                .unwrap_or_default()
+                .replace(' ', ""),
+            message: String::new(),
+        });
+    }
+    match quick_xml::de::from_str::<ErrorBody>(&body) {
+        Ok(error) => SessionError::S3RequestRejected(S3Error {
+            status: status.as_u16(),
+            code: error.code,
+            message: error.message,
+        }),
+        Err(_) => SessionError::ErrorDeserializationFailed,
+    }
+}
+
+/// Pass successful responses through, and turn the rest into errors
+async fn checked(response: Response) -> Result<Response, SessionError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(rejection(response).await)
+    }
+}
+
+/// Read and deserialize an XML response body
+async fn xml_body<T: for<'de> Deserialize<'de>>(
+    response: Response,
+) -> Result<T, SessionError> {
+    let body = response.text().await?;
+    quick_xml::de::from_str(&body)
+        .map_err(|_error| SessionError::SuccessfulDeserializationFailed)
+}
+
+impl S3Client {
+    /// Create a client for the API at `endpoint`, signing for `region`
+    #[must_use]
+    pub fn new(
+        endpoint: impl Into<String>,
+        region: impl Into<String>,
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+    ) -> Self {
+        Self::with_http_client(
+            Client::new(),
+            None,
+            &endpoint.into(),
+            region.into(),
+            access_key_id.into(),
+            secret_access_key.into(),
+        )
+    }
+
+    /// Create a client sending requests with an existing HTTP client, and
+    /// through a session if it was made from one
+    fn with_http_client(
+        http_client: Client,
+        session: Option<Session>,
+        endpoint: &str,
+        region: String,
+        access_key_id: String,
+        secret_access_key: String,
+    ) -> Self {
+        Self {
+            inner: Arc::new(ClientInner {
+                http_client,
+                session,
+                endpoint: endpoint.trim_end_matches('/').to_owned(),
+                region,
+                access_key_id,
+                secret_access_key,
+            }),
+        }
+    }
+
+    /// The URL of the API
+    #[must_use]
+    pub fn endpoint(&self) -> &str {
+        &self.inner.endpoint
+    }
+
+    /// The region requests are signed for
+    #[must_use]
+    pub fn region(&self) -> &str {
+        &self.inner.region
+    }
+
+    /// A handle on a bucket, which isn't checked to exist
+    #[must_use]
+    pub fn bucket(&self, name: &str) -> S3Bucket {
+        S3Bucket {
+            client: self.clone(),
+            name: name.to_owned(),
+        }
+    }
+
+    /// The throttles transfers have to pass, which are the session's if the
+    /// client was made from one
+    fn throttles(&self) -> Vec<Arc<Throttle>> {
+        self.inner.session.as_ref().map(Session::throttles).unwrap_or_default()
+    }
+
+    /// The URL of a request, and its canonical query string
+    fn url(
+        &self,
+        request: &S3Request<'_>,
+    ) -> Result<(Url, String), SessionError> {
+        let mut query = request
+            .query
+            .iter()
+            .map(|(name, value)| {
+                format!("{}={}", encode_query(name), encode_query(value))
+            })
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+        let mut url =
+            format!("{}/{}", self.inner.endpoint, encode_query(request.bucket));
+        if let Some(key) = request.key {
+            url.push('/');
+            url.push_str(&encode_key(key));
+        }
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+        let url =
+            Url::parse(&url).map_err(|_error| SessionError::RequestFailed)?;
+        Ok((url, query))
+    }
+
+    /// Send a signed request once, through the session if there is one
+    async fn dispatch(
+        &self,
+        operation: &str,
+        bytes_sent: u64,
+        builder: RequestBuilder,
+    ) -> Result<Response, SessionError> {
+        match &self.inner.session {
+            Some(session) => {
+                session.send_request(operation, bytes_sent, builder).await
+            }
+            None => Ok(builder.send().await?),
+        }
+    }
+
+    /// Sign and send a request, retrying connection failures and server
+    /// errors
+    async fn send(
+        &self,
+        request: S3Request<'_>,
+    ) -> Result<Response, SessionError> {
+        let inner = &self.inner;
+        let (url, query) = self.url(&request)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_owned(),
+            (None, _) => return Err(SessionError::RequestFailed),
+        };
+        let payload_hash = sha256_hex(&request.body);
+        let bytes_sent = u64::try_from(request.body.len()).unwrap_or(u64::MAX);
+        let signer = Signer {
+            access_key_id: &inner.access_key_id,
+            secret_access_key: &inner.secret_access_key,
+            region: &inner.region,
+        };
+        let span = operation_span(request.operation, Some(request.bucket));
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        async {
+            loop {
+                let now = Utc::now();
+                let mut headers = request.headers.clone();
+                headers.extend([
+                    ("host".to_owned(), host.clone()),
+                    ("x-amz-content-sha256".to_owned(), payload_hash.clone()),
+                    ("x-amz-date".to_owned(), amz_date(now)),
+                ]);
+                headers.sort();
+                let authorization = signer.authorization(
+                    &CanonicalRequest {
+                        method: request.method.as_str(),
+                        path: url.path(),
+                        query: &query,
+                        headers: &headers,
+                        payload_hash: &payload_hash,
+                    },
+                    now,
+                );
+                let builder = headers
+                    .iter()
+                    .filter(|(name, _)| name != "host")
+                    .fold(
+                        inner
+                            .http_client
+                            .request(request.method.clone(), url.clone()),
+                        |builder, (name, value)| builder.header(name, value),
+                    )
+                    .header(AUTHORIZATION, authorization)
+                    .header(CONTENT_LENGTH, request.body.len())
+                    .body(upload_body(
+                        self.throttles(),
+                        Transfer::unobserved(),
+                        request.body.clone(),
+                    ));
+                let result =
+                    self.dispatch(request.operation, bytes_sent, builder).await;
+                let retryable = match &result {
+                    Ok(response) => {
+                        response.status() == StatusCode::INTERNAL_SERVER_ERROR
+                            || response.status()
+                                == StatusCode::SERVICE_UNAVAILABLE
+                    }
+                    Err(error) => matches!(error, SessionError::RequestFailed),
+                };
+                if !retryable || attempt >= MAX_ATTEMPTS {
+                    return result;
+                }
+                if let Some(session) = &inner.session {
+                    session.inner.metrics.retry(request.operation, attempt);
+                } else {
+                    tracing::Span::current().record("retries", attempt);
+                    tracing::debug!(attempt, "Retrying S3 request");
+                }
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+impl Session {
+    /// A client for the S3-compatible API of this session's account
+    ///
+    /// The client signs requests with the session's application key, so it
+    /// has the same capabilities and restrictions. Its requests count toward
+    /// the session's metrics, usage and budget, and pass the same request cap
+    /// and throttles as the session's own.
+    #[must_use]
+    pub fn s3_client(&self) -> S3Client {
+        let endpoint = self.authorization().storage_api_info.s3_api_url.clone();
+        let credentials = self.credentials();
+        S3Client::with_http_client(
+            self.http_client().clone(),
+            Some(self.clone()),
+            &endpoint,
+            region_of(&endpoint),
+            credentials.application_key_id.clone(),
+            credentials.application_key.clone(),
+        )
+    }
+}
+
+/// A bucket of an S3-compatible API
+#[derive(Clone, Debug)]
+pub struct S3Bucket {
+    /// The client requests are sent with
+    client: S3Client,
+    /// The name of the bucket
+    name: String,
+}
+
+/// The objects and common prefixes found by a listing
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct S3Listing {
+    /// The objects, in key order
+    pub objects: Vec<ObjectInfo>,
+    /// The distinct prefixes up to the delimiter, when listing with one
+    pub common_prefixes: Vec<String>,
+}
+
+/// A page of a ``ListObjectsV2`` response
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListBucketResult {
+    /// The objects on this page
+    #[serde(default)]
+    contents: Vec<ListedObject>,
+    /// The common prefixes on this page
+    #[serde(default)]
+    common_prefixes: Vec<CommonPrefix>,
+    /// Whether more pages follow
+    #[serde(default)]
+    is_truncated: bool,
+    /// The token to request the next page with
+    next_continuation_token: Option<String>,
+}
+
+/// An object in a listing
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedObject {
+    /// The key of the object
+    key: String,
+    /// The size of the object in bytes
+    size: u64,
+    /// When the object was uploaded
+    last_modified: Option<DateTime<Utc>>,
+}
+
+/// A common prefix in a listing
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CommonPrefix {
+    /// The prefix, including the delimiter
+    prefix: String,
+}
+
+/// The response to ``CreateMultipartUpload``
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct InitiateMultipartUploadResult {
+    /// The ID of the new upload
+    upload_id: String,
+}
+
+impl S3Bucket {
+    /// The name of the bucket
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The client the bucket is accessed with
+    #[must_use]
+    pub fn client(&self) -> &S3Client {
+        &self.client
+    }
+
+    /// Upload an object with ``PutObject``
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails or is
+    /// rejected.
+    pub async fn put_object(
+        &self,
+        key: &str,
+        data: impl Into<Bytes>,
+        options: &UploadOptions,
+    ) -> Result<ObjectInfo, SessionError> {
+        let data = data.into();
+        let size = u64::try_from(data.len()).unwrap_or(u64::MAX);
+        let response = self
+            .client
+            .send(S3Request {
+                operation: "s3_put_object",
+                method: Method::PUT,
+                bucket: &self.name,
+                key: Some(key),
+                query: Vec::new(),
+                headers: upload_headers(options),
+                body: data,
+            })
+            .await?;
+        let response = checked(response).await?;
+        let headers = response.headers();
+        Ok(ObjectInfo {
+            key: key.to_owned(),
+            size,
+            content_type: options.content_type.clone(),
+            last_modified: date_header(headers, DATE),
+            version_id: header(headers, "x-amz-version-id").map(str::to_owned),
+            metadata: options.file_info.clone(),
+        })
+    }
+
+    /// Download an object, or the bytes of it in `range`, with ``GetObject``
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `range` is empty, or if the
+    /// request fails or is rejected, including when the object doesn't exist.
+    pub async fn get_object(
+        &self,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Object, SessionError> {
+        let headers = range
+            .as_ref()
+            .map(range_header)
+            .transpose()?
+            .map(|range| ("range".to_owned(), range))
+            .into_iter()
+            .collect();
+        let response = self
+            .client
+            .send(S3Request {
+                operation: "s3_get_object",
+                method: Method::GET,
+                bucket: &self.name,
+                key: Some(key),
+                query: Vec::new(),
+                headers,
+                body: Bytes::new(),
+            })
+            .await?;
+        let response = checked(response).await?;
+        let headers = response.headers().clone();
+        let data = download_body(
+            &self.client.throttles(),
+            &Transfer::unobserved(),
+            response,
+        )
+        .await?;
+        let size = header(&headers, CONTENT_RANGE)
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok())
+            .unwrap_or_else(|| u64::try_from(data.len()).unwrap_or(u64::MAX));
+        Ok(Object {
+            info: ObjectInfo {
+                key: key.to_owned(),
+                size,
+                content_type: header(&headers, CONTENT_TYPE).map(str::to_owned),
+                last_modified: date_header(&headers, LAST_MODIFIED),
+                version_id: header(&headers, "x-amz-version-id")
+                    .map(str::to_owned),
+                metadata: metadata(&headers),
+            },
+            data,
+        })
+    }
+
+    /// Look up an object with ``HeadObject``
+    ///
+    /// Returns `None` if the object doesn't exist.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails or is
+    /// rejected.
+    pub async fn head_object(
+        &self,
+        key: &str,
+    ) -> Result<Option<ObjectInfo>, SessionError> {
+        let response = self
+            .client
+            .send(S3Request {
+                operation: "s3_head_object",
+                method: Method::HEAD,
+                bucket: &self.name,
+                key: Some(key),
+                query: Vec::new(),
+                headers: Vec::new(),
+                body: Bytes::new(),
+            })
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = checked(response).await?;
+        let headers = response.headers();
+        Ok(Some(ObjectInfo {
+            key: key.to_owned(),
+            size: header(headers, CONTENT_LENGTH)
+                .and_then(|length| length.parse().ok())
+                .unwrap_or_default(),
+            content_type: header(headers, CONTENT_TYPE).map(str::to_owned),
+            last_modified: date_header(headers, LAST_MODIFIED),
+            version_id: header(headers, "x-amz-version-id").map(str::to_owned),
+            metadata: metadata(headers),
+        }))
+    }
+
+    /// List the objects whose keys start with `prefix` with
+    /// ``ListObjectsV2``, following continuation tokens to the end
+    ///
+    /// With a delimiter, keys containing it after the prefix are rolled up
+    /// into common prefixes instead, like folders.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any request fails or is
+    /// rejected.
+    pub async fn list_objects(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+    ) -> Result<S3Listing, SessionError> {
+        let mut listing = S3Listing::default();
+        let mut continuation_token = None;
+        loop {
+            let mut query = vec![
+                ("list-type", "2".to_owned()),
+                ("prefix", prefix.to_owned()),
+            ];
+            query.extend(
+                delimiter.map(|delimiter| ("delimiter", delimiter.to_owned())),
+            );
+            query.extend(
+                continuation_token.map(|token| ("continuation-token", token)),
+            );
+            let response = self
+                .client
+                .send(S3Request {
+                    operation: "s3_list_objects_v2",
+                    method: Method::GET,
+                    bucket: &self.name,
+                    key: None,
+                    query,
+                    headers: Vec::new(),
+                    body: Bytes::new(),
+                })
+                .await?;
+            let page: ListBucketResult =
+                xml_body(checked(response).await?).await?;
+            listing.objects.extend(page.contents.into_iter().map(|object| {
+                ObjectInfo {
+                    key: object.key,
+                    size: object.size,
+                    content_type: None,
+                    last_modified: object.last_modified,
+                    version_id: None,
+                    metadata: BTreeMap::new(),
+                }
+            }));
+            listing.common_prefixes.extend(
+                page.common_prefixes.into_iter().map(|common| common.prefix),
+            );
+            continuation_token =
+                page.next_continuation_token.filter(|_| page.is_truncated);
+            if continuation_token.is_none() {
+                return Ok(listing);
+            }
+        }
+    }
+
+    /// Delete an object with ``DeleteObject``
+    ///
+    /// On a versioned store such as B2, this adds a delete marker hiding the
+    /// object. Deleting an object that doesn't exist succeeds.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails or is
+    /// rejected.
+    pub async fn delete_object(&self, key: &str) -> Result<(), SessionError> {
+        let response = self
+            .client
+            .send(S3Request {
+                operation: "s3_delete_object",
+                method: Method::DELETE,
+                bucket: &self.name,
+                key: Some(key),
+                query: Vec::new(),
+                headers: Vec::new(),
+                body: Bytes::new(),
+            })
+            .await?;
+        checked(response).await.map(|_| ())
+    }
+
+    /// Start a multipart upload with ``CreateMultipartUpload``
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails or is
+    /// rejected.
+    pub async fn create_multipart_upload(
+        &self,
+        key: &str,
+        options: &UploadOptions,
+    ) -> Result<MultipartUpload, SessionError> {
+        let response = self
+            .client
+            .send(S3Request {
+                operation: "s3_create_multipart_upload",
+                method: Method::POST,
+                bucket: &self.name,
+                key: Some(key),
+                query: vec![("uploads", String::new())],
+                headers: upload_headers(options),
+                body: Bytes::new(),
+            })
+            .await?;
+        let result: InitiateMultipartUploadResult =
+            xml_body(checked(response).await?).await?;
+        Ok(MultipartUpload {
+            bucket: self.clone(),
+            key: key.to_owned(),
+            upload_id: result.upload_id,
+            options: options.clone(),
+            parts: Mutex::new(BTreeMap::new()),
+        })
+    }
+}
+
+/// A multipart upload that has been started but not completed or aborted
+///
+/// Parts can be uploaded concurrently. Like a B2 large file, every part but
+/// the last must be at least the store's minimum part size.
+#[derive(Debug)]
+pub struct MultipartUpload {
+    /// The bucket the object is uploaded into
+    bucket: S3Bucket,
+    /// The key of the object
+    key: String,
+    /// The ID of the upload
+    upload_id: String,
+    /// The options the upload was started with
+    options: UploadOptions,
+    /// The entity tag and size of every uploaded part, by part number
+    parts: Mutex<BTreeMap<u32, (String, u64)>>,
+}
+
+/// Escape text for an XML element
+fn escape_xml(text: &str) -> String {
+    quick_xml::escape::escape(text).into_owned()
+}
+
+impl MultipartUpload {
+    /// The key of the object being uploaded
+    #[must_use]
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The ID of the upload
+    #[must_use]
+    pub fn upload_id(&self) -> &str {
+        &self.upload_id
+    }
+
+    /// The parts uploaded so far
+    fn parts(&self) -> std::sync::MutexGuard<'_, BTreeMap<u32, (String, u64)>> {
+        self.parts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Upload a part with ``UploadPart``
+    ///
+    /// Part numbers run from 1 to 10000. Uploading a part number again
+    /// replaces the earlier part.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails or is
+    /// rejected, or if the response has no entity tag.
+    pub async fn upload_part(
+        &self,
+        part_number: u32,
+        data: impl Into<Bytes>,
+    ) -> Result<(), SessionError> {
+        let data = data.into();
+        let size = u64::try_from(data.len()).unwrap_or(u64::MAX);
+        let response = self
+            .bucket
+            .client
+            .send(S3Request {
+                operation: "s3_upload_part",
+                method: Method::PUT,
+                bucket: &self.bucket.name,
+                key: Some(&self.key),
+                query: vec![
+                    ("partNumber", part_number.to_string()),
+                    ("uploadId", self.upload_id.clone()),
+                ],
+                headers: Vec::new(),
+                body: data,
+            })
+            .await?;
+        let response = checked(response).await?;
+        let etag = header(response.headers(), ETAG)
+            .ok_or(SessionError::SuccessfulDeserializationFailed)?
+            .to_owned();
+        self.parts().insert(part_number, (etag, size));
+        Ok(())
+    }
+
+    /// Assemble the uploaded parts into the object with
+    /// ``CompleteMultipartUpload``
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails or is
+    /// rejected, such as when a part other than the last is too small.
+    pub async fn complete(self) -> Result<ObjectInfo, SessionError> {
+        let (mut body, size) = self.parts().iter().fold(
+            ("<CompleteMultipartUpload>".to_owned(), 0_u64),
+            |(mut body, size), (part_number, (etag, part_size))| {
+                body.push_str("<Part><PartNumber>");
+                body.push_str(&part_number.to_string());
+                body.push_str("</PartNumber><ETag>");
+                body.push_str(&escape_xml(etag));
+                body.push_str("</ETag></Part>");
+                (body, size.saturating_add(*part_size))
+            },
+        );
+        body.push_str("</CompleteMultipartUpload>");
+        let response = self
+            .bucket
+            .client
+            .send(S3Request {
+                operation: "s3_complete_multipart_upload",
+                method: Method::POST,
+                bucket: &self.bucket.name,
+                key: Some(&self.key),
+                query: vec![("uploadId", self.upload_id.clone())],
+                headers: vec![(
+                    CONTENT_TYPE.as_str().to_owned(),
+                    "application/xml".to_owned(),
+                )],
+                body: Bytes::from(body),
+            })
+            .await?;
+        let response = checked(response).await?;
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let text = response.text().await?;
+        // Completion can fail after the response has started, in which case
+        // the error arrives in the body of a 200 response
+        if let Ok(error) = quick_xml::de::from_str::<ErrorBody>(&text) {
+            return Err(SessionError::S3RequestRejected(S3Error {
+                status,
+                code: error.code,
+                message: error.message,
+            }));
+        }
+        Ok(ObjectInfo {
+            key: self.key,
+            size,
+            content_type: self.options.content_type,
+            last_modified: date_header(&headers, DATE),
+            version_id: header(&headers, "x-amz-version-id").map(str::to_owned),
+            metadata: self.options.file_info,
+        })
+    }
+
+    /// Discard the upload and its parts with ``AbortMultipartUpload``
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the request fails or is
+    /// rejected.
+    pub async fn abort(self) -> Result<(), SessionError> {
+        let response = self
+            .bucket
+            .client
+            .send(S3Request {
+                operation: "s3_abort_multipart_upload",
+                method: Method::DELETE,
+                bucket: &self.bucket.name,
+                key: Some(&self.key),
+                query: vec![("uploadId", self.upload_id.clone())],
+                headers: Vec::new(),
+                body: Bytes::new(),
+            })
+            .await?;
+        checked(response).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use b2fake::{Config, FakeB2};
+    use bytes::Bytes;
+
+    use crate::{
+        s3::region_of, tests::session, Budget, BudgetLimit, ObjectStore,
+        S3Client, SessionError, TransactionClass, UploadOptions,
+    };
+
+    #[test]
+    fn regions_come_from_b2_hosts() {
+        assert_eq!(
+            region_of("https://s3.us-west-004.backblazeb2.com"),
+            "us-west-004"
+        );
+        assert_eq!(region_of("http://127.0.0.1:9000/s3"), "us-east-1");
+    }
+
+    #[tokio::test]
+    async fn objects_round_trip() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let _id = server.create_bucket("photos", "allPrivate");
+        let bucket = session(&server).await.s3_client().bucket("photos");
+        let options = UploadOptions {
+            content_type: Some("text/plain".to_owned()),
+            file_info: BTreeMap::from([(
+                "camera".to_owned(),
+                "x100".to_owned(),
+            )]),
+        };
+        for key in ["2024/trip photo.txt", "2024/other.txt", "2025/new.txt"] {
+            let _info = bucket
+                .put_object(key, b"hello world".to_vec(), &options)
+                .await
+                .expect("Upload should succeed");
+        }
+
+        let object = bucket
+            .get_object("2024/trip photo.txt", None)
+            .await
+            .expect("Download should succeed");
+        assert_eq!(object.data, Bytes::from_static(b"hello world"));
+        assert_eq!(object.info.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(object.info.metadata, options.file_info);
+        let range = bucket
+            .get_object("2024/trip photo.txt", Some(6..11))
+            .await
+            .expect("Range download should succeed");
+        assert_eq!(range.data, Bytes::from_static(b"world"));
+        assert_eq!(range.info.size, 11);
+        assert!(matches!(
+            bucket.get_object("2024/trip photo.txt", Some(6..6)).await,
+            Err(SessionError::EmptyRange)
+        ));
+
+        let head = bucket
+            .head_object("2024/other.txt")
+            .await
+            .expect("Head should succeed")
+            .expect("Object should exist");
+        assert_eq!(head.size, 11);
+        assert!(bucket
+            .head_object("missing.txt")
+            .await
+            .expect("Head should succeed")
+            .is_none());
+
+        let listing = bucket
+            .list_objects("", Some("/"))
+            .await
+            .expect("Listing should succeed");
+        assert!(listing.objects.is_empty());
+        assert_eq!(listing.common_prefixes, ["2024/", "2025/"]);
+        let listing = bucket
+            .list_objects("2024/", None)
+            .await
+            .expect("Listing should succeed");
+        let keys = listing.objects.iter().map(|object| object.key.as_str());
+        assert_eq!(
+            keys.collect::<Vec<_>>(),
+            ["2024/other.txt", "2024/trip photo.txt"]
+        );
+
+        bucket
+            .delete_object("2024/other.txt")
+            .await
+            .expect("Delete should succeed");
+        assert!(bucket
+            .head_object("2024/other.txt")
+            .await
+            .expect("Head should succeed")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn session_clients_count_toward_the_session() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let _id = server.create_bucket("photos", "allPrivate");
+        let session = session(&server).await;
+        session.reset_metrics();
+        session.reset_usage();
+        let bucket = session.s3_client().bucket("photos");
+        let _info = bucket
+            .put_object(
+                "a.txt",
+                b"hello world".to_vec(),
+                &UploadOptions::default(),
+            )
+            .await
+            .expect("Upload should succeed");
+        let _object = bucket
+            .get_object("a.txt", None)
+            .await
+            .expect("Download should succeed");
+        let _head = bucket.head_object("a.txt").await.expect("Head");
+        let _listing =
+            bucket.list_objects("", None).await.expect("Listing should work");
+
+        let metrics = session.metrics();
+        for operation in ["s3_put_object", "s3_get_object", "s3_head_object"] {
+            assert_eq!(metrics.endpoints[operation].requests, 1);
+        }
+        let usage = session.usage();
+        assert_eq!(usage.transactions(TransactionClass::A), 1);
+        assert_eq!(usage.transactions(TransactionClass::B), 2);
+        assert_eq!(usage.transactions(TransactionClass::C), 1);
+        assert_eq!(usage.bytes_uploaded, 11);
+        assert_eq!(usage.bytes_downloaded, 11);
+
+        session.set_budget(Some(Budget {
+            max_class_b: Some(2),
+            ..Budget::default()
+        }));
+        assert!(matches!(
+            bucket.get_object("a.txt", None).await,
+            Err(SessionError::BudgetExceeded {
+                limit: BudgetLimit::ClassB
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn multipart_uploads_assemble_parts() {
+        let server = FakeB2::start_with(Config {
+            absolute_minimum_part_size: 5,
+            ..Config::default()
+        })
+        .await
+        .expect("Fake server should start");
+        let _id = server.create_bucket("photos", "allPrivate");
+        let bucket = session(&server).await.s3_client().bucket("photos");
+
+        let upload = bucket
+            .create_multipart_upload("big.bin", &UploadOptions::default())
+            .await
+            .expect("Upload should start");
+        upload
+            .upload_part(2, b"world".to_vec())
+            .await
+            .expect("Part should upload");
+        upload
+            .upload_part(1, b"hello ".to_vec())
+            .await
+            .expect("Part should upload");
+        let info = upload.complete().await.expect("Upload should complete");
+        assert_eq!(info.size, 11);
+        let object = bucket
+            .get_object("big.bin", None)
+            .await
+            .expect("Download should succeed");
+        assert_eq!(object.data, Bytes::from_static(b"hello world"));
+
+        let upload = bucket
+            .create_multipart_upload("abandoned.bin", &UploadOptions::default())
+            .await
+            .expect("Upload should start");
+        upload
+            .upload_part(1, b"partial".to_vec())
+            .await
+            .expect("Part should upload");
+        upload.abort().await.expect("Abort should succeed");
+        assert!(bucket
+            .head_object("abandoned.bin")
+            .await
+            .expect("Head should succeed")
+            .is_none());
+    }
+
+    async fn exercise(store: &impl ObjectStore) -> Vec<String> {
+        let options = UploadOptions::default();
+        let _info = store
+            .put("docs/a.txt", Bytes::from_static(b"first"), &options)
+            .await
+            .expect("Upload should succeed");
+        let parts =
+            vec![Bytes::from_static(b"part one "), Bytes::from_static(b"two")];
+        let _info = store
+            .put_parts("docs/b.txt", parts, &options)
+            .await
+            .expect("Parts should upload");
+        let object = store
+            .get("docs/b.txt", Some(5..8))
+            .await
+            .expect("Download should succeed");
+        assert_eq!(object.data, Bytes::from_static(b"one"));
+        assert_eq!(object.info.size, 12);
+        store.delete("docs/a.txt").await.expect("Delete should succeed");
+        store
+            .delete("docs/never.txt")
+            .await
+            .expect("Deleting a missing object should succeed");
+        assert!(store
+            .head("docs/a.txt")
+            .await
+            .expect("Head should succeed")
+            .is_none());
+        store
+            .list("docs/")
+            .await
+            .expect("Listing should succeed")
+            .into_iter()
+            .map(|object| object.key)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn both_apis_see_the_same_objects() {
+        let server = FakeB2::start_with(Config {
+            absolute_minimum_part_size: 5,
+            ..Config::default()
+        })
+        .await
+        .expect("Fake server should start");
+        let _id = server.create_bucket("native-api", "allPrivate");
+        let _id = server.create_bucket("s3-api", "allPrivate");
+        let session = session(&server).await;
+        let native =
+            session.bucket("native-api").await.expect("Bucket should exist");
+        let s3 = session.s3_client().bucket("s3-api");
+
+        assert_eq!(exercise(&native).await, ["docs/b.txt"]);
+        assert_eq!(exercise(&s3).await, ["docs/b.txt"]);
+
+        // Objects written through one API are visible through the other
+        let across = session.s3_client().bucket("native-api");
+        let object = across
+            .get_object("docs/b.txt", None)
+            .await
+            .expect("Download should succeed");
+        assert_eq!(object.data, Bytes::from_static(b"part one two"));
+    }
+
+    #[tokio::test]
+    async fn wrong_secrets_are_rejected() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let _id = server.create_bucket("photos", "allPrivate");
+        let credentials = server.master_credentials();
+        let client = S3Client::new(
+            server.s3_url(),
+            "us-west-004",
+            credentials.key_id,
+            "not the secret",
+        );
+        let error = client
+            .bucket("photos")
+            .put_object("a.txt", b"data".to_vec(), &UploadOptions::default())
+            .await
+            .expect_err("Upload should be rejected");
+        let SessionError::S3RequestRejected(error) = error else {
+            panic!("Expected an S3 rejection, got {error:?}");
+        };
+        assert_eq!(error.status, 403);
+        assert_eq!(error.code, "SignatureDoesNotMatch");
+    }
+}
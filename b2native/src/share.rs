@@ -0,0 +1,352 @@
+//! Sharing files in private buckets through time-limited links
+//!
+//! ``b2_get_download_authorization`` issues a token that can only download
+//! files under one prefix of one bucket, and only for a limited time. Put in
+//! the query of a download URL, it lets anyone with the link fetch the file
+//! without an application key.
+
+use std::{fmt, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
+
+use crate::{
+    api::{b2_get_download_authorization, percent_encode, OutgoingRequest},
+    Bucket, SessionError,
+};
+
+/// The longest B2 lets a download authorization stay valid
+pub const MAX_SHARE_DURATION: Duration = Duration::from_hours(7 * 24);
+
+/// Headers to override when a shared file is downloaded
+///
+/// Every override set here is baked into the authorization, so links made
+/// from it only work with exactly these values.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShareOptions {
+    /// The `Content-Disposition` to serve, such as
+    /// `attachment; filename="report.pdf"`
+    pub content_disposition: Option<String>,
+    /// The `Content-Language` to serve
+    pub content_language: Option<String>,
+    /// The `Expires` header to serve
+    pub expires: Option<String>,
+    /// The `Cache-Control` to serve
+    pub cache_control: Option<String>,
+    /// The `Content-Encoding` to serve
+    pub content_encoding: Option<String>,
+    /// The `Content-Type` to serve
+    pub content_type: Option<String>,
+}
+
+impl ShareOptions {
+    /// The overrides as query parameters, in the order B2 documents them
+    fn parameters(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("b2ContentDisposition", &self.content_disposition),
+            ("b2ContentLanguage", &self.content_language),
+            ("b2Expires", &self.expires),
+            ("b2CacheControl", &self.cache_control),
+            ("b2ContentEncoding", &self.content_encoding),
+            ("b2ContentType", &self.content_type),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+    }
+}
+
+/// A token allowing downloads of files under a prefix of a bucket
+///
+/// Create one with [`Bucket::download_authorization`]. Anyone holding the
+/// token can download the files it covers until it expires, so it should be
+/// handed out as carefully as the files themselves.
+#[derive(Clone)]
+pub struct DownloadAuthorization {
+    /// The name of the bucket the token is for
+    bucket_name: String,
+    /// The prefix the names of downloadable files start with
+    file_name_prefix: String,
+    /// The token
+    token: String,
+    /// When the token expires
+    expires_at: DateTime<Utc>,
+    /// The base URL downloads are served from
+    download_url: String,
+    /// The headers the token was issued to override
+    options: ShareOptions,
+}
+
+impl fmt::Debug for DownloadAuthorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DownloadAuthorization")
+            .field("bucket_name", &self.bucket_name)
+            .field("file_name_prefix", &self.file_name_prefix)
+            .field("token", &"<redacted>")
+            .field("expires_at", &self.expires_at)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DownloadAuthorization {
+    /// The prefix the names of downloadable files start with
+    #[must_use]
+    pub fn file_name_prefix(&self) -> &str {
+        &self.file_name_prefix
+    }
+
+    /// The token, for sending in an `Authorization` header
+    #[must_use]
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// When the token expires
+    #[must_use]
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.expires_at
+    }
+
+    /// A link that downloads a file with this token
+    ///
+    /// Returns `None` if the file's name doesn't start with the token's
+    /// prefix, since B2 would refuse the download.
+    #[must_use]
+    pub fn url(&self, file_name: &str) -> Option<String> {
+        if !file_name.starts_with(self.file_name_prefix.as_str()) {
+            return None;
+        }
+        let overrides = self.options.parameters().fold(
+            String::new(),
+            |mut query, (name, value)| {
+                query.push('&');
+                query.push_str(name);
+                query.push('=');
+                query.extend(utf8_percent_encode(value, NON_ALPHANUMERIC));
+                query
+            },
+        );
+        Some(format!(
+            "{}/file/{}/{}?Authorization={}{overrides}",
+            self.download_url,
+            percent_encode(&self.bucket_name),
+            percent_encode(file_name),
+            utf8_percent_encode(&self.token, NON_ALPHANUMERIC),
+        ))
+    }
+}
+
+/// A time-limited link to a single file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareLink {
+    /// The name of the shared file
+    pub file_name: String,
+    /// The link, including the token
+    pub url: String,
+    /// When the link stops working
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Bucket {
+    /// Create a token allowing downloads of files under a prefix of this
+    /// bucket for a limited time
+    ///
+    /// `valid_for` is rounded up to whole seconds. B2 accepts at most
+    /// [`MAX_SHARE_DURATION`]; longer durations are rejected by the server.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the following errors:
+    /// - `SessionError::RequestFailed`
+    /// - `SessionError::RequestRejected`
+    /// - `SessionError::MissingCapability`
+    /// - `SessionError::OutsideBucketRestriction`
+    /// - `SessionError::OutsideNamePrefixRestriction`
+    pub async fn download_authorization(
+        &self,
+        file_name_prefix: &str,
+        valid_for: Duration,
+        options: &ShareOptions,
+    ) -> Result<DownloadAuthorization, SessionError> {
+        let seconds = valid_for
+            .as_secs()
+            .saturating_add(u64::from(valid_for.subsec_nanos() > 0))
+            .max(1);
+        let issued_at = Utc::now();
+        let response = self
+            .session()
+            .send(b2_get_download_authorization::Request {
+                bucket_id: self.id().to_owned(),
+                file_name_prefix: file_name_prefix.to_owned(),
+                valid_duration_in_seconds: seconds,
+                b2_content_disposition: options.content_disposition.clone(),
+                b2_content_language: options.content_language.clone(),
+                b2_expires: options.expires.clone(),
+                b2_cache_control: options.cache_control.clone(),
+                b2_content_encoding: options.content_encoding.clone(),
+                b2_content_type: options.content_type.clone(),
+            })
+            .await
+            .into_result()?;
+        let valid_for = i64::try_from(seconds)
+            .ok()
+            .and_then(TimeDelta::try_seconds)
+            .unwrap_or(TimeDelta::MAX);
+        Ok(DownloadAuthorization {
+            bucket_name: self.name().to_owned(),
+            file_name_prefix: response.file_name_prefix,
+            token: response.authorization_token,
+            expires_at: issued_at
+                .checked_add_signed(valid_for)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            download_url: self
+                .session()
+                .authorization()
+                .storage_api_info
+                .download_url
+                .clone(),
+            options: options.clone(),
+        })
+    }
+
+    /// Create a time-limited link to a single file in this bucket
+    ///
+    /// The link's token only covers names starting with the file's name, so
+    /// it can't be used to download anything else in the bucket, apart from
+    /// files whose names extend this one.
+    ///
+    /// # Errors
+    ///
+    /// This function can return the same errors as
+    /// [`Bucket::download_authorization`].
+    pub async fn share_link(
+        &self,
+        file_name: &str,
+        valid_for: Duration,
+        options: &ShareOptions,
+    ) -> Result<ShareLink, SessionError> {
+        let authorization =
+            self.download_authorization(file_name, valid_for, options).await?;
+        let url = authorization.url(file_name).unwrap_or_default();
+        Ok(ShareLink {
+            file_name: file_name.to_owned(),
+            url,
+            expires_at: authorization.expires_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use b2fake::{FakeB2, KeySpec};
+    use reqwest::{header::CONTENT_DISPOSITION, StatusCode};
+
+    use crate::{
+        tests::authorize, Capability, Session, SessionError, ShareOptions,
+        UploadOptions,
+    };
+
+    async fn session(server: &FakeB2, capabilities: &[&str]) -> Session {
+        let spec = KeySpec {
+            name: "sharing".to_owned(),
+            capabilities: capabilities.iter().map(|&c| c.to_owned()).collect(),
+            ..KeySpec::default()
+        };
+        authorize(server, server.add_key(spec)).await
+    }
+
+    #[tokio::test]
+    async fn links_download_single_files() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let _id = server.create_bucket("restored", "allPrivate");
+        let session =
+            session(&server, &["listBuckets", "writeFiles", "shareFiles"])
+                .await;
+        let bucket =
+            session.bucket("restored").await.expect("Bucket should exist");
+        for name in ["customer/report final.pdf", "customer/other.pdf"] {
+            let _file = bucket
+                .upload_file(
+                    name,
+                    b"restored".to_vec(),
+                    &UploadOptions::default(),
+                )
+                .await
+                .expect("Upload should succeed");
+        }
+        let options = ShareOptions {
+            content_disposition: Some(
+                "attachment; filename=\"report.pdf\"".to_owned(),
+            ),
+            ..ShareOptions::default()
+        };
+        let link = bucket
+            .share_link(
+                "customer/report final.pdf",
+                Duration::from_hours(1),
+                &options,
+            )
+            .await
+            .expect("Sharing should succeed");
+        let response =
+            reqwest::get(&link.url).await.expect("Download should be sent");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=\"report.pdf\""
+        );
+        assert_eq!(
+            response.bytes().await.expect("Body should arrive").as_ref(),
+            b"restored"
+        );
+
+        let other = link.url.replace("report%20final", "other");
+        let response =
+            reqwest::get(&other).await.expect("Download should be sent");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let authorization = bucket
+            .download_authorization(
+                "customer/",
+                Duration::from_secs(1),
+                &ShareOptions::default(),
+            )
+            .await
+            .expect("Sharing should succeed");
+        assert!(authorization.url("elsewhere.pdf").is_none());
+        let url = authorization
+            .url("customer/other.pdf")
+            .expect("File is under the prefix");
+        server.expire_tokens();
+        let response = reqwest::get(&url).await.expect("Download is sent");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn sharing_needs_the_capability() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let _id = server.create_bucket("restored", "allPrivate");
+        let session = session(&server, &["listBuckets"]).await;
+        let bucket =
+            session.bucket("restored").await.expect("Bucket should exist");
+        let error = bucket
+            .share_link(
+                "a.txt",
+                Duration::from_hours(1),
+                &ShareOptions::default(),
+            )
+            .await
+            .expect_err("Sharing should be refused");
+        assert!(matches!(
+            error,
+            SessionError::MissingCapability {
+                capability: Capability::ShareFiles
+            }
+        ));
+        assert_eq!(server.calls("b2_get_download_authorization"), 0);
+    }
+}
@@ -0,0 +1,591 @@
+//! Limiting the bandwidth used by transfers
+//!
+//! Every session has a [`Throttle`] that all of its uploads and downloads
+//! draw from, so a limit applies to the session as a whole no matter how many
+//! transfers run at once. The limits follow a weekly [`Schedule`], and both the
+//! schedule and the clock it is read from can be replaced at runtime.
+
+use std::{
+    cmp::Ordering,
+    convert::Infallible,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{progress::Transfer, Session};
+
+/// How many bytes of a transfer are throttled at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How far ahead of its limit a transfer may get
+///
+/// Transfers that were idle may send this long's worth of data at once before
+/// being slowed down.
+const BURST: Duration = Duration::from_secs(1);
+
+/// A source of time for a [`Throttle`]
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The time elapsed since some fixed point, which never goes backwards
+    fn elapsed(&self) -> Duration;
+
+    /// The current local date and time, used to follow a [`Schedule`]
+    fn local_time(&self) -> NaiveDateTime;
+
+    /// Wait for some time to pass
+    fn sleep(
+        &self,
+        duration: Duration,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The clock of the system the library runs on
+#[derive(Debug)]
+pub struct SystemClock {
+    /// When the clock was created
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn local_time(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+
+    fn sleep(
+        &self,
+        duration: Duration,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves when told to, for testing
+///
+/// Sleeping on a mock clock advances it by the time slept and returns
+/// immediately, so throttled transfers finish instantly while the clock shows
+/// how long they would have taken.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    /// The local time the clock started at
+    start: NaiveDateTime,
+    /// The time elapsed since the clock started
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl MockClock {
+    /// Create a clock starting at a local date and time
+    #[must_use]
+    pub fn new(start: NaiveDateTime) -> Self {
+        Self {
+            start,
+            elapsed: Arc::default(),
+        }
+    }
+
+    /// Move the clock forward
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner) +=
+            duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn local_time(&self) -> NaiveDateTime {
+        self.start + self.elapsed()
+    }
+
+    fn sleep(
+        &self,
+        duration: Duration,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.advance(duration);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+/// Bandwidth limits in bytes per second
+///
+/// `None` means unlimited.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    /// The limit for uploads
+    pub upload: Option<u64>,
+    /// The limit for downloads
+    pub download: Option<u64>,
+}
+
+impl Limits {
+    /// No limits at all
+    pub const UNLIMITED: Self = Self {
+        upload: None,
+        download: None,
+    };
+
+    /// The limit for a direction of transfer
+    fn get(self, direction: Direction) -> Option<u64> {
+        match direction {
+            Direction::Upload => self.upload,
+            Direction::Download => self.download,
+        }
+    }
+}
+
+/// A period of the week with its own limits
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleRule {
+    /// The days the period starts on
+    pub days: Vec<Weekday>,
+    /// The local time the period starts at
+    pub start: NaiveTime,
+    /// The local time the period ends at
+    ///
+    /// An end before the start means the period runs past midnight into the
+    /// next day. An end equal to the start means the period lasts all day.
+    pub end: NaiveTime,
+    /// The limits during the period
+    pub limits: Limits,
+}
+
+impl ScheduleRule {
+    /// A period on Monday through Friday
+    #[must_use]
+    pub fn weekdays(start: NaiveTime, end: NaiveTime, limits: Limits) -> Self {
+        Self {
+            days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+            start,
+            end,
+            limits,
+        }
+    }
+
+    /// A period on every day of the week
+    #[must_use]
+    pub fn every_day(start: NaiveTime, end: NaiveTime, limits: Limits) -> Self {
+        Self {
+            days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+            ],
+            start,
+            end,
+            limits,
+        }
+    }
+
+    /// Whether the period includes a local date and time
+    fn includes(&self, at: NaiveDateTime) -> bool {
+        let (day, time) = (at.weekday(), at.time());
+        match self.start.cmp(&self.end) {
+            Ordering::Equal => self.days.contains(&day),
+            Ordering::Less => {
+                self.days.contains(&day)
+                    && self.start <= time
+                    && time < self.end
+            }
+            Ordering::Greater => {
+                (self.days.contains(&day) && self.start <= time)
+                    || (self.days.contains(&day.pred()) && time < self.end)
+            }
+        }
+    }
+}
+
+/// Weekly bandwidth limits
+///
+/// The limits at any time are those of the first rule including that time,
+/// or the default limits if no rule does.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    /// The periods with their own limits, in order of precedence
+    pub rules: Vec<ScheduleRule>,
+    /// The limits outside of every period
+    pub default: Limits,
+}
+
+impl Schedule {
+    /// A schedule with the same limits at all times
+    #[must_use]
+    pub fn constant(limits: Limits) -> Self {
+        Self {
+            rules: Vec::new(),
+            default: limits,
+        }
+    }
+
+    /// Add a rule, with lower precedence than the rules already added
+    #[must_use]
+    pub fn with_rule(mut self, rule: ScheduleRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// The limits at a local date and time
+    #[must_use]
+    pub fn limits_at(&self, at: NaiveDateTime) -> Limits {
+        self.rules
+            .iter()
+            .find(|rule| rule.includes(at))
+            .map_or(self.default, |rule| rule.limits)
+    }
+}
+
+/// A direction of transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// Sending data to B2
+    Upload,
+    /// Receiving data from B2
+    Download,
+}
+
+/// The state of one direction of a throttle
+///
+/// This implements the generic cell rate algorithm: every byte moves the
+/// theoretical arrival time forward by the time it takes to send at the
+/// limit, and transfers wait whenever it gets too far ahead of the clock.
+#[derive(Debug, Default)]
+struct Lane {
+    /// The limit the arrival time was computed with
+    rate: Option<u64>,
+    /// When the bytes allowed so far would have finished at the limit
+    arrival: Duration,
+}
+
+impl Lane {
+    /// Account for `bytes` at time `now`, returning how long to wait
+    fn take(
+        &mut self,
+        rate: Option<u64>,
+        bytes: usize,
+        now: Duration,
+    ) -> Duration {
+        if rate != self.rate {
+            self.rate = rate;
+            self.arrival = now;
+        }
+        let Some(rate) = rate.filter(|&rate| rate > 0) else {
+            return Duration::ZERO;
+        };
+        let nanos = u128::try_from(bytes).unwrap_or(u128::MAX) * 1_000_000_000
+            / u128::from(rate);
+        let cost =
+            Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX));
+        self.arrival = self.arrival.max(now) + cost;
+        self.arrival.saturating_sub(now).saturating_sub(BURST)
+    }
+}
+
+/// The mutable state of a [`Throttle`]
+#[derive(Debug)]
+struct ThrottleState {
+    /// The schedule limits are read from
+    schedule: Schedule,
+    /// The state of uploads
+    upload: Lane,
+    /// The state of downloads
+    download: Lane,
+}
+
+/// A bandwidth limiter shared by transfers
+///
+/// A throttle can be shared between sessions with [`Session::set_throttle`]
+/// so that they stay under one limit together.
+///
+/// [`Session::set_throttle`]: crate::Session::set_throttle
+#[derive(Debug)]
+pub struct Throttle {
+    /// The clock the schedule and limits are measured against
+    clock: Arc<dyn Clock>,
+    /// The schedule and the state of each direction
+    state: Mutex<ThrottleState>,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(Schedule::default())
+    }
+}
+
+impl Throttle {
+    /// Create a throttle following a schedule on the system clock
+    #[must_use]
+    pub fn new(schedule: Schedule) -> Self {
+        Self::with_clock(schedule, Arc::new(SystemClock::default()))
+    }
+
+    /// Create a throttle following a schedule on a specific clock
+    #[must_use]
+    pub fn with_clock(schedule: Schedule, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            state: Mutex::new(ThrottleState {
+                schedule,
+                upload: Lane::default(),
+                download: Lane::default(),
+            }),
+        }
+    }
+
+    /// Lock the state of the throttle
+    fn state(&self) -> std::sync::MutexGuard<'_, ThrottleState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The schedule the throttle follows
+    #[must_use]
+    pub fn schedule(&self) -> Schedule {
+        self.state().schedule.clone()
+    }
+
+    /// Replace the schedule the throttle follows
+    ///
+    /// Transfers already in progress pick up the new limits with their next
+    /// chunk of data.
+    pub fn set_schedule(&self, schedule: Schedule) {
+        self.state().schedule = schedule;
+    }
+
+    /// Follow the same limits at all times
+    pub fn set_limits(&self, limits: Limits) {
+        self.set_schedule(Schedule::constant(limits));
+    }
+
+    /// The limits in effect right now
+    #[must_use]
+    pub fn current_limits(&self) -> Limits {
+        self.state().schedule.limits_at(self.clock.local_time())
+    }
+
+    /// Wait until `bytes` more bytes may be transferred
+    pub(crate) async fn acquire(&self, direction: Direction, bytes: usize) {
+        let wait = {
+            let now = self.clock.elapsed();
+            let mut state = self.state();
+            let rate = state
+                .schedule
+                .limits_at(self.clock.local_time())
+                .get(direction);
+            let lane = match direction {
+                Direction::Upload => &mut state.upload,
+                Direction::Download => &mut state.download,
+            };
+            lane.take(rate, bytes, now)
+        };
+        if !wait.is_zero() {
+            self.clock.sleep(wait).await;
+        }
+    }
+}
+
+impl Session {
+    /// The throttle limiting the transfers of this session
+    #[must_use]
+    pub fn throttle(&self) -> Arc<Throttle> {
+        Arc::clone(
+            &self.inner.throttle.read().unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Replace the throttle limiting the transfers of this session
+    ///
+    /// Passing the same throttle to several sessions keeps their combined
+    /// transfers under one limit. Transfers already in progress keep using
+    /// the throttle they started with.
+    pub fn set_throttle(&self, throttle: Arc<Throttle>) {
+        *self.inner.throttle.write().unwrap_or_else(PoisonError::into_inner) =
+            throttle;
+    }
+}
+
+/// Turn data to upload into a request body that is throttled as it is sent
+///
+/// Each chunk has to pass every throttle in `throttles`, in order.
+/// Every chunk is reported to `transfer` as it is handed to the connection.
+pub(crate) fn upload_body(
+    throttles: Vec<Arc<Throttle>>,
+    transfer: Transfer,
+    data: Bytes,
+) -> reqwest::Body {
+    let chunks = stream::unfold(
+        (throttles, transfer, data),
+        |(throttles, transfer, mut data)| async {
+            if data.is_empty() {
+                return None;
+            }
+            let chunk = data.split_to(CHUNK_SIZE.min(data.len()));
+            for throttle in &throttles {
+                throttle.acquire(Direction::Upload, chunk.len()).await;
+            }
+            transfer.sent(chunk.len());
+            Some((Ok::<_, Infallible>(chunk), (throttles, transfer, data)))
+        },
+    );
+    reqwest::Body::wrap_stream(chunks)
+}
+
+/// Read the body of a download, passing every chunk through each of
+/// `throttles` as it is received
+///
+/// The body is read as a stream, and the next piece is only read from the
+/// connection once the previous one has passed the throttles, so a limited
+/// download doesn't get ahead of its limit. Pieces larger than a throttling
+/// chunk are split so the limit is applied just as evenly as for uploads.
+/// The body itself is still collected in memory, because downloads are
+/// handed back whole.
+pub(crate) async fn download_body(
+    throttles: &[Arc<Throttle>],
+    transfer: &Transfer,
+    response: reqwest::Response,
+) -> Result<Bytes, reqwest::Error> {
+    let mut body = BytesMut::new();
+    let mut pieces = response.bytes_stream();
+    while let Some(mut piece) = pieces.next().await.transpose()? {
+        while !piece.is_empty() {
+            let chunk = piece.split_to(CHUNK_SIZE.min(piece.len()));
+            for throttle in throttles {
+                throttle.acquire(Direction::Download, chunk.len()).await;
+            }
+            transfer.received(chunk.len());
+            body.extend_from_slice(&chunk);
+        }
+    }
+    Ok(body.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+
+    use crate::throttle::{
+        Clock, Direction, Limits, MockClock, Schedule, ScheduleRule, Throttle,
+    };
+
+    /// 2024-01-01 was a Monday
+    fn monday(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, 1)
+            .and_then(|date| date.and_hms_opt(hour, minute, 0))
+            .expect("Date should be valid")
+    }
+
+    fn time(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).expect("Time should be valid")
+    }
+
+    fn office_hours() -> Schedule {
+        Schedule::default().with_rule(ScheduleRule::weekdays(
+            time(9),
+            time(18),
+            Limits {
+                upload: Some(2_000_000),
+                download: None,
+            },
+        ))
+    }
+
+    #[test]
+    fn schedule_follows_the_week() {
+        let schedule = office_hours();
+        let limited = Limits {
+            upload: Some(2_000_000),
+            download: None,
+        };
+        assert_eq!(schedule.limits_at(monday(8, 59)), Limits::UNLIMITED);
+        assert_eq!(schedule.limits_at(monday(9, 0)), limited);
+        assert_eq!(schedule.limits_at(monday(17, 59)), limited);
+        assert_eq!(schedule.limits_at(monday(18, 0)), Limits::UNLIMITED);
+        let saturday = monday(12, 0) + chrono::Duration::days(5);
+        assert_eq!(schedule.limits_at(saturday), Limits::UNLIMITED);
+    }
+
+    #[test]
+    fn overnight_rules_wrap() {
+        let schedule = Schedule::default().with_rule(ScheduleRule {
+            days: vec![chrono::Weekday::Mon],
+            start: time(22),
+            end: time(6),
+            limits: Limits {
+                upload: Some(1),
+                download: Some(1),
+            },
+        });
+        assert_eq!(schedule.limits_at(monday(21, 0)), Limits::UNLIMITED);
+        assert_ne!(schedule.limits_at(monday(23, 0)), Limits::UNLIMITED);
+        let tuesday = monday(5, 0) + chrono::Duration::days(1);
+        assert_ne!(schedule.limits_at(tuesday), Limits::UNLIMITED);
+        assert_eq!(schedule.limits_at(monday(5, 0)), Limits::UNLIMITED);
+    }
+
+    #[tokio::test]
+    async fn throttle_limits_rate() {
+        let clock = MockClock::new(monday(12, 0));
+        let throttle = Throttle::with_clock(
+            Schedule::constant(Limits {
+                upload: Some(1000),
+                download: None,
+            }),
+            Arc::new(clock.clone()),
+        );
+        for _ in 0..10 {
+            throttle.acquire(Direction::Upload, 500).await;
+        }
+        assert_eq!(clock.elapsed(), Duration::from_secs(4));
+        throttle.acquire(Direction::Download, 1_000_000).await;
+        assert_eq!(clock.elapsed(), Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn throttle_follows_schedule() {
+        let clock = MockClock::new(monday(8, 59));
+        let throttle =
+            Throttle::with_clock(office_hours(), Arc::new(clock.clone()));
+        throttle.acquire(Direction::Upload, 10_000_000).await;
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        clock.advance(Duration::from_mins(1));
+        throttle.acquire(Direction::Upload, 10_000_000).await;
+        assert_eq!(clock.elapsed(), Duration::from_secs(64));
+        throttle.set_limits(Limits::UNLIMITED);
+        throttle.acquire(Direction::Upload, 10_000_000).await;
+        assert_eq!(clock.elapsed(), Duration::from_secs(64));
+    }
+}
@@ -0,0 +1,331 @@
+//! Reusing upload URLs between uploads
+//!
+//! Every upload needs an upload URL and a matching authorization token from
+//! ``b2_get_upload_url`` or ``b2_get_upload_part_url``. Getting one costs a
+//! round trip, but each URL only accepts one upload at a time, so URLs are
+//! kept in a pool: an upload takes a URL out, and puts it back once the upload
+//! succeeds. URLs that fail are dropped, as B2 asks, and the upload is retried
+//! with a different one.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::Instrument;
+
+use crate::{
+    api::{
+        b2_get_upload_part_url, b2_get_upload_url, ApiResponse, ApiResult,
+        OutgoingRequest,
+    },
+    metrics::operation_span,
+    progress::Transfer,
+    ApiError, Session, SessionError,
+};
+
+/// How many times an upload is attempted before giving up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long to wait before the first retry of an upload
+///
+/// The wait doubles with every further retry.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What an upload URL uploads into
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum UploadTarget {
+    /// Simple uploads into the bucket with this ID
+    Bucket(String),
+    /// Part uploads into the unfinished large file with this ID
+    LargeFile(String),
+}
+
+/// An upload URL and the authorization token to use with it
+#[derive(Clone)]
+pub(crate) struct UploadUrl {
+    /// The URL to send uploads to
+    pub(crate) upload_url: String,
+    /// The authorization token to send with uploads
+    pub(crate) authorization_token: String,
+}
+
+impl std::fmt::Debug for UploadUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UploadUrl")
+            .field("upload_url", &self.upload_url)
+            .field("authorization_token", &"<redacted>")
+            .finish()
+    }
+}
+
+/// The upload URLs of one target
+#[derive(Debug)]
+struct Slot {
+    /// URLs that aren't in use
+    idle: Vec<UploadUrl>,
+    /// One permit for every URL that may exist at once
+    permits: Arc<Semaphore>,
+}
+
+/// A pool of upload URLs shared by every clone of a session
+#[derive(Debug)]
+pub(crate) struct UploadUrlPool {
+    /// The most URLs that may exist at once for any one target
+    limit: usize,
+    /// The URLs of every target that has been uploaded to
+    slots: Mutex<HashMap<UploadTarget, Slot>>,
+}
+
+/// Permission to use an upload URL, taken from an [`UploadUrlPool`]
+///
+/// The URL is `None` when the pool had no idle URL and a new one has to be
+/// requested. Dropping the lease drops the URL.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    /// The target the URL uploads into
+    target: UploadTarget,
+    /// An idle URL taken from the pool
+    url: Option<UploadUrl>,
+    /// Counts this URL against the limit of its target
+    _permit: OwnedSemaphorePermit,
+}
+
+impl UploadUrlPool {
+    /// Create an empty pool allowing `limit` URLs per target
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lock the slots of the pool
+    fn slots(&self) -> std::sync::MutexGuard<'_, HashMap<UploadTarget, Slot>> {
+        self.slots.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Take a URL for `target`, waiting if the limit of URLs for it is in use
+    pub(crate) async fn acquire(&self, target: &UploadTarget) -> Lease {
+        let permits = Arc::clone(
+            &self
+                .slots()
+                .entry(target.clone())
+                .or_insert_with(|| Slot {
+                    idle: Vec::new(),
+                    permits: Arc::new(Semaphore::new(self.limit)),
+                })
+                .permits,
+        );
+        let permit = permits
+            .acquire_owned()
+            .await
+            .expect("Upload URL semaphores are never closed");
+        let url = self.slots().get_mut(target).and_then(|slot| slot.idle.pop());
+        Lease {
+            target: target.clone(),
+            url,
+            _permit: permit,
+        }
+    }
+
+    /// Put a URL that just succeeded back into the pool
+    pub(crate) fn release(&self, lease: Lease, url: UploadUrl) {
+        // The permit is only given up once the URL is idle again, so that a
+        // waiting upload finds it instead of requesting a new one
+        let Lease {
+            target,
+            _permit,
+            ..
+        } = lease;
+        if let Some(slot) = self.slots().get_mut(&target) {
+            slot.idle.push(url);
+        }
+    }
+
+    /// Forget every URL of a target that won't be uploaded to again
+    pub(crate) fn forget(&self, target: &UploadTarget) {
+        self.slots().remove(target);
+    }
+
+    /// The number of idle URLs for a target
+    #[cfg(test)]
+    fn idle(&self, target: &UploadTarget) -> usize {
+        self.slots().get(target).map_or(0, |slot| slot.idle.len())
+    }
+}
+
+/// Whether an upload that failed this way should be retried with a new URL
+fn is_retryable(
+    result: &ApiResult<impl Sized, ApiError, SessionError>,
+) -> bool {
+    match result {
+        ApiResult::Response(ApiResponse::Ok(_)) => false,
+        ApiResult::Response(ApiResponse::Error(error)) => {
+            error.status >= 500
+                || error.status == 408
+                || error.status == 429
+                || error.code.is_token_rejection()
+        }
+        ApiResult::Failure(failure) => {
+            matches!(failure, SessionError::RequestFailed)
+        }
+    }
+}
+
+/// Describe why an upload is being retried
+fn retry_reason(
+    result: &ApiResult<impl Sized, ApiError, SessionError>,
+) -> String {
+    match result {
+        ApiResult::Response(ApiResponse::Error(error)) => {
+            format!("{} {}", error.status, error.message)
+        }
+        ApiResult::Response(ApiResponse::Ok(_)) | ApiResult::Failure(_) => {
+            "the request failed".to_owned()
+        }
+    }
+}
+
+impl Session {
+    /// Request a new upload URL for a target
+    async fn new_upload_url(
+        &self,
+        target: &UploadTarget,
+    ) -> Result<UploadUrl, SessionError> {
+        match target {
+            UploadTarget::Bucket(bucket_id) => {
+                let response = self
+                    .send(b2_get_upload_url::Request {
+                        bucket_id: bucket_id.clone(),
+                    })
+                    .await
+                    .into_result()?;
+                Ok(UploadUrl {
+                    upload_url: response.upload_url,
+                    authorization_token: response.authorization_token,
+                })
+            }
+            UploadTarget::LargeFile(file_id) => {
+                let response = self
+                    .send(b2_get_upload_part_url::Request {
+                        file_id: file_id.clone(),
+                    })
+                    .await
+                    .into_result()?;
+                Ok(UploadUrl {
+                    upload_url: response.upload_url,
+                    authorization_token: response.authorization_token,
+                })
+            }
+        }
+    }
+
+    /// Run an upload with a URL from the pool, retrying with new URLs
+    ///
+    /// The URL is put back into the pool if `upload` succeeds and dropped if
+    /// it fails. Failures that B2 documents as worth retrying, such as 503
+    /// responses and connection errors, are retried a few times with backoff,
+    /// and every retry is reported to `transfer`.
+    pub(crate) async fn with_upload_url<T, F, Fut>(
+        &self,
+        target: &UploadTarget,
+        transfer: &Transfer,
+        mut upload: F,
+    ) -> Result<T, SessionError>
+    where
+        F: FnMut(UploadUrl) -> Fut,
+        Fut: Future<Output = ApiResult<T, ApiError, SessionError>>,
+    {
+        let (endpoint, bucket) = match target {
+            UploadTarget::Bucket(bucket_id) => {
+                ("b2_upload_file", Some(bucket_id.as_str()))
+            }
+            UploadTarget::LargeFile(_) => ("b2_upload_part", None),
+        };
+        async {
+            let mut backoff = INITIAL_BACKOFF;
+            let mut attempt = 1;
+            loop {
+                let mut lease = self.inner.upload_urls.acquire(target).await;
+                let url = match lease.url.take() {
+                    Some(url) => url,
+                    None => self.new_upload_url(target).await?,
+                };
+                let result = upload(url.clone()).await;
+                if attempt < MAX_ATTEMPTS && is_retryable(&result) {
+                    drop(lease);
+                    transfer.retry(attempt, retry_reason(&result));
+                    self.inner.metrics.retry(endpoint, attempt);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                    continue;
+                }
+                if let ApiResult::Response(ApiResponse::Ok(_)) = result {
+                    self.inner.upload_urls.release(lease, url);
+                }
+                return result.into_result();
+            }
+        }
+        .instrument(operation_span(endpoint, bucket))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::upload_urls::{UploadTarget, UploadUrl, UploadUrlPool};
+
+    fn url(name: &str) -> UploadUrl {
+        UploadUrl {
+            upload_url: name.to_owned(),
+            authorization_token: "token".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn released_urls_are_reused() {
+        let pool = UploadUrlPool::new(2);
+        let target = UploadTarget::Bucket("bucket".to_owned());
+        let lease = pool.acquire(&target).await;
+        assert!(lease.url.is_none());
+        pool.release(lease, url("a"));
+        assert_eq!(pool.idle(&target), 1);
+        let lease = pool.acquire(&target).await;
+        assert_eq!(
+            lease.url.as_ref().map(|u| u.upload_url.as_str()),
+            Some("a")
+        );
+        drop(lease);
+        assert_eq!(pool.idle(&target), 0);
+    }
+
+    #[tokio::test]
+    async fn leases_are_capped_per_target() {
+        let pool = UploadUrlPool::new(2);
+        let target = UploadTarget::Bucket("bucket".to_owned());
+        let other = UploadTarget::LargeFile("file".to_owned());
+        let first = pool.acquire(&target).await;
+        let _second = pool.acquire(&target).await;
+        let _elsewhere = pool.acquire(&other).await;
+        assert!(tokio::time::timeout(
+            Duration::from_millis(50),
+            pool.acquire(&target)
+        )
+        .await
+        .is_err());
+        drop(first);
+        assert!(tokio::time::timeout(
+            Duration::from_millis(50),
+            pool.acquire(&target)
+        )
+        .await
+        .is_ok());
+    }
+}
@@ -0,0 +1,1066 @@
+//! Backing up directories into a repository
+//!
+//! A backup run scans the sources, splits every file into chunks, compresses
+//! and packs the chunks the repository doesn't hold yet and uploads the
+//! packs as they fill, and finishes by writing an index of the new packs and
+//! the snapshot manifest. Chunks are shared by every file, snapshot and
+//! machine that backs up into the repository, so each is only stored once.
+//! Until the manifest is written the run leaves no snapshot behind, only
+//! packs that a later run will reuse. With a local cache, files that haven't
+//! changed since the last run aren't read at all. Paths the backup rules
+//! exclude are never scanned.
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use b2native::ObjectStore;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::{
+    cache::{CachedFile, FileCache, FileStamp},
+    chunker::chunks,
+    compression::{compress, ratio, DEFAULT_COMPRESSION_LEVEL},
+    pack::{PackIndex, PackWriter},
+    rules::Matcher,
+    snapshot::{new_snapshot_id, Entry, EntryKind, Snapshot, Source},
+    BackupRules, EngineError, Repository,
+};
+
+/// The name of this machine, as snapshots record it
+pub(crate) fn local_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Optional settings for a backup run
+#[derive(Clone, Debug)]
+pub struct BackupOptions {
+    /// The machine name to record in the snapshot
+    ///
+    /// When `None`, the name of this machine is used.
+    pub hostname: Option<String>,
+    /// The labels to record in the snapshot
+    pub tags: Vec<String>,
+    /// The zstd level new chunks are compressed at, or `None` to store them
+    /// uncompressed
+    ///
+    /// Chunks that don't look compressible are stored uncompressed either
+    /// way. Levels outside zstd's range are clamped to it.
+    pub compression_level: Option<i32>,
+    /// The local cache to skip unchanged files with, and to record the
+    /// files read in
+    pub cache: Option<Arc<FileCache>>,
+    /// Which paths under the directories to back up
+    pub rules: BackupRules,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        Self {
+            hostname: None,
+            tags: Vec::new(),
+            compression_level: Some(DEFAULT_COMPRESSION_LEVEL),
+            cache: None,
+            rules: BackupRules::default(),
+        }
+    }
+}
+
+/// What a backup run did
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupSummary {
+    /// The ID of the snapshot that was written
+    pub snapshot_id: String,
+    /// The number of files in the snapshot
+    pub files: u64,
+    /// The number of files the local cache showed were unchanged, which
+    /// weren't read
+    pub unchanged_files: u64,
+    /// The number of files and directories the rules left out, not
+    /// counting what is under excluded directories
+    pub excluded: u64,
+    /// The number of directories in the snapshot
+    pub directories: u64,
+    /// The total size of the files in the snapshot
+    pub bytes: u64,
+    /// The number of chunks the files were split into
+    pub chunks: u64,
+    /// The number of chunks the repository didn't hold yet, which were
+    /// uploaded
+    pub uploaded_chunks: u64,
+    /// The total size of the chunks uploaded
+    pub uploaded_bytes: u64,
+    /// The total size of the chunks uploaded, as stored after compression
+    pub stored_bytes: u64,
+    /// The number of packs the chunks were uploaded in
+    pub uploaded_packs: u64,
+    /// The paths that couldn't be backed up, which the snapshot leaves out
+    pub skipped: Vec<SkippedPath>,
+}
+
+impl BackupSummary {
+    /// How many times smaller compression made the chunks uploaded, or
+    /// `None` if nothing was uploaded
+    #[must_use]
+    pub fn compression_ratio(&self) -> Option<f64> {
+        ratio(self.uploaded_bytes, self.stored_bytes)
+    }
+}
+
+/// A path a backup run had to leave out
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkippedPath {
+    /// The local path
+    pub path: PathBuf,
+    /// Why it was left out
+    pub reason: String,
+}
+
+/// An entry found by scanning, with where its data is read from
+struct Scanned {
+    /// The local path of the entry
+    local: PathBuf,
+    /// The entry, without its content
+    entry: Entry,
+    /// The metadata of a file that tells whether it changed
+    stamp: Option<FileStamp>,
+    /// The chunks of a file the cache holds for its current metadata
+    cached: Option<Vec<String>>,
+}
+
+/// A local cache and the ID of the repository its entries are for
+type CacheFor<'a> = Option<(&'a FileCache, &'a str)>;
+
+/// The chunks of a backup run that is in progress
+struct Packing {
+    /// The IDs of every chunk in the repository or in a pack of the run
+    known: HashSet<String>,
+    /// The pack being filled
+    writer: PackWriter,
+    /// The packs the run uploaded
+    packs: Vec<PackIndex>,
+}
+
+/// Why a file couldn't be backed up
+enum Failure {
+    /// The file couldn't be read, and is left out of the snapshot
+    Skip(String),
+    /// The run can't go on
+    Fatal(EngineError),
+}
+
+/// Whether a file read in a run can be recorded in the cache
+///
+/// It must have been read at the size it had when it was scanned, and not
+/// been modified since the run started: a later write in the same tick of
+/// the file system's clock would leave its metadata unchanged.
+fn is_settled(stamp: &FileStamp, size: u64, start: DateTime<Utc>) -> bool {
+    stamp.size == size
+        && start
+            .timestamp_nanos_opt()
+            .is_some_and(|start| stamp.modified < start)
+}
+
+/// The cached chunks of a file, if the cache holds them for its current
+/// metadata and the repository still holds all of them
+fn unchanged(
+    stamp: Option<FileStamp>,
+    cached: Option<Vec<String>>,
+    known: &HashSet<String>,
+) -> Option<(FileStamp, Vec<String>)> {
+    stamp
+        .zip(cached)
+        .filter(|(_stamp, content)| content.iter().all(|id| known.contains(id)))
+}
+
+/// Resolve the paths to back up and give each a unique name
+fn sources(paths: &[impl AsRef<Path>]) -> Result<Vec<Source>, EngineError> {
+    let mut names = HashSet::new();
+    paths
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            let path =
+                path.canonicalize().map_err(|error| EngineError::Io {
+                    path: path.to_owned(),
+                    error,
+                })?;
+            if !path.is_dir() {
+                return Err(EngineError::NotADirectory {
+                    path,
+                });
+            }
+            let base = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("root")
+                .to_owned();
+            let mut name = base.clone();
+            let mut suffix = 1;
+            while !names.insert(name.clone()) {
+                suffix += 1;
+                name = format!("{base}-{suffix}");
+            }
+            Ok(Source {
+                name,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// The path of an entry in a snapshot, if every component is valid UTF-8
+fn entry_path(source: &Source, relative: &Path) -> Option<String> {
+    relative.components().try_fold(source.name.clone(), |mut path, part| {
+        if let Component::Normal(part) = part {
+            path.push('/');
+            path.push_str(part.to_str()?);
+        }
+        Some(path)
+    })
+}
+
+/// Describe an entry from its metadata
+fn describe(
+    path: String,
+    local: &Path,
+    metadata: &std::fs::Metadata,
+) -> Result<Entry, String> {
+    let file_type = metadata.file_type();
+    let (kind, target) = if file_type.is_dir() {
+        (EntryKind::Directory, None)
+    } else if file_type.is_file() {
+        (EntryKind::File, None)
+    } else if file_type.is_symlink() {
+        let target =
+            std::fs::read_link(local).map_err(|error| error.to_string())?;
+        let target = target
+            .to_str()
+            .ok_or_else(|| "symlink target is not valid UTF-8".to_owned())?
+            .to_owned();
+        (EntryKind::Symlink, Some(target))
+    } else {
+        return Err("not a file, directory or symlink".to_owned());
+    };
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let mode = None;
+    Ok(Entry {
+        path,
+        kind,
+        size: 0,
+        modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        mode,
+        target,
+        content: Vec::new(),
+    })
+}
+
+/// Find every entry under the sources that their rules include, in path
+/// order, and look up files in the cache
+///
+/// Entries that can't be read are left out and reported instead, and
+/// excluded entries are counted.
+fn scan(
+    sources: &[Source],
+    matchers: Vec<Matcher>,
+    cache: CacheFor<'_>,
+) -> (Vec<Scanned>, Vec<SkippedPath>, u64) {
+    let mut scanned = Vec::new();
+    let mut skipped = Vec::new();
+    let mut excluded = 0;
+    for (source, mut matcher) in sources.iter().zip(matchers) {
+        let mut walk = WalkDir::new(&source.path)
+            .follow_links(false)
+            .sort_by_file_name()
+            .into_iter();
+        while let Some(item) = walk.next() {
+            let item = match item {
+                Ok(item) => item,
+                Err(error) => {
+                    skipped.push(SkippedPath {
+                        path: error.path().unwrap_or(&source.path).to_owned(),
+                        reason: error.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let depth = item.depth();
+            matcher.leave(depth);
+            if depth > 0 {
+                let decision = item
+                    .metadata()
+                    .map(|metadata| matcher.check(item.path(), &metadata));
+                if decision.is_ok_and(|decision| !decision.included) {
+                    excluded += 1;
+                    if item.file_type().is_dir() {
+                        walk.skip_current_dir();
+                    }
+                    continue;
+                }
+            }
+            if item.file_type().is_dir() {
+                matcher.enter(item.path(), depth);
+            }
+            let local = item.path().to_owned();
+            let relative = local.strip_prefix(&source.path).unwrap_or(&local);
+            let described = entry_path(source, relative)
+                .ok_or_else(|| "name is not valid UTF-8".to_owned())
+                .and_then(|path| {
+                    let metadata =
+                        item.metadata().map_err(|error| error.to_string())?;
+                    let entry = describe(path, &local, &metadata)?;
+                    let stamp = (entry.kind == EntryKind::File)
+                        .then(|| FileStamp::of(&metadata));
+                    Ok((entry, stamp))
+                });
+            match described {
+                Ok((entry, stamp)) => scanned.push(Scanned {
+                    cached: cache.zip(stamp).and_then(
+                        |((cache, repository), stamp)| {
+                            cache.lookup(repository, &local, stamp)
+                        },
+                    ),
+                    local,
+                    entry,
+                    stamp,
+                }),
+                Err(reason) => skipped.push(SkippedPath {
+                    path: local,
+                    reason,
+                }),
+            }
+        }
+    }
+    (scanned, skipped, excluded)
+}
+
+impl<S: ObjectStore + Sync> Repository<S> {
+    /// Back up directories into a new snapshot
+    ///
+    /// Files that can't be read are left out of the snapshot and listed in
+    /// the summary, rather than failing the run. Only chunks that aren't in
+    /// the repository when the run starts are uploaded, whichever machine
+    /// stored them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a path isn't a directory, or if
+    /// uploading to the store fails.
+    pub async fn backup(
+        &self,
+        paths: &[impl AsRef<Path>],
+        options: &BackupOptions,
+    ) -> Result<BackupSummary, EngineError> {
+        let time = Utc::now();
+        let sources = sources(paths)?;
+        let (scanned, mut skipped, excluded) =
+            self.scan(&sources, options, time).await?;
+        let mut packing = Packing {
+            known: self.chunk_index().await?.into_keys().collect(),
+            writer: PackWriter::default(),
+            packs: Vec::new(),
+        };
+        let mut summary = BackupSummary {
+            snapshot_id: new_snapshot_id(time),
+            excluded,
+            ..BackupSummary::default()
+        };
+        let mut entries = Vec::with_capacity(scanned.len());
+        let mut read = Vec::new();
+        for Scanned {
+            local,
+            mut entry,
+            stamp,
+            cached,
+        } in scanned
+        {
+            match entry.kind {
+                EntryKind::Directory => summary.directories += 1,
+                EntryKind::Symlink => {}
+                EntryKind::File => {
+                    let (size, content) = if let Some((stamp, content)) =
+                        unchanged(stamp, cached, &packing.known)
+                    {
+                        summary.unchanged_files += 1;
+                        summary.chunks +=
+                            u64::try_from(content.len()).unwrap_or(u64::MAX);
+                        (stamp.size, content)
+                    } else {
+                        let level = options.compression_level;
+                        match self
+                            .back_up_file(
+                                &local,
+                                level,
+                                &mut packing,
+                                &mut summary,
+                            )
+                            .await
+                        {
+                            Ok((size, content)) => {
+                                read.extend(stamp.map(|stamp| {
+                                    (local, stamp, size, content.clone())
+                                }));
+                                (size, content)
+                            }
+                            Err(Failure::Skip(reason)) => {
+                                skipped.push(SkippedPath {
+                                    path: local,
+                                    reason,
+                                });
+                                continue;
+                            }
+                            Err(Failure::Fatal(error)) => return Err(error),
+                        }
+                    };
+                    summary.files += 1;
+                    summary.bytes += size;
+                    entry.size = size;
+                    entry.content = content;
+                }
+            }
+            entries.push(entry);
+        }
+        self.finish_packing(packing, &mut summary).await?;
+        let snapshot = Snapshot {
+            id: summary.snapshot_id.clone(),
+            time,
+            hostname: options.hostname.clone().unwrap_or_else(local_hostname),
+            sources,
+            tags: options.tags.clone(),
+            entries,
+            uploaded_bytes: summary.uploaded_bytes,
+            stored_bytes: summary.stored_bytes,
+        };
+        self.write_snapshot(&snapshot).await?;
+        if let Some(cache) = &options.cache {
+            self.record_in_cache(cache, read, time).await;
+        }
+        for path in &skipped {
+            tracing::warn!(path = %path.path.display(), reason = path.reason, "Skipped");
+        }
+        tracing::info!(
+            snapshot = snapshot.id,
+            files = summary.files,
+            uploaded_bytes = summary.uploaded_bytes,
+            "Backup finished"
+        );
+        summary.skipped = skipped;
+        Ok(summary)
+    }
+
+    /// Find every entry under the sources that the rules include on a
+    /// blocking thread, looking up files in the cache
+    async fn scan(
+        &self,
+        sources: &[Source],
+        options: &BackupOptions,
+        start: DateTime<Utc>,
+    ) -> Result<(Vec<Scanned>, Vec<SkippedPath>, u64), EngineError> {
+        let matchers = sources
+            .iter()
+            .map(|source| Matcher::new(&source.path, &options.rules, start))
+            .collect::<Result<_, _>>()?;
+        let sources = sources.to_vec();
+        let cache = options.cache.clone();
+        let repository = self.config().id.clone();
+        Ok(tokio::task::spawn_blocking(move || {
+            scan(
+                &sources,
+                matchers,
+                cache.as_deref().map(|cache| (cache, &*repository)),
+            )
+        })
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic())))
+    }
+
+    /// Upload the last pack of a run and the index of its packs
+    async fn finish_packing(
+        &self,
+        mut packing: Packing,
+        summary: &mut BackupSummary,
+    ) -> Result<(), EngineError> {
+        if !packing.writer.is_empty() {
+            packing.packs.push(self.write_pack(&mut packing.writer).await?);
+            summary.uploaded_packs += 1;
+        }
+        if !packing.packs.is_empty() {
+            self.write_index(&summary.snapshot_id, &packing.packs).await?;
+        }
+        Ok(())
+    }
+
+    /// Record the files a run started at `start` read in the cache, with
+    /// their metadata when they were scanned and the size they were read at
+    ///
+    /// The snapshot is stored by now, so failing to record them only costs
+    /// the next run the time to read them again.
+    async fn record_in_cache(
+        &self,
+        cache: &Arc<FileCache>,
+        read: Vec<(PathBuf, FileStamp, u64, Vec<String>)>,
+        start: DateTime<Utc>,
+    ) {
+        let read = read
+            .into_iter()
+            .filter(|(_path, stamp, size, _content)| {
+                is_settled(stamp, *size, start)
+            })
+            .map(|(path, stamp, _size, content)| CachedFile {
+                path,
+                stamp,
+                content,
+            })
+            .collect::<Vec<_>>();
+        let cache = Arc::clone(cache);
+        let repository = self.config().id.clone();
+        let recorded = tokio::task::spawn_blocking(move || {
+            cache.record(&repository, &read)
+        })
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()));
+        if let Err(error) = recorded {
+            tracing::warn!(%error, "Couldn't record the files in the local cache");
+        }
+    }
+
+    /// Compress and pack the chunks of a file that aren't known yet,
+    /// uploading packs as they fill
+    ///
+    /// Returns the size of the file and the IDs of its chunks, in order.
+    async fn back_up_file(
+        &self,
+        path: &Path,
+        compression_level: Option<i32>,
+        packing: &mut Packing,
+        summary: &mut BackupSummary,
+    ) -> Result<(u64, Vec<String>), Failure> {
+        let mut receiver = chunks(path.to_owned(), self.config().chunker);
+        let mut size = 0;
+        let mut content = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            let data =
+                chunk.map_err(|error| Failure::Skip(error.to_string()))?;
+            let length = u64::try_from(data.len()).unwrap_or(u64::MAX);
+            let id = self.master_key().id(&data);
+            if packing.known.insert(id.clone()) {
+                let master_key = self.master_key().clone();
+                let (compression, stored, sealed) =
+                    tokio::task::spawn_blocking(move || {
+                        let (compression, compressed) =
+                            compress(&data, compression_level);
+                        let stored = compressed.as_deref().unwrap_or(&data);
+                        (compression, stored.len(), master_key.encrypt(stored))
+                    })
+                    .await
+                    .unwrap_or_else(|error| {
+                        std::panic::resume_unwind(error.into_panic())
+                    });
+                packing.writer.add(
+                    id.clone(),
+                    compression,
+                    Bytes::from(sealed),
+                );
+                summary.uploaded_chunks += 1;
+                summary.uploaded_bytes += length;
+                summary.stored_bytes +=
+                    u64::try_from(stored).unwrap_or(u64::MAX);
+            }
+            if packing.writer.size() >= self.config().pack_size {
+                let pack = self
+                    .write_pack(&mut packing.writer)
+                    .await
+                    .map_err(Failure::Fatal)?;
+                packing.packs.push(pack);
+                summary.uploaded_packs += 1;
+            }
+            summary.chunks += 1;
+            size += length;
+            content.push(id);
+        }
+        Ok((size, content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use b2fake::FakeB2;
+    use b2native::{Bucket, ObjectStore};
+    use filetime::FileTime;
+
+    use crate::{
+        test_support::{bucket, CHEAP},
+        BackupOptions, BackupRules, ChunkerParams, EngineError, EntryKind,
+        FileCache, InitOptions, Preset, Repository, Snapshot, IGNORE_FILE,
+    };
+
+    async fn repository(server: &FakeB2) -> Repository<Bucket> {
+        let options = InitOptions {
+            chunker: ChunkerParams {
+                min_size: 1024,
+                avg_size: 4096,
+                max_size: 16384,
+            },
+            pack_size: 64 * 1024,
+            kdf: CHEAP,
+        };
+        Repository::init(bucket(server).await, "backmate", "secret", &options)
+            .await
+            .expect("Repository should be created")
+    }
+
+    /// Deterministic data that doesn't repeat within a chunk
+    fn noise(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn snapshots_record_every_entry() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let documents = root.path().join("Documents");
+        fs::create_dir_all(documents.join("taxes")).expect("Create directory");
+        fs::write(documents.join("notes.txt"), "hello").expect("Write file");
+        fs::write(documents.join("taxes/2025.txt"), "hello")
+            .expect("Write file");
+        fs::write(documents.join("taxes/2026.txt"), "pending")
+            .expect("Write file");
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("notes.txt", documents.join("latest"))
+            .expect("Create symlink");
+        let options = BackupOptions {
+            hostname: Some("laptop".to_owned()),
+            ..BackupOptions::default()
+        };
+
+        let summary = repository
+            .backup(&[&documents], &options)
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(summary.files, 3);
+        assert_eq!(summary.directories, 2);
+        assert_eq!(summary.bytes, 17);
+        // Identical files share one chunk
+        assert_eq!(summary.chunks, 3);
+        assert_eq!(summary.uploaded_chunks, 2);
+        assert_eq!(summary.uploaded_packs, 1);
+        assert!(summary.skipped.is_empty());
+
+        let snapshot = repository
+            .snapshot(&summary.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        assert_eq!(snapshot.hostname, "laptop");
+        assert_eq!(snapshot.sources[0].name, "Documents");
+        let paths = snapshot.entries.iter().map(|entry| entry.path.as_str());
+        let mut expected = vec![
+            "Documents",
+            "Documents/notes.txt",
+            "Documents/taxes",
+            "Documents/taxes/2025.txt",
+            "Documents/taxes/2026.txt",
+        ];
+        if cfg!(unix) {
+            expected.insert(1, "Documents/latest");
+        }
+        assert_eq!(paths.collect::<Vec<_>>(), expected);
+        let notes = snapshot
+            .entries
+            .iter()
+            .find(|entry| entry.path == "Documents/notes.txt")
+            .expect("File should be recorded");
+        assert_eq!(notes.kind, EntryKind::File);
+        assert_eq!(notes.size, 5);
+        assert_eq!(notes.content, [repository.master_key().id(b"hello")]);
+        assert!(notes.modified.is_some());
+
+        // The bucket holds no names or contents in the clear
+        let objects = repository
+            .store()
+            .list("backmate/")
+            .await
+            .expect("Objects should list");
+        for object in objects {
+            assert!(!object.key.contains(&summary.snapshot_id));
+            let data = repository
+                .store()
+                .get(&object.key, None)
+                .await
+                .expect("Object should download")
+                .data;
+            for plaintext in [&b"notes.txt"[..], b"pending", b"laptop"] {
+                assert!(!data.windows(plaintext.len()).any(|w| w == plaintext));
+            }
+        }
+
+        // Nothing changed, so nothing new is uploaded
+        let again = repository
+            .backup(&[&documents], &options)
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(again.uploaded_chunks, 0);
+        assert_eq!(again.uploaded_packs, 0);
+        let ids = repository
+            .snapshots()
+            .await
+            .expect("Snapshots should list")
+            .into_iter()
+            .map(|snapshot| snapshot.id);
+        assert_eq!(
+            ids.collect::<Vec<_>>(),
+            [summary.snapshot_id, again.snapshot_id]
+        );
+    }
+
+    #[tokio::test]
+    async fn compressible_chunks_are_stored_smaller() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let logs = root.path().join("logs");
+        fs::create_dir_all(&logs).expect("Create directory");
+        let log = (0..4000)
+            .map(|line| format!("2026-10-19 INFO request {line} served\n"))
+            .collect::<Vec<_>>()
+            .concat();
+        fs::write(logs.join("server.log"), &log).expect("Write file");
+        fs::write(logs.join("photo.jpg"), noise(64 * 1024, 3))
+            .expect("Write file");
+
+        let summary = repository
+            .backup(&[&logs], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let photo = 64 * 1024;
+        let log_size = u64::try_from(log.len()).expect("Size");
+        assert_eq!(summary.uploaded_bytes, log_size + photo);
+        // The log shrinks, and the photo is stored as it is
+        assert!(summary.stored_bytes < photo + log_size / 3, "{summary:?}");
+        assert!(summary.stored_bytes > photo);
+        let snapshot = repository
+            .snapshot(&summary.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        assert_eq!(snapshot.compression_ratio(), summary.compression_ratio());
+        let index = repository.chunk_index().await.expect("Index");
+        let content = &snapshot
+            .entries
+            .iter()
+            .find(|entry| entry.path == "logs/server.log")
+            .expect("File should be recorded")
+            .content;
+        let mut restored = Vec::new();
+        for id in content {
+            let chunk = repository.read_chunk(&index, id).await.expect("Chunk");
+            restored.extend_from_slice(&chunk);
+        }
+        assert_eq!(restored, log.as_bytes());
+
+        fs::write(logs.join("server.log"), log.to_uppercase())
+            .expect("Write file");
+        let uncompressed = repository
+            .backup(
+                &[&logs],
+                &BackupOptions {
+                    compression_level: None,
+                    ..BackupOptions::default()
+                },
+            )
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(uncompressed.stored_bytes, log_size);
+        assert_eq!(uncompressed.compression_ratio(), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn unchanged_files_are_not_read_again() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let documents = root.path().join("Documents");
+        fs::create_dir_all(&documents).expect("Create directory");
+        fs::write(documents.join("notes.txt"), "hello").expect("Write file");
+        fs::write(documents.join("report.bin"), noise(32 * 1024, 5))
+            .expect("Write file");
+        let cache_path = root.path().join("cache/files.sqlite");
+        let options = BackupOptions {
+            cache: Some(Arc::new(
+                FileCache::open(&cache_path).expect("Cache should open"),
+            )),
+            ..BackupOptions::default()
+        };
+
+        let first = repository
+            .backup(&[&documents], &options)
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(first.unchanged_files, 0);
+        fs::write(documents.join("notes.txt"), "jello").expect("Write file");
+        let second = repository
+            .backup(&[&documents], &options)
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(second.files, 2);
+        assert_eq!(second.unchanged_files, 1);
+        assert_eq!(second.chunks, first.chunks);
+        assert_eq!(second.uploaded_chunks, 1);
+        let first_snapshot = repository
+            .snapshot(&first.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let second_snapshot = repository
+            .snapshot(&second.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let report = |snapshot: &Snapshot| {
+            snapshot
+                .entries
+                .iter()
+                .find(|entry| entry.path == "Documents/report.bin")
+                .cloned()
+                .expect("File should be recorded")
+        };
+        assert_eq!(report(&second_snapshot), report(&first_snapshot));
+
+        // A lost cache is rebuilt from the snapshots
+        drop(options);
+        fs::remove_dir_all(root.path().join("cache")).expect("Remove cache");
+        let cache =
+            Arc::new(FileCache::open(&cache_path).expect("Cache should open"));
+        let recorded = repository
+            .rebuild_cache(&cache, None)
+            .await
+            .expect("Cache should be rebuilt");
+        assert_eq!(recorded, 2);
+        let third = repository
+            .backup(
+                &[&documents],
+                &BackupOptions {
+                    cache: Some(cache),
+                    ..BackupOptions::default()
+                },
+            )
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(third.unchanged_files, 2);
+        assert_eq!(third.uploaded_chunks, 0);
+    }
+
+    #[tokio::test]
+    async fn cache_rebuilds_only_use_one_machine() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let documents = root.path().join("Documents");
+        fs::create_dir_all(&documents).expect("Create directory");
+        let notes = documents.join("notes.txt");
+        let write = |contents: &str, seconds: i64| {
+            fs::write(&notes, contents).expect("Write file");
+            filetime::set_file_mtime(
+                &notes,
+                FileTime::from_unix_time(1_700_000_000 + seconds, 0),
+            )
+            .expect("Set time");
+        };
+        let options = |hostname: &str, cache| BackupOptions {
+            hostname: Some(hostname.to_owned()),
+            cache,
+            ..BackupOptions::default()
+        };
+        // Another machine backed up the same path after this one did
+        write("alpha", 0);
+        repository
+            .backup(&[&documents], &options("desktop", None))
+            .await
+            .expect("Backup should succeed");
+        write("gamma", 60);
+        repository
+            .backup(&[&documents], &options("laptop", None))
+            .await
+            .expect("Backup should succeed");
+        write("alpha", 0);
+
+        let cache = Arc::new(
+            FileCache::open(root.path().join("cache/files.sqlite"))
+                .expect("Cache should open"),
+        );
+        let recorded = repository
+            .rebuild_cache(&cache, Some("desktop"))
+            .await
+            .expect("Cache should be rebuilt");
+        assert_eq!(recorded, 1);
+        let summary = repository
+            .backup(&[&documents], &options("desktop", Some(cache)))
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(summary.unchanged_files, 1);
+        assert_eq!(summary.uploaded_chunks, 0);
+    }
+
+    #[tokio::test]
+    async fn rules_leave_out_excluded_paths() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let project = root.path().join("project");
+        fs::create_dir_all(project.join("node_modules/left-pad"))
+            .expect("Create directory");
+        fs::create_dir_all(project.join("logs")).expect("Create directory");
+        fs::write(project.join("node_modules/left-pad/index.js"), "pad")
+            .expect("Write file");
+        fs::write(project.join("main.js"), "main").expect("Write file");
+        fs::write(project.join("logs/today.log"), "log").expect("Write file");
+        fs::write(project.join("logs/keep.log"), "log").expect("Write file");
+        fs::write(project.join("logs").join(IGNORE_FILE), "!keep.log\n")
+            .expect("Write file");
+        let options = BackupOptions {
+            rules: BackupRules {
+                patterns: vec!["*.log".to_owned()],
+                presets: vec![Preset::NodeModules],
+                ..BackupRules::default()
+            },
+            ..BackupOptions::default()
+        };
+
+        let summary = repository
+            .backup(&[&project], &options)
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(summary.excluded, 2);
+        let snapshot = repository
+            .snapshot(&summary.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let paths = snapshot
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "project",
+                "project/logs",
+                "project/logs/.backmateignore",
+                "project/logs/keep.log",
+                "project/main.js"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sources_get_unique_names() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let first = root.path().join("work/docs");
+        let second = root.path().join("home/docs");
+        fs::create_dir_all(&first).expect("Create directory");
+        fs::create_dir_all(&second).expect("Create directory");
+        fs::write(first.join("a.txt"), "a").expect("Write file");
+
+        let summary = repository
+            .backup(&[&first, &second], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let snapshot = repository
+            .snapshot(&summary.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let names = snapshot.sources.iter().map(|source| source.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["docs", "docs-2"]);
+
+        let error = repository
+            .backup(&[first.join("a.txt")], &BackupOptions::default())
+            .await
+            .expect_err("Files can't be sources");
+        assert!(matches!(error, EngineError::NotADirectory { .. }));
+    }
+
+    #[tokio::test]
+    async fn chunks_are_shared_across_files_and_machines() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let laptop = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let data = noise(256 * 1024, 7);
+        let work = root.path().join("laptop/work");
+        fs::create_dir_all(&work).expect("Create directory");
+        fs::write(work.join("report.bin"), &data).expect("Write file");
+        let mut copy = data.clone();
+        copy.extend(noise(1000, 8));
+        fs::write(work.join("report-final.bin"), &copy).expect("Write file");
+
+        let first = laptop
+            .backup(&[&work], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        // The copy only adds the chunks around its new tail
+        assert!(first.chunks > 40);
+        assert!(first.uploaded_chunks < first.chunks / 2 + 3);
+        // Small chunks are bundled into a few packs
+        assert!((4..=6).contains(&first.uploaded_packs), "{first:?}");
+
+        // Another machine sharing the repository edits one byte of its own
+        // copy, and uploads little more than the chunk holding it
+        let desktop =
+            Repository::open(bucket(&server).await, "backmate", "secret")
+                .await
+                .expect("Repository should open");
+        let mut edited = data;
+        edited[128 * 1024] ^= 0xFF;
+        let home = root.path().join("desktop/home");
+        fs::create_dir_all(&home).expect("Create directory");
+        fs::write(home.join("report.bin"), &edited).expect("Write file");
+        let second = desktop
+            .backup(
+                &[&home],
+                &BackupOptions {
+                    hostname: Some("desktop".to_owned()),
+                    ..BackupOptions::default()
+                },
+            )
+            .await
+            .expect("Backup should succeed");
+        assert!(second.uploaded_chunks <= 3, "{second:?}");
+        assert!(second.uploaded_bytes < 64 * 1024);
+        assert_eq!(second.uploaded_packs, 1);
+
+        let snapshot = desktop
+            .snapshot(&second.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let report = snapshot
+            .entries
+            .iter()
+            .find(|entry| entry.path == "home/report.bin")
+            .expect("File should be recorded");
+        assert_eq!(report.size, 256 * 1024);
+        assert_eq!(
+            u64::try_from(report.content.len()).expect("Chunk count"),
+            second.chunks
+        );
+    }
+}
@@ -0,0 +1,432 @@
+//! A local cache of the files backed up, so unchanged files aren't read
+//! again
+//!
+//! Reading and chunking every file on every run is what makes backups of
+//! large volumes slow. The cache is a `SQLite` database on the machine being
+//! backed up that records, for every file a run stored, its size,
+//! modification and change times and inode along with the IDs of its chunks.
+//! The next run takes the chunks of a file whose metadata still matches from
+//! the cache, as long as the repository still holds them, without opening
+//! the file.
+//!
+//! A run writes its entries in a single transaction once its snapshot is
+//! stored, and the database is kept in WAL mode, so a crash never leaves the
+//! cache pointing at chunks that weren't stored. The cache only ever saves
+//! work: when it can't be read or written, files are read as if it were
+//! empty, and when it is lost it can be rebuilt from the snapshots in the
+//! repository.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use b2native::ObjectStore;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::{
+    backup::local_hostname, snapshot::EntryKind, EngineError, Repository,
+};
+
+/// The metadata of a file that tells whether it changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    /// The size of the file
+    pub(crate) size: u64,
+    /// When the file was last modified, in nanoseconds since the epoch
+    pub(crate) modified: i64,
+    /// When the file or its metadata last changed, in nanoseconds since the
+    /// epoch, or 0 where the platform doesn't report it
+    changed: i64,
+    /// The inode of the file, or 0 where the platform has none
+    inode: u64,
+}
+
+impl FileStamp {
+    /// The stamp of a file from its metadata
+    #[cfg(unix)]
+    pub(crate) fn of(metadata: &Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        let nanos = |seconds: i64, nanoseconds: i64| {
+            seconds.saturating_mul(1_000_000_000).saturating_add(nanoseconds)
+        };
+        Self {
+            size: metadata.len(),
+            modified: nanos(metadata.mtime(), metadata.mtime_nsec()),
+            changed: nanos(metadata.ctime(), metadata.ctime_nsec()),
+            inode: metadata.ino(),
+        }
+    }
+
+    /// The stamp of a file from its metadata
+    #[cfg(not(unix))]
+    pub(crate) fn of(metadata: &Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .and_then(|since| i64::try_from(since.as_nanos()).ok())
+            .unwrap_or(0);
+        Self {
+            size: metadata.len(),
+            modified,
+            changed: 0,
+            inode: 0,
+        }
+    }
+}
+
+/// A file to record in the cache
+#[derive(Clone, Debug)]
+pub(crate) struct CachedFile {
+    /// The absolute local path of the file
+    pub(crate) path: PathBuf,
+    /// The metadata of the file when it was read
+    pub(crate) stamp: FileStamp,
+    /// The IDs of the chunks holding the file's data, in order
+    pub(crate) content: Vec<String>,
+}
+
+/// The local cache of the files backed up, shared by every repository this
+/// machine backs up into
+#[derive(Debug)]
+pub struct FileCache {
+    /// Where the database is
+    path: PathBuf,
+    /// The open database
+    connection: Mutex<Connection>,
+}
+
+impl FileCache {
+    /// Open the cache at a path, creating it and the directory it is in if
+    /// they don't exist
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the directory can't be created
+    /// or the database can't be opened.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, EngineError> {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|error| {
+                EngineError::Io {
+                    path: parent.to_owned(),
+                    error,
+                }
+            })?;
+        }
+        let fail = |error| EngineError::Cache {
+            path: path.clone(),
+            error,
+        };
+        let connection = Connection::open(&path).map_err(fail)?;
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |_row| Ok(()))
+            .map_err(fail)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS files (
+                    repository TEXT NOT NULL,
+                    path BLOB NOT NULL,
+                    size INTEGER NOT NULL,
+                    modified INTEGER NOT NULL,
+                    changed INTEGER NOT NULL,
+                    inode INTEGER NOT NULL,
+                    content TEXT NOT NULL,
+                    PRIMARY KEY (repository, path)
+                )",
+            )
+            .map_err(fail)?;
+        Ok(Self {
+            path,
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Where the database is
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of files the cache holds for a repository
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the database can't be read.
+    pub fn file_count(&self, repository: &str) -> Result<u64, EngineError> {
+        let count = self
+            .connection()
+            .query_row(
+                "SELECT COUNT(*) FROM files WHERE repository = ?1",
+                params![repository],
+                |row| row.get::<_, i64>(0),
+            )
+            .map_err(|error| self.fail(error))?;
+        Ok(u64::try_from(count).unwrap_or(0))
+    }
+
+    /// The open database, even if a thread panicked while using it
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The error for a failure of the database
+    fn fail(&self, error: rusqlite::Error) -> EngineError {
+        EngineError::Cache {
+            path: self.path.clone(),
+            error,
+        }
+    }
+
+    /// The chunks of a file recorded for a repository, if its metadata still
+    /// matches
+    ///
+    /// A cache that can't be read is treated as holding nothing.
+    pub(crate) fn lookup(
+        &self,
+        repository: &str,
+        path: &Path,
+        stamp: FileStamp,
+    ) -> Option<Vec<String>> {
+        let found = self
+            .connection()
+            .query_row(
+                "SELECT size, modified, changed, inode, content FROM files
+                 WHERE repository = ?1 AND path = ?2",
+                params![repository, path.as_os_str().as_encoded_bytes()],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, i64>(3)?,
+                        row.get::<_, String>(4)?,
+                    ))
+                },
+            )
+            .optional();
+        let (size, modified, changed, inode, content) = match found {
+            Ok(found) => found?,
+            Err(error) => {
+                tracing::warn!(%error, "Couldn't read the local cache");
+                return None;
+            }
+        };
+        let matches = u64::try_from(size).ok() == Some(stamp.size)
+            && modified == stamp.modified
+            && changed == stamp.changed
+            && inode.cast_unsigned() == stamp.inode;
+        matches.then(|| serde_json::from_str(&content).ok()).flatten()
+    }
+
+    /// Record files for a repository, replacing what was recorded for them
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the database can't be written,
+    /// in which case nothing is recorded.
+    pub(crate) fn record(
+        &self,
+        repository: &str,
+        files: &[CachedFile],
+    ) -> Result<(), EngineError> {
+        let mut connection = self.connection();
+        let transaction =
+            connection.transaction().map_err(|error| self.fail(error))?;
+        insert(&transaction, repository, files)
+            .and_then(|()| transaction.commit())
+            .map_err(|error| self.fail(error))
+    }
+
+    /// Replace everything recorded for a repository with some files
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the database can't be written,
+    /// in which case the cache is left as it was.
+    pub(crate) fn replace(
+        &self,
+        repository: &str,
+        files: &[CachedFile],
+    ) -> Result<(), EngineError> {
+        let mut connection = self.connection();
+        let transaction =
+            connection.transaction().map_err(|error| self.fail(error))?;
+        transaction
+            .execute(
+                "DELETE FROM files WHERE repository = ?1",
+                params![repository],
+            )
+            .and_then(|_deleted| insert(&transaction, repository, files))
+            .and_then(|()| transaction.commit())
+            .map_err(|error| self.fail(error))
+    }
+}
+
+/// Insert files into the cache as part of a transaction
+fn insert(
+    transaction: &Transaction<'_>,
+    repository: &str,
+    files: &[CachedFile],
+) -> Result<(), rusqlite::Error> {
+    let mut statement = transaction.prepare(
+        "INSERT OR REPLACE INTO files
+         (repository, path, size, modified, changed, inode, content)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for file in files {
+        let content = serde_json::to_string(&file.content)
+            .expect("Chunk IDs always serialize");
+        let _inserted = statement.execute(params![
+            repository,
+            file.path.as_os_str().as_encoded_bytes(),
+            i64::try_from(file.stamp.size).unwrap_or(i64::MAX),
+            file.stamp.modified,
+            file.stamp.changed,
+            file.stamp.inode.cast_signed(),
+            content,
+        ])?;
+    }
+    Ok(())
+}
+
+impl<S: ObjectStore + Sync> Repository<S> {
+    /// Rebuild the cache entries of the repository from its snapshots,
+    /// replacing whatever the cache held for it
+    ///
+    /// Snapshots don't record change times or inodes, so a local file is
+    /// taken to be unchanged since the newest snapshot holding it if its size
+    /// and modification time still match. Only the snapshots `hostname` took
+    /// are used, or those of this machine if `None`: the same path on another
+    /// machine says nothing about the local file. Returns the number of files
+    /// recorded.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the store can't be reached or
+    /// the cache can't be written.
+    pub async fn rebuild_cache(
+        &self,
+        cache: &Arc<FileCache>,
+        hostname: Option<&str>,
+    ) -> Result<u64, EngineError> {
+        let hostname = hostname.map_or_else(local_hostname, ToOwned::to_owned);
+        let known =
+            self.chunk_index().await?.into_keys().collect::<HashSet<_>>();
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        for snapshot in self.snapshots().await?.into_iter().rev() {
+            if snapshot.hostname != hostname {
+                continue;
+            }
+            let sources = snapshot
+                .sources
+                .iter()
+                .map(|source| (source.name.as_str(), &source.path))
+                .collect::<HashMap<_, _>>();
+            for entry in snapshot.entries {
+                if entry.kind != EntryKind::File
+                    || !entry.content.iter().all(|id| known.contains(id))
+                {
+                    continue;
+                }
+                let mut parts = entry.path.split('/');
+                let Some(root) =
+                    parts.next().and_then(|name| sources.get(name))
+                else {
+                    continue;
+                };
+                let path =
+                    parts.fold((*root).clone(), |path, part| path.join(part));
+                let Some(modified) =
+                    entry.modified.and_then(|time| time.timestamp_nanos_opt())
+                else {
+                    continue;
+                };
+                if seen.insert(path.clone()) {
+                    candidates.push((
+                        path,
+                        entry.size,
+                        modified,
+                        entry.content,
+                    ));
+                }
+            }
+        }
+        let cache = Arc::clone(cache);
+        let repository = self.config().id.clone();
+        tokio::task::spawn_blocking(move || {
+            let files = candidates
+                .into_iter()
+                .filter_map(|(path, size, modified, content)| {
+                    let metadata = std::fs::symlink_metadata(&path).ok()?;
+                    let stamp = FileStamp::of(&metadata);
+                    (metadata.is_file()
+                        && stamp.size == size
+                        && stamp.modified == modified)
+                        .then_some(CachedFile {
+                            path,
+                            stamp,
+                            content,
+                        })
+                })
+                .collect::<Vec<_>>();
+            cache.replace(&repository, &files)?;
+            tracing::info!(files = files.len(), "Rebuilt the local cache");
+            Ok(u64::try_from(files.len()).unwrap_or(u64::MAX))
+        })
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{CachedFile, FileCache, FileStamp};
+
+    #[test]
+    fn files_are_found_while_unchanged() {
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let cache = FileCache::open(root.path().join("cache/files.sqlite"))
+            .expect("Cache should open");
+        let path = root.path().join("notes.txt");
+        fs::write(&path, "hello").expect("Write file");
+        let stamp =
+            FileStamp::of(&fs::metadata(&path).expect("File should exist"));
+        let file = CachedFile {
+            path: path.clone(),
+            stamp,
+            content: vec!["a".to_owned(), "b".to_owned()],
+        };
+        cache.record("repo", &[file]).expect("Files should be recorded");
+
+        assert_eq!(
+            cache.lookup("repo", &path, stamp),
+            Some(vec!["a".to_owned(), "b".to_owned()])
+        );
+        assert_eq!(cache.lookup("other", &path, stamp), None);
+        let grown = FileStamp {
+            size: 6,
+            ..stamp
+        };
+        assert_eq!(cache.lookup("repo", &path, grown), None);
+        let touched = FileStamp {
+            modified: stamp.modified + 1,
+            ..stamp
+        };
+        assert_eq!(cache.lookup("repo", &path, touched), None);
+
+        // Entries survive reopening, and are replaced wholesale
+        drop(cache);
+        let cache = FileCache::open(root.path().join("cache/files.sqlite"))
+            .expect("Cache should open");
+        assert_eq!(cache.file_count("repo").expect("Cache should count"), 1);
+        cache.replace("repo", &[]).expect("Cache should be replaced");
+        assert_eq!(cache.lookup("repo", &path, stamp), None);
+    }
+}
@@ -0,0 +1,171 @@
+//! Splitting files into content-defined chunks
+//!
+//! Chunk boundaries are picked by `FastCDC` from the data itself, so an edit
+//! only changes the chunks around it and the rest of the file still matches
+//! what the repository holds. Every machine sharing a repository has to cut
+//! at the same places, which is why the sizes are part of its config.
+
+use std::{fs::File, io, path::PathBuf};
+
+use bytes::Bytes;
+use fastcdc::v2020::StreamCDC;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// The smallest minimum chunk size `FastCDC` accepts
+const MINIMUM_MIN_SIZE: u32 = 64;
+
+/// The largest maximum chunk size `FastCDC` accepts
+const MAXIMUM_MAX_SIZE: u32 = 16 * 1024 * 1024;
+
+/// How many chunks are read ahead of the upload
+const READ_AHEAD: usize = 4;
+
+/// The sizes chunks are cut to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkerParams {
+    /// The smallest chunk, except for the last chunk of a file
+    pub min_size: u32,
+    /// The size chunks are cut to on average
+    pub avg_size: u32,
+    /// The largest chunk
+    pub max_size: u32,
+}
+
+impl Default for ChunkerParams {
+    fn default() -> Self {
+        Self {
+            min_size: 512 * 1024,
+            avg_size: 1024 * 1024,
+            max_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkerParams {
+    /// Whether `FastCDC` can cut chunks with these sizes
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        (MINIMUM_MIN_SIZE..=1024 * 1024).contains(&self.min_size)
+            && (256..=4 * 1024 * 1024).contains(&self.avg_size)
+            && (1024..=MAXIMUM_MAX_SIZE).contains(&self.max_size)
+            && self.min_size <= self.avg_size
+            && self.avg_size <= self.max_size
+    }
+}
+
+/// Read a file as a stream of chunks
+///
+/// The file is read on a blocking thread, a few chunks ahead of the
+/// receiver. A read error ends the stream.
+pub(crate) fn chunks(
+    path: PathBuf,
+    params: ChunkerParams,
+) -> mpsc::Receiver<io::Result<Bytes>> {
+    let (sender, receiver) = mpsc::channel(READ_AHEAD);
+    tokio::task::spawn_blocking(move || {
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(error) => {
+                let _sent = sender.blocking_send(Err(error));
+                return;
+            }
+        };
+        let chunker = StreamCDC::new(
+            file,
+            params.min_size,
+            params.avg_size,
+            params.max_size,
+        );
+        for chunk in chunker {
+            let chunk = chunk
+                .map(|chunk| Bytes::from(chunk.data))
+                .map_err(io::Error::from);
+            let failed = chunk.is_err();
+            if sender.blocking_send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use bytes::Bytes;
+
+    use super::{chunks, ChunkerParams};
+
+    /// Deterministic data that doesn't repeat within a chunk
+    fn noise(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect()
+    }
+
+    async fn read_chunks(path: std::path::PathBuf) -> Vec<Bytes> {
+        let params = ChunkerParams {
+            min_size: 1024,
+            avg_size: 4096,
+            max_size: 16384,
+        };
+        let mut receiver = chunks(path, params);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            chunks.push(chunk.expect("Chunk should read"));
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn edits_only_change_nearby_chunks() {
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let mut data = noise(256 * 1024, 1);
+        fs::write(root.path().join("before"), &data).expect("Write file");
+        data[100_000] ^= 0xFF;
+        data.splice(200_000..200_000, noise(100, 2));
+        fs::write(root.path().join("after"), &data).expect("Write file");
+
+        let before = read_chunks(root.path().join("before")).await;
+        let after = read_chunks(root.path().join("after")).await;
+        assert!(before.len() > 20);
+        let changed =
+            after.iter().filter(|chunk| !before.contains(chunk)).count();
+        assert!(changed <= 6, "{changed} of {} chunks changed", after.len());
+    }
+
+    #[tokio::test]
+    async fn missing_files_fail_to_read() {
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let mut receiver =
+            chunks(root.path().join("missing"), ChunkerParams::default());
+        assert!(receiver.recv().await.expect("An error is sent").is_err());
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[test]
+    fn params_are_checked() {
+        assert!(ChunkerParams::default().is_valid());
+        assert!(!ChunkerParams {
+            min_size: 4096,
+            avg_size: 1024,
+            max_size: 16384,
+        }
+        .is_valid());
+        assert!(!ChunkerParams {
+            min_size: 16,
+            avg_size: 1024,
+            max_size: 16384,
+        }
+        .is_valid());
+    }
+}
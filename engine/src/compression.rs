@@ -0,0 +1,119 @@
+//! Compression of chunks before they are encrypted
+//!
+//! Text, logs and databases shrink several times over with zstd, while
+//! photos, video and archives are compressed already and only cost time to
+//! compress again. Each new chunk is judged by compressing a few small
+//! samples of it at the fastest level: if they don't shrink by a tenth, the
+//! chunk is stored as it is. The choice is recorded for every chunk in the
+//! header of its pack, so chunks stored either way can share a pack and
+//! restores know how to read each.
+
+use serde::{Deserialize, Serialize};
+
+/// The zstd level backups compress with unless told otherwise
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// The size of each sample taken from a chunk
+const SAMPLE_SIZE: usize = 4096;
+
+/// The number of samples taken from a chunk, spread over its length
+const SAMPLES: usize = 4;
+
+/// How many times smaller data got, or `None` if there was no data
+#[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+pub(crate) fn ratio(bytes: u64, stored_bytes: u64) -> Option<f64> {
+    (stored_bytes > 0).then(|| bytes as f64 / stored_bytes as f64)
+}
+
+/// How a chunk is stored in its pack
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Compression {
+    /// As it is
+    #[default]
+    None,
+    /// Compressed with zstd
+    Zstd,
+}
+
+/// Whether compressed data is worth storing instead of the original, which
+/// takes saving at least a tenth
+fn saves_enough(original: usize, compressed: usize) -> bool {
+    compressed.saturating_mul(10) <= original.saturating_mul(9)
+}
+
+/// Whether samples of the data shrink enough to compress all of it
+fn looks_compressible(data: &[u8]) -> bool {
+    if data.len() <= SAMPLE_SIZE * SAMPLES {
+        return true;
+    }
+    let step = (data.len() - SAMPLE_SIZE) / (SAMPLES - 1);
+    let sample = (0..SAMPLES)
+        .flat_map(|index| &data[index * step..index * step + SAMPLE_SIZE])
+        .copied()
+        .collect::<Vec<_>>();
+    zstd::bulk::compress(&sample, 1)
+        .is_ok_and(|compressed| saves_enough(sample.len(), compressed.len()))
+}
+
+/// Compress a chunk at a zstd level if that is worthwhile
+///
+/// Returns how the chunk is stored and, if it is compressed, the compressed
+/// data. Without a level, nothing is compressed.
+pub(crate) fn compress(
+    data: &[u8],
+    level: Option<i32>,
+) -> (Compression, Option<Vec<u8>>) {
+    let Some(level) = level else {
+        return (Compression::None, None);
+    };
+    if !looks_compressible(data) {
+        return (Compression::None, None);
+    }
+    match zstd::bulk::compress(data, level) {
+        Ok(compressed) if saves_enough(data.len(), compressed.len()) => {
+            (Compression::Zstd, Some(compressed))
+        }
+        _ => (Compression::None, None),
+    }
+}
+
+/// Undo the compression of a chunk, or `None` if it doesn't decompress to at
+/// most `max_size` bytes
+pub(crate) fn decompress(
+    compression: Compression,
+    data: Vec<u8>,
+    max_size: usize,
+) -> Option<Vec<u8>> {
+    match compression {
+        Compression::None => Some(data),
+        Compression::Zstd => zstd::bulk::decompress(&data, max_size).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, Compression};
+
+    #[test]
+    fn only_compressible_chunks_are_compressed() {
+        let text = "2026-10-19 INFO backup finished\n".repeat(2000);
+        let (compression, compressed) = compress(text.as_bytes(), Some(3));
+        assert_eq!(compression, Compression::Zstd);
+        let compressed = compressed.expect("Text should compress");
+        assert!(compressed.len() * 10 < text.len());
+        assert_eq!(
+            decompress(compression, compressed.clone(), text.len()),
+            Some(text.clone().into_bytes())
+        );
+        assert_eq!(decompress(compression, compressed, 100), None);
+
+        let random =
+            (0..64 * 1024).map(|_| rand::random()).collect::<Vec<u8>>();
+        assert_eq!(compress(&random, Some(3)), (Compression::None, None));
+        assert_eq!(compress(text.as_bytes(), None), (Compression::None, None));
+        assert_eq!(compress(b"", Some(3)), (Compression::None, None));
+    }
+}
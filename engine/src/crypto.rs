@@ -0,0 +1,254 @@
+//! Encryption of everything a repository stores
+//!
+//! Every repository has a random master key. Its first half encrypts config,
+//! chunks, pack headers, indexes and manifests with XChaCha20-Poly1305,
+//! each under a fresh random nonce stored in front of the ciphertext. Its
+//! second half keys the HMAC-SHA256 that chunk IDs and object names are
+//! made with, so neither reveals anything about the data to someone reading
+//! the bucket, while machines that share the key still agree on them.
+//!
+//! The master key itself is only stored wrapped, encrypted with a key that
+//! Argon2id derives from a passphrase or another secret.
+
+use std::fmt;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// The size of a nonce, which is stored in front of every ciphertext
+const NONCE_SIZE: usize = 24;
+
+/// The size of each half of the master key
+const KEY_SIZE: usize = 32;
+
+/// How hard deriving a key from a passphrase or other secret is made
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KdfParams {
+    /// The memory used, in KiB
+    pub memory_kib: u32,
+    /// The number of passes over the memory
+    pub iterations: u32,
+    /// The number of lanes computed in parallel
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// The Argon2id parameters, if they are valid
+    fn argon2(self) -> Option<Argon2<'static>> {
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            Some(KEY_SIZE),
+        )
+        .ok()?;
+        Some(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    /// Whether Argon2id accepts the parameters
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.argon2().is_some()
+    }
+}
+
+/// The key every object of a repository is encrypted and named with
+#[derive(Clone)]
+pub(crate) struct MasterKey {
+    /// The cipher keyed with the first half of the key
+    cipher: XChaCha20Poly1305,
+    /// The whole key, as it is wrapped
+    bytes: Zeroizing<[u8; 2 * KEY_SIZE]>,
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+/// A master key wrapped with a key derived from a secret
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WrappedKey {
+    /// How the wrapping key is derived
+    kdf: KdfParams,
+    /// The hex salt of the derivation
+    salt: String,
+    /// The hex nonce and ciphertext of the master key
+    ciphertext: String,
+}
+
+/// Make a random secret for a recovery key or a machine key file
+///
+/// The secret is 256 bits, written as hex in groups of eight digits.
+#[must_use]
+pub fn new_secret() -> String {
+    let bytes = Zeroizing::new(rand::random::<[u8; KEY_SIZE]>());
+    bytes.chunks(4).map(hex::encode).collect::<Vec<_>>().join("-")
+}
+
+/// Encrypt data under a fresh nonce
+fn seal(cipher: &XChaCha20Poly1305, plaintext: &[u8]) -> Vec<u8> {
+    let nonce = rand::random::<[u8; NONCE_SIZE]>();
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .expect("Encrypting in memory doesn't fail");
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    sealed
+}
+
+/// Decrypt data sealed by [`seal`], if it is authentic
+fn open(cipher: &XChaCha20Poly1305, sealed: &[u8]) -> Option<Vec<u8>> {
+    let (nonce, ciphertext) = sealed.split_first_chunk::<NONCE_SIZE>()?;
+    cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()
+}
+
+/// Derive the cipher that wraps a master key from a secret
+fn wrapping_cipher(
+    secret: &str,
+    salt: &[u8],
+    kdf: KdfParams,
+) -> Option<XChaCha20Poly1305> {
+    let mut key = Zeroizing::new([0; KEY_SIZE]);
+    kdf.argon2()?
+        .hash_password_into(secret.as_bytes(), salt, key.as_mut_slice())
+        .ok()?;
+    XChaCha20Poly1305::new_from_slice(key.as_slice()).ok()
+}
+
+impl MasterKey {
+    /// Make a new random key
+    pub(crate) fn generate() -> Self {
+        let mut bytes = Zeroizing::new([0; 2 * KEY_SIZE]);
+        rand::thread_rng().fill(bytes.as_mut_slice());
+        Self::from_bytes(bytes)
+    }
+
+    /// Use the bytes of a key
+    fn from_bytes(bytes: Zeroizing<[u8; 2 * KEY_SIZE]>) -> Self {
+        let cipher = XChaCha20Poly1305::new_from_slice(&bytes[..KEY_SIZE])
+            .expect("Keys have the cipher's size");
+        Self {
+            cipher,
+            bytes,
+        }
+    }
+
+    /// Encrypt data under a fresh nonce
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        seal(&self.cipher, plaintext)
+    }
+
+    /// Decrypt data, or `None` if it wasn't encrypted with this key or was
+    /// changed since
+    pub(crate) fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        open(&self.cipher, ciphertext)
+    }
+
+    /// The hex HMAC of some data, which doesn't reveal the data without
+    /// the key
+    pub(crate) fn id(&self, data: &[u8]) -> String {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.bytes[KEY_SIZE..])
+                .expect("HMAC takes keys of any size");
+        mac.update(data);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// The opaque name of a repository object of some kind
+    pub(crate) fn name(&self, kind: &str, id: &str) -> String {
+        self.id(format!("{kind}\0{id}").as_bytes())
+    }
+
+    /// Wrap the key with a key derived from a secret
+    ///
+    /// Returns `None` if the derivation parameters are invalid.
+    pub(crate) fn wrap(
+        &self,
+        secret: &str,
+        kdf: KdfParams,
+    ) -> Option<WrappedKey> {
+        let salt = rand::random::<[u8; 16]>();
+        let cipher = wrapping_cipher(secret, &salt, kdf)?;
+        Some(WrappedKey {
+            kdf,
+            salt: hex::encode(salt),
+            ciphertext: hex::encode(seal(&cipher, self.bytes.as_slice())),
+        })
+    }
+
+    /// Unwrap a wrapped key, or `None` if the secret doesn't open it
+    pub(crate) fn unwrap(wrapped: &WrappedKey, secret: &str) -> Option<Self> {
+        let salt = hex::decode(&wrapped.salt).ok()?;
+        let cipher = wrapping_cipher(secret, &salt, wrapped.kdf)?;
+        let wrapped = hex::decode(&wrapped.ciphertext).ok()?;
+        let bytes = Zeroizing::new(open(&cipher, &wrapped)?);
+        let mut key = Zeroizing::new([0; 2 * KEY_SIZE]);
+        if bytes.len() != key.len() {
+            return None;
+        }
+        key.copy_from_slice(&bytes);
+        Some(Self::from_bytes(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KdfParams, MasterKey};
+    use crate::test_support::CHEAP;
+
+    #[test]
+    fn keys_wrap_with_a_passphrase() {
+        let key = MasterKey::generate();
+        let file = key.wrap("correct horse", CHEAP).expect("Params are valid");
+        assert!(MasterKey::unwrap(&file, "wrong horse").is_none());
+        let unwrapped = MasterKey::unwrap(&file, "correct horse")
+            .expect("Passphrase should open the key file");
+        assert_eq!(unwrapped.id(b"data"), key.id(b"data"));
+
+        let sealed = key.encrypt(b"secret");
+        assert_eq!(
+            unwrapped.decrypt(&sealed).as_deref(),
+            Some(b"secret".as_slice())
+        );
+        let mut tampered = sealed.clone();
+        tampered[30] ^= 1;
+        assert!(key.decrypt(&tampered).is_none());
+        assert!(MasterKey::generate().decrypt(&sealed).is_none());
+        assert_ne!(key.encrypt(b"secret"), sealed);
+    }
+
+    #[test]
+    fn names_depend_on_the_key() {
+        let key = MasterKey::generate();
+        assert_eq!(key.name("snapshot", "a"), key.name("snapshot", "a"));
+        assert_ne!(key.name("snapshot", "a"), key.name("index", "a"));
+        assert_ne!(
+            key.name("snapshot", "a"),
+            MasterKey::generate().name("snapshot", "a")
+        );
+        assert!(!KdfParams {
+            memory_kib: 1,
+            ..CHEAP
+        }
+        .is_valid());
+    }
+}
@@ -0,0 +1,590 @@
+//! Key slots, each wrapping the master key of a repository
+//!
+//! A repository can be opened by several secrets: the passphrase of a user,
+//! a recovery key kept by an administrator, or a key file on a machine that
+//! backs up unattended. Each has a key slot of its own, a key file holding
+//! the master key wrapped with that secret, so slots can be added, removed
+//! and rotated without touching any other object. Only what unwrapping
+//! needs is stored in the clear: the kind, label and creation time of a
+//! slot are encrypted with the master key, so only those who can open the
+//! repository can list its slots.
+//!
+//! Removing a slot hides its key file, and a bucket keeps hidden versions
+//! until its lifecycle rules delete them. When a secret has leaked,
+//! [`Repository::rekey`] moves everything to a new master key that no old
+//! key file wraps.
+
+use std::{fmt, str::FromStr};
+
+use b2native::{ObjectStore, UploadOptions};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    crypto::{KdfParams, MasterKey, WrappedKey},
+    repository::CONFIG,
+    EngineError, Repository,
+};
+
+/// The prefix of key files
+const KEYS: &str = "keys/";
+
+/// What kind of secret a key slot is opened with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeySlotKind {
+    /// A passphrase a person remembers
+    Passphrase,
+    /// A random key an administrator keeps for when passphrases are lost
+    RecoveryKey,
+    /// A random key kept in a file on a machine that backs up unattended
+    MachineKey,
+}
+
+impl FromStr for KeySlotKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "passphrase" => Ok(Self::Passphrase),
+            "recovery-key" => Ok(Self::RecoveryKey),
+            "machine-key" => Ok(Self::MachineKey),
+            _ => Err(format!(
+                "unknown key slot kind {value:?}, expected passphrase, \
+                 recovery-key or machine-key"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for KeySlotKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::Passphrase => "passphrase",
+            Self::RecoveryKey => "recovery-key",
+            Self::MachineKey => "machine-key",
+        })
+    }
+}
+
+/// A key slot of a repository
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeySlot {
+    /// The ID of the slot
+    pub id: String,
+    /// What kind of secret opens the slot
+    pub kind: KeySlotKind,
+    /// A description of whose or which secret it is
+    pub label: String,
+    /// When the slot was added
+    pub created_at: DateTime<Utc>,
+}
+
+/// A key slot as its key file stores it
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyFile {
+    /// The wrapped master key
+    #[serde(flatten)]
+    key: WrappedKey,
+    /// The hex nonce and ciphertext of the [`SlotMetadata`], encrypted with
+    /// the master key
+    metadata: String,
+}
+
+/// What describes a key slot, encrypted in its key file
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SlotMetadata {
+    /// What kind of secret opens the slot
+    kind: KeySlotKind,
+    /// A description of whose or which secret it is
+    label: String,
+    /// When the slot was added
+    created_at: DateTime<Utc>,
+}
+
+/// What a re-key did
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RekeySummary {
+    /// The number of chunks encrypted again
+    pub chunks: u64,
+    /// The number of packs written
+    pub packs: u64,
+    /// The number of snapshot manifests written
+    pub snapshots: u64,
+    /// The number of objects under the old master key that were removed
+    pub removed_objects: u64,
+}
+
+/// Read and decode a key file, or just the wrapped key it holds
+async fn read_key_file<S: ObjectStore + Sync, T: DeserializeOwned>(
+    store: &S,
+    key: &str,
+) -> Result<T, EngineError> {
+    let data = store.get(key, None).await?.data;
+    serde_json::from_slice(&data).map_err(|_error| {
+        EngineError::MalformedObject {
+            key: key.to_owned(),
+        }
+    })
+}
+
+/// Run Argon2id and the cipher away from the async threads
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> T {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|error| std::panic::resume_unwind(error.into_panic()))
+}
+
+/// Unwrap the master key of the first key slot that `secret` opens and
+/// whose master key decrypts `config`
+///
+/// Checking the config skips slots a re-key that was cut short left behind.
+pub(crate) async fn unlock<S: ObjectStore + Sync>(
+    store: &S,
+    prefix: &str,
+    secret: &str,
+    config: &[u8],
+) -> Result<MasterKey, EngineError> {
+    for object in store.list(&format!("{prefix}{KEYS}")).await? {
+        let wrapped =
+            read_key_file::<_, WrappedKey>(store, &object.key).await?;
+        let secret = secret.to_owned();
+        let unwrapped =
+            blocking(move || MasterKey::unwrap(&wrapped, &secret)).await;
+        if let Some(master_key) = unwrapped {
+            if master_key.decrypt(config).is_some() {
+                return Ok(master_key);
+            }
+        }
+    }
+    Err(EngineError::WrongPassphrase)
+}
+
+/// The error for key derivation parameters Argon2id doesn't accept
+pub(crate) fn invalid_kdf() -> EngineError {
+    EngineError::InvalidOptions {
+        reason: "invalid key derivation parameters".to_owned(),
+    }
+}
+
+impl<S: ObjectStore + Sync> Repository<S> {
+    /// Every key slot of the repository
+    ///
+    /// Slots wrapping another master key, which a re-key that was cut short
+    /// left behind, are skipped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a key file is malformed, or if
+    /// the store can't be reached.
+    pub async fn key_slots(&self) -> Result<Vec<KeySlot>, EngineError> {
+        let prefix = self.key(KEYS);
+        let mut slots = Vec::new();
+        for object in self.store().list(&prefix).await? {
+            let Some(id) = object.key.strip_prefix(&prefix) else {
+                continue;
+            };
+            let file =
+                read_key_file::<_, KeyFile>(self.store(), &object.key).await?;
+            let Some(metadata) = hex::decode(&file.metadata)
+                .ok()
+                .and_then(|sealed| self.master_key().decrypt(&sealed))
+            else {
+                continue;
+            };
+            let metadata = serde_json::from_slice::<SlotMetadata>(&metadata)
+                .map_err(|_error| EngineError::MalformedObject {
+                    key: object.key.clone(),
+                })?;
+            slots.push(KeySlot {
+                id: id.to_owned(),
+                kind: metadata.kind,
+                label: metadata.label,
+                created_at: metadata.created_at,
+            });
+        }
+        slots.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(slots)
+    }
+
+    /// The contents of the key file of a slot, with everything but the
+    /// wrapped key encrypted with the master key
+    fn key_file(&self, key: WrappedKey, slot: &KeySlot) -> Vec<u8> {
+        let metadata = serde_json::to_vec(&SlotMetadata {
+            kind: slot.kind,
+            label: slot.label.clone(),
+            created_at: slot.created_at,
+        })
+        .expect("Key slot metadata always serializes");
+        let file = KeyFile {
+            key,
+            metadata: hex::encode(self.master_key().encrypt(&metadata)),
+        };
+        serde_json::to_vec(&file).expect("Key files always serialize")
+    }
+
+    /// Add a key slot that `secret` opens
+    ///
+    /// Use [`crate::new_secret`] to make the secret of a recovery key or a
+    /// machine key.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the key derivation parameters
+    /// are invalid, or if the store can't be reached.
+    pub async fn add_key_slot(
+        &self,
+        kind: KeySlotKind,
+        label: &str,
+        secret: &str,
+        kdf: KdfParams,
+    ) -> Result<KeySlot, EngineError> {
+        let key = {
+            let (master_key, secret) =
+                (self.master_key().clone(), secret.to_owned());
+            blocking(move || master_key.wrap(&secret, kdf))
+                .await
+                .ok_or_else(invalid_kdf)?
+        };
+        let slot = KeySlot {
+            id: hex::encode(rand::random::<[u8; 16]>()),
+            kind,
+            label: label.to_owned(),
+            created_at: Utc::now(),
+        };
+        let data = self.key_file(key, &slot);
+        let options = UploadOptions {
+            content_type: Some("application/json".to_owned()),
+            ..UploadOptions::default()
+        };
+        self.store()
+            .put(
+                &self.key(&format!("{KEYS}{}", slot.id)),
+                Bytes::from(data),
+                &options,
+            )
+            .await?;
+        tracing::info!(id = slot.id, %kind, "Added key slot");
+        Ok(slot)
+    }
+
+    /// Remove a key slot, so its secret no longer opens the repository
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no slot has the ID, if it is
+    /// the last slot, or if the store can't be reached.
+    pub async fn remove_key_slot(&self, id: &str) -> Result<(), EngineError> {
+        let slots = self.key_slots().await?;
+        if !slots.iter().any(|slot| slot.id == id) {
+            return Err(EngineError::KeySlotNotFound {
+                id: id.to_owned(),
+            });
+        }
+        if slots.len() == 1 {
+            return Err(EngineError::LastKeySlot);
+        }
+        self.store().delete(&self.key(&format!("{KEYS}{id}"))).await?;
+        tracing::info!(id, "Removed key slot");
+        Ok(())
+    }
+
+    /// Replace the secret of a key slot, keeping its kind and label
+    ///
+    /// The new slot is added before the old one is removed, so the
+    /// repository can't be left without a way in.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no slot has the ID, if the key
+    /// derivation parameters are invalid, or if the store can't be reached.
+    pub async fn rotate_key_slot(
+        &self,
+        id: &str,
+        secret: &str,
+        kdf: KdfParams,
+    ) -> Result<KeySlot, EngineError> {
+        let slot = self
+            .key_slots()
+            .await?
+            .into_iter()
+            .find(|slot| slot.id == id)
+            .ok_or_else(|| EngineError::KeySlotNotFound {
+                id: id.to_owned(),
+            })?;
+        let rotated =
+            self.add_key_slot(slot.kind, &slot.label, secret, kdf).await?;
+        self.store().delete(&self.key(&format!("{KEYS}{id}"))).await?;
+        Ok(rotated)
+    }
+
+    /// Move the repository to a new master key, encrypting every chunk,
+    /// index and manifest again
+    ///
+    /// Every existing key slot wraps the old master key and is removed; the
+    /// repository is left with a single slot that `secret` opens, and more
+    /// can be added afterwards. No other machine may back up into the
+    /// repository while it is re-keyed. A re-key that is cut short leaves
+    /// the repository readable with the old or the new secret, and running
+    /// it again finishes the job.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the key derivation parameters
+    /// are invalid, if a chunk or manifest is corrupt, or if the store can't
+    /// be reached.
+    pub async fn rekey(
+        &mut self,
+        kind: KeySlotKind,
+        label: &str,
+        secret: &str,
+        kdf: KdfParams,
+    ) -> Result<RekeySummary, EngineError> {
+        if !kdf.is_valid() {
+            return Err(invalid_kdf());
+        }
+        // Everything there now is replaced, including anything an earlier
+        // re-key left behind
+        let config = self.key(CONFIG);
+        let old_objects = self
+            .store()
+            .list(self.prefix())
+            .await?
+            .into_iter()
+            .filter(|object| object.key != config)
+            .map(|object| object.key)
+            .collect::<Vec<_>>();
+        let index = self.chunk_index().await?;
+        let snapshots = self.snapshots().await?;
+
+        let old_key = self.replace_master_key(MasterKey::generate());
+        let (ids, packs) = self.repack(&old_key, &index).await?;
+        let rekey_id = hex::encode(rand::random::<[u8; 16]>());
+        if !packs.is_empty() {
+            self.write_index(&format!("rekey-{rekey_id}"), &packs).await?;
+        }
+        for mut snapshot in snapshots.iter().cloned() {
+            for entry in &mut snapshot.entries {
+                for id in &mut entry.content {
+                    id.clone_from(ids.get(id.as_str()).ok_or_else(|| {
+                        EngineError::ChunkNotFound {
+                            id: id.clone(),
+                        }
+                    })?);
+                }
+            }
+            self.write_snapshot(&snapshot).await?;
+        }
+        let _slot = self.add_key_slot(kind, label, secret, kdf).await?;
+        // From here on only the new secret opens the repository
+        self.write_json(CONFIG, self.config()).await?;
+        for key in &old_objects {
+            self.store().delete(key).await?;
+        }
+        let summary = RekeySummary {
+            chunks: u64::try_from(ids.len()).unwrap_or(u64::MAX),
+            packs: u64::try_from(packs.len()).unwrap_or(u64::MAX),
+            snapshots: u64::try_from(snapshots.len()).unwrap_or(u64::MAX),
+            removed_objects: u64::try_from(old_objects.len())
+                .unwrap_or(u64::MAX),
+        };
+        tracing::info!(
+            chunks = summary.chunks,
+            snapshots = summary.snapshots,
+            "Re-keyed repository"
+        );
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use b2fake::FakeB2;
+    use b2native::{Bucket, ObjectStore};
+
+    use crate::{
+        new_secret,
+        test_support::{bucket, CHEAP},
+        BackupOptions, EngineError, InitOptions, KeySlotKind, Repository,
+        RestoreOptions,
+    };
+
+    async fn open(
+        server: &FakeB2,
+        secret: &str,
+    ) -> Result<Repository<Bucket>, EngineError> {
+        Repository::open(bucket(server).await, "backmate", secret).await
+    }
+
+    #[tokio::test]
+    async fn slots_are_added_rotated_and_removed() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let options = InitOptions {
+            kdf: CHEAP,
+            ..InitOptions::default()
+        };
+        let repository = Repository::init(
+            bucket(&server).await,
+            "backmate",
+            "alice",
+            &options,
+        )
+        .await
+        .expect("Repository should be created");
+
+        let recovery = new_secret();
+        assert_eq!(recovery.len(), 71);
+        let admin = repository
+            .add_key_slot(KeySlotKind::RecoveryKey, "IT", &recovery, CHEAP)
+            .await
+            .expect("Slot should be added");
+        let machine = new_secret();
+        let _server = repository
+            .add_key_slot(KeySlotKind::MachineKey, "nas", &machine, CHEAP)
+            .await
+            .expect("Slot should be added");
+        let key_files = repository
+            .store()
+            .list("backmate/keys/")
+            .await
+            .expect("Key files should list");
+        for object in &key_files {
+            let data = repository
+                .store()
+                .get(&object.key, None)
+                .await
+                .expect("Key file should download")
+                .data;
+            assert!(!data.windows(3).any(|window| window == b"nas"));
+            assert!(!data.windows(10).any(|window| window == b"machineKey"));
+        }
+        let slots = repository.key_slots().await.expect("Slots should list");
+        let kinds = slots.iter().map(|slot| slot.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                KeySlotKind::Passphrase,
+                KeySlotKind::RecoveryKey,
+                KeySlotKind::MachineKey
+            ]
+        );
+        for secret in ["alice", recovery.as_str(), machine.as_str()] {
+            let opened = open(&server, secret).await.expect("Secret opens");
+            assert_eq!(opened.config(), repository.config());
+        }
+
+        // Alice leaves, and her slot is rotated to her successor's
+        let alice = &slots[0];
+        let rotated = repository
+            .rotate_key_slot(&alice.id, "bob", CHEAP)
+            .await
+            .expect("Slot should rotate");
+        assert_eq!(rotated.label, alice.label);
+        assert!(matches!(
+            open(&server, "alice").await,
+            Err(EngineError::WrongPassphrase)
+        ));
+        let _bob = open(&server, "bob").await.expect("Bob should get in");
+
+        repository
+            .remove_key_slot(&admin.id)
+            .await
+            .expect("Slot should be removed");
+        assert!(open(&server, &recovery).await.is_err());
+        let error = repository
+            .remove_key_slot(&admin.id)
+            .await
+            .expect_err("Slot is gone");
+        assert!(matches!(error, EngineError::KeySlotNotFound { .. }));
+
+        // The last slot stays, so the repository can still be opened
+        let mut removed = Vec::new();
+        for slot in repository.key_slots().await.expect("Slots should list") {
+            removed.push(repository.remove_key_slot(&slot.id).await);
+        }
+        assert!(matches!(removed[..], [Ok(()), Err(EngineError::LastKeySlot)]));
+        let _bob = open(&server, "bob").await.expect("Bob should get in");
+    }
+
+    #[tokio::test]
+    async fn rekeys_keep_every_snapshot() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let options = InitOptions {
+            kdf: CHEAP,
+            ..InitOptions::default()
+        };
+        let mut repository = Repository::init(
+            bucket(&server).await,
+            "backmate",
+            "leaked",
+            &options,
+        )
+        .await
+        .expect("Repository should be created");
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let source = root.path().join("docs");
+        fs::create_dir_all(&source).expect("Create directory");
+        fs::write(source.join("a.txt"), "alpha").expect("Write file");
+        let first = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        fs::write(source.join("b.txt"), "beta").expect("Write file");
+        let second = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let old_id = repository.master_key().id(b"alpha");
+
+        let summary = repository
+            .rekey(KeySlotKind::Passphrase, "new", "fresh", CHEAP)
+            .await
+            .expect("Re-key should succeed");
+        assert_eq!(summary.chunks, 2);
+        assert_eq!(summary.snapshots, 2);
+        assert!(matches!(
+            open(&server, "leaked").await,
+            Err(EngineError::WrongPassphrase)
+        ));
+
+        let reopened = open(&server, "fresh").await.expect("New secret opens");
+        assert_ne!(reopened.master_key().id(b"alpha"), old_id);
+        let mut ids = vec![first.snapshot_id, second.snapshot_id.clone()];
+        ids.sort();
+        assert_eq!(
+            reopened.snapshot_ids().await.expect("Snapshots should list"),
+            ids
+        );
+        assert_eq!(reopened.key_slots().await.expect("Slots").len(), 1);
+        let snapshot = reopened
+            .snapshot(&second.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let target = root.path().join("restored");
+        let restored = reopened
+            .restore(&snapshot, &target, &RestoreOptions::default())
+            .await
+            .expect("Restore should succeed");
+        assert_eq!(restored.restored, 2);
+        assert_eq!(
+            fs::read_to_string(target.join("docs/b.txt")).expect("Read file"),
+            "beta"
+        );
+
+        // Nothing new is uploaded for data the repository already held
+        let again = reopened
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        assert_eq!(again.uploaded_chunks, 0);
+    }
+}
@@ -0,0 +1,235 @@
+#![doc = include_str!("../README.md")]
+
+mod backup;
+mod cache;
+mod chunker;
+mod compression;
+mod crypto;
+mod keys;
+mod pack;
+mod prune;
+mod repository;
+mod restore;
+mod rules;
+mod snapshot;
+#[cfg(test)]
+mod test_support;
+
+use std::{fmt, io, path::PathBuf};
+
+use b2native::SessionError;
+pub use backup::{BackupOptions, BackupSummary, SkippedPath};
+pub use cache::FileCache;
+use chrono::{DateTime, Utc};
+pub use chunker::ChunkerParams;
+pub use compression::DEFAULT_COMPRESSION_LEVEL;
+pub use crypto::{new_secret, KdfParams};
+pub use keys::{KeySlot, KeySlotKind, RekeySummary};
+pub use prune::{BackupSet, PlannedSnapshot, PrunePlan, RetentionPolicy};
+pub use repository::{
+    InitOptions, Repository, RepositoryConfig, DEFAULT_PREFIX,
+};
+pub use restore::{ConflictPolicy, RestoreOptions, RestoreSummary};
+pub use rules::{BackupRules, Decision, Preset, IGNORE_FILE};
+pub use snapshot::{Entry, EntryKind, Snapshot, Source};
+
+/// Errors that can be returned by the backup engine
+#[derive(Debug)]
+pub enum EngineError {
+    /// A request to the bucket failed.
+    Session(SessionError),
+    /// A local file or directory couldn't be read or written.
+    Io {
+        /// The path that failed
+        path: PathBuf,
+        /// Why it failed
+        error: io::Error,
+    },
+    /// The local cache of backed up files couldn't be opened, read or
+    /// written.
+    Cache {
+        /// The path of the cache database
+        path: PathBuf,
+        /// Why it failed
+        error: rusqlite::Error,
+    },
+    /// A repository was to be created where one already exists.
+    RepositoryExists {
+        /// The prefix of the existing repository
+        prefix: String,
+    },
+    /// No repository exists at the prefix that was opened.
+    RepositoryNotFound {
+        /// The prefix that was opened
+        prefix: String,
+    },
+    /// The passphrase or other secret doesn't open any key slot of the
+    /// repository.
+    WrongPassphrase,
+    /// No key slot with the requested ID exists.
+    KeySlotNotFound {
+        /// The ID of the key slot
+        id: String,
+    },
+    /// The only key slot of a repository can't be removed.
+    LastKeySlot,
+    /// The repository has a format this version of the engine can't read.
+    UnsupportedVersion {
+        /// The format version of the repository
+        version: u32,
+    },
+    /// An object in the repository couldn't be decoded.
+    ///
+    /// The object is corrupt, or wasn't written by the engine.
+    MalformedObject {
+        /// The key of the object
+        key: String,
+    },
+    /// No snapshot with the requested ID exists.
+    SnapshotNotFound {
+        /// The ID of the snapshot
+        id: String,
+    },
+    /// No snapshot was taken at or before the requested time, or there are
+    /// no snapshots at all.
+    NoSnapshot {
+        /// The time snapshots had to be taken by, if any
+        before: Option<DateTime<Utc>>,
+    },
+    /// A snapshot refers to a chunk that no pack holds.
+    ///
+    /// The files that use the chunk can't be restored.
+    ChunkNotFound {
+        /// The ID of the chunk
+        id: String,
+    },
+    /// A chunk doesn't hold the data its ID says it does.
+    ///
+    /// The chunk is corrupt, and the files that use it can't be restored.
+    ChunkMismatch {
+        /// The ID of the chunk
+        id: String,
+    },
+    /// A snapshot records an entry whose path would lead outside the
+    /// directory it is restored into.
+    ///
+    /// The manifest is corrupt or was tampered with.
+    UnsafePath {
+        /// The path recorded in the snapshot
+        path: String,
+    },
+    /// A pattern of the backup rules isn't a valid gitignore-style glob.
+    InvalidPattern {
+        /// The pattern
+        pattern: String,
+        /// Why it is invalid
+        reason: String,
+    },
+    /// A retention policy has no rules, so pruning with it would forget all
+    /// but the newest snapshot of every backup set.
+    EmptyRetentionPolicy,
+    /// A path to back up isn't a directory.
+    NotADirectory {
+        /// The path
+        path: PathBuf,
+    },
+    /// The chunk sizes or key derivation parameters a repository or key
+    /// slot was to be created with are invalid.
+    InvalidOptions {
+        /// What is invalid
+        reason: String,
+    },
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Session(error) => {
+                write!(f, "bucket request failed: {error:?}")
+            }
+            Self::Io {
+                path,
+                error,
+            } => write!(f, "{}: {error}", path.display()),
+            Self::Cache {
+                path,
+                error,
+            } => write!(f, "local cache {}: {error}", path.display()),
+            Self::RepositoryExists {
+                prefix,
+            } => {
+                write!(f, "a repository already exists at {prefix:?}")
+            }
+            Self::RepositoryNotFound {
+                prefix,
+            } => {
+                write!(f, "no repository exists at {prefix:?}")
+            }
+            Self::WrongPassphrase => {
+                write!(f, "the passphrase doesn't open the repository")
+            }
+            Self::KeySlotNotFound {
+                id,
+            } => write!(f, "key slot {id:?} does not exist"),
+            Self::LastKeySlot => {
+                write!(f, "the last key slot of a repository can't be removed")
+            }
+            Self::UnsupportedVersion {
+                version,
+            } => {
+                write!(
+                    f,
+                    "repository format version {version} is not supported"
+                )
+            }
+            Self::MalformedObject {
+                key,
+            } => {
+                write!(f, "repository object {key:?} is malformed")
+            }
+            Self::SnapshotNotFound {
+                id,
+            } => {
+                write!(f, "snapshot {id:?} does not exist")
+            }
+            Self::NoSnapshot {
+                before: Some(before),
+            } => write!(f, "no snapshot was taken before {before}"),
+            Self::NoSnapshot {
+                before: None,
+            } => write!(f, "the repository has no snapshots"),
+            Self::ChunkNotFound {
+                id,
+            } => write!(f, "chunk {id} is missing"),
+            Self::ChunkMismatch {
+                id,
+            } => write!(f, "chunk {id} is corrupt"),
+            Self::UnsafePath {
+                path,
+            } => write!(f, "snapshot path {path:?} is unsafe to restore"),
+            Self::InvalidPattern {
+                pattern,
+                reason,
+            } => write!(f, "invalid pattern {pattern:?}: {reason}"),
+            Self::EmptyRetentionPolicy => {
+                write!(f, "the retention policy doesn't keep any snapshots")
+            }
+            Self::NotADirectory {
+                path,
+            } => {
+                write!(f, "{} is not a directory", path.display())
+            }
+            Self::InvalidOptions {
+                reason,
+            } => write!(f, "invalid options: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<SessionError> for EngineError {
+    fn from(value: SessionError) -> Self {
+        Self::Session(value)
+    }
+}
@@ -0,0 +1,728 @@
+//! Run backups without the `BackMate` app
+//!
+//! The credentials are read from `B2_APPLICATION_KEY_ID` and
+//! `B2_APPLICATION_KEY`, the repository passphrase from
+//! `BACKMATE_PASSPHRASE` (or a machine key file named by
+//! `BACKMATE_KEY_FILE`), and the authorization endpoint can be overridden
+//! with `B2NATIVE_AUTHORIZE_ACCOUNT_ENDPOINT`, as it can for the app. The
+//! local cache of backed up files is kept in the user's cache directory
+//! unless `BACKMATE_CACHE` names another file.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use b2native::{Bucket, Session};
+use backmate_engine::{
+    new_secret, BackupOptions, BackupRules, ConflictPolicy, EngineError,
+    FileCache, InitOptions, KdfParams, KeySlotKind, Preset, PrunePlan,
+    Repository, RestoreOptions, RetentionPolicy, DEFAULT_COMPRESSION_LEVEL,
+    DEFAULT_PREFIX,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand};
+
+/// Back up directories into a Backblaze B2 bucket
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// The ID of the application key to use
+    #[arg(long, env = "B2_APPLICATION_KEY_ID", hide_env_values = true)]
+    key_id: String,
+    /// The application key to use
+    #[arg(long, env = "B2_APPLICATION_KEY", hide_env_values = true)]
+    key: String,
+    /// The bucket holding the repository
+    #[arg(long, env = "BACKMATE_BUCKET")]
+    bucket: String,
+    /// Where in the bucket the repository is kept
+    #[arg(long, default_value = DEFAULT_PREFIX)]
+    prefix: String,
+    /// The passphrase or recovery key that opens the repository
+    #[arg(
+        long,
+        env = "BACKMATE_PASSPHRASE",
+        hide_env_values = true,
+        required_unless_present = "key_file"
+    )]
+    passphrase: Option<String>,
+    /// A machine key file that opens the repository, used instead of the
+    /// passphrase
+    #[arg(long, env = "BACKMATE_KEY_FILE")]
+    key_file: Option<PathBuf>,
+    /// The local cache of backed up files, which lets backups skip files
+    /// that haven't changed
+    #[arg(long, env = "BACKMATE_CACHE")]
+    cache: Option<PathBuf>,
+    /// What to do
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The operations the binary can run
+#[derive(Subcommand)]
+enum Command {
+    /// Create a repository
+    Init,
+    /// Back up directories into a new snapshot
+    Backup(BackupArgs),
+    /// List the snapshots in the repository
+    Snapshots,
+    /// Restore a snapshot into a directory
+    Restore(RestoreArgs),
+    /// Manage the key slots that open the repository
+    Keys {
+        /// What to do with the key slots
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    /// Encrypt the whole repository again under a new master key, replacing
+    /// every key slot with a single new one
+    Rekey(NewSlotArgs),
+    /// Rebuild the local cache from the snapshots in the repository, after
+    /// it was lost
+    RebuildCache {
+        /// The machine name the snapshots were recorded with, if not this
+        /// machine's
+        #[arg(long)]
+        hostname: Option<String>,
+    },
+    /// Explain whether backing up directories would include a path, and
+    /// which rule decides it
+    Explain(ExplainArgs),
+    /// Forget the snapshots a retention policy doesn't keep
+    ///
+    /// The newest snapshot of every backup set, the snapshots one machine
+    /// took of the same directories, is always kept.
+    Prune(PruneArgs),
+}
+
+/// The operations on key slots
+#[derive(Subcommand)]
+enum KeysCommand {
+    /// List the key slots
+    List,
+    /// Add a key slot
+    Add(NewSlotArgs),
+    /// Replace the secret of a key slot, keeping its kind and label
+    Rotate {
+        /// The ID of the key slot
+        id: String,
+        /// Where the new secret comes from
+        #[command(flatten)]
+        secret: NewSecretArgs,
+    },
+    /// Remove a key slot
+    Remove {
+        /// The ID of the key slot
+        id: String,
+    },
+}
+
+/// The key slot to add
+#[derive(Args)]
+struct NewSlotArgs {
+    /// What opens the slot: passphrase, recovery-key or machine-key
+    #[arg(long, default_value = "passphrase")]
+    kind: KeySlotKind,
+    /// A note on whose or which machine's slot this is
+    #[arg(long, default_value = "")]
+    label: String,
+    /// Where the new secret comes from
+    #[command(flatten)]
+    secret: NewSecretArgs,
+}
+
+/// Where the secret of a new key slot comes from
+///
+/// Passphrases are given by the user; recovery keys are generated and
+/// printed once, and machine keys are generated and written to a file.
+#[derive(Args)]
+struct NewSecretArgs {
+    /// The new passphrase, for passphrase slots
+    #[arg(long, env = "BACKMATE_NEW_PASSPHRASE", hide_env_values = true)]
+    new_passphrase: Option<String>,
+    /// The file to write a new machine key to, which must not exist yet
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+/// The secret that opens the repository
+fn secret(cli: &Cli) -> Result<String, EngineError> {
+    match &cli.key_file {
+        Some(path) => fs::read_to_string(path)
+            .map(|secret| secret.trim().to_owned())
+            .map_err(|error| EngineError::Io {
+                path: path.clone(),
+                error,
+            }),
+        None => Ok(cli.passphrase.clone().unwrap_or_default()),
+    }
+}
+
+/// Get or make the secret of a new key slot, before the slot is written
+///
+/// A machine key is written to its file here, so a slot is never added
+/// for a key that was lost.
+fn new_slot_secret(
+    kind: KeySlotKind,
+    args: &NewSecretArgs,
+) -> Result<String, EngineError> {
+    match kind {
+        KeySlotKind::Passphrase => {
+            let Some(passphrase) = &args.new_passphrase else {
+                Cli::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "passphrase slots need --new-passphrase or \
+                         BACKMATE_NEW_PASSPHRASE",
+                    )
+                    .exit()
+            };
+            Ok(passphrase.clone())
+        }
+        KeySlotKind::RecoveryKey => Ok(new_secret()),
+        KeySlotKind::MachineKey => {
+            let Some(path) = &args.output else {
+                Cli::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "machine key slots need --output",
+                    )
+                    .exit()
+            };
+            let secret = new_secret();
+            write_key_file(path, &secret).map_err(|error| EngineError::Io {
+                path: path.clone(),
+                error,
+            })?;
+            Ok(secret)
+        }
+    }
+}
+
+/// Write a machine key to a new file only its owner can read
+fn write_key_file(path: &Path, secret: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{secret}")?;
+    file.sync_all()
+}
+
+/// Tell the user about the secret of a key slot that was just written
+fn report_slot(
+    kind: KeySlotKind,
+    id: &str,
+    secret: &str,
+    args: &NewSecretArgs,
+) {
+    match kind {
+        KeySlotKind::Passphrase => println!("Added key slot {id}"),
+        KeySlotKind::RecoveryKey => {
+            println!("Added key slot {id} with recovery key\n\n    {secret}\n");
+            println!("Keep it somewhere safe; it is not shown again");
+        }
+        KeySlotKind::MachineKey => println!(
+            "Added key slot {id} with the machine key in {}",
+            args.output.as_deref().unwrap_or(Path::new("")).display()
+        ),
+    }
+}
+
+/// What to back up, and how
+#[derive(Args)]
+struct BackupArgs {
+    /// The directories to back up
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// The machine name to record instead of this machine's
+    #[arg(long)]
+    hostname: Option<String>,
+    /// The zstd level to compress new chunks at, from 1 to 22
+    #[arg(long, default_value_t = DEFAULT_COMPRESSION_LEVEL)]
+    compression_level: i32,
+    /// Store new chunks uncompressed
+    #[arg(long, conflicts_with = "compression_level")]
+    no_compression: bool,
+    /// Read every file, neither using nor updating the local cache
+    #[arg(long)]
+    no_cache: bool,
+    /// A label to record in the snapshot, which `prune --keep-tag` keeps it
+    /// by
+    #[arg(long = "tag", value_name = "TAG")]
+    tags: Vec<String>,
+    /// Which paths to back up
+    #[command(flatten)]
+    rules: RuleArgs,
+}
+
+/// Which paths under the directories to back up
+#[derive(Args)]
+struct RuleArgs {
+    /// A gitignore-style pattern of paths to leave out, or to back up when
+    /// it starts with `!`
+    #[arg(long = "exclude", value_name = "PATTERN")]
+    patterns: Vec<String>,
+    /// A built-in set of patterns to leave out: node-modules, build-target,
+    /// browser-caches or trash
+    #[arg(long = "preset", value_name = "PRESET")]
+    presets: Vec<Preset>,
+    /// Leave out files larger than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_size: Option<u64>,
+    /// Leave out files last modified more than this many days ago
+    #[arg(long, value_name = "DAYS")]
+    max_age: Option<u32>,
+    /// Back up directories tagged with `CACHEDIR.TAG` too
+    #[arg(long)]
+    no_exclude_caches: bool,
+    /// Ignore the patterns in `.backmateignore` files
+    #[arg(long)]
+    no_ignore_files: bool,
+}
+
+impl From<RuleArgs> for BackupRules {
+    fn from(args: RuleArgs) -> Self {
+        Self {
+            patterns: args.patterns,
+            presets: args.presets,
+            max_size: args.max_size,
+            max_age: args.max_age.map(|days| TimeDelta::days(days.into())),
+            exclude_caches: !args.no_exclude_caches,
+            ignore_files: !args.no_ignore_files,
+        }
+    }
+}
+
+/// The retention policy to prune with
+#[derive(Args)]
+struct PruneArgs {
+    /// Keep this many of the newest snapshots
+    #[arg(long, default_value_t)]
+    keep_last: usize,
+    /// Keep the newest snapshot of each of this many hours
+    #[arg(long, default_value_t)]
+    keep_hourly: usize,
+    /// Keep the newest snapshot of each of this many days
+    #[arg(long, default_value_t)]
+    keep_daily: usize,
+    /// Keep the newest snapshot of each of this many weeks
+    #[arg(long, default_value_t)]
+    keep_weekly: usize,
+    /// Keep the newest snapshot of each of this many months
+    #[arg(long, default_value_t)]
+    keep_monthly: usize,
+    /// Keep the newest snapshot of each of this many years
+    #[arg(long, default_value_t)]
+    keep_yearly: usize,
+    /// Keep every snapshot taken up to this many days before the newest
+    #[arg(long, value_name = "DAYS")]
+    keep_within: Option<u32>,
+    /// Keep every snapshot with this tag
+    #[arg(long = "keep-tag", value_name = "TAG")]
+    keep_tags: Vec<String>,
+    /// Show what would be forgotten without forgetting it
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl From<PruneArgs> for RetentionPolicy {
+    fn from(args: PruneArgs) -> Self {
+        Self {
+            last: args.keep_last,
+            hourly: args.keep_hourly,
+            daily: args.keep_daily,
+            weekly: args.keep_weekly,
+            monthly: args.keep_monthly,
+            yearly: args.keep_yearly,
+            within: args.keep_within.map(|days| TimeDelta::days(days.into())),
+            tags: args.keep_tags,
+        }
+    }
+}
+
+/// The path to explain, and the backup it would be part of
+#[derive(Args)]
+struct ExplainArgs {
+    /// The path to explain
+    path: PathBuf,
+    /// A directory that would be backed up
+    #[arg(long = "source", value_name = "DIRECTORY", required = true)]
+    sources: Vec<PathBuf>,
+    /// Which paths to back up
+    #[command(flatten)]
+    rules: RuleArgs,
+}
+
+/// What to restore, and where
+#[derive(Args)]
+struct RestoreArgs {
+    /// The ID of the snapshot to restore, or the newest one when not given
+    snapshot: Option<String>,
+    /// Restore the newest snapshot taken at or before this time, such as
+    /// `2026-01-31T18:00:00Z`
+    #[arg(long, conflicts_with = "snapshot")]
+    before: Option<DateTime<Utc>>,
+    /// The directory to restore into
+    #[arg(long)]
+    target: PathBuf,
+    /// Only restore this snapshot path and everything under it
+    #[arg(long)]
+    include: Vec<String>,
+    /// What to do with files that already exist: skip, overwrite, rename or
+    /// only-if-newer
+    #[arg(long, default_value = "skip")]
+    conflict: ConflictPolicy,
+}
+
+/// A compression ratio as the user reads it
+fn ratio(ratio: Option<f64>) -> String {
+    ratio.map_or_else(|| "-".to_owned(), |ratio| format!("{ratio:.2}x"))
+}
+
+/// Open the local cache at the path given, or in the user's cache directory
+///
+/// Returns `None` if no path was given and there is no cache directory.
+fn open_cache(path: Option<PathBuf>) -> Result<Option<FileCache>, EngineError> {
+    path.or_else(|| {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .or_else(|| env::var_os("LOCALAPPDATA"))
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var_os("HOME")
+                    .map(|home| PathBuf::from(home).join(".cache"))
+            })?;
+        Some(base.join("backmate").join("files.sqlite"))
+    })
+    .map(FileCache::open)
+    .transpose()
+}
+
+/// Back up directories and report what was uploaded
+async fn backup(
+    repository: &Repository<Bucket>,
+    cache: Option<PathBuf>,
+    args: BackupArgs,
+) -> Result<(), EngineError> {
+    let cache = if args.no_cache {
+        None
+    } else {
+        open_cache(cache)?.map(Arc::new)
+    };
+    let options = BackupOptions {
+        hostname: args.hostname,
+        compression_level: (!args.no_compression)
+            .then_some(args.compression_level),
+        tags: args.tags,
+        cache,
+        rules: args.rules.into(),
+    };
+    let summary = repository.backup(&args.paths, &options).await?;
+    for skipped in &summary.skipped {
+        eprintln!("Skipped {}: {}", skipped.path.display(), skipped.reason);
+    }
+    println!(
+        "Snapshot {}: {} files ({} unchanged, {} paths excluded), {} bytes, \
+         {} of {} chunks new, {} bytes compressed {} to {} bytes in {} packs",
+        summary.snapshot_id,
+        summary.files,
+        summary.unchanged_files,
+        summary.excluded,
+        summary.bytes,
+        summary.uploaded_chunks,
+        summary.chunks,
+        summary.uploaded_bytes,
+        ratio(summary.compression_ratio()),
+        summary.stored_bytes,
+        summary.uploaded_packs
+    );
+    Ok(())
+}
+
+/// Restore a snapshot, stopping cleanly on Ctrl-C
+async fn restore(
+    repository: &Repository<Bucket>,
+    args: RestoreArgs,
+) -> Result<(), EngineError> {
+    let snapshot = match args.snapshot {
+        Some(id) => repository.snapshot(&id).await?,
+        None => repository.latest_snapshot(args.before).await?,
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    let interrupt = tokio::spawn({
+        let stop = Arc::clone(&stop);
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Stopping after the current file");
+                stop.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    let options = RestoreOptions {
+        include: args.include,
+        conflict: args.conflict,
+        stop: Some(stop),
+    };
+    let summary = repository.restore(&snapshot, &args.target, &options).await;
+    interrupt.abort();
+    let summary = summary?;
+    for skipped in &summary.skipped {
+        eprintln!("Skipped {}: {}", skipped.path.display(), skipped.reason);
+    }
+    println!(
+        "Snapshot {}: {} files restored, {} bytes, {} kept",
+        summary.snapshot_id, summary.restored, summary.bytes, summary.kept
+    );
+    if summary.stopped {
+        println!("Stopped early; run the same command again to resume");
+    }
+    Ok(())
+}
+
+/// Run a key slot command
+async fn keys(
+    repository: &Repository<Bucket>,
+    command: KeysCommand,
+) -> Result<(), EngineError> {
+    match command {
+        KeysCommand::List => {
+            for slot in repository.key_slots().await? {
+                println!(
+                    "{}  {}  {:<12}  {}",
+                    slot.id,
+                    slot.created_at.format("%Y-%m-%d %H:%M:%S"),
+                    slot.kind,
+                    slot.label
+                );
+            }
+        }
+        KeysCommand::Add(args) => {
+            let secret = new_slot_secret(args.kind, &args.secret)?;
+            let slot = repository
+                .add_key_slot(
+                    args.kind,
+                    &args.label,
+                    &secret,
+                    KdfParams::default(),
+                )
+                .await?;
+            report_slot(args.kind, &slot.id, &secret, &args.secret);
+        }
+        KeysCommand::Rotate {
+            id,
+            secret: args,
+        } => {
+            let kind = repository
+                .key_slots()
+                .await?
+                .into_iter()
+                .find(|slot| slot.id == id)
+                .map(|slot| slot.kind)
+                .ok_or(EngineError::KeySlotNotFound {
+                    id: id.clone(),
+                })?;
+            let secret = new_slot_secret(kind, &args)?;
+            let slot = repository
+                .rotate_key_slot(&id, &secret, KdfParams::default())
+                .await?;
+            report_slot(kind, &slot.id, &secret, &args);
+        }
+        KeysCommand::Remove {
+            id,
+        } => {
+            repository.remove_key_slot(&id).await?;
+            println!("Removed key slot {id}");
+        }
+    }
+    Ok(())
+}
+
+/// Move the repository to a new master key and report the new key slot
+async fn rekey(
+    repository: &mut Repository<Bucket>,
+    args: &NewSlotArgs,
+) -> Result<(), EngineError> {
+    let secret = new_slot_secret(args.kind, &args.secret)?;
+    let summary = repository
+        .rekey(args.kind, &args.label, &secret, KdfParams::default())
+        .await?;
+    println!(
+        "Re-keyed {} chunks in {} packs and {} snapshots, removing {} old \
+         objects",
+        summary.chunks,
+        summary.packs,
+        summary.snapshots,
+        summary.removed_objects
+    );
+    let slot = repository
+        .key_slots()
+        .await?
+        .into_iter()
+        .next()
+        .map(|slot| slot.id)
+        .unwrap_or_default();
+    report_slot(args.kind, &slot, &secret, &args.secret);
+    Ok(())
+}
+
+/// Show a prune plan, and carry it out unless it is a dry run
+async fn prune(
+    repository: &Repository<Bucket>,
+    args: PruneArgs,
+) -> Result<(), EngineError> {
+    let dry_run = args.dry_run;
+    let PrunePlan {
+        keep,
+        forget,
+    } = repository.plan_prune(&args.into()).await?;
+    let mut plan = keep
+        .iter()
+        .map(|snapshot| ("keep  ", snapshot))
+        .chain(forget.iter().map(|snapshot| ("forget", snapshot)))
+        .collect::<Vec<_>>();
+    plan.sort_by(|(_, a), (_, b)| (&a.set, a.time).cmp(&(&b.set, b.time)));
+    let mut set = None;
+    for (action, snapshot) in plan {
+        if set != Some(&snapshot.set) {
+            println!("{}", snapshot.set);
+            set = Some(&snapshot.set);
+        }
+        println!(
+            "  {action}  {}  {}  {}",
+            snapshot.id,
+            snapshot.time.format("%Y-%m-%d %H:%M:%S"),
+            snapshot.reasons.join(", ")
+        );
+    }
+    if dry_run {
+        println!("Would forget {} snapshots", forget.len());
+    } else {
+        let forgotten = repository
+            .prune(&PrunePlan {
+                keep,
+                forget,
+            })
+            .await?;
+        println!("Forgot {forgotten} snapshots");
+    }
+    Ok(())
+}
+
+/// Explain whether a path would be backed up, without connecting
+fn explain(args: ExplainArgs) -> Result<(), EngineError> {
+    let decision =
+        BackupRules::from(args.rules).explain(&args.sources, &args.path)?;
+    println!("{}: {}", args.path.display(), decision.reason);
+    Ok(())
+}
+
+/// Run the command given on the command line
+async fn run(cli: Cli) -> Result<(), EngineError> {
+    let secret = secret(&cli)?;
+    let session = Session::try_new(cli.key_id, cli.key).await?;
+    let bucket = session.bucket(&cli.bucket).await?;
+    match cli.command {
+        Command::Init => {
+            let repository = Repository::init(
+                bucket,
+                &cli.prefix,
+                &secret,
+                &InitOptions::default(),
+            )
+            .await?;
+            println!("Created repository {}", repository.config().id);
+        }
+        Command::Backup(args) => {
+            let repository =
+                Repository::open(bucket, &cli.prefix, &secret).await?;
+            backup(&repository, cli.cache, args).await?;
+        }
+        Command::RebuildCache {
+            hostname,
+        } => {
+            let repository =
+                Repository::open(bucket, &cli.prefix, &secret).await?;
+            let Some(cache) = open_cache(cli.cache)? else {
+                Cli::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "no cache directory was found; pass --cache",
+                    )
+                    .exit()
+            };
+            let files = repository
+                .rebuild_cache(&Arc::new(cache), hostname.as_deref())
+                .await?;
+            println!("Recorded {files} unchanged files in the local cache");
+        }
+        Command::Snapshots => {
+            let repository =
+                Repository::open(bucket, &cli.prefix, &secret).await?;
+            for snapshot in repository.snapshots().await? {
+                let paths = snapshot
+                    .sources
+                    .iter()
+                    .map(|source| source.path.display().to_string())
+                    .collect::<Vec<_>>();
+                println!(
+                    "{}  {}  {}  {:>6}  {}",
+                    snapshot.id,
+                    snapshot.time.format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.hostname,
+                    ratio(snapshot.compression_ratio()),
+                    paths.join(", ")
+                );
+            }
+        }
+        Command::Restore(args) => {
+            let repository =
+                Repository::open(bucket, &cli.prefix, &secret).await?;
+            restore(&repository, args).await?;
+        }
+        Command::Keys {
+            command,
+        } => {
+            let repository =
+                Repository::open(bucket, &cli.prefix, &secret).await?;
+            keys(&repository, command).await?;
+        }
+        Command::Rekey(args) => {
+            let mut repository =
+                Repository::open(bucket, &cli.prefix, &secret).await?;
+            rekey(&mut repository, &args).await?;
+        }
+        Command::Prune(args) => {
+            let repository =
+                Repository::open(bucket, &cli.prefix, &secret).await?;
+            prune(&repository, args).await?;
+        }
+        Command::Explain(args) => explain(args)?,
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Explain(args) => explain(args),
+        _ => run(cli).await,
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! Pack files bundling many chunks into one object
+//!
+//! Storing every chunk as an object of its own would cost a transaction per
+//! chunk to upload, list and delete. A backup run fills packs of a few tens
+//! of megabytes instead, and uploads each as a single object:
+//!
+//! ```text
+//! "BMPK"  version (u32 LE)  header length (u32 LE)  header  chunks...
+//! ```
+//!
+//! The header is an encrypted JSON list of the ID, length and compression of
+//! every chunk, in the order they follow it, so a pack can be read on its
+//! own. Chunks are encrypted one by one, so each can be downloaded and
+//! decrypted without the rest of its pack. Packs are named by the SHA256 of
+//! what they hold, which is all ciphertext. Each run also writes an index
+//! object listing where its chunks went, so opening a repository doesn't
+//! take a download per pack, and restores fetch single chunks with range
+//! requests.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use b2native::{ObjectStore, UploadOptions};
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    compression::{decompress, Compression},
+    crypto::MasterKey,
+    EngineError, Repository,
+};
+
+/// The first bytes of every pack
+const MAGIC: &[u8; 4] = b"BMPK";
+
+/// The pack format written by this version of the engine
+const PACK_VERSION: u32 = 3;
+
+/// The size of the magic, version and header length
+const PREAMBLE_SIZE: u64 = 12;
+
+/// The prefix of packs
+const PACKS: &str = "packs/";
+
+/// The prefix of index objects
+const INDEX: &str = "index/";
+
+/// A chunk as the header of a pack lists it
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HeaderEntry {
+    /// The ID of the chunk
+    id: String,
+    /// The size of the chunk
+    length: u64,
+    /// How the chunk is compressed
+    compression: Compression,
+}
+
+/// A chunk and where it starts in its pack
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PackedChunk {
+    /// The ID of the chunk
+    pub(crate) id: String,
+    /// The offset of the chunk from the start of the pack
+    pub(crate) offset: u64,
+    /// The size of the chunk
+    pub(crate) length: u64,
+    /// How the chunk is compressed
+    pub(crate) compression: Compression,
+}
+
+/// Every chunk of a pack, as an index object records them
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PackIndex {
+    /// The ID of the pack
+    pub(crate) pack: String,
+    /// The chunks of the pack
+    pub(crate) chunks: Vec<PackedChunk>,
+}
+
+/// Where a chunk is stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ChunkLocation {
+    /// The ID of the pack holding the chunk
+    pub(crate) pack: String,
+    /// The offset of the chunk from the start of the pack
+    pub(crate) offset: u64,
+    /// The size of the chunk
+    pub(crate) length: u64,
+    /// How the chunk is compressed
+    pub(crate) compression: Compression,
+}
+
+/// The chunks of a pack that is being filled
+#[derive(Debug, Default)]
+pub(crate) struct PackWriter {
+    /// The encrypted chunks added so far, in order
+    chunks: Vec<(String, Compression, Bytes)>,
+    /// The total size of the encrypted chunks
+    size: u64,
+}
+
+impl PackWriter {
+    /// Add an encrypted chunk to the pack
+    pub(crate) fn add(
+        &mut self,
+        id: String,
+        compression: Compression,
+        data: Bytes,
+    ) {
+        self.size += u64::try_from(data.len()).unwrap_or(u64::MAX);
+        self.chunks.push((id, compression, data));
+    }
+
+    /// The total size of the encrypted chunks added so far
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether no chunk has been added
+    pub(crate) fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Assemble the pack from the chunks added, leaving the writer empty
+    fn finish(&mut self, master_key: &MasterKey) -> (Bytes, PackIndex) {
+        let chunks = std::mem::take(&mut self.chunks);
+        self.size = 0;
+        let entries = chunks
+            .iter()
+            .map(|(id, compression, data)| HeaderEntry {
+                id: id.clone(),
+                length: u64::try_from(data.len()).unwrap_or(u64::MAX),
+                compression: *compression,
+            })
+            .collect::<Vec<_>>();
+        let header = master_key.encrypt(
+            &serde_json::to_vec(&entries)
+                .expect("Pack headers always serialize"),
+        );
+        let mut data = BytesMut::new();
+        data.put_slice(MAGIC);
+        data.put_u32_le(PACK_VERSION);
+        data.put_u32_le(
+            u32::try_from(header.len()).expect("Pack headers are small"),
+        );
+        data.put_slice(&header);
+        for (_id, _compression, chunk) in &chunks {
+            data.put_slice(chunk);
+        }
+        let data = data.freeze();
+        let index = PackIndex {
+            pack: pack_id(&data),
+            chunks: locate(PREAMBLE_SIZE + header_size(&header), entries),
+        };
+        (data, index)
+    }
+}
+
+/// The hex SHA256 of a pack, which is its ID
+fn pack_id(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// The size of a header, as a pack offset
+fn header_size(header: &[u8]) -> u64 {
+    u64::try_from(header.len()).unwrap_or(u64::MAX)
+}
+
+/// Give the chunks of a header their offsets, the first starting at `start`
+fn locate(start: u64, entries: Vec<HeaderEntry>) -> Vec<PackedChunk> {
+    let mut offset = start;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let chunk = PackedChunk {
+                id: entry.id,
+                offset,
+                length: entry.length,
+                compression: entry.compression,
+            };
+            offset += entry.length;
+            chunk
+        })
+        .collect()
+}
+
+/// The length of the header following a preamble, if the preamble is valid
+fn header_length(preamble: &[u8]) -> Option<u64> {
+    let (magic, rest) = preamble.split_first_chunk::<4>()?;
+    let (version, rest) = rest.split_first_chunk::<4>()?;
+    let (length, _rest) = rest.split_first_chunk::<4>()?;
+    (magic == MAGIC && u32::from_le_bytes(*version) == PACK_VERSION)
+        .then(|| u64::from(u32::from_le_bytes(*length)))
+}
+
+impl<S: ObjectStore + Sync> Repository<S> {
+    /// The largest a chunk of the repository can be, which bounds how far
+    /// one is decompressed
+    fn max_chunk_size(&self) -> usize {
+        usize::try_from(self.config().chunker.max_size).unwrap_or(usize::MAX)
+    }
+
+    /// Upload the chunks of a writer as a pack, leaving the writer empty
+    pub(crate) async fn write_pack(
+        &self,
+        writer: &mut PackWriter,
+    ) -> Result<PackIndex, EngineError> {
+        let (data, index) = writer.finish(self.master_key());
+        let key = self.key(&format!("{PACKS}{}", index.pack));
+        self.store().put(&key, data, &UploadOptions::default()).await?;
+        tracing::debug!(
+            pack = index.pack,
+            chunks = index.chunks.len(),
+            "Uploaded pack"
+        );
+        Ok(index)
+    }
+
+    /// Write the index object of a backup run, listing the chunks of the
+    /// packs it uploaded
+    pub(crate) async fn write_index(
+        &self,
+        snapshot_id: &str,
+        packs: &[PackIndex],
+    ) -> Result<(), EngineError> {
+        let name = self.master_key().name("index", snapshot_id);
+        self.write_json(&format!("{INDEX}{name}"), &packs).await
+    }
+
+    /// Read the header of a pack, or `None` if it was encrypted with
+    /// another master key
+    async fn read_pack_header(
+        &self,
+        pack: &str,
+    ) -> Result<Option<Vec<PackedChunk>>, EngineError> {
+        let key = self.key(&format!("{PACKS}{pack}"));
+        let malformed = || EngineError::MalformedObject {
+            key: key.clone(),
+        };
+        let preamble = self.store().get(&key, Some(0..PREAMBLE_SIZE)).await?;
+        let length = header_length(&preamble.data).ok_or_else(malformed)?;
+        let header = self
+            .store()
+            .get(&key, Some(PREAMBLE_SIZE..PREAMBLE_SIZE + length))
+            .await?;
+        let Some(json) = self.master_key().decrypt(&header.data) else {
+            tracing::warn!(key, "Skipping a pack under another key");
+            return Ok(None);
+        };
+        let entries =
+            serde_json::from_slice(&json).map_err(|_error| malformed())?;
+        Ok(Some(locate(PREAMBLE_SIZE + length, entries)))
+    }
+
+    /// Where every chunk in the repository is stored
+    ///
+    /// Packs that no index object lists, which a backup run that was cut
+    /// short leaves behind, are found from their headers, so their chunks
+    /// are reused rather than uploaded again.
+    pub(crate) async fn chunk_index(
+        &self,
+    ) -> Result<HashMap<String, ChunkLocation>, EngineError> {
+        let mut index = HashMap::new();
+        let mut add = |pack: &str, chunks: Vec<PackedChunk>| {
+            for chunk in chunks {
+                let _previous = index.insert(
+                    chunk.id,
+                    ChunkLocation {
+                        pack: pack.to_owned(),
+                        offset: chunk.offset,
+                        length: chunk.length,
+                        compression: chunk.compression,
+                    },
+                );
+            }
+        };
+        let mut indexed = HashSet::new();
+        let prefix = self.key(INDEX);
+        for object in self.store().list(&prefix).await? {
+            let Some(name) = object.key.strip_prefix(&prefix) else {
+                continue;
+            };
+            let packs = self
+                .read_listed_json::<Vec<PackIndex>>(&format!("{INDEX}{name}"))
+                .await?;
+            for pack in packs.into_iter().flatten() {
+                add(&pack.pack, pack.chunks);
+                indexed.insert(pack.pack);
+            }
+        }
+        let prefix = self.key(PACKS);
+        for object in self.store().list(&prefix).await? {
+            let Some(pack) = object.key.strip_prefix(&prefix) else {
+                continue;
+            };
+            if !indexed.contains(pack) {
+                tracing::info!(pack, "Reading the header of an unindexed pack");
+                if let Some(chunks) = self.read_pack_header(pack).await? {
+                    add(pack, chunks);
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    /// Download, decrypt and decompress a chunk, and check that it holds
+    /// what its ID says
+    pub(crate) async fn read_chunk(
+        &self,
+        index: &HashMap<String, ChunkLocation>,
+        id: &str,
+    ) -> Result<Bytes, EngineError> {
+        let location =
+            index.get(id).ok_or_else(|| EngineError::ChunkNotFound {
+                id: id.to_owned(),
+            })?;
+        let data = self
+            .store()
+            .get(
+                &self.key(&format!("{PACKS}{}", location.pack)),
+                Some(location.offset..location.offset + location.length),
+            )
+            .await?
+            .data;
+        self.master_key()
+            .decrypt(&data)
+            .and_then(|data| {
+                decompress(location.compression, data, self.max_chunk_size())
+            })
+            .filter(|data| self.master_key().id(data) == id)
+            .map(Bytes::from)
+            .ok_or_else(|| EngineError::ChunkMismatch {
+                id: id.to_owned(),
+            })
+    }
+
+    /// Copy every chunk of `index`, which is encrypted with `old_key`, into
+    /// new packs under the current master key
+    ///
+    /// Each pack is downloaded once. Returns the new ID of every chunk by its
+    /// old one, and the new packs.
+    pub(crate) async fn repack(
+        &self,
+        old_key: &MasterKey,
+        index: &HashMap<String, ChunkLocation>,
+    ) -> Result<(HashMap<String, String>, Vec<PackIndex>), EngineError> {
+        let mut by_pack =
+            BTreeMap::<&str, Vec<(&String, &ChunkLocation)>>::new();
+        for (id, location) in index {
+            by_pack.entry(&location.pack).or_default().push((id, location));
+        }
+        let mut ids = HashMap::new();
+        let mut packs = Vec::new();
+        let mut writer = PackWriter::default();
+        for (pack, chunks) in by_pack {
+            let data = self
+                .store()
+                .get(&self.key(&format!("{PACKS}{pack}")), None)
+                .await?
+                .data;
+            for (id, location) in chunks {
+                let sealed = usize::try_from(location.offset)
+                    .ok()
+                    .zip(usize::try_from(location.length).ok())
+                    .and_then(|(offset, length)| {
+                        data.get(offset..offset.checked_add(length)?)
+                    });
+                // The chunk stays compressed as it was, and is only
+                // decompressed to check it and to give it its new ID
+                let (stored, plaintext) = sealed
+                    .and_then(|sealed| old_key.decrypt(sealed))
+                    .and_then(|stored| {
+                        let plaintext = decompress(
+                            location.compression,
+                            stored.clone(),
+                            self.max_chunk_size(),
+                        )?;
+                        Some((stored, plaintext))
+                    })
+                    .filter(|(_stored, plaintext)| old_key.id(plaintext) == *id)
+                    .ok_or_else(|| EngineError::ChunkMismatch {
+                        id: id.clone(),
+                    })?;
+                let new_id = self.master_key().id(&plaintext);
+                let sealed = self.master_key().encrypt(&stored);
+                writer.add(
+                    new_id.clone(),
+                    location.compression,
+                    Bytes::from(sealed),
+                );
+                ids.insert(id.clone(), new_id);
+                if writer.size() >= self.config().pack_size {
+                    packs.push(self.write_pack(&mut writer).await?);
+                }
+            }
+        }
+        if !writer.is_empty() {
+            packs.push(self.write_pack(&mut writer).await?);
+        }
+        Ok((ids, packs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::{header_length, pack_id, PackWriter, PREAMBLE_SIZE};
+    use crate::{compression::Compression, crypto::MasterKey};
+
+    #[test]
+    fn packs_describe_their_chunks() {
+        let master_key = MasterKey::generate();
+        let mut writer = PackWriter::default();
+        for chunk in ["first", "second", "third"] {
+            writer.add(
+                master_key.id(chunk.as_bytes()),
+                Compression::None,
+                Bytes::from(master_key.encrypt(chunk.as_bytes())),
+            );
+        }
+        let (data, index) = writer.finish(&master_key);
+        assert!(writer.is_empty());
+        assert_eq!(index.pack, pack_id(&data));
+
+        let length = header_length(&data).expect("Preamble should be valid");
+        let start = usize::try_from(PREAMBLE_SIZE + length).expect("Offset");
+        let header = master_key
+            .decrypt(&data[12..start])
+            .expect("Header should decrypt");
+        let header = serde_json::from_slice::<serde_json::Value>(&header)
+            .expect("Header should be JSON");
+        assert_eq!(header[1]["id"], master_key.id(b"second").as_str());
+        assert_eq!(header[1]["compression"], "none");
+        for (chunk, expected) in index.chunks.iter().zip(["first", "second"]) {
+            let offset = usize::try_from(chunk.offset).expect("Offset");
+            let end = offset + usize::try_from(chunk.length).expect("Length");
+            let plaintext = master_key
+                .decrypt(&data[offset..end])
+                .expect("Chunk should decrypt");
+            assert_eq!(plaintext, expected.as_bytes());
+        }
+        assert_eq!(index.chunks[0].offset, PREAMBLE_SIZE + length);
+        assert_eq!(header_length(b"BMPK\x02\0\0\0\x10\0\0\0"), None);
+    }
+}
@@ -0,0 +1,439 @@
+//! Retention policies and pruning snapshots
+//!
+//! Snapshots are grouped into backup sets, the snapshots one machine took of
+//! the same directories, and a retention policy is applied to each set on
+//! its own. A snapshot is kept if any rule of the policy keeps it, and the
+//! newest snapshot of every set is always kept, so pruning never leaves a
+//! set without one. Calendar periods are counted in UTC.
+//!
+//! Pruning only forgets snapshots by deleting their manifests: the chunks
+//! they used stay in their packs.
+
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+use b2native::ObjectStore;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+
+use crate::{snapshot::Snapshot, EngineError, Repository};
+
+/// Which snapshots of each backup set to keep
+///
+/// The counts keep the newest snapshot of each of that many of the most
+/// recent periods that have snapshots, and 0 turns a rule off.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// How many of the newest snapshots to keep
+    pub last: usize,
+    /// How many hours to keep a snapshot of
+    pub hourly: usize,
+    /// How many days to keep a snapshot of
+    pub daily: usize,
+    /// How many ISO weeks to keep a snapshot of
+    pub weekly: usize,
+    /// How many months to keep a snapshot of
+    pub monthly: usize,
+    /// How many years to keep a snapshot of
+    pub yearly: usize,
+    /// Keep every snapshot taken this long before the newest one or later
+    pub within: Option<TimeDelta>,
+    /// Keep every snapshot with any of these tags
+    pub tags: Vec<String>,
+}
+
+/// The snapshots one machine took of the same directories
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupSet {
+    /// The name of the machine
+    pub hostname: String,
+    /// The directories, sorted
+    pub paths: Vec<PathBuf>,
+}
+
+impl BackupSet {
+    /// The backup set a snapshot belongs to
+    fn of(snapshot: &Snapshot) -> Self {
+        let mut paths = snapshot
+            .sources
+            .iter()
+            .map(|source| source.path.clone())
+            .collect::<Vec<_>>();
+        paths.sort();
+        Self {
+            hostname: snapshot.hostname.clone(),
+            paths,
+        }
+    }
+}
+
+impl fmt::Display for BackupSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.hostname)?;
+        for path in &self.paths {
+            write!(f, " {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot in a prune plan
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedSnapshot {
+    /// The ID of the snapshot
+    pub id: String,
+    /// When the snapshot was taken
+    pub time: DateTime<Utc>,
+    /// The backup set the snapshot belongs to
+    pub set: BackupSet,
+    /// Why the snapshot is kept, for the user to read, or nothing if it is
+    /// forgotten
+    pub reasons: Vec<String>,
+}
+
+/// Which snapshots a retention policy keeps and forgets
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PrunePlan {
+    /// The snapshots that are kept, by backup set and oldest first
+    pub keep: Vec<PlannedSnapshot>,
+    /// The snapshots that are forgotten, by backup set and oldest first
+    pub forget: Vec<PlannedSnapshot>,
+}
+
+/// A calendar period snapshots are kept one per
+#[derive(Clone, Copy)]
+enum Period {
+    /// An hour
+    Hour,
+    /// A day
+    Day,
+    /// An ISO week, starting on Monday
+    Week,
+    /// A month
+    Month,
+    /// A year
+    Year,
+}
+
+impl Period {
+    /// Every period, shortest first
+    const ALL: [Self; 5] =
+        [Self::Hour, Self::Day, Self::Week, Self::Month, Self::Year];
+
+    /// What names the period a time is in
+    fn of(self, time: DateTime<Utc>) -> String {
+        let format = match self {
+            Self::Hour => "%Y-%m-%d %H",
+            Self::Day => "%Y-%m-%d",
+            Self::Week => "%G-W%V",
+            Self::Month => "%Y-%m",
+            Self::Year => "%Y",
+        };
+        time.format(format).to_string()
+    }
+
+    /// The adjective for snapshots kept one per period
+    fn adjective(self) -> &'static str {
+        match self {
+            Self::Hour => "hourly",
+            Self::Day => "daily",
+            Self::Week => "weekly",
+            Self::Month => "monthly",
+            Self::Year => "yearly",
+        }
+    }
+}
+
+/// A span of time, for the user to read
+fn span(delta: TimeDelta) -> String {
+    if delta == TimeDelta::days(delta.num_days()) {
+        format!("{} days", delta.num_days())
+    } else {
+        format!("{} hours", delta.num_hours())
+    }
+}
+
+impl RetentionPolicy {
+    /// Whether no rule keeps any snapshot
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.last == 0
+            && Period::ALL.into_iter().all(|period| self.count(period) == 0)
+            && self.within.is_none()
+            && self.tags.is_empty()
+    }
+
+    /// How many periods to keep a snapshot of
+    fn count(&self, period: Period) -> usize {
+        match period {
+            Period::Hour => self.hourly,
+            Period::Day => self.daily,
+            Period::Week => self.weekly,
+            Period::Month => self.monthly,
+            Period::Year => self.yearly,
+        }
+    }
+
+    /// Why a policy keeps the snapshots of one backup set, newest first
+    fn reasons(&self, snapshots: &[&Snapshot]) -> Vec<Vec<String>> {
+        let newest = snapshots.first().map(|snapshot| snapshot.time);
+        let mut periods = Period::ALL.map(|period| (self.count(period), None));
+        snapshots
+            .iter()
+            .enumerate()
+            .map(|(index, snapshot)| {
+                let mut reasons = Vec::new();
+                if index == 0 {
+                    reasons.push("the newest of its backup set".to_owned());
+                }
+                if index < self.last {
+                    reasons.push(format!("one of the last {}", self.last));
+                }
+                for (period, (remaining, last)) in
+                    Period::ALL.into_iter().zip(&mut periods)
+                {
+                    let current = period.of(snapshot.time);
+                    if *remaining > 0 && last.as_ref() != Some(&current) {
+                        *remaining -= 1;
+                        reasons.push(format!(
+                            "the {} snapshot of {current}",
+                            period.adjective()
+                        ));
+                        *last = Some(current);
+                    }
+                }
+                if let Some(within) = self.within.filter(|within| {
+                    newest
+                        .and_then(|newest| newest.checked_sub_signed(*within))
+                        .is_some_and(|start| snapshot.time >= start)
+                }) {
+                    reasons.push(format!(
+                        "taken within {} of the newest",
+                        span(within)
+                    ));
+                }
+                if let Some(tag) =
+                    snapshot.tags.iter().find(|tag| self.tags.contains(tag))
+                {
+                    reasons.push(format!("tagged {tag:?}"));
+                }
+                reasons
+            })
+            .collect()
+    }
+
+    /// Decide which snapshots to keep and which to forget
+    pub(crate) fn plan(&self, snapshots: &[Snapshot]) -> PrunePlan {
+        let mut sets = BTreeMap::<BackupSet, Vec<&Snapshot>>::new();
+        for snapshot in snapshots {
+            sets.entry(BackupSet::of(snapshot)).or_default().push(snapshot);
+        }
+        let mut plan = PrunePlan::default();
+        for (set, mut snapshots) in sets {
+            snapshots.sort_by(|a, b| (b.time, &b.id).cmp(&(a.time, &a.id)));
+            let reasons = self.reasons(&snapshots);
+            for (snapshot, reasons) in snapshots.into_iter().zip(reasons).rev()
+            {
+                let planned = PlannedSnapshot {
+                    id: snapshot.id.clone(),
+                    time: snapshot.time,
+                    set: set.clone(),
+                    reasons,
+                };
+                if planned.reasons.is_empty() {
+                    plan.forget.push(planned);
+                } else {
+                    plan.keep.push(planned);
+                }
+            }
+        }
+        plan
+    }
+}
+
+impl<S: ObjectStore + Sync> Repository<S> {
+    /// Decide which snapshots a retention policy keeps and which it forgets,
+    /// without changing anything
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the policy has no rules, which
+    /// would forget all but the newest snapshot of every backup set, if any
+    /// manifest is malformed, or if the store can't be reached.
+    pub async fn plan_prune(
+        &self,
+        policy: &RetentionPolicy,
+    ) -> Result<PrunePlan, EngineError> {
+        if policy.is_empty() {
+            return Err(EngineError::EmptyRetentionPolicy);
+        }
+        Ok(policy.plan(&self.snapshots().await?))
+    }
+
+    /// Forget the snapshots a plan forgets by deleting their manifests,
+    /// returning how many were deleted
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the store can't be reached.
+    /// The snapshots deleted before it are forgotten.
+    pub async fn prune(&self, plan: &PrunePlan) -> Result<u64, EngineError> {
+        let mut forgotten = 0;
+        for snapshot in &plan.forget {
+            let key = self.key(&self.snapshot_name(&snapshot.id));
+            self.store().delete(&key).await?;
+            tracing::info!(snapshot = snapshot.id, "Forgot snapshot");
+            forgotten += 1;
+        }
+        Ok(forgotten)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use b2fake::FakeB2;
+    use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+
+    use crate::{
+        test_support::{bucket, CHEAP},
+        BackupOptions, BackupSet, EngineError, InitOptions, PlannedSnapshot,
+        Repository, RetentionPolicy, Snapshot, Source,
+    };
+
+    fn snapshot(
+        id: &str,
+        time: DateTime<Utc>,
+        hostname: &str,
+        tags: &[&str],
+    ) -> Snapshot {
+        Snapshot {
+            id: id.to_owned(),
+            time,
+            hostname: hostname.to_owned(),
+            sources: vec![Source {
+                name: "Documents".to_owned(),
+                path: PathBuf::from("/home/ada/Documents"),
+            }],
+            tags: tags.iter().map(|tag| (*tag).to_owned()).collect(),
+            entries: Vec::new(),
+            uploaded_bytes: 0,
+            stored_bytes: 0,
+        }
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, day, hour, 0, 0)
+            .single()
+            .expect("Date is valid")
+    }
+
+    #[test]
+    fn policies_keep_snapshots_per_backup_set() {
+        let snapshots = [
+            snapshot("a", at(1, 9), "laptop", &["before-upgrade"]),
+            snapshot("b", at(2, 9), "laptop", &[]),
+            snapshot("c", at(2, 18), "laptop", &[]),
+            snapshot("d", at(3, 9), "laptop", &[]),
+            snapshot("e", at(4, 9), "laptop", &[]),
+            snapshot("f", at(4, 18), "laptop", &[]),
+            snapshot("g", at(1, 12), "desktop", &[]),
+        ];
+        let policy = RetentionPolicy {
+            last: 1,
+            daily: 2,
+            tags: vec!["before-upgrade".to_owned()],
+            ..RetentionPolicy::default()
+        };
+        let plan = policy.plan(&snapshots);
+        let ids = |planned: &[PlannedSnapshot]| {
+            planned.iter().map(|planned| planned.id.clone()).collect::<Vec<_>>()
+        };
+        // The desktop's only snapshot is the newest of its set
+        assert_eq!(ids(&plan.keep), ["g", "a", "d", "f"]);
+        assert_eq!(ids(&plan.forget), ["b", "c", "e"]);
+        assert_eq!(
+            plan.keep[0].set,
+            BackupSet {
+                hostname: "desktop".to_owned(),
+                paths: vec![PathBuf::from("/home/ada/Documents")],
+            }
+        );
+        assert_eq!(plan.keep[1].reasons, ["tagged \"before-upgrade\""]);
+        assert_eq!(plan.keep[2].reasons, ["the daily snapshot of 2026-03-03"]);
+        assert_eq!(
+            plan.keep[3].reasons,
+            [
+                "the newest of its backup set",
+                "one of the last 1",
+                "the daily snapshot of 2026-03-04"
+            ]
+        );
+
+        let policy = RetentionPolicy {
+            within: Some(TimeDelta::days(2)),
+            ..RetentionPolicy::default()
+        };
+        let plan = policy.plan(&snapshots);
+        assert_eq!(ids(&plan.keep), ["g", "c", "d", "e", "f"]);
+        assert_eq!(plan.keep[1].reasons, ["taken within 2 days of the newest"]);
+    }
+
+    #[tokio::test]
+    async fn pruning_deletes_forgotten_manifests() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server).await;
+        let options = InitOptions {
+            kdf: CHEAP,
+            ..InitOptions::default()
+        };
+        let repository =
+            Repository::init(bucket, "backmate", "secret", &options)
+                .await
+                .expect("Repository should be created");
+        let root = tempfile::tempdir().expect("Temporary directory");
+        fs::write(root.path().join("notes.txt"), "hello").expect("Write file");
+        let mut ids = Vec::new();
+        for tags in [vec!["keep".to_owned()], Vec::new(), Vec::new()] {
+            let summary = repository
+                .backup(
+                    &[root.path()],
+                    &BackupOptions {
+                        tags,
+                        ..BackupOptions::default()
+                    },
+                )
+                .await
+                .expect("Backup should succeed");
+            ids.push(summary.snapshot_id);
+        }
+
+        assert!(matches!(
+            repository.plan_prune(&RetentionPolicy::default()).await,
+            Err(EngineError::EmptyRetentionPolicy)
+        ));
+        let plan = repository
+            .plan_prune(&RetentionPolicy {
+                tags: vec!["keep".to_owned()],
+                ..RetentionPolicy::default()
+            })
+            .await
+            .expect("Plan should be made");
+        assert_eq!(plan.forget.len(), 1);
+        assert_eq!(plan.forget[0].id, ids[1]);
+        assert_eq!(repository.prune(&plan).await.expect("Prune"), 1);
+        let mut expected = vec![ids[0].clone(), ids[2].clone()];
+        expected.sort();
+        assert_eq!(
+            repository.snapshot_ids().await.expect("Snapshots should list"),
+            expected
+        );
+        assert!(matches!(
+            repository.snapshot(&ids[1]).await,
+            Err(EngineError::SnapshotNotFound { .. })
+        ));
+    }
+}
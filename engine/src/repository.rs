@@ -0,0 +1,512 @@
+//! Repositories of snapshots in a bucket
+//!
+//! A repository is every object under a prefix of a bucket: key files
+//! wrapping the master key, a config object naming the format, packs of file
+//! data chunks with the indexes that list them, and one manifest per
+//! snapshot. Everything but the key files is encrypted with the master key,
+//! and manifests and indexes are named by keyed hashes of their IDs.
+
+use b2native::{ObjectStore, UploadOptions};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    chunker::ChunkerParams,
+    crypto::{KdfParams, MasterKey},
+    keys::{invalid_kdf, unlock, KeySlotKind},
+    snapshot::Snapshot,
+    EngineError,
+};
+
+/// The prefix repositories are created under unless another is given
+pub const DEFAULT_PREFIX: &str = "backmate/";
+
+/// The format version written by this version of the engine
+const FORMAT_VERSION: u32 = 5;
+
+/// The name of the config object
+pub(crate) const CONFIG: &str = "config";
+
+/// The size packs are filled to unless another is given
+const DEFAULT_PACK_SIZE: u64 = 32 * 1024 * 1024;
+
+/// The prefix of snapshot manifests
+const SNAPSHOTS: &str = "snapshots/";
+
+/// What describes a repository as a whole
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositoryConfig {
+    /// The format version of the repository
+    pub version: u32,
+    /// The unique ID of the repository
+    pub id: String,
+    /// When the repository was created
+    pub created_at: DateTime<Utc>,
+    /// The sizes files are chunked to
+    #[serde(default)]
+    pub chunker: ChunkerParams,
+    /// The size packs are filled to before they are uploaded
+    pub pack_size: u64,
+}
+
+/// Optional settings for a new repository
+#[derive(Clone, Debug)]
+pub struct InitOptions {
+    /// The sizes files are chunked to
+    ///
+    /// They can't be changed once the repository is created.
+    pub chunker: ChunkerParams,
+    /// The size packs are filled to before they are uploaded
+    pub pack_size: u64,
+    /// How hard deriving the key that wraps the master key from the
+    /// passphrase is made
+    pub kdf: KdfParams,
+}
+
+impl Default for InitOptions {
+    fn default() -> Self {
+        Self {
+            chunker: ChunkerParams::default(),
+            pack_size: DEFAULT_PACK_SIZE,
+            kdf: KdfParams::default(),
+        }
+    }
+}
+
+/// A repository of snapshots under a prefix of an object store
+#[derive(Debug)]
+pub struct Repository<S> {
+    /// The store holding the repository
+    store: S,
+    /// The prefix of every object of the repository, empty or ending in `/`
+    prefix: String,
+    /// The config read when the repository was opened
+    config: RepositoryConfig,
+    /// The key every object but the key files is encrypted with
+    master_key: MasterKey,
+}
+
+/// Make a prefix empty or end with `/`
+fn normalize_prefix(prefix: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        prefix.to_owned()
+    } else {
+        format!("{prefix}/")
+    }
+}
+
+/// Decrypt and decode a JSON object
+fn decode<T: DeserializeOwned>(
+    master_key: &MasterKey,
+    key: String,
+    data: &[u8],
+) -> Result<T, EngineError> {
+    master_key
+        .decrypt(data)
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(EngineError::MalformedObject {
+            key,
+        })
+}
+
+impl<S: ObjectStore + Sync> Repository<S> {
+    /// Create a new repository under `prefix`, with a new master key wrapped
+    /// with `passphrase`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a repository already exists
+    /// there, if the chunk sizes or key derivation parameters are invalid,
+    /// or if the store can't be reached.
+    pub async fn init(
+        store: S,
+        prefix: &str,
+        passphrase: &str,
+        options: &InitOptions,
+    ) -> Result<Self, EngineError> {
+        if !options.chunker.is_valid() {
+            return Err(EngineError::InvalidOptions {
+                reason: "invalid chunk sizes".to_owned(),
+            });
+        }
+        if !options.kdf.is_valid() {
+            return Err(invalid_kdf());
+        }
+        let prefix = normalize_prefix(prefix);
+        let key = format!("{prefix}{CONFIG}");
+        if store.head(&key).await?.is_some() {
+            return Err(EngineError::RepositoryExists {
+                prefix,
+            });
+        }
+        let config = RepositoryConfig {
+            version: FORMAT_VERSION,
+            id: hex::encode(rand::random::<[u8; 16]>()),
+            created_at: Utc::now(),
+            chunker: options.chunker,
+            pack_size: options.pack_size,
+        };
+        let repository = Self {
+            store,
+            prefix,
+            config,
+            master_key: MasterKey::generate(),
+        };
+        let _slot = repository
+            .add_key_slot(
+                KeySlotKind::Passphrase,
+                "initial passphrase",
+                passphrase,
+                options.kdf,
+            )
+            .await?;
+        repository.write_json(CONFIG, &repository.config).await?;
+        tracing::info!(id = repository.config.id, "Created repository");
+        Ok(repository)
+    }
+
+    /// Open the existing repository under `prefix` with the secret of any of
+    /// its key slots
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no repository exists there, if
+    /// the secret doesn't open any of its key slots, if it has a format this
+    /// version doesn't support, or if the store can't be reached.
+    pub async fn open(
+        store: S,
+        prefix: &str,
+        passphrase: &str,
+    ) -> Result<Self, EngineError> {
+        let prefix = normalize_prefix(prefix);
+        let key = format!("{prefix}{CONFIG}");
+        if store.head(&key).await?.is_none() {
+            return Err(EngineError::RepositoryNotFound {
+                prefix,
+            });
+        }
+        let data = store.get(&key, None).await?.data;
+        let master_key = unlock(&store, &prefix, passphrase, &data).await?;
+        let config =
+            decode::<RepositoryConfig>(&master_key, key.clone(), &data)
+                .ok()
+                .filter(|config| config.chunker.is_valid())
+                .ok_or(EngineError::MalformedObject {
+                    key,
+                })?;
+        if config.version != FORMAT_VERSION {
+            return Err(EngineError::UnsupportedVersion {
+                version: config.version,
+            });
+        }
+        Ok(Self {
+            store,
+            prefix,
+            config,
+            master_key,
+        })
+    }
+
+    /// Open the repository under `prefix`, creating it with `options` if
+    /// there is none
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the passphrase doesn't open an
+    /// existing repository, if it has a format this version doesn't support,
+    /// if a repository is created and the chunk sizes or key derivation
+    /// parameters are invalid, or if the store can't be reached.
+    pub async fn open_or_init(
+        store: S,
+        prefix: &str,
+        passphrase: &str,
+        options: &InitOptions,
+    ) -> Result<Self, EngineError> {
+        let key = format!("{}{CONFIG}", normalize_prefix(prefix));
+        if store.head(&key).await?.is_some() {
+            Self::open(store, prefix, passphrase).await
+        } else {
+            Self::init(store, prefix, passphrase, options).await
+        }
+    }
+
+    /// The config of the repository
+    #[must_use]
+    pub fn config(&self) -> &RepositoryConfig {
+        &self.config
+    }
+
+    /// The prefix of every object of the repository
+    #[must_use]
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The store holding the repository
+    #[must_use]
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The key of an object of the repository
+    pub(crate) fn key(&self, name: &str) -> String {
+        format!("{}{name}", self.prefix)
+    }
+
+    /// The master key of the repository
+    pub(crate) fn master_key(&self) -> &MasterKey {
+        &self.master_key
+    }
+
+    /// Switch to a new master key, returning the old one
+    pub(crate) fn replace_master_key(
+        &mut self,
+        master_key: MasterKey,
+    ) -> MasterKey {
+        std::mem::replace(&mut self.master_key, master_key)
+    }
+
+    /// Read, decrypt and decode a JSON object of the repository
+    pub(crate) async fn read_json<T: DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Result<T, EngineError> {
+        let key = self.key(name);
+        let data = self.store.get(&key, None).await?.data;
+        decode(&self.master_key, key, &data)
+    }
+
+    /// Read, decrypt and decode a JSON object of the repository, or `None`
+    /// if it was encrypted with another master key
+    ///
+    /// Objects under another key are left behind by a re-key that was cut
+    /// short, and are skipped rather than failing the whole listing.
+    pub(crate) async fn read_listed_json<T: DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Result<Option<T>, EngineError> {
+        let key = self.key(name);
+        let data = self.store.get(&key, None).await?.data;
+        let Some(json) = self.master_key.decrypt(&data) else {
+            tracing::warn!(key, "Skipping an object under another key");
+            return Ok(None);
+        };
+        serde_json::from_slice(&json).map(Some).map_err(|_error| {
+            EngineError::MalformedObject {
+                key,
+            }
+        })
+    }
+
+    /// Encode, encrypt and write a JSON object of the repository
+    pub(crate) async fn write_json<T: Serialize + Sync>(
+        &self,
+        name: &str,
+        value: &T,
+    ) -> Result<(), EngineError> {
+        let json = serde_json::to_vec(value)
+            .expect("Repository objects always serialize");
+        let data = self.master_key.encrypt(&json);
+        self.store
+            .put(&self.key(name), Bytes::from(data), &UploadOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    /// The name of the manifest of a snapshot
+    pub(crate) fn snapshot_name(&self, id: &str) -> String {
+        format!("{SNAPSHOTS}{}", self.master_key.name("snapshot", id))
+    }
+
+    /// The IDs of every snapshot in the repository, sorted
+    ///
+    /// IDs sort by the second their snapshots were taken in; use
+    /// [`Repository::snapshots`] for the exact order.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any manifest is malformed, or
+    /// if the store can't be reached.
+    pub async fn snapshot_ids(&self) -> Result<Vec<String>, EngineError> {
+        let mut ids = self
+            .snapshots()
+            .await?
+            .into_iter()
+            .map(|snapshot| snapshot.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Read the manifest of a snapshot
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no snapshot has the ID, if its
+    /// manifest is malformed, or if the store can't be reached.
+    pub async fn snapshot(&self, id: &str) -> Result<Snapshot, EngineError> {
+        let name = self.snapshot_name(id);
+        let key = self.key(&name);
+        if self.store.head(&key).await?.is_none() {
+            return Err(EngineError::SnapshotNotFound {
+                id: id.to_owned(),
+            });
+        }
+        let snapshot = self.read_json::<Snapshot>(&name).await?;
+        // A manifest moved to another snapshot's name mustn't pass for it
+        if snapshot.id != id {
+            return Err(EngineError::MalformedObject {
+                key,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    /// Read the manifest of every snapshot, oldest first
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if any manifest is malformed, or
+    /// if the store can't be reached.
+    pub async fn snapshots(&self) -> Result<Vec<Snapshot>, EngineError> {
+        let prefix = self.key(SNAPSHOTS);
+        let mut snapshots = Vec::<Snapshot>::new();
+        for object in self.store.list(&prefix).await? {
+            if let Some(name) = object.key.strip_prefix(&self.prefix) {
+                snapshots.extend(self.read_listed_json(name).await?);
+            }
+        }
+        snapshots.sort_by(|a, b| (a.time, &a.id).cmp(&(b.time, &b.id)));
+        Ok(snapshots)
+    }
+
+    /// The newest snapshot, or the newest taken at or before `before`
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is no such snapshot, if
+    /// any manifest is malformed, or if the store can't be reached.
+    pub async fn latest_snapshot(
+        &self,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Snapshot, EngineError> {
+        self.snapshots()
+            .await?
+            .into_iter()
+            .rev()
+            .find(|snapshot| {
+                before.is_none_or(|before| snapshot.time <= before)
+            })
+            .ok_or(EngineError::NoSnapshot {
+                before,
+            })
+    }
+
+    /// Write the manifest of a new snapshot
+    pub(crate) async fn write_snapshot(
+        &self,
+        snapshot: &Snapshot,
+    ) -> Result<(), EngineError> {
+        self.write_json(&self.snapshot_name(&snapshot.id), snapshot).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use b2fake::FakeB2;
+
+    use crate::{
+        test_support::{bucket, CHEAP},
+        ChunkerParams, EngineError, InitOptions, KdfParams, KeySlotKind,
+        Repository,
+    };
+
+    #[tokio::test]
+    async fn repositories_are_created_once() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let bucket = bucket(&server).await;
+
+        let error = Repository::open(bucket.clone(), "laptop", "secret")
+            .await
+            .expect_err("No repository should exist yet");
+        assert!(matches!(error, EngineError::RepositoryNotFound { .. }));
+
+        let options = InitOptions {
+            chunker: ChunkerParams {
+                min_size: 1024,
+                avg_size: 4096,
+                max_size: 16384,
+            },
+            pack_size: 1024 * 1024,
+            kdf: CHEAP,
+        };
+        for invalid in [
+            InitOptions {
+                chunker: ChunkerParams {
+                    min_size: 16384,
+                    ..options.chunker
+                },
+                ..options.clone()
+            },
+            InitOptions {
+                kdf: KdfParams {
+                    memory_kib: 1,
+                    ..CHEAP
+                },
+                ..options.clone()
+            },
+        ] {
+            let error =
+                Repository::init(bucket.clone(), "laptop", "secret", &invalid)
+                    .await
+                    .expect_err("Invalid options should be refused");
+            assert!(matches!(error, EngineError::InvalidOptions { .. }));
+        }
+        let created =
+            Repository::init(bucket.clone(), "laptop", "secret", &options)
+                .await
+                .expect("Repository should be created");
+        assert_eq!(created.prefix(), "laptop/");
+        let error =
+            Repository::init(bucket.clone(), "laptop/", "secret", &options)
+                .await
+                .expect_err("Repository should already exist");
+        assert!(matches!(error, EngineError::RepositoryExists { .. }));
+
+        let error = Repository::open(bucket.clone(), "laptop", "guess")
+            .await
+            .expect_err("Only the passphrase should open the repository");
+        assert!(matches!(error, EngineError::WrongPassphrase));
+        let opened = Repository::open_or_init(
+            bucket,
+            "laptop",
+            "secret",
+            &InitOptions::default(),
+        )
+        .await
+        .expect("Repository should open");
+        // The repository keeps the chunk sizes it was created with
+        assert_eq!(opened.config(), created.config());
+        assert_eq!(opened.config().chunker, options.chunker);
+        let error = opened
+            .add_key_slot(
+                KeySlotKind::RecoveryKey,
+                "spare",
+                "other",
+                KdfParams {
+                    memory_kib: 1,
+                    ..CHEAP
+                },
+            )
+            .await
+            .expect_err("Invalid parameters should be refused");
+        assert!(matches!(error, EngineError::InvalidOptions { .. }));
+        assert!(opened
+            .snapshot_ids()
+            .await
+            .expect("Snapshots should list")
+            .is_empty());
+    }
+}
@@ -0,0 +1,900 @@
+//! Restoring snapshots into a directory
+//!
+//! Every file is written under a temporary name next to where it belongs,
+//! with its modification time and permissions already set, and then renamed
+//! into place, so a restore that stops part way never leaves a half-written
+//! file behind. Finished paths are appended to a journal in the target
+//! directory, and restoring the same snapshot into the same directory again
+//! picks up where the journal ends. The journal is removed once the restore
+//! completes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use b2native::ObjectStore;
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    pack::ChunkLocation,
+    snapshot::{Entry, EntryKind, Snapshot},
+    EngineError, Repository, SkippedPath,
+};
+
+/// The start of the name of the journal of a restore
+const JOURNAL_PREFIX: &str = ".backmate-restore-";
+
+/// The end of the temporary name files are written under
+const PART_SUFFIX: &str = ".backmate-part";
+
+/// What to do when a file being restored already exists
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictPolicy {
+    /// Keep the existing file
+    #[default]
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Keep the existing file, and restore next to it under a new name
+    Rename,
+    /// Replace the existing file only if the snapshot's copy is newer
+    OnlyIfNewer,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            "rename" => Ok(Self::Rename),
+            "only-if-newer" => Ok(Self::OnlyIfNewer),
+            _ => Err(format!(
+                "unknown conflict policy {value:?}, expected skip, overwrite, \
+                 rename or only-if-newer"
+            )),
+        }
+    }
+}
+
+/// Optional settings for a restore
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// The snapshot paths to restore, with everything under them
+    ///
+    /// When empty, the whole snapshot is restored.
+    pub include: Vec<String>,
+    /// What to do with files that already exist
+    pub conflict: ConflictPolicy,
+    /// Stops the restore after the file being written when set
+    ///
+    /// A stopped restore resumes when it is run again.
+    pub stop: Option<Arc<AtomicBool>>,
+}
+
+impl RestoreOptions {
+    /// Whether an entry at `path` should be restored
+    fn includes(&self, path: &str) -> bool {
+        self.include.is_empty()
+            || self.include.iter().any(|include| {
+                let include = include.trim_end_matches('/');
+                path == include
+                    || path
+                        .strip_prefix(include)
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+
+    /// Whether the restore has been asked to stop
+    fn stop_requested(&self) -> bool {
+        self.stop.as_ref().is_some_and(|stop| stop.load(Ordering::Relaxed))
+    }
+}
+
+/// What a restore did
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreSummary {
+    /// The ID of the snapshot that was restored
+    pub snapshot_id: String,
+    /// The number of files and symlinks written
+    pub restored: u64,
+    /// The total size of the files written
+    pub bytes: u64,
+    /// The number of existing files left alone by the conflict policy
+    pub kept: u64,
+    /// The number of files an earlier, stopped run already restored
+    pub resumed: u64,
+    /// The paths that couldn't be restored
+    pub skipped: Vec<SkippedPath>,
+    /// Whether the restore was stopped before it finished
+    pub stopped: bool,
+}
+
+/// Why an entry wasn't restored
+enum Failure {
+    /// Only this entry failed, and the restore goes on without it
+    Skip(String),
+    /// The restore can't go on
+    Fatal(EngineError),
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        Self::Skip(error.to_string())
+    }
+}
+
+impl From<EngineError> for Failure {
+    fn from(error: EngineError) -> Self {
+        Self::Fatal(error)
+    }
+}
+
+/// A file being written under its temporary name, which is removed again
+/// unless it is renamed into place
+struct PartFile {
+    /// The temporary path
+    path: PathBuf,
+    /// Whether the file was renamed into place
+    persisted: bool,
+}
+
+impl PartFile {
+    /// The temporary file for a path
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.with_file_name(format!(
+                ".{}{PART_SUFFIX}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            )),
+            persisted: false,
+        }
+    }
+
+    /// Rename the file into place
+    fn persist(&mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _removed = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Where an entry is restored to, if its path is safe
+fn local_path(target: &Path, path: &str) -> Result<PathBuf, EngineError> {
+    path.split('/')
+        .try_fold(target.to_owned(), |mut local, part| {
+            let mut components = Path::new(part).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => {
+                    local.push(part);
+                    Some(local)
+                }
+                _ => None,
+            }
+        })
+        .ok_or_else(|| EngineError::UnsafePath {
+            path: path.to_owned(),
+        })
+}
+
+/// Refuse to restore an entry of `kind` at `local` through a symlink under
+/// `target`
+///
+/// A snapshot can hold a symlink and then entries beneath it, such as
+/// `docs/link -> /etc` followed by `docs/link/passwd`. Once the symlink is
+/// restored, writing those entries would follow it out of `target`, so they
+/// are skipped instead. Directories are created through their own path, so
+/// it is checked as well, while files and symlinks replace whatever is there.
+fn check_no_symlinks(
+    target: &Path,
+    local: &Path,
+    kind: EntryKind,
+) -> Result<(), Failure> {
+    let path = match kind {
+        EntryKind::Directory => local,
+        EntryKind::File | EntryKind::Symlink => {
+            local.parent().unwrap_or(target)
+        }
+    };
+    let relative = path.strip_prefix(target).map_err(|_error| {
+        EngineError::UnsafePath {
+            path: local.display().to_string(),
+        }
+    })?;
+    let mut ancestor = target.to_owned();
+    for component in relative.components() {
+        ancestor.push(component);
+        match fs::symlink_metadata(&ancestor) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(Failure::Skip(format!(
+                    "{} is a symlink",
+                    ancestor.display()
+                )));
+            }
+            Ok(_) => {}
+            // Nothing below a missing directory exists either
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(())
+}
+
+/// A path next to `path` with a suffix before the extension
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => {
+            format!("{stem}{suffix}.{}", extension.to_string_lossy())
+        }
+        None => format!("{stem}{suffix}"),
+    };
+    path.with_file_name(name)
+}
+
+/// The first free name of the form `name (restored).ext`
+fn renamed(path: &Path) -> PathBuf {
+    (1..=u32::MAX)
+        .map(|n| {
+            if n == 1 {
+                with_suffix(path, " (restored)")
+            } else {
+                with_suffix(path, &format!(" (restored {n})"))
+            }
+        })
+        .find(|candidate| fs::symlink_metadata(candidate).is_err())
+        .unwrap_or_else(|| with_suffix(path, " (restored)"))
+}
+
+/// Where to write an entry given what is already at its path, or `None` to
+/// leave the existing file alone
+fn destination(
+    local: &Path,
+    entry: &Entry,
+    policy: ConflictPolicy,
+) -> Result<Option<PathBuf>, Failure> {
+    let existing = match fs::symlink_metadata(local) {
+        Ok(existing) => existing,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Ok(Some(local.to_owned()));
+        }
+        Err(error) => return Err(error.into()),
+    };
+    if existing.is_dir() && policy != ConflictPolicy::Rename {
+        return Err(Failure::Skip("a directory is in the way".to_owned()));
+    }
+    Ok(match policy {
+        ConflictPolicy::Skip => None,
+        ConflictPolicy::Overwrite => Some(local.to_owned()),
+        ConflictPolicy::Rename => Some(renamed(local)),
+        ConflictPolicy::OnlyIfNewer => {
+            let existing = existing.modified().ok().map(DateTime::<Utc>::from);
+            (entry.modified > existing).then(|| local.to_owned())
+        }
+    })
+}
+
+/// Set the modification time and permissions of a restored entry
+fn apply_metadata(path: &Path, entry: &Entry) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = entry.mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if let Some(modified) = entry.modified {
+        filetime::set_file_mtime(
+            path,
+            FileTime::from_system_time(SystemTime::from(modified)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Create a symlink, replacing whatever is at its path
+#[cfg(unix)]
+fn restore_symlink(path: &Path, entry: &Entry) -> Result<(), Failure> {
+    let target = entry.target.as_deref().unwrap_or_default();
+    if fs::symlink_metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+    std::os::unix::fs::symlink(target, path)?;
+    if let Some(modified) = entry.modified {
+        let time = FileTime::from_system_time(SystemTime::from(modified));
+        filetime::set_symlink_file_times(path, time, time)?;
+    }
+    Ok(())
+}
+
+/// Symlinks are only restored on Unix
+#[cfg(not(unix))]
+fn restore_symlink(_path: &Path, _entry: &Entry) -> Result<(), Failure> {
+    Err(Failure::Skip("symlinks can only be restored on Unix".to_owned()))
+}
+
+/// Read the paths an earlier run of a restore finished
+fn read_journal(path: &Path) -> io::Result<HashSet<String>> {
+    match fs::read_to_string(path) {
+        Ok(journal) => Ok(journal
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(error) => Err(error),
+    }
+}
+
+impl<S: ObjectStore + Sync> Repository<S> {
+    /// Restore a snapshot, or the included parts of it, into `target`
+    ///
+    /// Entries are restored at their snapshot paths under `target`, so the
+    /// files of a source named `Documents` end up in `target/Documents`.
+    /// Files that can't be written are left out and listed in the summary.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `target` can't be written to,
+    /// if the snapshot refers to chunks that are missing or corrupt, or if
+    /// the store can't be reached. Files restored before the error are kept,
+    /// and running the restore again resumes after them.
+    pub async fn restore(
+        &self,
+        snapshot: &Snapshot,
+        target: &Path,
+        options: &RestoreOptions,
+    ) -> Result<RestoreSummary, EngineError> {
+        let io_error = |path: &Path| {
+            let path = path.to_owned();
+            move |error| EngineError::Io {
+                path,
+                error,
+            }
+        };
+        fs::create_dir_all(target).map_err(io_error(target))?;
+        let journal_path =
+            target.join(format!("{JOURNAL_PREFIX}{}", snapshot.id));
+        let finished =
+            read_journal(&journal_path).map_err(io_error(&journal_path))?;
+        let mut journal = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .map_err(io_error(&journal_path))?;
+        let mut summary = RestoreSummary {
+            snapshot_id: snapshot.id.clone(),
+            restored: 0,
+            bytes: 0,
+            kept: 0,
+            resumed: 0,
+            skipped: Vec::new(),
+            stopped: false,
+        };
+        let index = self.chunk_index().await?;
+        let mut directories = Vec::new();
+        for entry in &snapshot.entries {
+            if !options.includes(&entry.path) {
+                continue;
+            }
+            if options.stop_requested() {
+                summary.stopped = true;
+                return Ok(summary);
+            }
+            let local = local_path(target, &entry.path)?;
+            if finished.contains(&entry.path) {
+                summary.resumed += 1;
+                continue;
+            }
+            let checked = check_no_symlinks(target, &local, entry.kind);
+            let result = match (checked, entry.kind) {
+                (Err(failure), _) => Err(failure),
+                (Ok(()), EntryKind::Directory) => {
+                    match fs::create_dir_all(&local) {
+                        Ok(()) => {
+                            directories.push((entry, local.clone()));
+                            continue;
+                        }
+                        Err(error) => Err(error.into()),
+                    }
+                }
+                (Ok(()), EntryKind::File | EntryKind::Symlink) => {
+                    self.restore_entry(entry, &local, options.conflict, &index)
+                        .await
+                }
+            };
+            match result {
+                Ok(Some(bytes)) => {
+                    summary.restored += 1;
+                    summary.bytes += bytes;
+                }
+                Ok(None) => summary.kept += 1,
+                Err(Failure::Skip(reason)) => {
+                    summary.skipped.push(SkippedPath {
+                        path: local,
+                        reason,
+                    });
+                    continue;
+                }
+                Err(Failure::Fatal(error)) => return Err(error),
+            }
+            serde_json::to_writer(&mut journal, &entry.path)
+                .map_err(io::Error::from)
+                .and_then(|()| writeln!(journal))
+                .map_err(io_error(&journal_path))?;
+        }
+        // Directories get their times last, since restoring their contents
+        // changes them, and deepest first, so read-only parents come last
+        for (entry, local) in directories.into_iter().rev() {
+            if let Err(error) = apply_metadata(&local, entry) {
+                summary.skipped.push(SkippedPath {
+                    path: local,
+                    reason: error.to_string(),
+                });
+            }
+        }
+        drop(journal);
+        fs::remove_file(&journal_path).map_err(io_error(&journal_path))?;
+        tracing::info!(
+            snapshot = snapshot.id,
+            restored = summary.restored,
+            bytes = summary.bytes,
+            "Restore finished"
+        );
+        Ok(summary)
+    }
+
+    /// Restore one file or symlink, returning the bytes written, or `None`
+    /// if the conflict policy kept an existing file
+    async fn restore_entry(
+        &self,
+        entry: &Entry,
+        local: &Path,
+        policy: ConflictPolicy,
+        index: &HashMap<String, ChunkLocation>,
+    ) -> Result<Option<u64>, Failure> {
+        let Some(path) = destination(local, entry, policy)? else {
+            return Ok(None);
+        };
+        // Parents that weren't included still have to exist
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if entry.kind == EntryKind::Symlink {
+            restore_symlink(&path, entry)?;
+            return Ok(Some(0));
+        }
+        let mut part = PartFile::new(&path);
+        let mut file = fs::File::create(&part.path)?;
+        let mut written = 0_u64;
+        for id in &entry.content {
+            let data = self.read_chunk(index, id).await?;
+            file.write_all(&data)?;
+            written += u64::try_from(data.len()).unwrap_or(u64::MAX);
+        }
+        file.sync_all()?;
+        drop(file);
+        if written != entry.size {
+            return Err(Failure::Fatal(EngineError::ChunkMismatch {
+                id: entry.content.join(","),
+            }));
+        }
+        apply_metadata(&part.path, entry)?;
+        part.persist(&path)?;
+        Ok(Some(written))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::Path,
+        sync::{atomic::AtomicBool, Arc},
+        time::{Duration, SystemTime},
+    };
+
+    use b2fake::FakeB2;
+    use b2native::{Bucket, ObjectStore, UploadOptions};
+    use bytes::Bytes;
+    use filetime::FileTime;
+
+    use crate::{
+        restore::{local_path, renamed, PartFile},
+        test_support::{bucket, CHEAP},
+        BackupOptions, ConflictPolicy, EngineError, EntryKind, InitOptions,
+        Repository, RestoreOptions,
+    };
+
+    async fn repository(server: &FakeB2) -> Repository<Bucket> {
+        let bucket = bucket(server).await;
+        let options = InitOptions {
+            kdf: CHEAP,
+            ..InitOptions::default()
+        };
+        Repository::init(bucket, "backmate", "secret", &options)
+            .await
+            .expect("Repository should be created")
+    }
+
+    fn write(path: &Path, contents: &str, age: Duration) {
+        fs::create_dir_all(path.parent().expect("Path has a parent"))
+            .expect("Create directory");
+        fs::write(path, contents).expect("Write file");
+        let modified = SystemTime::now() - age;
+        filetime::set_file_mtime(path, FileTime::from_system_time(modified))
+            .expect("Set time");
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).expect("Read file")
+    }
+
+    #[test]
+    fn unsafe_paths_are_refused() {
+        let target = Path::new("/restore");
+        assert_eq!(
+            local_path(target, "docs/a.txt").expect("Path is safe"),
+            Path::new("/restore/docs/a.txt")
+        );
+        for path in ["docs/../../etc", "/etc/passwd", "docs//a", "."] {
+            assert!(matches!(
+                local_path(target, path),
+                Err(EngineError::UnsafePath { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn renamed_files_get_free_names() {
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let path = root.path().join("report.pdf");
+        assert_eq!(renamed(&path), root.path().join("report (restored).pdf"));
+        fs::write(root.path().join("report (restored).pdf"), "")
+            .expect("Write file");
+        assert_eq!(renamed(&path), root.path().join("report (restored 2).pdf"));
+    }
+
+    #[test]
+    fn part_files_are_removed_unless_persisted() {
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let path = root.path().join("a.txt");
+        let part = PartFile::new(&path);
+        fs::write(&part.path, "partial").expect("Write file");
+        drop(part);
+        assert_eq!(
+            fs::read_dir(root.path()).expect("Read directory").count(),
+            0
+        );
+
+        let mut part = PartFile::new(&path);
+        fs::write(&part.path, "whole").expect("Write file");
+        part.persist(&path).expect("Rename file");
+        drop(part);
+        assert_eq!(fs::read_to_string(&path).expect("Read file"), "whole");
+
+        // A rename that fails leaves nothing behind either
+        let missing = root.path().join("missing").join("b.txt");
+        let mut part = PartFile::new(&root.path().join("b.txt"));
+        fs::write(&part.path, "partial").expect("Write file");
+        assert!(part.persist(&missing).is_err());
+        drop(part);
+        assert_eq!(
+            fs::read_dir(root.path()).expect("Read directory").count(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn restores_preserve_contents_and_metadata() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let source = root.path().join("docs");
+        let day = Duration::from_hours(24);
+        write(&source.join("a.txt"), "alpha", day);
+        write(&source.join("deep/b.txt"), "beta", day * 2);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(
+                source.join("a.txt"),
+                fs::Permissions::from_mode(0o600),
+            )
+            .expect("Set permissions");
+            std::os::unix::fs::symlink("a.txt", source.join("link"))
+                .expect("Create symlink");
+        }
+        let summary = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let snapshot = repository
+            .snapshot(&summary.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+
+        let target = root.path().join("restored");
+        let restored = repository
+            .restore(&snapshot, &target, &RestoreOptions::default())
+            .await
+            .expect("Restore should succeed");
+        assert!(restored.skipped.is_empty());
+        assert!(!restored.stopped);
+        assert_eq!(restored.bytes, 9);
+        for path in ["a.txt", "deep/b.txt", "deep"] {
+            let original =
+                fs::metadata(source.join(path)).expect("Original exists");
+            let copy = fs::metadata(target.join("docs").join(path))
+                .expect("Copy exists");
+            assert_eq!(
+                copy.modified().ok(),
+                original.modified().ok(),
+                "{path}"
+            );
+            assert_eq!(copy.permissions(), original.permissions(), "{path}");
+        }
+        assert_eq!(read(&target.join("docs/deep/b.txt")), "beta");
+        #[cfg(unix)]
+        assert_eq!(
+            fs::read_link(target.join("docs/link")).expect("Link exists"),
+            Path::new("a.txt")
+        );
+        // Nothing is left behind but the restored files
+        let names = fs::read_dir(&target)
+            .expect("List target")
+            .map(|entry| entry.expect("Entry").file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["docs"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn restores_never_write_through_symlinks() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let source = root.path().join("docs");
+        let day = Duration::from_hours(24);
+        write(&source.join("link/x.txt"), "x", day);
+        write(&source.join("link/sub/y.txt"), "y", day);
+        let summary = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let mut snapshot = repository
+            .snapshot(&summary.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        // A snapshot that restores a symlink, then entries beneath it
+        let outside = root.path().join("outside");
+        fs::create_dir(&outside).expect("Create directory");
+        let link = snapshot
+            .entries
+            .iter_mut()
+            .find(|entry| entry.path == "docs/link")
+            .expect("Directory is in the snapshot");
+        link.kind = EntryKind::Symlink;
+        link.target = Some(outside.display().to_string());
+
+        let target = root.path().join("restored");
+        let restored = repository
+            .restore(&snapshot, &target, &RestoreOptions::default())
+            .await
+            .expect("Restore should succeed");
+        assert_eq!(
+            fs::read_link(target.join("docs/link")).expect("Link exists"),
+            outside
+        );
+        let skipped: Vec<_> =
+            restored.skipped.iter().map(|skipped| &skipped.path).collect();
+        assert_eq!(
+            skipped,
+            [
+                &target.join("docs/link/sub"),
+                &target.join("docs/link/sub/y.txt"),
+                &target.join("docs/link/x.txt"),
+            ]
+        );
+        assert_eq!(fs::read_dir(&outside).expect("List outside").count(), 0);
+    }
+
+    #[tokio::test]
+    async fn restores_pick_snapshots_and_paths() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let source = root.path().join("docs");
+        write(&source.join("a.txt"), "first", Duration::ZERO);
+        write(&source.join("b/c.txt"), "unchanged", Duration::ZERO);
+        let first = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let first_time = repository
+            .snapshot(&first.snapshot_id)
+            .await
+            .expect("Snapshot should exist")
+            .time;
+        write(&source.join("a.txt"), "second", Duration::ZERO);
+        let _second = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+
+        let snapshot = repository
+            .latest_snapshot(Some(first_time))
+            .await
+            .expect("A snapshot was taken by then");
+        assert_eq!(snapshot.id, first.snapshot_id);
+        let target = root.path().join("restored");
+        let options = RestoreOptions {
+            include: vec!["docs/a.txt".to_owned()],
+            ..RestoreOptions::default()
+        };
+        let summary = repository
+            .restore(&snapshot, &target, &options)
+            .await
+            .expect("Restore should succeed");
+        assert_eq!(summary.restored, 1);
+        assert_eq!(read(&target.join("docs/a.txt")), "first");
+        assert!(!target.join("docs/b").exists());
+
+        let latest =
+            repository.latest_snapshot(None).await.expect("Snapshots exist");
+        let error = repository
+            .latest_snapshot(Some(first_time - Duration::from_secs(1)))
+            .await
+            .expect_err("No snapshot was taken by then");
+        assert!(matches!(error, EngineError::NoSnapshot { .. }));
+        assert_ne!(latest.id, first.snapshot_id);
+    }
+
+    #[tokio::test]
+    async fn conflicts_follow_the_policy() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let source = root.path().join("docs");
+        let hour = Duration::from_hours(1);
+        write(&source.join("a.txt"), "backed up", hour);
+        let backup = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let snapshot = repository
+            .snapshot(&backup.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let target = root.path().join("restored");
+        let existing = target.join("docs/a.txt");
+        let restore = |conflict| {
+            let options = RestoreOptions {
+                conflict,
+                ..RestoreOptions::default()
+            };
+            let repository = &repository;
+            let snapshot = &snapshot;
+            let target = &target;
+            async move {
+                repository
+                    .restore(snapshot, target, &options)
+                    .await
+                    .expect("Restore should succeed")
+            }
+        };
+
+        write(&existing, "local", Duration::ZERO);
+        assert_eq!(restore(ConflictPolicy::Skip).await.kept, 1);
+        assert_eq!(read(&existing), "local");
+        assert_eq!(restore(ConflictPolicy::OnlyIfNewer).await.kept, 1);
+        assert_eq!(read(&existing), "local");
+        assert_eq!(restore(ConflictPolicy::Rename).await.restored, 1);
+        assert_eq!(read(&existing), "local");
+        assert_eq!(read(&target.join("docs/a (restored).txt")), "backed up");
+
+        write(&existing, "older", hour * 2);
+        assert_eq!(restore(ConflictPolicy::OnlyIfNewer).await.restored, 1);
+        assert_eq!(read(&existing), "backed up");
+        write(&existing, "local", Duration::ZERO);
+        assert_eq!(restore(ConflictPolicy::Overwrite).await.restored, 1);
+        assert_eq!(read(&existing), "backed up");
+    }
+
+    #[tokio::test]
+    async fn restores_resume_after_stopping() {
+        let server = FakeB2::start().await.expect("Fake server should start");
+        let repository = repository(&server).await;
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let source = root.path().join("docs");
+        write(&source.join("a.txt"), "alpha", Duration::ZERO);
+        write(&source.join("b.txt"), "beta", Duration::ZERO);
+        let backup = repository
+            .backup(&[&source], &BackupOptions::default())
+            .await
+            .expect("Backup should succeed");
+        let snapshot = repository
+            .snapshot(&backup.snapshot_id)
+            .await
+            .expect("Snapshot should exist");
+        let target = root.path().join("restored");
+
+        let stop = Arc::new(AtomicBool::new(true));
+        let options = RestoreOptions {
+            conflict: ConflictPolicy::Rename,
+            stop: Some(Arc::clone(&stop)),
+            ..RestoreOptions::default()
+        };
+        let stopped = repository
+            .restore(&snapshot, &target, &options)
+            .await
+            .expect("Restore should stop cleanly");
+        assert!(stopped.stopped);
+
+        // Corrupt the chunk of b.txt, so the restore fails after a.txt
+        let b = &snapshot.entries[2];
+        assert_eq!(b.path, "docs/b.txt");
+        let index = repository.chunk_index().await.expect("Index should load");
+        let location = &index[&b.content[0]];
+        let key = repository.key(&format!("packs/{}", location.pack));
+        let pack = repository
+            .store()
+            .get(&key, None)
+            .await
+            .expect("Pack should download")
+            .data;
+        let mut tampered = pack.to_vec();
+        let offset = usize::try_from(location.offset).expect("Offset");
+        tampered[offset] ^= 0xFF;
+        let _info = repository
+            .store()
+            .put(&key, Bytes::from(tampered), &UploadOptions::default())
+            .await
+            .expect("Upload should succeed");
+        stop.store(false, std::sync::atomic::Ordering::Relaxed);
+        let error = repository
+            .restore(&snapshot, &target, &options)
+            .await
+            .expect_err("Corrupt chunks should fail the restore");
+        assert!(matches!(error, EngineError::ChunkMismatch { .. }));
+        assert_eq!(read(&target.join("docs/a.txt")), "alpha");
+        assert!(!target.join("docs/b.txt").exists());
+
+        // Once the chunk is repaired, the restore carries on after a.txt
+        // instead of restoring it again under a new name
+        let _info = repository
+            .store()
+            .put(&key, pack, &UploadOptions::default())
+            .await
+            .expect("Upload should succeed");
+        let resumed = repository
+            .restore(&snapshot, &target, &options)
+            .await
+            .expect("Restore should succeed");
+        assert_eq!(resumed.resumed, 1);
+        assert_eq!(resumed.restored, 1);
+        assert_eq!(read(&target.join("docs/b.txt")), "beta");
+        assert!(!target.join("docs/a (restored).txt").exists());
+    }
+}
@@ -0,0 +1,539 @@
+//! Which paths a backup includes
+//!
+//! Paths are matched against gitignore-style patterns, which exclude what
+//! they match unless they start with `!`, and are anchored to the directory
+//! being backed up when they start with `/` or have a `/` before their end.
+//! Patterns come from the backup rules, from the presets they name, and from
+//! `.backmateignore` files, which apply to the directory they are in and
+//! everything under it. As in git, the pattern that decides a path is the
+//! last one to match it, with files deeper in the tree coming after the
+//! rules, and the rules after the presets.
+//!
+//! A path no pattern includes is also left out if it is a directory tagged
+//! with `CACHEDIR.TAG`, or a file larger or older than the limits set. An
+//! excluded directory is skipped along with everything under it.
+
+use std::{
+    fmt,
+    fs::{self, Metadata},
+    io::Read,
+    path::Path,
+    str::FromStr,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::EngineError;
+
+/// The name of the files that hold patterns for their directory
+pub const IGNORE_FILE: &str = ".backmateignore";
+
+/// The start of every cache directory tag, as the Cache Directory Tagging
+/// Specification defines it
+const CACHEDIR_SIGNATURE: &[u8; 43] =
+    b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// A built-in set of patterns for common junk
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Preset {
+    /// Packages installed by npm and other Node.js package managers
+    NodeModules,
+    /// The `target` directories Cargo and Maven build in
+    BuildTarget,
+    /// The caches of Firefox, Chrome, Chromium, Edge, Brave and Safari
+    BrowserCaches,
+    /// The trash of Linux desktops, macOS and Windows
+    Trash,
+}
+
+impl Preset {
+    /// Every preset
+    pub const ALL: [Self; 4] = [
+        Self::NodeModules,
+        Self::BuildTarget,
+        Self::BrowserCaches,
+        Self::Trash,
+    ];
+
+    /// The patterns of the preset
+    fn patterns(self) -> &'static [&'static str] {
+        match self {
+            Self::NodeModules => &["node_modules/"],
+            Self::BuildTarget => &["target/"],
+            Self::BrowserCaches => &[
+                "**/.cache/mozilla/",
+                "**/.cache/google-chrome/",
+                "**/.cache/chromium/",
+                "**/.cache/microsoft-edge/",
+                "**/.cache/BraveSoftware/",
+                "**/Library/Caches/",
+                "**/.mozilla/firefox/*/cache2/",
+                "**/AppData/Local/Mozilla/Firefox/Profiles/*/cache2/",
+                "**/User Data/*/Cache/",
+                "**/User Data/*/Code Cache/",
+            ],
+            Self::Trash => &[
+                ".Trash/",
+                ".Trash-*/",
+                "**/.local/share/Trash/",
+                "$RECYCLE.BIN/",
+            ],
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|preset| preset.to_string() == value)
+            .ok_or_else(|| {
+                format!(
+                    "unknown preset {value:?}, expected node-modules, \
+                     build-target, browser-caches or trash"
+                )
+            })
+    }
+}
+
+impl fmt::Display for Preset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Self::NodeModules => "node-modules",
+            Self::BuildTarget => "build-target",
+            Self::BrowserCaches => "browser-caches",
+            Self::Trash => "trash",
+        })
+    }
+}
+
+/// What a backup includes of the directories it backs up
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupRules {
+    /// Gitignore-style patterns, relative to each directory backed up
+    pub patterns: Vec<String>,
+    /// The presets whose patterns to exclude
+    pub presets: Vec<Preset>,
+    /// The size above which files are left out
+    pub max_size: Option<u64>,
+    /// How long ago files must have been modified at most to be backed up
+    pub max_age: Option<TimeDelta>,
+    /// Whether directories tagged with `CACHEDIR.TAG` are left out
+    pub exclude_caches: bool,
+    /// Whether `.backmateignore` files are read
+    pub ignore_files: bool,
+}
+
+impl Default for BackupRules {
+    fn default() -> Self {
+        Self {
+            patterns: Vec::new(),
+            presets: Vec::new(),
+            max_size: None,
+            max_age: None,
+            exclude_caches: true,
+            ignore_files: true,
+        }
+    }
+}
+
+/// Whether a path is backed up, and why
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Decision {
+    /// Whether the path is backed up
+    pub included: bool,
+    /// The rule that decided it, for the user to read
+    pub reason: String,
+}
+
+impl Decision {
+    /// A path that is backed up
+    fn include(reason: impl Into<String>) -> Self {
+        Self {
+            included: true,
+            reason: reason.into(),
+        }
+    }
+
+    /// A path that is left out
+    fn exclude(reason: impl Into<String>) -> Self {
+        Self {
+            included: false,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Patterns from one place
+#[derive(Debug)]
+struct Layer {
+    /// The depth of the directory holding the ignore file the patterns are
+    /// from, or `None` for the rules and presets
+    depth: Option<usize>,
+    /// Where the patterns are from, for the user to read
+    origin: String,
+    /// The patterns
+    patterns: Gitignore,
+}
+
+/// The rules compiled for one directory being backed up
+#[derive(Debug)]
+pub(crate) struct Matcher {
+    /// The pattern layers, the last to match deciding
+    layers: Vec<Layer>,
+    /// Whether `.backmateignore` files are read
+    ignore_files: bool,
+    /// Whether directories tagged with `CACHEDIR.TAG` are left out
+    exclude_caches: bool,
+    /// The size above which files are left out
+    max_size: Option<u64>,
+    /// The time before which files must have been modified to be left out
+    modified_after: Option<DateTime<Utc>>,
+}
+
+/// Compile patterns anchored to a directory
+fn compile(
+    root: &Path,
+    patterns: &[impl AsRef<str>],
+) -> Result<Gitignore, EngineError> {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        builder.add_line(None, pattern).map_err(|error| {
+            EngineError::InvalidPattern {
+                pattern: pattern.to_owned(),
+                reason: error.to_string(),
+            }
+        })?;
+    }
+    builder.build().map_err(|error| EngineError::InvalidPattern {
+        pattern: patterns
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>()
+            .join(", "),
+        reason: error.to_string(),
+    })
+}
+
+/// Whether a directory holds a valid `CACHEDIR.TAG`
+fn is_tagged_cache(directory: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_SIGNATURE.len()];
+    fs::File::open(directory.join("CACHEDIR.TAG"))
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok_and(|()| &signature == CACHEDIR_SIGNATURE)
+}
+
+impl Matcher {
+    /// Compile the rules for a directory being backed up, for a run started
+    /// at `start`
+    pub(crate) fn new(
+        root: &Path,
+        rules: &BackupRules,
+        start: DateTime<Utc>,
+    ) -> Result<Self, EngineError> {
+        let mut layers = Vec::new();
+        for preset in &rules.presets {
+            layers.push(Layer {
+                depth: None,
+                origin: format!("of the {preset} preset"),
+                patterns: compile(root, preset.patterns())?,
+            });
+        }
+        layers.push(Layer {
+            depth: None,
+            origin: "of the backup rules".to_owned(),
+            patterns: compile(root, &rules.patterns)?,
+        });
+        Ok(Self {
+            layers,
+            ignore_files: rules.ignore_files,
+            exclude_caches: rules.exclude_caches,
+            max_size: rules.max_size,
+            modified_after: rules
+                .max_age
+                .and_then(|max_age| start.checked_sub_signed(max_age)),
+        })
+    }
+
+    /// Forget the ignore files of directories at `depth` or deeper, which
+    /// the walk has left
+    pub(crate) fn leave(&mut self, depth: usize) {
+        while self
+            .layers
+            .last()
+            .and_then(|layer| layer.depth)
+            .is_some_and(|layer| layer >= depth)
+        {
+            self.layers.pop();
+        }
+    }
+
+    /// Read the ignore file of a directory at `depth` that the walk entered
+    ///
+    /// Lines that aren't valid patterns are skipped with a warning.
+    pub(crate) fn enter(&mut self, directory: &Path, depth: usize) {
+        if !self.ignore_files {
+            return;
+        }
+        let path = directory.join(IGNORE_FILE);
+        if !path.is_file() {
+            return;
+        }
+        let mut builder = GitignoreBuilder::new(directory);
+        if let Some(error) = builder.add(&path) {
+            tracing::warn!(path = %path.display(), %error, "Skipping invalid ignore patterns");
+        }
+        match builder.build() {
+            Ok(patterns) => self.layers.push(Layer {
+                depth: Some(depth),
+                origin: format!("in {}", path.display()),
+                patterns,
+            }),
+            Err(error) => {
+                tracing::warn!(path = %path.display(), %error, "Skipping an invalid ignore file");
+            }
+        }
+    }
+
+    /// Whether a path under the directory being backed up is included
+    ///
+    /// The directories above it must have been entered, and included.
+    pub(crate) fn check(&self, path: &Path, metadata: &Metadata) -> Decision {
+        let is_dir = metadata.is_dir();
+        for layer in self.layers.iter().rev() {
+            match layer.patterns.matched(path, is_dir) {
+                Match::None => {}
+                Match::Ignore(glob) => {
+                    return Decision::exclude(format!(
+                        "excluded by pattern {:?} {}",
+                        glob.original(),
+                        layer.origin
+                    ));
+                }
+                Match::Whitelist(glob) => {
+                    return Decision::include(format!(
+                        "included by pattern {:?} {}",
+                        glob.original(),
+                        layer.origin
+                    ));
+                }
+            }
+        }
+        if is_dir && self.exclude_caches && is_tagged_cache(path) {
+            return Decision::exclude(
+                "excluded as a cache directory tagged with CACHEDIR.TAG",
+            );
+        }
+        if metadata.is_file() {
+            if let Some(max_size) =
+                self.max_size.filter(|max_size| metadata.len() > *max_size)
+            {
+                return Decision::exclude(format!(
+                    "excluded as larger than {max_size} bytes"
+                ));
+            }
+            let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+            if let Some(after) = self.modified_after.filter(|after| {
+                modified.is_some_and(|modified| modified < *after)
+            }) {
+                return Decision::exclude(format!(
+                    "excluded as last modified before {}",
+                    after.format("%Y-%m-%d %H:%M:%S")
+                ));
+            }
+        }
+        Decision::include("included as no rule excludes it")
+    }
+}
+
+impl BackupRules {
+    /// Explain whether a backup of some directories would include a path,
+    /// and why
+    ///
+    /// A path under an excluded directory is excluded because of it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a pattern is invalid, or if the
+    /// path or a directory above it can't be read.
+    pub fn explain(
+        &self,
+        sources: &[impl AsRef<Path>],
+        path: &Path,
+    ) -> Result<Decision, EngineError> {
+        let io = |path: &Path| {
+            let path = path.to_owned();
+            move |error| EngineError::Io {
+                path,
+                error,
+            }
+        };
+        // The path itself isn't resolved, in case it is a symlink
+        let path = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if parent != Path::new("") => {
+                parent.canonicalize().map_err(io(parent))?.join(name)
+            }
+            _ => path.canonicalize().map_err(io(path))?,
+        };
+        let mut roots = Vec::new();
+        for source in sources {
+            let source = source.as_ref();
+            roots.push(source.canonicalize().map_err(io(source))?);
+        }
+        let Some(root) = roots
+            .into_iter()
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+        else {
+            return Ok(Decision::exclude(
+                "excluded as it isn't under a directory being backed up",
+            ));
+        };
+        let mut matcher = Matcher::new(&root, self, Utc::now())?;
+        let parts = path
+            .strip_prefix(&root)
+            .map(|relative| relative.components().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let Some((last, parents)) = parts.split_last() else {
+            return Ok(Decision::include(
+                "included as a directory being backed up",
+            ));
+        };
+        matcher.enter(&root, 0);
+        let mut current = root;
+        for (depth, part) in parents.iter().enumerate() {
+            current.push(part);
+            let metadata =
+                fs::symlink_metadata(&current).map_err(io(&current))?;
+            let decision = matcher.check(&current, &metadata);
+            if !decision.included {
+                return Ok(Decision::exclude(format!(
+                    "excluded as {} is {}",
+                    current.display(),
+                    decision.reason
+                )));
+            }
+            matcher.enter(&current, depth + 1);
+        }
+        current.push(last);
+        let metadata = fs::symlink_metadata(&current).map_err(io(&current))?;
+        Ok(matcher.check(&current, &metadata))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::TimeDelta;
+
+    use super::{BackupRules, Preset, CACHEDIR_SIGNATURE};
+    use crate::EngineError;
+
+    #[test]
+    fn rules_explain_their_decisions() {
+        let root = tempfile::tempdir().expect("Temporary directory");
+        let home = root.path().join("home");
+        for directory in [
+            "project/node_modules/left-pad",
+            "project/build",
+            "project/logs",
+            ".cache/thumbnails",
+        ] {
+            fs::create_dir_all(home.join(directory)).expect("Create directory");
+        }
+        for file in [
+            "project/node_modules/left-pad/index.js",
+            "project/build/app",
+            "project/logs/today.log",
+            "project/logs/keep.log",
+            "notes.txt",
+        ] {
+            fs::write(home.join(file), "data").expect("Write file");
+        }
+        fs::write(home.join("project/video.mp4"), vec![0; 2048])
+            .expect("Write file");
+        fs::write(home.join("project/.backmateignore"), "/build\n*.log\n")
+            .expect("Write file");
+        fs::write(home.join("project/logs/.backmateignore"), "!keep.log\n")
+            .expect("Write file");
+        fs::write(home.join(".cache/CACHEDIR.TAG"), CACHEDIR_SIGNATURE)
+            .expect("Write file");
+
+        let rules = BackupRules {
+            patterns: vec!["*.txt".to_owned(), "!notes.txt".to_owned()],
+            presets: vec![Preset::NodeModules],
+            max_size: Some(1024),
+            max_age: Some(TimeDelta::days(1)),
+            ..BackupRules::default()
+        };
+        let explain = |path: &str| {
+            rules.explain(&[&home], &home.join(path)).expect("Explained")
+        };
+        let decision = explain("project/node_modules");
+        assert!(!decision.included);
+        assert_eq!(
+            decision.reason,
+            "excluded by pattern \"node_modules/\" of the node-modules preset"
+        );
+        let decision = explain("project/node_modules/left-pad/index.js");
+        assert!(!decision.included);
+        assert!(decision.reason.contains("node_modules is excluded by"));
+        let decision = explain("project/build");
+        assert!(!decision.included);
+        assert!(decision.reason.contains(".backmateignore"));
+        assert!(!explain("project/logs/today.log").included);
+        let decision = explain("project/logs/keep.log");
+        assert!(decision.included);
+        assert!(decision.reason.contains("\"!keep.log\""));
+        let decision = explain("notes.txt");
+        assert!(decision.included);
+        assert!(decision.reason.contains("of the backup rules"));
+        assert_eq!(
+            explain(".cache").reason,
+            "excluded as a cache directory tagged with CACHEDIR.TAG"
+        );
+        assert_eq!(
+            explain("project/video.mp4").reason,
+            "excluded as larger than 1024 bytes"
+        );
+        assert!(explain("project").included);
+        assert!(explain("").included);
+
+        let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(home.join("project/logs/keep.log"), old)
+            .expect("Set time");
+        // Patterns that include a file win over the other filters
+        assert!(explain("project/logs/keep.log").included);
+        filetime::set_file_mtime(home.join("project/build/app"), old)
+            .expect("Set time");
+        let stale = BackupRules {
+            max_age: Some(TimeDelta::days(1)),
+            ignore_files: false,
+            ..BackupRules::default()
+        };
+        let decision = stale
+            .explain(&[&home], &home.join("project/build/app"))
+            .expect("Explained");
+        assert!(decision.reason.starts_with("excluded as last modified"));
+
+        let error = BackupRules {
+            patterns: vec!["logs/{a".to_owned()],
+            ..BackupRules::default()
+        }
+        .explain(&[&home], &home)
+        .expect_err("Pattern is invalid");
+        assert!(matches!(error, EngineError::InvalidPattern { .. }));
+        assert_eq!("trash".parse(), Ok(Preset::Trash));
+    }
+}
@@ -0,0 +1,129 @@
+//! Snapshots and their manifests
+//!
+//! A snapshot records the state of the backed up directories at the time of
+//! a backup run. It is written once and never changed, and refers to file
+//! contents only by the IDs of content objects, so any number of snapshots
+//! can share the same data.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::compression::ratio;
+
+/// A backup run, as recorded in its manifest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    /// The unique ID of the snapshot, which sorts by time
+    pub id: String,
+    /// When the backup run started
+    pub time: DateTime<Utc>,
+    /// The name of the machine that was backed up
+    pub hostname: String,
+    /// The directories that were backed up
+    pub sources: Vec<Source>,
+    /// Labels given to the snapshot, which retention policies can keep it
+    /// by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Every directory, file and symlink in the sources, in path order
+    pub entries: Vec<Entry>,
+    /// The total size of the chunks the run uploaded
+    pub uploaded_bytes: u64,
+    /// The total size of the chunks the run uploaded, as stored after
+    /// compression
+    pub stored_bytes: u64,
+}
+
+impl Snapshot {
+    /// How many times smaller compression made the chunks the run uploaded,
+    /// or `None` if it uploaded nothing
+    #[must_use]
+    pub fn compression_ratio(&self) -> Option<f64> {
+        ratio(self.uploaded_bytes, self.stored_bytes)
+    }
+}
+
+/// A directory that was backed up
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Source {
+    /// The name the directory's entries are recorded under
+    ///
+    /// This is the last component of its path, made unique within the
+    /// snapshot.
+    pub name: String,
+    /// The absolute path of the directory
+    pub path: PathBuf,
+}
+
+/// What an entry in a snapshot is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EntryKind {
+    /// A directory
+    Directory,
+    /// A regular file
+    File,
+    /// A symbolic link, which isn't followed
+    Symlink,
+}
+
+/// A directory, file or symlink in a snapshot
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    /// The path of the entry, starting with the name of its source and
+    /// separated by `/`
+    pub path: String,
+    /// What the entry is
+    pub kind: EntryKind,
+    /// The size of a file in bytes, and 0 for anything else
+    pub size: u64,
+    /// When the entry was last modified, if the platform reports it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Utc>>,
+    /// The Unix permission bits of the entry, if the platform has them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Where a symlink points
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// The IDs of the content objects holding a file's data, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content: Vec<String>,
+}
+
+/// A new snapshot ID for a run started at `time`
+///
+/// IDs start with the time so that they sort in the order snapshots were
+/// taken, and end with random digits so that runs started in the same second
+/// on different machines don't collide.
+pub(crate) fn new_snapshot_id(time: DateTime<Utc>) -> String {
+    format!("{}-{:08x}", time.format("%Y%m%dT%H%M%SZ"), rand::random::<u32>())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::snapshot::new_snapshot_id;
+
+    #[test]
+    fn ids_sort_by_time() {
+        let earlier = Utc
+            .with_ymd_and_hms(2026, 3, 9, 23, 59, 59)
+            .single()
+            .expect("Date is valid");
+        let later = Utc
+            .with_ymd_and_hms(2026, 10, 1, 0, 0, 0)
+            .single()
+            .expect("Date is valid");
+        let id = new_snapshot_id(earlier);
+        assert!(id.starts_with("20260309T235959Z-"));
+        assert_eq!(id.len(), 25);
+        assert!(id < new_snapshot_id(later));
+    }
+}
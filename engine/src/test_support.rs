@@ -0,0 +1,31 @@
+//! Helpers shared by the tests of the engine
+
+use b2fake::FakeB2;
+use b2native::{Bucket, Session};
+
+use crate::KdfParams;
+
+/// Key derivation parameters that keep tests fast
+pub(crate) const CHEAP: KdfParams = KdfParams {
+    memory_kib: 64,
+    iterations: 1,
+    parallelism: 1,
+};
+
+/// Open the bucket tests keep repositories in with the master key of a fake
+/// server, creating it the first time
+pub(crate) async fn bucket(server: &FakeB2) -> Bucket {
+    let credentials = server.master_credentials();
+    let session = Session::try_new_with_endpoint(
+        &server.authorize_url(),
+        credentials.key_id,
+        credentials.key,
+    )
+    .await
+    .expect("Key should authorize");
+    if let Ok(bucket) = session.bucket("backups").await {
+        return bucket;
+    }
+    let _id = server.create_bucket("backups", "allPrivate");
+    session.bucket("backups").await.expect("Bucket should exist")
+}
@@ -1,5 +1,17 @@
 #![doc = include_str!("../README.md")]
 
+use std::{
+    path::Path,
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use b2native::{
+    KeyExpiryWarning, Session, SessionError, DEFAULT_KEY_EXPIRY_WARNING,
+};
+use backmate_engine::{
+    BackupOptions, BackupSummary, ConflictPolicy, EngineError, FileCache,
+    InitOptions, Repository, RestoreOptions, RestoreSummary, DEFAULT_PREFIX,
+};
 use tauri::{Manager, State};
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
@@ -9,20 +21,150 @@ use tauri::{Manager, State};
 // }
 
 /// Test if the user is currently logged in.
-///
-/// This is currently a testing function with no real functionality
 #[tauri::command]
 #[allow(clippy::needless_pass_by_value)]
 fn logged_in(state: State<'_, Auth>) -> bool {
-    state.logged_in
+    state.session.read().unwrap_or_else(PoisonError::into_inner).is_some()
+}
+
+/// Log in with an application key, replacing any current session
+///
+/// Errors are returned as messages to show.
+#[tauri::command(rename_all = "snake_case")]
+async fn login(
+    state: State<'_, Auth>,
+    api_key_id: String,
+    api_key: String,
+) -> Result<(), String> {
+    let session = Session::try_new(api_key_id, api_key).await.map_err(
+        |error| match error {
+            SessionError::AuthenticationRejected {
+                message,
+                ..
+            } => message,
+            _ => "Backblaze couldn't be reached, or sent something unexpected"
+                .to_owned(),
+        },
+    )?;
+    *state.session.write().unwrap_or_else(PoisonError::into_inner) =
+        Some(session);
+    Ok(())
+}
+
+/// Check whether the application key of the current session expires soon
+///
+/// Returns nothing when no one is logged in or the key doesn't expire within
+/// the warning period, so the frontend only shows its banner when needed.
+#[tauri::command]
+#[allow(clippy::needless_pass_by_value)]
+fn key_expiry_warning(state: State<'_, Auth>) -> Option<KeyExpiryWarning> {
+    state
+        .session
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()?
+        .key_expiry_warning(DEFAULT_KEY_EXPIRY_WARNING)
+}
+
+/// Back up local directories into a bucket of the current session
+///
+/// The repository is created under [`DEFAULT_PREFIX`] the first time a
+/// bucket is backed up into, and `passphrase` wraps its master key. Files
+/// the local cache shows are unchanged aren't read. Errors are returned as
+/// messages to show.
+#[tauri::command]
+async fn backup(
+    state: State<'_, Auth>,
+    cache: State<'_, LocalCache>,
+    bucket: String,
+    passphrase: String,
+    paths: Vec<String>,
+) -> Result<BackupSummary, String> {
+    let session = state
+        .session
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .ok_or_else(|| "Not logged in".to_owned())?;
+    let bucket = session
+        .bucket(&bucket)
+        .await
+        .map_err(|error| EngineError::from(error).to_string())?;
+    let repository = Repository::open_or_init(
+        bucket,
+        DEFAULT_PREFIX,
+        &passphrase,
+        &InitOptions::default(),
+    )
+    .await
+    .map_err(|error| error.to_string())?;
+    repository
+        .backup(
+            &paths,
+            &BackupOptions {
+                cache: cache.0.clone(),
+                ..BackupOptions::default()
+            },
+        )
+        .await
+        .map_err(|error| error.to_string())
+}
+
+/// Restore a snapshot from a bucket of the current session into a directory
+///
+/// Without a snapshot ID the newest snapshot is restored, and without
+/// include paths the whole snapshot is. Running the same restore again
+/// resumes it if it was interrupted.
+#[tauri::command]
+async fn restore(
+    state: State<'_, Auth>,
+    bucket: String,
+    passphrase: String,
+    snapshot_id: Option<String>,
+    target: String,
+    include: Vec<String>,
+    conflict: ConflictPolicy,
+) -> Result<RestoreSummary, String> {
+    let session = state
+        .session
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .ok_or_else(|| "Not logged in".to_owned())?;
+    let bucket = session
+        .bucket(&bucket)
+        .await
+        .map_err(|error| EngineError::from(error).to_string())?;
+    let repository = Repository::open(bucket, DEFAULT_PREFIX, &passphrase)
+        .await
+        .map_err(|error| error.to_string())?;
+    let snapshot = match snapshot_id {
+        Some(id) => repository.snapshot(&id).await,
+        None => repository.latest_snapshot(None).await,
+    }
+    .map_err(|error| error.to_string())?;
+    let options = RestoreOptions {
+        include,
+        conflict,
+        ..RestoreOptions::default()
+    };
+    repository
+        .restore(&snapshot, Path::new(&target), &options)
+        .await
+        .map_err(|error| error.to_string())
 }
 
 /// A struct for managing state regarding user authentication
 pub struct Auth {
-    /// If the user is logged in or not
-    pub logged_in: bool,
+    /// The session of the logged in user, if anyone is logged in
+    pub session: RwLock<Option<Session>>,
 }
 
+/// The local cache of backed up files, if it could be opened
+///
+/// Backups work without it, only more slowly.
+pub struct LocalCache(pub Option<Arc<FileCache>>);
+
 /// Run the tauri application
 ///
 /// # Panics
@@ -33,11 +175,22 @@ pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_http::init())
         .plugin(tauri_plugin_shell::init())
-        .invoke_handler(tauri::generate_handler![logged_in])
+        .invoke_handler(tauri::generate_handler![
+            logged_in,
+            login,
+            key_expiry_warning,
+            backup,
+            restore
+        ])
         .setup(|app| {
             app.manage(Auth {
-                logged_in: false,
+                session: RwLock::default(),
             });
+            let cache =
+                app.path().app_cache_dir().ok().and_then(|dir| {
+                    FileCache::open(dir.join("files.sqlite")).ok()
+                });
+            app.manage(LocalCache(cache.map(Arc::new)));
             Ok(())
         })
         .run(tauri::generate_context!())
@@ -13,7 +13,15 @@ extern "C" {
     #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "core"])]
     // Allow unused qualifications because this confuses Clippy
     #[allow(unused_qualifications)]
-    async fn invoke(cmd: &str, args: JsValue) -> JsValue;
+    pub(crate) async fn invoke(cmd: &str, args: JsValue) -> JsValue;
+
+    /// Invoke a command whose errors the caller handles
+    #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "core"], js_name = invoke, catch)]
+    #[allow(unused_qualifications)]
+    pub(crate) async fn try_invoke(
+        cmd: &str,
+        args: JsValue,
+    ) -> Result<JsValue, JsValue>;
 }
 
 // #[derive(Serialize, Deserialize)]
@@ -1,9 +1,62 @@
 //! The Dashboard view where the main app interactions will go
 
 use dioxus::prelude::*;
+use serde::Deserialize;
+use wasm_bindgen::JsValue;
+
+use crate::app::invoke;
+
+/// A warning that the application key expires soon, as sent by the backend
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyExpiryWarning {
+    /// When the key expires, as an RFC 3339 timestamp
+    expires_at: String,
+    /// Whether the key has already expired
+    expired: bool,
+    /// How many whole days are left before the key expires
+    days_remaining: u64,
+}
+
+/// Render a banner when the application key expires soon
+#[allow(non_snake_case)]
+fn KeyExpiryBanner() -> Element {
+    let warning = use_resource(|| async {
+        let value = invoke("key_expiry_warning", JsValue::NULL).await;
+        serde_wasm_bindgen::from_value::<Option<KeyExpiryWarning>>(value)
+            .ok()
+            .flatten()
+    });
+    let Some(Some(warning)) = &*warning.read() else {
+        return rsx! {};
+    };
+    let message = if warning.expired {
+        format!(
+            "Your application key expired at {}. Backups will fail until you \
+             sign in with a new key.",
+            warning.expires_at
+        )
+    } else {
+        format!(
+            "Your application key expires in {} days, at {}. Create a new key \
+             before then to keep backups running.",
+            warning.days_remaining, warning.expires_at
+        )
+    };
+    rsx! {
+        div {
+            class: "p-4 mb-4 text-sm text-yellow-800 rounded-lg bg-yellow-50 dark:bg-gray-800 dark:text-yellow-300",
+            role: "alert",
+            "{message}"
+        }
+    }
+}
 
 /// Render the dashboard view
 #[allow(non_snake_case)]
 pub(crate) fn Dashboard() -> Element {
-    rsx! { h1 {"Dashboard View"} }
+    rsx! {
+        KeyExpiryBanner {}
+        h1 {"Dashboard View"}
+    }
 }
@@ -3,7 +3,7 @@
 use dioxus::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::views::Route;
+use crate::{app::try_invoke, views::Route};
 
 /// Arguments used to invoke authentication via tauri
 #[derive(Serialize, Deserialize)]
@@ -14,9 +14,39 @@ struct LoginArgs<'a> {
     api_key: &'a str,
 }
 
+/// Log in through the backend, returning the message to show if it fails
+async fn login(api_key_id: &str, api_key: &str) -> Result<(), String> {
+    let args = serde_wasm_bindgen::to_value(&LoginArgs {
+        api_key_id,
+        api_key,
+    })
+    .map_err(|error| error.to_string())?;
+    try_invoke("login", args).await.map(|_| ()).map_err(|error| {
+        error.as_string().unwrap_or_else(|| "Sign in failed".to_owned())
+    })
+}
+
 /// Render the login view
 #[allow(non_snake_case)]
+// The event handlers `rsx!` generates confuse the lint
+#[allow(unused_qualifications)]
 pub(crate) fn Login() -> Element {
+    let mut api_key_id = use_signal(String::new);
+    let mut api_key = use_signal(String::new);
+    let mut error = use_signal(|| None::<String>);
+    let navigator = use_navigator();
+    let submit = move |event: FormEvent| {
+        event.prevent_default();
+        let (id, key) = (api_key_id(), api_key());
+        spawn(async move {
+            match login(&id, &key).await {
+                Ok(()) => {
+                    navigator.push(Route::Dashboard {});
+                }
+                Err(message) => error.set(Some(message)),
+            }
+        });
+    };
     rsx! {
         section {
             class: "bg-gray-50 dark:bg-gray-900",
@@ -39,9 +69,17 @@ pub(crate) fn Login() -> Element {
                             class: "text-xl font-bold leading-tight tracking-tight text-gray-900 md:text-2xl dark:text-white",
                             "Sign in to your account"
                         }
+                        if let Some(message) = error() {
+                            div {
+                                class: "p-4 text-sm text-red-800 rounded-lg bg-red-50 dark:bg-gray-800 dark:text-red-400",
+                                role: "alert",
+                                "{message}"
+                            }
+                        }
                         form {
                             class: "space-y-4 md:space-y-6",
                             action: "#",
+                            onsubmit: submit,
                             div {
                                 label {
                                     class: "block mb-2 text-sm font-medium text-gray-900 dark:text-white",
@@ -50,7 +88,11 @@ pub(crate) fn Login() -> Element {
                                 input {
                                     class: "bg-gray-50 border border-gray-300 text-gray-900 rounded-lg focus:ring-primary-600 focus:border-primary-600 block w-full p-2.5 dark:bg-gray-700 dark:border-gray-600 dark:placeholder-gray-400 dark:text-white dark:focus:ring-blue-500 dark:focus:border-blue-500",
                                     placeholder: "",
-                                    required: ""
+                                    required: "",
+                                    value: "{api_key_id}",
+                                    oninput: move |event: FormEvent| {
+                                        api_key_id.set(event.value());
+                                    }
                                 }
                             }
                             div {
@@ -60,20 +102,23 @@ pub(crate) fn Login() -> Element {
                                 },
                                 input {
                                     class: "bg-gray-50 border border-gray-300 text-gray-900 rounded-lg focus:ring-primary-600 focus:border-primary-600 block w-full p-2.5 dark:bg-gray-700 dark:border-gray-600 dark:placeholder-gray-400 dark:text-white dark:focus:ring-blue-500 dark:focus:border-blue-500",
+                                    r#type: "password",
                                     placeholder: "••••••••",
-                                    required: ""
+                                    required: "",
+                                    value: "{api_key}",
+                                    oninput: move |event: FormEvent| {
+                                        api_key.set(event.value());
+                                    }
                                 }
                             }
                             div {
                                 class: "mt-2",
-                                Link {
-                                to: Route::Dashboard {},
                                 button {
+                                    r#type: "submit",
                                     class: "w-full text-white bg-red-600 hover:bg-primary-700 focus:ring-4 focus:outline-none focus:ring-primary-300 font-medium rounded-lg text-sm px-5 py-2.5 text-center dark:bg-primary-600 dark:hover:bg-primary-700 dark:focus:ring-primary-800",
                                     "Sign In"
                                 }
                             }
-                            }
                         }
                     }
                 }